@@ -0,0 +1,675 @@
+use crate::commands::{console_commands, parse_command, ClientCommand};
+use crate::output::{display_name, DisplayNames, Event, Output};
+use common::communication::common_message::{
+    ClientToServerMessage, CommandInfo, ContactInfo, DirectoryPage, DirectoryQuery, ExportFormat, MessageInfo, PrivacySettings, ProfileInfo, SearchQuery, SearchResults, ServerToClientMessage,
+    UserInfo,
+};
+use common::logic::command::{
+    ArgKind, ArgSpec, Command, CommandArgs, CommandError, CommandSet, CommandSpec, FlagSpec,
+    Invocation,
+};
+use common::communication::avatar::parse_avatar_frame;
+use common::logic::input_parser::{parse_input, tokens_from_args};
+use futures_util::{SinkExt, StreamExt};
+use std::fmt;
+use std::process::ExitCode;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+// exit codes of the one-shot commands
+const EXIT_FAILED: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_CONNECTION: u8 = 3;
+
+const SERVER_ENV: &str = "CHAT_SERVER";
+const BOT_KEY_ENV: &str = "CHAT_BOT_KEY";
+const PASSWORD_ENV: &str = "CHAT_PASSWORD";
+
+const SERVER: FlagSpec = FlagSpec::with_value(
+    "server",
+    ArgKind::Text,
+    "server address, ws:// may be left out; defaults to $CHAT_SERVER",
+);
+const AS: FlagSpec = FlagSpec::with_value("as", ArgKind::Text, "username to set before anything else");
+const BOT_KEY: FlagSpec = FlagSpec::with_value(
+    "bot-key",
+    ArgKind::Text,
+    "log in as the bot named with --as; defaults to $CHAT_BOT_KEY",
+);
+const PASSWORD: FlagSpec = FlagSpec::with_value(
+    "password",
+    ArgKind::Text,
+    "log in to the account named with --as; defaults to $CHAT_PASSWORD",
+);
+const JSON: FlagSpec = FlagSpec::switch("json", "print one JSON object per line instead of text");
+
+// where to connect to, who to be and how to print, shared by all subcommands
+pub struct Connection {
+    server: Option<String>,
+    username: Option<String>,
+    bot_key: Option<String>,
+    password: Option<String>,
+    json: bool,
+}
+
+impl Connection {
+    fn from_args(args: &CommandArgs) -> Self {
+        Connection {
+            server: args.optional_string("server"),
+            username: args.optional_string("as"),
+            bot_key: args
+                .optional_string("bot-key")
+                .or_else(|| std::env::var(BOT_KEY_ENV).ok()),
+            password: args
+                .optional_string("password")
+                .or_else(|| std::env::var(PASSWORD_ENV).ok()),
+            json: args.flag("json"),
+        }
+    }
+
+    fn login(&self) -> Option<ClientToServerMessage> {
+        let username = self.username.clone()?;
+        Some(match (&self.bot_key, &self.password) {
+            (Some(key), _) => ClientToServerMessage::AuthenticateBot(username, key.clone()),
+            (None, Some(password)) => ClientToServerMessage::Login(username, password.clone()),
+            (None, None) => ClientToServerMessage::SetUsername(username),
+        })
+    }
+
+    fn url(&self) -> Option<String> {
+        let server = self
+            .server
+            .clone()
+            .or_else(|| std::env::var(SERVER_ENV).ok())?;
+        if server.contains("://") {
+            Some(server)
+        } else {
+            Some(format!("ws://{}", server))
+        }
+    }
+}
+
+pub struct SendOnce {
+    connection: Connection,
+    to: String,
+    message: String,
+}
+
+impl Command for SendOnce {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "send",
+        aliases: &["msg"],
+        args: &[ArgSpec::required("message", ArgKind::Text)],
+        flags: &[
+            SERVER,
+            AS,
+            BOT_KEY,
+            PASSWORD,
+            JSON,
+            FlagSpec::with_value("to", ArgKind::Username, "user to send the message to"),
+        ],
+        help: "send one message and exit",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(SendOnce {
+            connection: Connection::from_args(args),
+            to: args.string("to")?,
+            message: args.string("message")?,
+        })
+    }
+}
+
+pub struct UsersOnce {
+    connection: Connection,
+}
+
+impl Command for UsersOnce {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "users",
+        aliases: &["usernames"],
+        args: &[],
+        flags: &[SERVER, AS, BOT_KEY, PASSWORD, JSON],
+        help: "print the users currently online, one per line",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(UsersOnce {
+            connection: Connection::from_args(args),
+        })
+    }
+}
+
+pub struct Batch {
+    connection: Connection,
+    file: Option<String>,
+}
+
+impl Command for Batch {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "batch",
+        aliases: &[],
+        args: &[ArgSpec::optional("file", ArgKind::Text)],
+        flags: &[SERVER, AS, BOT_KEY, PASSWORD, JSON],
+        help: "run console commands line by line from a file, or from stdin without one",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Batch {
+            connection: Connection::from_args(args),
+            file: args.optional_string("file"),
+        })
+    }
+}
+
+pub enum CliCommand {
+    Send(SendOnce),
+    Users(UsersOnce),
+    Batch(Batch),
+}
+
+impl CliCommand {
+    fn connection(&self) -> &Connection {
+        match self {
+            CliCommand::Send(send) => &send.connection,
+            CliCommand::Users(users) => &users.connection,
+            CliCommand::Batch(batch) => &batch.connection,
+        }
+    }
+}
+
+fn cli_commands() -> CommandSet<CliCommand> {
+    CommandSet::new()
+        .with(CliCommand::Send)
+        .with(CliCommand::Users)
+        .with(CliCommand::Batch)
+}
+
+enum Failure {
+    Usage(String),
+    // the server answered a request with an error
+    Operation(String),
+    Connection(String),
+}
+
+impl Failure {
+    fn event(&self, line: Option<usize>) -> Event {
+        Event::Error {
+            message: self.to_string(),
+            line,
+        }
+    }
+
+    fn exit_code(&self) -> ExitCode {
+        match self {
+            Failure::Usage(_) => ExitCode::from(EXIT_USAGE),
+            Failure::Operation(_) => ExitCode::from(EXIT_FAILED),
+            Failure::Connection(_) => ExitCode::from(EXIT_CONNECTION),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Usage(e) => write!(f, "{}", e),
+            Failure::Operation(e) => write!(f, "Operation failed: {}", e),
+            Failure::Connection(e) => write!(f, "Connection failed: {}", e),
+        }
+    }
+}
+
+struct Session {
+    ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    output: Output,
+    names: DisplayNames,
+}
+
+// avatars come as binary frames, everything else as JSON
+enum Reply {
+    Message(ServerToClientMessage),
+    Avatar(String, Vec<u8>),
+}
+
+impl Session {
+    async fn open(connection: &Connection, output: Output) -> Result<Session, Failure> {
+        let Some(url) = connection.url() else {
+            return Err(Failure::Usage(format!(
+                "No server given, use --server or set {}",
+                SERVER_ENV
+            )));
+        };
+        let (ws_stream, _) = connect_async(url.as_str())
+            .await
+            .map_err(|e| Failure::Connection(e.to_string()))?;
+        let mut session = Session {
+            ws_stream,
+            output,
+            names: DisplayNames::default(),
+        };
+        session.state_changed(Event::Connected { server: url });
+        if let Some(login) = connection.login() {
+            let response = match session.request(login).await {
+                Ok(response) => response,
+                Err(failure) => {
+                    session.close().await;
+                    return Err(failure);
+                }
+            };
+            session.state_changed(Event::Response {
+                ok: true,
+                message: response,
+            });
+        }
+        Ok(session)
+    }
+
+    // scripts reading text only care about results, JSON readers get the whole picture
+    fn state_changed(&self, event: Event) {
+        if self.output.json {
+            self.output.emit(&event);
+        }
+    }
+
+    fn usernames_received(&mut self, mut users: Vec<UserInfo>) {
+        self.names.learn_users(&users);
+        if self.output.json {
+            self.output.emit(&Event::usernames(users));
+        } else {
+            users.sort_by(|a, b| a.username.cmp(&b.username));
+            for user in users {
+                println!("{}", display_name(&user.username, user.bot));
+            }
+        }
+    }
+
+    async fn send(&mut self, message: ClientToServerMessage) -> Result<(), Failure> {
+        self.ws_stream
+            .send(crate::frame(&message))
+            .await
+            .map_err(|e| Failure::Connection(e.to_string()))
+    }
+
+    // the next reply from the server, chat messages arriving in between are printed
+    async fn reply(&mut self) -> Result<ServerToClientMessage, Failure> {
+        match self.next_reply().await? {
+            Reply::Message(message) => Ok(message),
+            Reply::Avatar(username, _) => Err(Failure::Connection(format!("unexpected avatar of {}", username))),
+        }
+    }
+
+    async fn next_reply(&mut self) -> Result<Reply, Failure> {
+        loop {
+            let text = match self.ws_stream.next().await {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Binary(frame))) => match parse_avatar_frame(&frame) {
+                    Some((username, image)) => return Ok(Reply::Avatar(username, image.to_vec())),
+                    None => return Err(Failure::Connection("invalid avatar frame".to_string())),
+                },
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(Failure::Connection("server closed the connection".to_string()))
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(Failure::Connection(e.to_string())),
+            };
+            match serde_json::from_str(&text) {
+                Ok(ServerToClientMessage::TextFrom(id, from, text, reply_to)) => {
+                    let name = self.names.get(&from);
+                    self.output.emit(&Event::Message { id: Some(id), from, name, text, bot: false, reply_to });
+                }
+                Ok(ServerToClientMessage::BotTextFrom(id, from, text, reply_to)) => {
+                    let name = self.names.get(&from);
+                    self.output.emit(&Event::Message { id, from, name, text, bot: true, reply_to });
+                }
+                Ok(ServerToClientMessage::SentText(id, to, text, reply_to)) => {
+                    self.output.emit(&Event::Sent { id, to, text, reply_to });
+                }
+                Ok(ServerToClientMessage::GroupTextFrom(id, group, from, text, reply_to)) => {
+                    let name = self.names.get(&from);
+                    self.output.emit(&Event::GroupMessage { id, group, from, name, text, reply_to });
+                }
+                Ok(ServerToClientMessage::GroupUpdated(info)) => {
+                    self.output.emit(&Event::Group {
+                        group: info.id,
+                        members: info.members,
+                    });
+                }
+                Ok(ServerToClientMessage::MessageEdited(id, text)) => {
+                    self.output.emit(&Event::Edited { id, text });
+                }
+                Ok(ServerToClientMessage::MessageDeleted(id)) => {
+                    self.output.emit(&Event::Deleted { id });
+                }
+                Ok(ServerToClientMessage::ReactionsUpdated(id, reactions)) => {
+                    self.output.emit(&Event::Reactions { id, reactions });
+                }
+                Ok(ServerToClientMessage::ContactUpdated(info)) => self.output.emit(&Event::contact(info)),
+                Ok(ServerToClientMessage::ContactRemoved(username)) => {
+                    self.output.emit(&Event::ContactRemoved { username });
+                }
+                Ok(ServerToClientMessage::ProfileUpdated(profile)) => {
+                    self.names.learn(&profile);
+                }
+                Ok(ServerToClientMessage::None) => {}
+                Ok(message) => return Ok(Reply::Message(message)),
+                Err(e) => return Err(Failure::Connection(format!("invalid message: {}", e))),
+            }
+        }
+    }
+
+    // sends a request the server answers with a Response
+    async fn request(&mut self, message: ClientToServerMessage) -> Result<String, Failure> {
+        self.send(message).await?;
+        match self.reply().await? {
+            ServerToClientMessage::Response(result) => result.map_err(Failure::Operation),
+            other => Err(Failure::Connection(format!("unexpected reply {:?}", other))),
+        }
+    }
+
+    async fn usernames(&mut self) -> Result<Vec<UserInfo>, Failure> {
+        self.send(ClientToServerMessage::GetUserList).await?;
+        match self.reply().await? {
+            ServerToClientMessage::UserList(users) => Ok(users),
+            other => Err(Failure::Connection(format!("unexpected reply {:?}", other))),
+        }
+    }
+
+    async fn thread(&mut self, id: u64) -> Result<Vec<MessageInfo>, Failure> {
+        self.send(ClientToServerMessage::GetThread(id)).await?;
+        match self.reply().await? {
+            ServerToClientMessage::Thread(messages) => Ok(messages),
+            ServerToClientMessage::Response(Err(e)) => Err(Failure::Operation(e)),
+            other => Err(Failure::Connection(format!("unexpected reply {:?}", other))),
+        }
+    }
+
+    async fn search(&mut self, query: SearchQuery) -> Result<SearchResults, Failure> {
+        self.send(ClientToServerMessage::Search(query)).await?;
+        match self.reply().await? {
+            ServerToClientMessage::SearchResults(results) => Ok(results),
+            ServerToClientMessage::Response(Err(e)) => Err(Failure::Operation(e)),
+            other => Err(Failure::Connection(format!("unexpected reply {:?}", other))),
+        }
+    }
+
+    // returns the export and how many messages it holds
+    async fn export(&mut self, conversation: Option<String>, format: ExportFormat) -> Result<(String, usize), Failure> {
+        self.send(ClientToServerMessage::Export { conversation, format }).await?;
+        match self.reply().await? {
+            ServerToClientMessage::Exported { content, messages } => Ok((content, messages)),
+            ServerToClientMessage::Response(Err(e)) => Err(Failure::Operation(e)),
+            other => Err(Failure::Connection(format!("unexpected reply {:?}", other))),
+        }
+    }
+
+    async fn privacy(&mut self, contacts_only: Option<bool>, hide_presence: Option<bool>) -> Result<PrivacySettings, Failure> {
+        self.send(ClientToServerMessage::SetPrivacy { contacts_only, hide_presence }).await?;
+        match self.reply().await? {
+            ServerToClientMessage::Privacy(settings) => Ok(settings),
+            ServerToClientMessage::Response(Err(e)) => Err(Failure::Operation(e)),
+            other => Err(Failure::Connection(format!("unexpected reply {:?}", other))),
+        }
+    }
+
+    async fn contacts(&mut self) -> Result<Vec<ContactInfo>, Failure> {
+        self.send(ClientToServerMessage::GetContacts).await?;
+        match self.reply().await? {
+            ServerToClientMessage::Contacts(contacts) => Ok(contacts),
+            ServerToClientMessage::Response(Err(e)) => Err(Failure::Operation(e)),
+            other => Err(Failure::Connection(format!("unexpected reply {:?}", other))),
+        }
+    }
+
+    async fn directory(&mut self, query: DirectoryQuery) -> Result<DirectoryPage, Failure> {
+        self.send(ClientToServerMessage::Directory(query)).await?;
+        match self.reply().await? {
+            ServerToClientMessage::Directory(page) => Ok(page),
+            ServerToClientMessage::Response(Err(e)) => Err(Failure::Operation(e)),
+            other => Err(Failure::Connection(format!("unexpected reply {:?}", other))),
+        }
+    }
+
+    // asking for a profile and changing one's own are both answered with the profile
+    async fn profile(&mut self, message: ClientToServerMessage) -> Result<ProfileInfo, Failure> {
+        self.send(message).await?;
+        match self.reply().await? {
+            ServerToClientMessage::Profile(profile) => {
+                self.names.learn(&profile);
+                Ok(profile)
+            }
+            ServerToClientMessage::Response(Err(e)) => Err(Failure::Operation(e)),
+            other => Err(Failure::Connection(format!("unexpected reply {:?}", other))),
+        }
+    }
+
+    async fn avatar(&mut self, username: String) -> Result<Vec<u8>, Failure> {
+        self.send(ClientToServerMessage::GetAvatar(username)).await?;
+        match self.next_reply().await? {
+            Reply::Avatar(_, image) => Ok(image),
+            Reply::Message(ServerToClientMessage::Response(Err(e))) => Err(Failure::Operation(e)),
+            Reply::Message(other) => Err(Failure::Connection(format!("unexpected reply {:?}", other))),
+        }
+    }
+
+    async fn commands(&mut self) -> Result<Vec<CommandInfo>, Failure> {
+        self.send(ClientToServerMessage::GetCommands).await?;
+        match self.reply().await? {
+            ServerToClientMessage::Commands(infos) => Ok(infos),
+            other => Err(Failure::Connection(format!("unexpected reply {:?}", other))),
+        }
+    }
+
+    async fn close(mut self) {
+        let _ = self.ws_stream.close(None).await;
+        self.state_changed(Event::Disconnected {
+            reason: "closed by user".to_string(),
+        });
+    }
+}
+
+// runs a subcommand given on the command line instead of the interactive console, output is
+// JSON with --json before the subcommand, which covers usage errors too, or after it
+pub async fn run(args: Vec<String>, output: Output) -> ExitCode {
+    let commands = cli_commands();
+    let command = match commands.parse(&tokens_from_args(args)) {
+        Ok(Invocation::Command(command)) => command,
+        Ok(Invocation::Help(help)) => {
+            output.emit(&Event::notice(format!(
+                "{}\nWithout a command the client starts the interactive console, --tui the terminal UI.",
+                help
+            )));
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            output.emit(&Event::error(e.to_string()));
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    let output = Output {
+        json: output.json || command.connection().json,
+    };
+    let result = match command {
+        CliCommand::Send(send) => send_once(send, output).await,
+        CliCommand::Users(users) => users_once(users, output).await,
+        CliCommand::Batch(batch) => return run_batch(batch, output).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            output.emit(&failure.event(None));
+            failure.exit_code()
+        }
+    }
+}
+
+async fn send_once(send: SendOnce, output: Output) -> Result<(), Failure> {
+    let mut session = Session::open(&send.connection, output).await?;
+    let result = session
+        .request(ClientToServerMessage::TextTo(send.to, send.message, None))
+        .await;
+    session.close().await;
+    output.emit(&Event::Response {
+        ok: true,
+        message: result?,
+    });
+    Ok(())
+}
+
+async fn users_once(users: UsersOnce, output: Output) -> Result<(), Failure> {
+    let mut session = Session::open(&users.connection, output).await?;
+    let usernames = session.usernames().await;
+    match usernames {
+        Ok(usernames) => session.usernames_received(usernames),
+        Err(failure) => {
+            session.close().await;
+            return Err(failure);
+        }
+    }
+    session.close().await;
+    Ok(())
+}
+
+// every line is a console command; failed lines are reported and skipped,
+// the exit code tells whether all of them succeeded
+async fn run_batch(batch: Batch, output: Output) -> ExitCode {
+    let input: Box<dyn AsyncBufRead + Unpin> = match &batch.file {
+        Some(file) => match tokio::fs::File::open(file).await {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                output.emit(&Event::error(format!("Failed to open {}: {}", file, e)));
+                return ExitCode::from(EXIT_USAGE);
+            }
+        },
+        None => Box::new(BufReader::new(tokio::io::stdin())),
+    };
+    let mut session = match Session::open(&batch.connection, output).await {
+        Ok(session) => session,
+        Err(failure) => {
+            output.emit(&failure.event(None));
+            return failure.exit_code();
+        }
+    };
+
+    let commands = console_commands();
+    let mut server_commands = match session.commands().await {
+        Ok(infos) => infos,
+        Err(failure) => {
+            output.emit(&failure.event(None));
+            return failure.exit_code();
+        }
+    };
+    let mut lines = input.lines();
+    let mut line_number = 0;
+    let mut exit_code = ExitCode::SUCCESS;
+    while let Ok(Some(line)) = lines.next_line().await {
+        line_number += 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invocation = parse_input(line)
+            .map_err(|e| format!("{}\n{}", e, e.underline(line)))
+            .and_then(|tokens| {
+                parse_command(&commands, &server_commands, &tokens).map_err(|e| e.to_string())
+            });
+        let result = match invocation {
+            Ok(Invocation::Help(help)) => {
+                output.emit(&Event::notice(help));
+                Ok(())
+            }
+            Ok(Invocation::Command(ClientCommand::Close(_))) => break,
+            Ok(Invocation::Command(ClientCommand::Usernames(_))) => session
+                .usernames()
+                .await
+                .map(|usernames| session.usernames_received(usernames)),
+            Ok(Invocation::Command(ClientCommand::Thread(thread))) => session
+                .thread(thread.id)
+                .await
+                .map(|messages| output.emit(&Event::Thread { messages })),
+            Ok(Invocation::Command(ClientCommand::Search(search))) => session
+                .search(search.query)
+                .await
+                .map(|results| output.emit(&Event::search(results))),
+            Ok(Invocation::Command(ClientCommand::Privacy(privacy))) => session
+                .privacy(privacy.contacts_only, privacy.hide_presence)
+                .await
+                .map(|settings| output.emit(&Event::privacy(settings))),
+            Ok(Invocation::Command(ClientCommand::Directory(directory))) => session
+                .directory(directory.query)
+                .await
+                .map(|page| output.emit(&Event::directory(page))),
+            Ok(Invocation::Command(ClientCommand::Contacts(_))) => session
+                .contacts()
+                .await
+                .map(|contacts| output.emit(&Event::Contacts { contacts })),
+            Ok(Invocation::Command(ClientCommand::Export(export))) => session
+                .export(export.conversation.clone(), export.format)
+                .await
+                .and_then(|(content, messages)| export.write(&content, messages).map_err(Failure::Operation))
+                .map(|message| output.emit(&Event::Response { ok: true, message })),
+            Ok(Invocation::Command(
+                command @ (ClientCommand::Profile(_) | ClientCommand::SetProfile(_) | ClientCommand::RemoveAvatar(_)),
+            )) => session
+                .profile(command.to_message().unwrap())
+                .await
+                .map(|profile| output.emit(&Event::profile(profile))),
+            Ok(Invocation::Command(ClientCommand::SetAvatar(set_avatar))) => match set_avatar.read() {
+                Ok(message) => session
+                    .profile(message)
+                    .await
+                    .map(|profile| output.emit(&Event::profile(profile))),
+                Err(e) => Err(Failure::Operation(e)),
+            },
+            Ok(Invocation::Command(ClientCommand::Avatar(avatar))) => session
+                .avatar(avatar.username.clone())
+                .await
+                .and_then(|image| avatar.write(&image).map_err(Failure::Operation))
+                .map(|message| output.emit(&Event::Response { ok: true, message })),
+            Ok(Invocation::Command(command)) if command.signs_in() => {
+                let result = session.request(command.to_message().unwrap()).await;
+                // which commands the server offers can depend on the name
+                match session.commands().await {
+                    Ok(infos) => server_commands = infos,
+                    Err(failure) => {
+                        output.emit(&failure.event(Some(line_number)));
+                        return failure.exit_code();
+                    }
+                }
+                result.map(|message| output.emit(&Event::Response { ok: true, message }))
+            }
+            Ok(Invocation::Command(command)) => match command.to_message() {
+                Some(message) => session.request(message).await.map(|message| {
+                    output.emit(&Event::Response { ok: true, message });
+                }),
+                None => Ok(()),
+            },
+            Err(message) => {
+                output.emit(&Event::Error {
+                    message,
+                    line: Some(line_number),
+                });
+                exit_code = ExitCode::from(EXIT_USAGE);
+                continue;
+            }
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(failure @ Failure::Operation(_)) => {
+                output.emit(&failure.event(Some(line_number)));
+                exit_code = failure.exit_code();
+            }
+            Err(failure) => {
+                output.emit(&failure.event(Some(line_number)));
+                return failure.exit_code();
+            }
+        }
+    }
+
+    session.close().await;
+    exit_code
+}
@@ -0,0 +1,1280 @@
+use common::communication::avatar::{check_avatar, Image};
+use common::communication::common_message::{
+    ClientToServerMessage, CommandInfo, DirectoryQuery, DirectorySort, ExportFormat, SearchQuery,
+};
+use common::logic::command::{
+    ArgKind, ArgSpec, Command, CommandArgs, CommandError, CommandSet, CommandSpec, FlagSpec, Invocation,
+    HELP_SPEC,
+};
+use common::logic::input_parser::InputToken;
+use std::path::PathBuf;
+
+// message ids are shown in brackets in front of every message
+fn message_id(spec: &CommandSpec, id: i64, arg: &'static str) -> Result<u64, CommandError> {
+    u64::try_from(id).map_err(|_| CommandError::InvalidArgument {
+        arg,
+        token: InputToken::Integer(id, id.to_string()),
+        usage: spec.usage(),
+    })
+}
+
+const REPLY: FlagSpec = FlagSpec::with_value("reply", ArgKind::Integer, "id of the message to reply to");
+
+fn reply_to(spec: &CommandSpec, args: &CommandArgs) -> Result<Option<u64>, CommandError> {
+    args.optional_integer("reply")
+        .map(|id| message_id(spec, id, "reply"))
+        .transpose()
+}
+
+pub struct Send {
+    pub username: String,
+    pub message: String,
+    pub reply_to: Option<u64>,
+}
+
+impl Command for Send {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "send",
+        aliases: &["msg"],
+        args: &[
+            ArgSpec::required("username", ArgKind::Username),
+            ArgSpec::required("message", ArgKind::Text),
+        ],
+        flags: &[REPLY],
+        help: "send a message to a user",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Send {
+            username: args.string("username")?,
+            message: args.string("message")?,
+            reply_to: reply_to(&Self::SPEC, args)?,
+        })
+    }
+}
+
+pub struct Group {
+    pub members: Vec<String>,
+}
+
+impl Command for Group {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "group",
+        aliases: &[],
+        args: &[ArgSpec::required("members", ArgKind::Usernames)],
+        flags: &[],
+        help: "start a group conversation with the listed users",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Group {
+            members: args.strings("members")?,
+        })
+    }
+}
+
+pub struct GroupSend {
+    pub group: String,
+    pub message: String,
+    pub reply_to: Option<u64>,
+}
+
+impl Command for GroupSend {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "group_send",
+        aliases: &["gsend"],
+        args: &[
+            ArgSpec::required("group", ArgKind::Text),
+            ArgSpec::required("message", ArgKind::Text),
+        ],
+        flags: &[REPLY],
+        help: "send a message to everyone in a group",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(GroupSend {
+            group: args.string("group")?,
+            message: args.string("message")?,
+            reply_to: reply_to(&Self::SPEC, args)?,
+        })
+    }
+}
+
+pub struct GroupAdd {
+    pub group: String,
+    pub username: String,
+}
+
+impl Command for GroupAdd {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "group_add",
+        aliases: &[],
+        args: &[
+            ArgSpec::required("group", ArgKind::Text),
+            ArgSpec::required("username", ArgKind::Username),
+        ],
+        flags: &[],
+        help: "add a user to a group",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(GroupAdd {
+            group: args.string("group")?,
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct GroupRemove {
+    pub group: String,
+    pub username: String,
+}
+
+impl Command for GroupRemove {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "group_remove",
+        aliases: &[],
+        args: &[
+            ArgSpec::required("group", ArgKind::Text),
+            ArgSpec::required("username", ArgKind::Username),
+        ],
+        flags: &[],
+        help: "remove a user from a group, remove yourself to leave it",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(GroupRemove {
+            group: args.string("group")?,
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct Edit {
+    pub id: u64,
+    pub text: String,
+}
+
+impl Command for Edit {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "edit",
+        aliases: &[],
+        args: &[
+            ArgSpec::required("id", ArgKind::Integer),
+            ArgSpec::required("text", ArgKind::Text),
+        ],
+        flags: &[],
+        help: "change the text of a message you sent recently",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Edit {
+            id: message_id(&Self::SPEC, args.integer("id")?, "id")?,
+            text: args.string("text")?,
+        })
+    }
+}
+
+pub struct Delete {
+    pub id: u64,
+}
+
+impl Command for Delete {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "delete",
+        aliases: &["del"],
+        args: &[ArgSpec::required("id", ArgKind::Integer)],
+        flags: &[],
+        help: "delete a message you sent recently",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Delete {
+            id: message_id(&Self::SPEC, args.integer("id")?, "id")?,
+        })
+    }
+}
+
+pub struct React {
+    pub id: u64,
+    pub emoji: String,
+}
+
+impl Command for React {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "react",
+        aliases: &[],
+        args: &[
+            ArgSpec::required("id", ArgKind::Integer),
+            ArgSpec::required("emoji", ArgKind::Text),
+        ],
+        flags: &[],
+        help: "react to a message with an emoji, again to take it back",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(React {
+            id: message_id(&Self::SPEC, args.integer("id")?, "id")?,
+            emoji: args.string("emoji")?,
+        })
+    }
+}
+
+pub struct Thread {
+    pub id: u64,
+}
+
+impl Command for Thread {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "thread",
+        aliases: &[],
+        args: &[ArgSpec::required("id", ArgKind::Integer)],
+        flags: &[],
+        help: "show the thread a message belongs to",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Thread {
+            id: message_id(&Self::SPEC, args.integer("id")?, "id")?,
+        })
+    }
+}
+
+pub struct Export {
+    pub file: PathBuf,
+    pub conversation: Option<String>,
+    pub format: ExportFormat,
+}
+
+impl Command for Export {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "export",
+        aliases: &[],
+        args: &[ArgSpec::required("file", ArgKind::Text)],
+        flags: &[
+            FlagSpec::with_value("in", ArgKind::Text, "only this user's conversation or this group"),
+            FlagSpec::with_value("format", ArgKind::Text, "jsonl, md or html, by default taken from the file name"),
+        ],
+        help: "save your message history to a file",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        let file = args.string("file")?;
+        let format = match args.optional_string("format") {
+            Some(name) => ExportFormat::from_name(&name).ok_or_else(|| CommandError::InvalidArgument {
+                arg: "format",
+                token: InputToken::String(name),
+                usage: Self::SPEC.usage(),
+            })?,
+            None => ExportFormat::for_file(&file).unwrap_or_default(),
+        };
+        Ok(Export {
+            file: PathBuf::from(file),
+            conversation: args.optional_string("in"),
+            format,
+        })
+    }
+}
+
+impl Export {
+    // the server sends the export back, it is written where the user asked for it
+    pub fn write(&self, content: &str, messages: usize) -> Result<String, String> {
+        std::fs::write(&self.file, content)
+            .map(|_| format!("Exported {} messages to {}", messages, self.file.display()))
+            .map_err(|e| format!("Failed to write {}: {}", self.file.display(), e))
+    }
+}
+
+pub struct Search {
+    pub query: SearchQuery,
+}
+
+impl Command for Search {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "search",
+        aliases: &["find"],
+        args: &[ArgSpec::required("terms", ArgKind::Text)],
+        flags: &[
+            FlagSpec::with_value("from", ArgKind::Username, "only messages sent by this user"),
+            FlagSpec::with_value("in", ArgKind::Text, "only this user's conversation or this group"),
+            FlagSpec::with_value("after", ArgKind::Text, "only messages since this date or time"),
+            FlagSpec::with_value("before", ArgKind::Text, "only messages before this date or time"),
+            FlagSpec::with_value("page", ArgKind::Integer, "page of the results, 1 for the newest"),
+        ],
+        help: "find messages containing all the given words",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        let page = match args.optional_integer("page") {
+            Some(page) => u32::try_from(page)
+                .ok()
+                .filter(|page| *page > 0)
+                .ok_or_else(|| CommandError::InvalidArgument {
+                    arg: "page",
+                    token: InputToken::Integer(page, page.to_string()),
+                    usage: Self::SPEC.usage(),
+                })?,
+            None => 1,
+        };
+        Ok(Search {
+            query: SearchQuery {
+                terms: args.string("terms")?,
+                from: args.optional_string("from"),
+                conversation: args.optional_string("in"),
+                after: args.optional_string("after"),
+                before: args.optional_string("before"),
+                page,
+            },
+        })
+    }
+}
+
+pub struct SetName {
+    pub username: String,
+}
+
+impl Command for SetName {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "set_name",
+        aliases: &["name"],
+        args: &[ArgSpec::required("username", ArgKind::Text)],
+        flags: &[],
+        help: "choose your username",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(SetName {
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct Login {
+    pub username: String,
+    pub password: String,
+}
+
+impl Command for Login {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "login",
+        aliases: &[],
+        args: &[
+            ArgSpec::required("username", ArgKind::Text),
+            ArgSpec::required("password", ArgKind::Text),
+        ],
+        flags: &[],
+        help: "log in to your account, or create it; accounts can be used from several clients",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Login {
+            username: args.string("username")?,
+            password: args.string("password")?,
+        })
+    }
+}
+
+pub struct Kick {
+    pub username: String,
+}
+
+impl Command for Kick {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "kick",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Username)],
+        flags: &[],
+        help: "disconnect a user, for moderators",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Kick {
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct Mute {
+    pub username: String,
+    pub minutes: u32,
+}
+
+impl Command for Mute {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "mute",
+        aliases: &[],
+        args: &[
+            ArgSpec::required("username", ArgKind::Username),
+            ArgSpec::required("minutes", ArgKind::Integer),
+        ],
+        flags: &[],
+        help: "keep a user from posting for a number of minutes, for moderators",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        let minutes = args.integer("minutes")?;
+        Ok(Mute {
+            username: args.string("username")?,
+            minutes: u32::try_from(minutes)
+                .ok()
+                .filter(|minutes| *minutes > 0)
+                .ok_or_else(|| CommandError::InvalidArgument {
+                    arg: "minutes",
+                    token: InputToken::Integer(minutes, minutes.to_string()),
+                    usage: Self::SPEC.usage(),
+                })?,
+        })
+    }
+}
+
+pub struct Unmute {
+    pub username: String,
+}
+
+impl Command for Unmute {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "unmute",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Username)],
+        flags: &[],
+        help: "let a muted user post again, for moderators",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Unmute {
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct Block {
+    pub username: String,
+}
+
+impl Command for Block {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "block",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Username)],
+        flags: &[],
+        help: "stop a user from messaging you and hide them from your user list",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Block {
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct Unblock {
+    pub username: String,
+}
+
+impl Command for Unblock {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "unblock",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Username)],
+        flags: &[],
+        help: "let a blocked user message you again",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Unblock {
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct Privacy {
+    pub contacts_only: Option<bool>,
+    pub hide_presence: Option<bool>,
+}
+
+impl Command for Privacy {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "privacy",
+        aliases: &[],
+        args: &[],
+        flags: &[
+            FlagSpec::with_value("contacts-only", ArgKind::Bool, "only take direct messages from contacts"),
+            FlagSpec::with_value("hide-presence", ArgKind::Bool, "leave yourself out of everyone's user list"),
+        ],
+        help: "show or change your privacy settings",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Privacy {
+            contacts_only: args.optional_bool("contacts-only"),
+            hide_presence: args.optional_bool("hide-presence"),
+        })
+    }
+}
+
+pub struct Profile {
+    pub username: String,
+}
+
+impl Command for Profile {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "profile",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Username)],
+        flags: &[],
+        help: "show the profile of a user",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Profile {
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct SetProfile {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub timezone: Option<String>,
+}
+
+impl Command for SetProfile {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "set_profile",
+        aliases: &[],
+        args: &[],
+        flags: &[
+            FlagSpec::with_value("name", ArgKind::Text, "the name shown next to your username"),
+            FlagSpec::with_value("bio", ArgKind::Text, "a few words about yourself"),
+            FlagSpec::with_value("timezone", ArgKind::Text, "like Europe/Berlin or +02:00"),
+        ],
+        help: "change your profile, an empty value clears a field",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(SetProfile {
+            display_name: args.optional_string("name"),
+            bio: args.optional_string("bio"),
+            timezone: args.optional_string("timezone"),
+        })
+    }
+}
+
+// read and checked before it is sent, as a binary frame
+pub struct SetAvatar {
+    pub file: PathBuf,
+}
+
+impl Command for SetAvatar {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "set_avatar",
+        aliases: &[],
+        args: &[ArgSpec::required("file", ArgKind::Text)],
+        flags: &[],
+        help: "upload a small PNG, JPEG, GIF or WebP image as your avatar",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(SetAvatar {
+            file: PathBuf::from(args.string("file")?),
+        })
+    }
+}
+
+impl SetAvatar {
+    pub fn read(&self) -> Result<ClientToServerMessage, String> {
+        let image = std::fs::read(&self.file).map_err(|e| format!("Failed to read {}: {}", self.file.display(), e))?;
+        check_avatar(&image)?;
+        Ok(ClientToServerMessage::SetAvatar(Image(image)))
+    }
+}
+
+pub struct RemoveAvatar;
+
+impl Command for RemoveAvatar {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "remove_avatar",
+        aliases: &[],
+        args: &[],
+        flags: &[],
+        help: "remove your avatar",
+    };
+
+    fn from_args(_args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(RemoveAvatar)
+    }
+}
+
+// the server answers with the image, which is saved to `file`
+pub struct Avatar {
+    pub username: String,
+    pub file: PathBuf,
+}
+
+impl Command for Avatar {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "avatar",
+        aliases: &[],
+        args: &[
+            ArgSpec::required("username", ArgKind::Username),
+            ArgSpec::required("file", ArgKind::Text),
+        ],
+        flags: &[],
+        help: "save the avatar of a user to a file",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Avatar {
+            username: args.string("username")?,
+            file: PathBuf::from(args.string("file")?),
+        })
+    }
+}
+
+impl Avatar {
+    pub fn write(&self, image: &[u8]) -> Result<String, String> {
+        std::fs::write(&self.file, image)
+            .map(|_| format!("Saved the avatar of {} to {}", self.username, self.file.display()))
+            .map_err(|e| format!("Failed to write {}: {}", self.file.display(), e))
+    }
+}
+
+pub struct Contacts;
+
+impl Command for Contacts {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "contacts",
+        aliases: &[],
+        args: &[],
+        flags: &[],
+        help: "list your contacts and open contact requests",
+    };
+
+    fn from_args(_args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Contacts)
+    }
+}
+
+pub struct ContactAdd {
+    pub username: String,
+}
+
+impl Command for ContactAdd {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "contact_add",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Username)],
+        flags: &[],
+        help: "ask a user to be your contact, or accept their request",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(ContactAdd {
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct ContactAccept {
+    pub username: String,
+}
+
+impl Command for ContactAccept {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "contact_accept",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Username)],
+        flags: &[],
+        help: "accept the contact request of a user",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(ContactAccept {
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct ContactDecline {
+    pub username: String,
+}
+
+impl Command for ContactDecline {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "contact_decline",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Username)],
+        flags: &[],
+        help: "decline the contact request of a user",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(ContactDecline {
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct ContactRemove {
+    pub username: String,
+}
+
+impl Command for ContactRemove {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "contact_remove",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Username)],
+        flags: &[],
+        help: "remove a contact, or take back your request",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(ContactRemove {
+            username: args.string("username")?,
+        })
+    }
+}
+
+pub struct Directory {
+    pub query: DirectoryQuery,
+}
+
+impl Command for Directory {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "directory",
+        aliases: &["dir"],
+        args: &[ArgSpec::optional("filter", ArgKind::Text)],
+        flags: &[
+            FlagSpec::switch("contains", "match the filter anywhere in the username, not just at its start"),
+            FlagSpec::switch("online", "only users who are online"),
+            FlagSpec::with_value("sort", ArgKind::Text, "name, name-desc or online"),
+            FlagSpec::with_value("limit", ArgKind::Integer, "users per page"),
+            FlagSpec::with_value("after", ArgKind::Text, "the cursor the previous page ended with"),
+            FlagSpec::switch("presence", "say who is online"),
+            FlagSpec::switch("profile", "show the profile of each user"),
+        ],
+        help: "look through all users, online or not, a page at a time",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        let sort = match args.optional_string("sort") {
+            Some(name) => DirectorySort::from_name(&name).ok_or_else(|| CommandError::InvalidArgument {
+                arg: "sort",
+                token: InputToken::String(name),
+                usage: Self::SPEC.usage(),
+            })?,
+            None => DirectorySort::default(),
+        };
+        let limit = match args.optional_integer("limit") {
+            Some(limit) => u32::try_from(limit)
+                .ok()
+                .filter(|limit| *limit > 0)
+                .ok_or_else(|| CommandError::InvalidArgument {
+                    arg: "limit",
+                    token: InputToken::Integer(limit, limit.to_string()),
+                    usage: Self::SPEC.usage(),
+                })?,
+            None => 0,
+        };
+        Ok(Directory {
+            query: DirectoryQuery {
+                filter: args.optional_string("filter").unwrap_or_default(),
+                substring: args.flag("contains"),
+                online_only: args.flag("online"),
+                sort,
+                after: args.optional_string("after"),
+                limit,
+                presence: args.flag("presence"),
+                profile: args.flag("profile"),
+            },
+        })
+    }
+}
+
+pub struct Usernames;
+
+impl Command for Usernames {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "usernames",
+        aliases: &["users"],
+        args: &[],
+        flags: &[],
+        help: "list the users currently online",
+    };
+
+    fn from_args(_args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Usernames)
+    }
+}
+
+pub struct Close;
+
+impl Command for Close {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "close",
+        aliases: &["quit", "exit"],
+        args: &[],
+        flags: &[],
+        help: "close the connection and exit",
+    };
+
+    fn from_args(_args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Close)
+    }
+}
+
+pub struct Open {
+    pub username: String,
+}
+
+impl Command for Open {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "open",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Username)],
+        flags: &[],
+        help: "open the conversation with a user",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Open {
+            username: args.string("username")?,
+        })
+    }
+}
+
+// a command this client does not know but the server advertised, checked by the server
+pub struct ServerCommand {
+    pub name: String,
+    pub args: Vec<InputToken>,
+}
+
+pub enum ClientCommand {
+    Send(Send),
+    SetName(SetName),
+    Login(Login),
+    Group(Group),
+    GroupSend(GroupSend),
+    GroupAdd(GroupAdd),
+    GroupRemove(GroupRemove),
+    Edit(Edit),
+    Delete(Delete),
+    React(React),
+    Thread(Thread),
+    Search(Search),
+    Export(Export),
+    Kick(Kick),
+    Mute(Mute),
+    Unmute(Unmute),
+    Block(Block),
+    Unblock(Unblock),
+    Privacy(Privacy),
+    Profile(Profile),
+    SetProfile(SetProfile),
+    SetAvatar(SetAvatar),
+    RemoveAvatar(RemoveAvatar),
+    Avatar(Avatar),
+    Contacts(Contacts),
+    ContactAdd(ContactAdd),
+    ContactAccept(ContactAccept),
+    ContactDecline(ContactDecline),
+    ContactRemove(ContactRemove),
+    Directory(Directory),
+    Usernames(Usernames),
+    Close(Close),
+    Open(Open),
+    Server(ServerCommand),
+}
+
+impl ClientCommand {
+    // the request this command sends to the server, if it is not handled locally
+    pub fn to_message(&self) -> Option<ClientToServerMessage> {
+        match self {
+            ClientCommand::Send(send) => Some(ClientToServerMessage::TextTo(
+                send.username.clone(),
+                send.message.clone(),
+                send.reply_to,
+            )),
+            ClientCommand::SetName(set_name) => {
+                Some(ClientToServerMessage::SetUsername(set_name.username.clone()))
+            }
+            ClientCommand::Login(login) => Some(ClientToServerMessage::Login(
+                login.username.clone(),
+                login.password.clone(),
+            )),
+            ClientCommand::Group(group) => Some(ClientToServerMessage::CreateGroup(group.members.clone())),
+            ClientCommand::GroupSend(send) => Some(ClientToServerMessage::GroupTextTo(
+                send.group.clone(),
+                send.message.clone(),
+                send.reply_to,
+            )),
+            ClientCommand::GroupAdd(add) => Some(ClientToServerMessage::AddToGroup(
+                add.group.clone(),
+                add.username.clone(),
+            )),
+            ClientCommand::GroupRemove(remove) => Some(ClientToServerMessage::RemoveFromGroup(
+                remove.group.clone(),
+                remove.username.clone(),
+            )),
+            ClientCommand::Edit(edit) => Some(ClientToServerMessage::EditMessage(edit.id, edit.text.clone())),
+            ClientCommand::Delete(delete) => Some(ClientToServerMessage::DeleteMessage(delete.id)),
+            ClientCommand::React(react) => Some(ClientToServerMessage::React(react.id, react.emoji.clone())),
+            ClientCommand::Thread(thread) => Some(ClientToServerMessage::GetThread(thread.id)),
+            ClientCommand::Search(search) => Some(ClientToServerMessage::Search(search.query.clone())),
+            ClientCommand::Export(export) => Some(ClientToServerMessage::Export {
+                conversation: export.conversation.clone(),
+                format: export.format,
+            }),
+            ClientCommand::Kick(kick) => Some(ClientToServerMessage::Kick(kick.username.clone())),
+            ClientCommand::Mute(mute) => Some(ClientToServerMessage::Mute(mute.username.clone(), mute.minutes)),
+            ClientCommand::Unmute(unmute) => Some(ClientToServerMessage::Unmute(unmute.username.clone())),
+            ClientCommand::Block(block) => Some(ClientToServerMessage::Block(block.username.clone())),
+            ClientCommand::Unblock(unblock) => Some(ClientToServerMessage::Unblock(unblock.username.clone())),
+            ClientCommand::Privacy(privacy) => Some(ClientToServerMessage::SetPrivacy {
+                contacts_only: privacy.contacts_only,
+                hide_presence: privacy.hide_presence,
+            }),
+            ClientCommand::Profile(profile) => Some(ClientToServerMessage::GetProfile(profile.username.clone())),
+            ClientCommand::SetProfile(set) => Some(ClientToServerMessage::SetProfile {
+                display_name: set.display_name.clone(),
+                bio: set.bio.clone(),
+                timezone: set.timezone.clone(),
+            }),
+            ClientCommand::RemoveAvatar(_) => Some(ClientToServerMessage::RemoveAvatar),
+            ClientCommand::Avatar(avatar) => Some(ClientToServerMessage::GetAvatar(avatar.username.clone())),
+            ClientCommand::Contacts(_) => Some(ClientToServerMessage::GetContacts),
+            ClientCommand::ContactAdd(add) => Some(ClientToServerMessage::AddContact(add.username.clone())),
+            ClientCommand::ContactAccept(accept) => {
+                Some(ClientToServerMessage::AnswerContact(accept.username.clone(), true))
+            }
+            ClientCommand::ContactDecline(decline) => {
+                Some(ClientToServerMessage::AnswerContact(decline.username.clone(), false))
+            }
+            ClientCommand::ContactRemove(remove) => Some(ClientToServerMessage::RemoveContact(remove.username.clone())),
+            ClientCommand::Directory(directory) => Some(ClientToServerMessage::Directory(directory.query.clone())),
+            ClientCommand::Usernames(_) => Some(ClientToServerMessage::GetUserList),
+            ClientCommand::Server(command) => Some(ClientToServerMessage::Command {
+                name: command.name.clone(),
+                args: command.args.clone(),
+            }),
+            // the image has to be read first, see SetAvatar::read
+            ClientCommand::SetAvatar(_) | ClientCommand::Close(_) | ClientCommand::Open(_) => None,
+        }
+    }
+
+    // changes who the user is, and with that which commands the server offers
+    pub fn signs_in(&self) -> bool {
+        matches!(self, ClientCommand::SetName(_) | ClientCommand::Login(_))
+    }
+}
+
+pub fn console_commands() -> CommandSet<ClientCommand> {
+    CommandSet::new()
+        .with(ClientCommand::Send)
+        .with(ClientCommand::SetName)
+        .with(ClientCommand::Login)
+        .with(ClientCommand::Group)
+        .with(ClientCommand::GroupSend)
+        .with(ClientCommand::GroupAdd)
+        .with(ClientCommand::GroupRemove)
+        .with(ClientCommand::Edit)
+        .with(ClientCommand::Delete)
+        .with(ClientCommand::React)
+        .with(ClientCommand::Thread)
+        .with(ClientCommand::Search)
+        .with(ClientCommand::Export)
+        .with(ClientCommand::Kick)
+        .with(ClientCommand::Mute)
+        .with(ClientCommand::Unmute)
+        .with(ClientCommand::Block)
+        .with(ClientCommand::Unblock)
+        .with(ClientCommand::Privacy)
+        .with(ClientCommand::Profile)
+        .with(ClientCommand::SetProfile)
+        .with(ClientCommand::SetAvatar)
+        .with(ClientCommand::RemoveAvatar)
+        .with(ClientCommand::Avatar)
+        .with(ClientCommand::Contacts)
+        .with(ClientCommand::ContactAdd)
+        .with(ClientCommand::ContactAccept)
+        .with(ClientCommand::ContactDecline)
+        .with(ClientCommand::ContactRemove)
+        .with(ClientCommand::Directory)
+        .with(ClientCommand::Usernames)
+        .with(ClientCommand::Close)
+}
+
+// the terminal UI additionally switches between conversations
+pub fn tui_commands() -> CommandSet<ClientCommand> {
+    console_commands().with(ClientCommand::Open)
+}
+
+fn find_server_command<'a>(server_commands: &'a [CommandInfo], name: &str) -> Option<&'a CommandInfo> {
+    server_commands
+        .iter()
+        .find(|c| c.name == name || c.aliases.iter().any(|a| a == name))
+}
+
+fn server_help_for(command: &CommandInfo) -> String {
+    let mut help = format!("{}\n  {}", command.usage, command.help);
+    if !command.aliases.is_empty() {
+        help.push_str(&format!("\n  aliases: {}", command.aliases.join(", ")));
+    }
+    help
+}
+
+// parses like `commands`, but falls back to the commands the server advertised
+pub fn parse_command(
+    commands: &CommandSet<ClientCommand>,
+    server_commands: &[CommandInfo],
+    tokens: &[InputToken],
+) -> Result<Invocation<ClientCommand>, CommandError> {
+    match commands.parse(tokens) {
+        Ok(Invocation::Help(mut help)) if tokens.len() == 1 && !server_commands.is_empty() => {
+            let width = server_commands.iter().map(|c| c.usage.chars().count()).max().unwrap_or(0);
+            help.push_str("\nServer commands:");
+            for command in server_commands {
+                help.push_str(&format!("\n  {:width$}  {}", command.usage, command.help, width = width));
+            }
+            Ok(Invocation::Help(help))
+        }
+        Err(CommandError::UnknownCommand { name, available }) => {
+            let Some(command) = find_server_command(server_commands, &name) else {
+                return Err(CommandError::UnknownCommand { name, available });
+            };
+            // either `help <server command>` or the server command itself
+            match &tokens[0] {
+                InputToken::General(first) if HELP_SPEC.matches(first) => {
+                    Ok(Invocation::Help(server_help_for(command)))
+                }
+                _ => Ok(Invocation::Command(ClientCommand::Server(ServerCommand {
+                    name: command.name.clone(),
+                    args: tokens[1..].to_vec(),
+                }))),
+            }
+        }
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{console_commands, parse_command, ClientCommand};
+    use common::communication::common_message::{
+        ClientToServerMessage, CommandInfo, DirectoryQuery, DirectorySort, ExportFormat, SearchQuery,
+    };
+    use common::logic::command::{CommandError, Invocation};
+    use common::logic::input_parser::{parse_input, InputToken};
+
+    fn server_commands() -> Vec<CommandInfo> {
+        vec![CommandInfo {
+            name: "remind".to_string(),
+            aliases: vec!["later".to_string()],
+            usage: "remind <minutes> \"<text>\"".to_string(),
+            help: "send you the text later".to_string(),
+        }]
+    }
+
+    fn parse(line: &str) -> Result<Invocation<ClientCommand>, CommandError> {
+        parse_command(&console_commands(), &server_commands(), &parse_input(line).unwrap())
+    }
+
+    #[test]
+    fn test_server_commands() {
+        let Ok(Invocation::Command(command)) = parse("later 5 \"tea\"") else {
+            panic!("expected a server command");
+        };
+        assert_eq!(
+            command.to_message(),
+            Some(ClientToServerMessage::Command {
+                name: "remind".to_string(),
+                args: vec![InputToken::Integer(5, "5".to_string()), InputToken::String("tea".to_string())],
+            })
+        );
+
+        let Ok(Invocation::Help(help)) = parse("help") else {
+            panic!("expected help");
+        };
+        assert!(help.contains("Server commands:\n  remind <minutes> \"<text>\"  send you the text later"));
+        let Ok(Invocation::Help(help)) = parse("help remind") else {
+            panic!("expected help");
+        };
+        assert!(help.starts_with("remind <minutes>"));
+
+        assert!(matches!(parse("whisper"), Err(CommandError::UnknownCommand { .. })));
+        assert!(matches!(parse("send \"bob\""), Err(CommandError::MissingArgument { .. })));
+    }
+
+    #[test]
+    fn test_message_changes() {
+        let Ok(Invocation::Command(command)) = parse("react 12 \"👍\"") else {
+            panic!("expected a reaction");
+        };
+        assert_eq!(
+            command.to_message(),
+            Some(ClientToServerMessage::React(12, "👍".to_string()))
+        );
+        let Ok(Invocation::Command(command)) = parse("del 3") else {
+            panic!("expected a deletion");
+        };
+        assert_eq!(command.to_message(), Some(ClientToServerMessage::DeleteMessage(3)));
+        assert!(matches!(
+            parse("edit -1 \"typo\""),
+            Err(CommandError::InvalidArgument { arg: "id", .. })
+        ));
+
+        let Ok(Invocation::Command(command)) = parse("gsend \"#1\" \"friday\" --reply=4") else {
+            panic!("expected a reply");
+        };
+        assert_eq!(
+            command.to_message(),
+            Some(ClientToServerMessage::GroupTextTo("#1".to_string(), "friday".to_string(), Some(4)))
+        );
+        assert!(matches!(
+            parse("send \"bob\" \"hi\" --reply=-4"),
+            Err(CommandError::InvalidArgument { arg: "reply", .. })
+        ));
+    }
+
+    #[test]
+    fn test_moderation() {
+        let Ok(Invocation::Command(command)) = parse("mute \"bob\" 10") else {
+            panic!("expected a mute");
+        };
+        assert_eq!(command.to_message(), Some(ClientToServerMessage::Mute("bob".to_string(), 10)));
+        assert!(matches!(
+            parse("mute \"bob\" 0"),
+            Err(CommandError::InvalidArgument { arg: "minutes", .. })
+        ));
+        let Ok(Invocation::Command(command)) = parse("kick \"bob\"") else {
+            panic!("expected a kick");
+        };
+        assert_eq!(command.to_message(), Some(ClientToServerMessage::Kick("bob".to_string())));
+    }
+
+    #[test]
+    fn test_privacy() {
+        let Ok(Invocation::Command(command)) = parse("privacy --contacts-only=true") else {
+            panic!("expected privacy settings");
+        };
+        assert_eq!(
+            command.to_message(),
+            Some(ClientToServerMessage::SetPrivacy {
+                contacts_only: Some(true),
+                hide_presence: None,
+            })
+        );
+        let Ok(Invocation::Command(command)) = parse("block \"mallory\"") else {
+            panic!("expected a block");
+        };
+        assert_eq!(command.to_message(), Some(ClientToServerMessage::Block("mallory".to_string())));
+    }
+
+    #[test]
+    fn test_profile() {
+        let Ok(Invocation::Command(command)) = parse("set_profile --name=\"Alice Liddell\" --timezone=\"\"") else {
+            panic!("expected a profile change");
+        };
+        assert_eq!(
+            command.to_message(),
+            Some(ClientToServerMessage::SetProfile {
+                display_name: Some("Alice Liddell".to_string()),
+                bio: None,
+                timezone: Some(String::new()),
+            })
+        );
+        let Ok(Invocation::Command(ClientCommand::SetAvatar(set_avatar))) = parse("set_avatar \"/nonexistent/avatar.png\"")
+        else {
+            panic!("expected an avatar upload");
+        };
+        assert!(set_avatar.read().is_err());
+    }
+
+    #[test]
+    fn test_contacts() {
+        let Ok(Invocation::Command(command)) = parse("contact_decline \"mallory\"") else {
+            panic!("expected an answer to a contact request");
+        };
+        assert_eq!(
+            command.to_message(),
+            Some(ClientToServerMessage::AnswerContact("mallory".to_string(), false))
+        );
+        let Ok(Invocation::Command(command)) = parse("contacts") else {
+            panic!("expected the contact list");
+        };
+        assert_eq!(command.to_message(), Some(ClientToServerMessage::GetContacts));
+        assert!(parse("contact_add").is_err());
+    }
+
+    #[test]
+    fn test_directory() {
+        let Ok(Invocation::Command(command)) = parse("dir \"al\" --online --sort=name-desc --limit=20 --after=alice") else {
+            panic!("expected a directory query");
+        };
+        assert_eq!(
+            command.to_message(),
+            Some(ClientToServerMessage::Directory(DirectoryQuery {
+                filter: "al".to_string(),
+                online_only: true,
+                sort: DirectorySort::NameDescending,
+                after: Some("alice".to_string()),
+                limit: 20,
+                ..DirectoryQuery::default()
+            }))
+        );
+        assert!(matches!(
+            parse("directory --sort=age"),
+            Err(CommandError::InvalidArgument { arg: "sort", .. })
+        ));
+    }
+
+    #[test]
+    fn test_search() {
+        let Ok(Invocation::Command(command)) = parse("find \"release notes\" --in=#1 --after=2024-05-31 --page=2") else {
+            panic!("expected a search");
+        };
+        assert_eq!(
+            command.to_message(),
+            Some(ClientToServerMessage::Search(SearchQuery {
+                terms: "release notes".to_string(),
+                from: None,
+                conversation: Some("#1".to_string()),
+                after: Some("2024-05-31".to_string()),
+                before: None,
+                page: 2,
+            }))
+        );
+        assert!(matches!(
+            parse("search \"notes\" --page=0"),
+            Err(CommandError::InvalidArgument { arg: "page", .. })
+        ));
+    }
+
+    #[test]
+    fn test_export() {
+        let Ok(Invocation::Command(command)) = parse("export \"chat with bob.md\" --in=bob") else {
+            panic!("expected an export");
+        };
+        assert_eq!(
+            command.to_message(),
+            Some(ClientToServerMessage::Export {
+                conversation: Some("bob".to_string()),
+                format: ExportFormat::Markdown,
+            })
+        );
+        let Ok(Invocation::Command(command)) = parse("export \"all.txt\" --format=html") else {
+            panic!("expected an export");
+        };
+        assert_eq!(
+            command.to_message(),
+            Some(ClientToServerMessage::Export {
+                conversation: None,
+                format: ExportFormat::Html,
+            })
+        );
+        assert!(matches!(
+            parse("export \"all.txt\" --format=pdf"),
+            Err(CommandError::InvalidArgument { arg: "format", .. })
+        ));
+    }
+}
@@ -0,0 +1,277 @@
+use crate::commands::{console_commands, parse_command, Avatar, ClientCommand, Export};
+use crate::frame;
+use crate::output::{DisplayNames, Event, Output};
+use common::communication::avatar::parse_avatar_frame;
+use common::communication::common_message::{ClientToServerMessage, CommandInfo, ServerToClientMessage};
+use common::logic::command::{CommandSet, Invocation};
+use common::logic::input_parser::parse_input;
+use common::logic::line_editor::{ConsoleInput, LineEditor, SharedUsernames};
+use futures_util::{SinkExt, StreamExt};
+use rustyline::ExternalPrinter;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+const HISTORY_FILE_NAME: &str = ".chat_client_history";
+
+// prints through the line editor so incoming messages do not clobber the line being typed
+struct Printer {
+    external: Option<Box<dyn ExternalPrinter + Send>>,
+    output: Output,
+}
+
+impl Printer {
+    fn print(&mut self, event: Event) {
+        let text = self.output.format(&event);
+        match &mut self.external {
+            Some(printer) => {
+                if printer.print(text.clone()).is_err() {
+                    println!("{}", text);
+                }
+            }
+            None => println!("{}", text),
+        }
+    }
+}
+
+pub async fn run(
+    mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    server: String,
+    output: Output,
+) {
+    let commands = console_commands();
+    let usernames: SharedUsernames = Arc::new(Mutex::new(Vec::new()));
+    let mut editor = match LineEditor::new(commands.specs(), usernames.clone(), HISTORY_FILE_NAME) {
+        Ok(editor) => editor,
+        Err(e) => {
+            println!("Failed to initialize the console: {}", e);
+            return;
+        }
+    };
+    let mut printer = Printer {
+        external: editor.external_printer(),
+        output,
+    };
+    // programs reading the JSON lines do not want prompts mixed into them
+    let prompt = if output.json { "" } else { "> " };
+
+    // rustyline blocks, so it gets its own thread; it only prompts for the next line once the
+    // previous one was handled, so the terminal is never left in raw mode when we exit
+    let (line_tx, mut line_rx) = tokio::sync::mpsc::channel(1);
+    let (next_line_tx, next_line_rx) = std::sync::mpsc::channel::<()>();
+    std::thread::spawn(move || {
+        while next_line_rx.recv().is_ok() {
+            if line_tx.blocking_send(editor.read_line(prompt)).is_err() {
+                break;
+            }
+        }
+    });
+
+    printer.print(Event::Connected { server });
+    let mut server_commands: Vec<CommandInfo> = Vec::new();
+    // where the export the server is preparing goes
+    let mut pending_export: Option<Export> = None;
+    let mut pending_avatar: Option<Avatar> = None;
+    let mut names = DisplayNames::default();
+    send(&mut ws_stream, &ClientToServerMessage::GetCommands).await;
+    let _ = next_line_tx.send(());
+
+    loop {
+        tokio::select! {
+            line = line_rx.recv() => {
+                let Some(ConsoleInput::Line(line)) = line else {
+                    ws_stream.close(None).await.expect("Failed to close connection");
+                    printer.print(Event::Disconnected { reason: "closed by user".to_string() });
+                    break;
+                };
+                let pending = (&mut pending_export, &mut pending_avatar);
+                if !handle_line(&line, &commands, &server_commands, pending, &mut ws_stream, &mut printer).await {
+                    break;
+                }
+                let _ = next_line_tx.send(());
+            }
+            msg = ws_stream.next() => {
+                if msg.is_none() {
+                    printer.print(Event::Disconnected { reason: "remote host closed abruptly".to_string() });
+                    printer.print(Event::notice("Press Enter to exit"));
+                    line_rx.recv().await;
+                    break;
+                }
+                let msg = msg.unwrap();
+                match msg {
+                    Ok(Message::Text(text)) => {
+                        let text = text.to_string();
+                        let message: ServerToClientMessage = serde_json::from_str(&text).unwrap();
+                        match message {
+                            ServerToClientMessage::TextFrom(id, from, text, reply_to) => {
+                                if names.should_fetch(&from) {
+                                    send(&mut ws_stream, &ClientToServerMessage::GetProfile(from.clone())).await;
+                                }
+                                let name = names.get(&from);
+                                printer.print(Event::Message { id: Some(id), from, name, text, bot: false, reply_to });
+                            }
+                            ServerToClientMessage::BotTextFrom(id, from, text, reply_to) => {
+                                let name = names.get(&from);
+                                printer.print(Event::Message { id, from, name, text, bot: true, reply_to });
+                            }
+                            ServerToClientMessage::SentText(id, to, text, reply_to) => {
+                                printer.print(Event::Sent { id, to, text, reply_to });
+                            }
+                            ServerToClientMessage::GroupTextFrom(id, group, from, text, reply_to) => {
+                                if names.should_fetch(&from) {
+                                    send(&mut ws_stream, &ClientToServerMessage::GetProfile(from.clone())).await;
+                                }
+                                let name = names.get(&from);
+                                printer.print(Event::GroupMessage { id, group, from, name, text, reply_to });
+                            }
+                            ServerToClientMessage::GroupUpdated(info) => {
+                                printer.print(Event::Group { group: info.id, members: info.members });
+                            }
+                            ServerToClientMessage::MessageEdited(id, text) => {
+                                printer.print(Event::Edited { id, text });
+                            }
+                            ServerToClientMessage::MessageDeleted(id) => printer.print(Event::Deleted { id }),
+                            ServerToClientMessage::ReactionsUpdated(id, reactions) => {
+                                printer.print(Event::Reactions { id, reactions });
+                            }
+                            ServerToClientMessage::Thread(messages) => printer.print(Event::Thread { messages }),
+                            ServerToClientMessage::SearchResults(results) => printer.print(Event::search(results)),
+                            ServerToClientMessage::Privacy(settings) => printer.print(Event::privacy(settings)),
+                            ServerToClientMessage::Directory(page) => printer.print(Event::directory(page)),
+                            // profiles fetched only for the display name are not shown
+                            ServerToClientMessage::Profile(profile) => {
+                                let fetched = names.learn(&profile);
+                                if !fetched {
+                                    printer.print(Event::profile(profile));
+                                }
+                            }
+                            ServerToClientMessage::ProfileUpdated(profile) => {
+                                names.learn(&profile);
+                            }
+                            ServerToClientMessage::Contacts(contacts) => printer.print(Event::Contacts { contacts }),
+                            ServerToClientMessage::ContactUpdated(info) => printer.print(Event::contact(info)),
+                            ServerToClientMessage::ContactRemoved(username) => {
+                                printer.print(Event::ContactRemoved { username });
+                            }
+                            ServerToClientMessage::Exported { content, messages } => {
+                                if let Some(export) = pending_export.take() {
+                                    printer.print(match export.write(&content, messages) {
+                                        Ok(message) => Event::notice(message),
+                                        Err(message) => Event::error(message),
+                                    });
+                                }
+                            }
+                            ServerToClientMessage::UserList(users) => {
+                                *usernames.lock().unwrap() = users.iter().map(|u| u.username.clone()).collect();
+                                names.learn_users(&users);
+                                printer.print(Event::usernames(users));
+                            }
+                            ServerToClientMessage::Commands(infos) => server_commands = infos,
+                            ServerToClientMessage::Response(result) => {
+                                let ok = result.is_ok();
+                                // a failed export is answered like any other failed request
+                                if !ok {
+                                    pending_export = None;
+                                    pending_avatar = None;
+                                }
+                                let message = result.unwrap_or_else(|e| e);
+                                printer.print(Event::Response { ok, message });
+                            }
+                        _ => {}}
+                    }
+                    Ok(Message::Close(_)) => {
+                        printer.print(Event::Disconnected { reason: "remote host closed the connection".to_string() });
+                        printer.print(Event::notice("Press Enter to exit"));
+                        line_rx.recv().await;
+                        break;
+                    }
+                    Ok(Message::Binary(frame)) => {
+                        let avatar = parse_avatar_frame(&frame)
+                            .and_then(|(username, image)| match pending_avatar.take() {
+                                Some(avatar) if avatar.username == username => Some(avatar.write(image)),
+                                other => {
+                                    pending_avatar = other;
+                                    None
+                                }
+                            });
+                        match avatar {
+                            Some(Ok(message)) => printer.print(Event::notice(message)),
+                            Some(Err(message)) => printer.print(Event::error(message)),
+                            None => printer.print(Event::error("Received an avatar nobody asked for")),
+                        }
+                    }
+                    Ok(_) => {
+                        printer.print(Event::error("Received non-text message from server, the client does not know how to parse it"));
+                    }
+                    Err(e) => {
+                        printer.print(Event::error(format!("Unexpected error: {}", e)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// returns false once the user asked to close the connection
+async fn handle_line(
+    line: &str,
+    commands: &CommandSet<ClientCommand>,
+    server_commands: &[CommandInfo],
+    (pending_export, pending_avatar): (&mut Option<Export>, &mut Option<Avatar>),
+    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+    printer: &mut Printer,
+) -> bool {
+    let line = line.trim();
+    let tokens = match parse_input(line) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            printer.print(Event::error(format!(
+                "Instruction is not grammatically correct: {}\n{}",
+                e,
+                e.underline(line)
+            )));
+            return true;
+        }
+    };
+    if tokens.is_empty() {
+        return true;
+    }
+
+    match parse_command(commands, server_commands, &tokens) {
+        Ok(Invocation::Help(help)) => printer.print(Event::notice(help)),
+        Ok(Invocation::Command(ClientCommand::Close(_))) => {
+            ws_stream.close(None).await.expect("Failed to close connection");
+            printer.print(Event::Disconnected {
+                reason: "closed by user".to_string(),
+            });
+            return false;
+        }
+        Ok(Invocation::Command(ClientCommand::SetAvatar(set_avatar))) => match set_avatar.read() {
+            Ok(message) => send(ws_stream, &message).await,
+            Err(e) => printer.print(Event::error(e)),
+        },
+        Ok(Invocation::Command(command)) => {
+            if let Some(message) = command.to_message() {
+                send(ws_stream, &message).await;
+            }
+            if command.signs_in() {
+                send(ws_stream, &ClientToServerMessage::GetCommands).await;
+            }
+            match command {
+                ClientCommand::Export(export) => *pending_export = Some(export),
+                ClientCommand::Avatar(avatar) => *pending_avatar = Some(avatar),
+                _ => {}
+            }
+        }
+        Err(e) => printer.print(Event::error(e.to_string())),
+    }
+    true
+}
+
+async fn send(ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>, message: &ClientToServerMessage) {
+    ws_stream
+        .send(frame(message))
+        .await
+        .expect("Failed to send message");
+}
@@ -1,151 +1,72 @@
-use futures_util::{SinkExt, StreamExt};
-use common::communication::common_message::{ClientToServerMessage, ServerToClientMessage};
-use common::logic::input_parser::{parse_input, InputToken};
-use tokio::io;
-use tokio::io::{AsyncBufReadExt, BufReader};
+mod cli;
+mod commands;
+mod console;
+mod output;
+mod tui;
+
+use common::communication::avatar::Image;
+use common::communication::common_message::ClientToServerMessage;
+use output::{Event, Output};
+use std::process::ExitCode;
 use tokio_tungstenite::connect_async;
-use tokio_tungstenite::tungstenite::protocol::Message;
-use tokio_tungstenite::tungstenite::Utf8Bytes;
+use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+
+// avatars go as binary frames, everything else as JSON text
+fn frame(message: &ClientToServerMessage) -> Message {
+    match message {
+        ClientToServerMessage::SetAvatar(Image(image)) => Message::Binary(image.clone().into()),
+        message => Message::Text(Utf8Bytes::from(serde_json::to_string(message).unwrap())),
+    }
+}
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // global options come before the subcommand, anything after it is the subcommand's
+    let global = args.iter().take_while(|arg| *arg == "--tui" || *arg == "--json").count();
+    let options: Vec<String> = args.drain(..global).collect();
+    // the full-screen terminal UI is opt-in, the plain console stays the default
+    let use_tui = options.iter().any(|arg| arg == "--tui");
+    let output = Output {
+        json: options.iter().any(|arg| arg == "--json"),
+    };
+    if !args.is_empty() {
+        if use_tui {
+            eprintln!("The terminal UI takes no subcommand");
+            return ExitCode::from(2);
+        }
+        return cli::run(args, output).await;
+    }
+    if use_tui && output.json {
+        eprintln!("The terminal UI has no JSON output, use --tui or --json");
+        return ExitCode::from(2);
+    }
+
     let mut url: String = "ws://".to_string();
-    println!("Please enter the server address: ws:// is already included");
+    // keep stdout clean for whoever reads the JSON lines
+    if output.json {
+        eprintln!("Please enter the server address: ws:// is already included");
+    } else {
+        println!("Please enter the server address: ws:// is already included");
+    }
     std::io::stdin()
         .read_line(&mut url)
         .expect("Failed to read line");
     let res = connect_async(url.trim()).await;
     if res.is_err() {
-        println!("Failed to connect to server: {}, program exits", res.err().unwrap());
-        return;
+        output.emit(&Event::error(format!(
+            "Failed to connect to server: {}, program exits",
+            res.err().unwrap()
+        )));
+        return ExitCode::FAILURE;
     }
-    let (mut ws_stream, _) = res.unwrap();
-    let mut reader = BufReader::new(io::stdin());
-
-    println!("Successfully connected to server");
+    let (ws_stream, _) = res.unwrap();
 
-    let username: Option<String> = None;
-
-    loop {
-        tokio::select! {
-            console_input = get_console_input_tokens(&mut reader) => {
-                if let Ok(tokens) = console_input {
-                    if tokens.is_empty() {
-                        continue;
-                    }
-                    if let InputToken::General(instruction) = &tokens[0] {
-                        match instruction.as_str() {
-                            "send" => {
-                                if tokens.len() != 3 {
-                                    println!("Grammar is not correct, should be: send \"<username>\" \"<message>\"");
-                                    continue;
-                                }
-                                if let (InputToken::String(username), InputToken::String(message))
-                                        = (&tokens[1], &tokens[2]) {
-                                    let message = ClientToServerMessage::TextTo(username.to_string(), message.to_string());
-                                    let message_text = serde_json::to_string(&message).unwrap();
-
-                                    ws_stream
-                                        .send(Message::Text(Utf8Bytes::from(message_text)))
-                                        .await
-                                        .expect("Failed to send message");
-                                } else {
-                                    println!("Grammar is not correct, should be: send \"<username>\" \"<message>\"");
-                                }
-                            }
-
-                            "set_name" => {
-                                if tokens.len() != 2 {
-                                    println!("Grammar is not correct, should be: set_name \"<username>\"");
-                                    continue;
-                                }
-                                if let InputToken::String(username) = &tokens[1] {
-                                    let message = ClientToServerMessage::SetUsername(username.to_string());
-                                    let message_text = serde_json::to_string(&message).unwrap();
-
-                                    ws_stream
-                                        .send(Message::Text(Utf8Bytes::from(message_text)))
-                                        .await
-                                        .expect("Failed to send message");
-                                } else {
-                                    println!("Grammar is not correct, should be: set_name \"<username>\"");
-                                }
-                            }
-                            "close" => {
-                                ws_stream.close(None).await.expect("Failed to close connection");
-                                break;
-                            }
-                            "usernames" => {
-                                let message = ClientToServerMessage::GetUsernames;
-                                let message_text = serde_json::to_string(&message).unwrap();
-
-                                ws_stream
-                                    .send(Message::Text(Utf8Bytes::from(message_text)))
-                                    .await
-                                    .expect("Failed to send message");
-                            }
-                            _ => {
-                                println!("Invalid instruction, available instructions are: send, set_name, usernames, close");
-                            }
-                        }
-                    } else {
-                        println!("Instruction is not grammatically correct, please try again");
-                    }
-                } else {
-                    println!("Instruction is not grammatically correct, please try again");
-                }
-            }
-            msg = ws_stream.next() => {
-                if msg.is_none() {
-                    println!("Connection closed: remote host closed abruptly");
-                    println!("Press any key to exit");
-                    break;
-                }
-                let msg = msg.unwrap();
-                match msg {
-                    Ok(Message::Text(text)) => {
-                        let text = text.to_string();
-                        let message: ServerToClientMessage = serde_json::from_str(&text).unwrap();
-                        match message {
-                            ServerToClientMessage::TextFrom(username, message) => {
-                                println!("Message from {}: {}", username, message);
-                            }
-                            ServerToClientMessage::Usernames(usernames) => {
-                                println!("Usernames: {:?}", usernames);
-                            }
-                            ServerToClientMessage::Response(Ok(_)) =>{
-                                println!("Operation successful");
-                            }
-                            ServerToClientMessage::Response(Err(e)) =>{
-                                println!("Operation failed: {}", e);
-                            }
-                        _ => {}}
-                    }
-                    Ok(Message::Close(_)) => {
-                        println!("Connection closed: remote host closed the connection");
-                        println!("Press any key to exit");
-                        break;
-                    }
-                    Ok(_) => {
-                        println!("Received non-text message from server, the client does not know how to parse it");
-                    }
-                    Err(e) => {
-                        println!("Unexpected error: {}", e);
-                    }
-                }
-            }
-        }
+    if use_tui {
+        tui::run(ws_stream, url.trim().to_string()).await;
+        return ExitCode::SUCCESS;
     }
-}
-
-async fn get_console_input_tokens(
-    reader: &mut BufReader<io::Stdin>,
-) -> Result<Vec<InputToken>, String> {
-    let mut input = String::new();
 
-    if reader.read_line(&mut input).await.is_ok() {
-        parse_input(input.trim())
-    } else {
-        Err(String::from("Failed to read line"))
-    }
+    console::run(ws_stream, url.trim().to_string(), output).await;
+    ExitCode::SUCCESS
 }
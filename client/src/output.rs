@@ -0,0 +1,571 @@
+use chrono::{SecondsFormat, Utc};
+use common::communication::common_message::{
+    ContactInfo, ContactState, DirectoryEntry, DirectoryPage, MessageInfo, PrivacySettings, ProfileInfo, ReactionInfo, ReplyInfo, SearchHit, SearchResults, UserInfo,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+// everything the client reports, either as text for people or as JSON lines for programs
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Connected { server: String },
+    Disconnected { reason: String },
+    Message {
+        // messages from server plugins have no id
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        from: String,
+        // the display name of the sender, if the client knows it
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        text: String,
+        bot: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reply_to: Option<ReplyInfo>,
+    },
+    // a message the user sent, from this or another client
+    Sent {
+        id: u64,
+        to: String,
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reply_to: Option<ReplyInfo>,
+    },
+    GroupMessage {
+        id: u64,
+        group: String,
+        from: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reply_to: Option<ReplyInfo>,
+    },
+    Edited { id: u64, text: String },
+    Deleted { id: u64 },
+    // every reaction on the message after one changed
+    Reactions { id: u64, reactions: Vec<ReactionInfo> },
+    // the messages of one thread, its first message first
+    Thread { messages: Vec<MessageInfo> },
+    // one page of search hits, newest first
+    Search { hits: Vec<SearchHit>, page: u32, pages: u32, total: usize },
+    // the members of a group after they changed
+    Group { group: String, members: Vec<String> },
+    // the user's privacy settings after asking for or changing them
+    Privacy { contacts_only: bool, hide_presence: bool, blocked: Vec<String> },
+    Profile {
+        username: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        display_name: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bio: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timezone: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        avatar: Option<String>,
+    },
+    // the user's contacts and open requests, by name
+    Contacts { contacts: Vec<ContactInfo> },
+    // a new request, an accepted one, or a contact coming online or going offline
+    Contact { username: String, state: ContactState, online: bool },
+    ContactRemoved { username: String },
+    // one page of the user directory, `next` continues it
+    Directory {
+        entries: Vec<DirectoryEntry>,
+        total: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next: Option<String>,
+    },
+    // every online user, the bots among them listed again in `bots`
+    Usernames { usernames: Vec<String>, bots: Vec<String> },
+    Response { ok: bool, message: String },
+    Notice { message: String },
+    Error {
+        message: String,
+        // line of the batch input the error belongs to
+        #[serde(skip_serializing_if = "Option::is_none")]
+        line: Option<usize>,
+    },
+}
+
+// how a sender or user is shown to people
+pub fn display_name(username: &str, bot: bool) -> String {
+    if bot {
+        format!("{} [bot]", username)
+    } else {
+        username.to_string()
+    }
+}
+
+// "Alice Liddell (alice)" once the display name is known
+pub fn sender(from: &str, name: &Option<String>, bot: bool) -> String {
+    match name {
+        Some(name) => format!("{} ({})", name, display_name(from, bot)),
+        None => display_name(from, bot),
+    }
+}
+
+// the display names of other users as far as this client has seen them
+#[derive(Debug, Default)]
+pub struct DisplayNames {
+    // None for users known to have no display name
+    names: HashMap<String, Option<String>>,
+    // asked for only to learn the name, so the answer is not shown
+    fetching: HashSet<String>,
+}
+
+impl DisplayNames {
+    pub fn get(&self, username: &str) -> Option<String> {
+        self.names.get(username).cloned().flatten()
+    }
+
+    pub fn learn_users(&mut self, users: &[UserInfo]) {
+        for user in users {
+            self.names.insert(user.username.clone(), user.display_name.clone());
+        }
+    }
+
+    // true if the profile was only fetched to learn the name
+    pub fn learn(&mut self, profile: &ProfileInfo) -> bool {
+        self.names.insert(profile.username.clone(), profile.display_name.clone());
+        self.fetching.remove(&profile.username)
+    }
+
+    // true once for each user whose name is not known yet, who is worth asking the server about
+    pub fn should_fetch(&mut self, username: &str) -> bool {
+        !self.names.contains_key(username) && self.fetching.insert(username.to_string())
+    }
+}
+
+// "👍 2, 🎉 1", as reactions are shown next to a message
+pub fn reaction_counts(reactions: &[ReactionInfo]) -> String {
+    reactions
+        .iter()
+        .map(|reaction| format!("{} {}", reaction.emoji, reaction.users.len()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// " (re [12] alice: hello)" after the sender of a reply, nothing for other messages
+fn quote(reply_to: &Option<ReplyInfo>) -> String {
+    match reply_to {
+        Some(reply) => format!(" (re [{}] {}: {})", reply.id, reply.from, reply.excerpt),
+        None => String::new(),
+    }
+}
+
+// "[13] bob (re [12]): friday (edited)", as threads and search results list messages
+fn stored_message(message: &MessageInfo) -> String {
+    let mut text = format!("[{}] {}", message.id, message.from);
+    if let Some(parent) = message.reply_to {
+        text.push_str(&format!(" (re [{}])", parent));
+    }
+    text.push_str(&format!(": {}", message.text));
+    if message.edited {
+        text.push_str(" (edited)");
+    }
+    text
+}
+
+// "alice [bot] (Alice Liddell, moderator, online)", with whatever the entry carries
+fn directory_entry(entry: &DirectoryEntry) -> String {
+    let mut details = Vec::new();
+    if let Some(profile) = &entry.profile {
+        details.extend(profile.display_name.clone());
+        details.push(profile.role.clone());
+    }
+    match entry.online {
+        Some(true) => details.push("online".to_string()),
+        Some(false) => details.push("offline".to_string()),
+        None => {}
+    }
+    let name = display_name(&entry.username, entry.profile.as_ref().is_some_and(|profile| profile.bot));
+    match details.is_empty() {
+        true => name,
+        false => format!("{} ({})", name, details.join(", ")),
+    }
+}
+
+// "alice is online", "bob asks to be your contact"
+fn contact_state(username: &str, state: ContactState, online: bool) -> String {
+    match (state, online) {
+        (ContactState::Contact, true) => format!("{} is online", username),
+        (ContactState::Contact, false) => format!("{} is offline", username),
+        (ContactState::Sent, _) => format!("{} has not answered your request yet", username),
+        (ContactState::Received, _) => format!("{} asks to be your contact", username),
+    }
+}
+
+impl Event {
+    pub fn search(results: SearchResults) -> Self {
+        Event::Search {
+            hits: results.hits,
+            page: results.page,
+            pages: results.pages,
+            total: results.total,
+        }
+    }
+
+    pub fn privacy(settings: PrivacySettings) -> Self {
+        Event::Privacy {
+            contacts_only: settings.contacts_only,
+            hide_presence: settings.hide_presence,
+            blocked: settings.blocked,
+        }
+    }
+
+    pub fn contact(info: ContactInfo) -> Self {
+        Event::Contact {
+            username: info.username,
+            state: info.state,
+            online: info.online,
+        }
+    }
+
+    pub fn directory(page: DirectoryPage) -> Self {
+        Event::Directory {
+            entries: page.entries,
+            total: page.total,
+            next: page.next,
+        }
+    }
+
+    pub fn profile(profile: ProfileInfo) -> Self {
+        Event::Profile {
+            username: profile.username,
+            display_name: profile.display_name,
+            bio: profile.bio,
+            timezone: profile.timezone,
+            avatar: profile.avatar,
+        }
+    }
+
+    pub fn usernames(mut users: Vec<UserInfo>) -> Self {
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        Event::Usernames {
+            bots: users
+                .iter()
+                .filter(|u| u.bot)
+                .map(|u| u.username.clone())
+                .collect(),
+            usernames: users.into_iter().map(|u| u.username).collect(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Event::Error {
+            message: message.into(),
+            line: None,
+        }
+    }
+
+    pub fn notice(message: impl Into<String>) -> Self {
+        Event::Notice {
+            message: message.into(),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            Event::Error { .. } | Event::Response { ok: false, .. }
+        )
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Event::Connected { .. } => "Successfully connected to server".to_string(),
+            Event::Disconnected { reason } => format!("Connection closed: {}", reason),
+            Event::Message {
+                id: Some(id),
+                from,
+                name,
+                text,
+                bot,
+                reply_to,
+            } => format!(
+                "[{}] Message from {}{}: {}",
+                id,
+                sender(from, name, *bot),
+                quote(reply_to),
+                text
+            ),
+            Event::Message {
+                id: None,
+                from,
+                name,
+                text,
+                bot,
+                reply_to,
+            } => format!("Message from {}{}: {}", sender(from, name, *bot), quote(reply_to), text),
+            Event::Sent {
+                id,
+                to,
+                text,
+                reply_to,
+            } => format!("[{}] Sent to {}{}: {}", id, to, quote(reply_to), text),
+            Event::GroupMessage {
+                id,
+                group,
+                from,
+                name,
+                text,
+                reply_to,
+            } => format!(
+                "[{}] Message in {} from {}{}: {}",
+                id,
+                group,
+                sender(from, name, false),
+                quote(reply_to),
+                text
+            ),
+            Event::Edited { id, text } => format!("[{}] Edited: {}", id, text),
+            Event::Deleted { id } => format!("[{}] Deleted", id),
+            Event::Reactions { id, reactions } if reactions.is_empty() => {
+                format!("[{}] No reactions left", id)
+            }
+            Event::Reactions { id, reactions } => format!("[{}] Reactions: {}", id, reaction_counts(reactions)),
+            Event::Thread { messages } => {
+                let mut text = "Thread:".to_string();
+                for message in messages {
+                    text.push_str(&format!("\n  {}", stored_message(message)));
+                }
+                text
+            }
+            Event::Search {
+                hits,
+                page,
+                pages,
+                total,
+            } => {
+                let mut text = format!("Found {} messages, page {} of {}", total, page, pages);
+                for hit in hits {
+                    text.push_str(&format!("\n{} at {}:", hit.conversation, hit.sent_at));
+                    for message in &hit.before {
+                        text.push_str(&format!("\n    {}", stored_message(message)));
+                    }
+                    text.push_str(&format!("\n  > {}", stored_message(&hit.message)));
+                    for message in &hit.after {
+                        text.push_str(&format!("\n    {}", stored_message(message)));
+                    }
+                }
+                text
+            }
+            Event::Group { group, members } => format!("Members of {}: {}", group, members.join(", ")),
+            Event::Privacy {
+                contacts_only,
+                hide_presence,
+                blocked,
+            } => format!(
+                "Direct messages from: {}; presence: {}; blocked: {}",
+                if *contacts_only { "contacts only" } else { "everyone" },
+                if *hide_presence { "hidden" } else { "visible" },
+                if blocked.is_empty() { "nobody".to_string() } else { blocked.join(", ") }
+            ),
+            Event::Profile {
+                username,
+                display_name,
+                bio,
+                timezone,
+                avatar,
+            } => {
+                let fields = [("Name", display_name), ("Bio", bio), ("Timezone", timezone), ("Avatar", avatar)];
+                let mut text = format!("Profile of {}", username);
+                for (label, value) in fields {
+                    if let Some(value) = value {
+                        text.push_str(&format!("\n  {}: {}", label, value));
+                    }
+                }
+                if fields.iter().all(|(_, value)| value.is_none()) {
+                    text.push_str(": nothing filled in yet");
+                }
+                text
+            }
+            Event::Contacts { contacts } if contacts.is_empty() => "No contacts yet".to_string(),
+            Event::Contacts { contacts } => {
+                let mut text = "Contacts:".to_string();
+                for contact in contacts {
+                    text.push_str(&format!("\n  {}", contact_state(&contact.username, contact.state, contact.online)));
+                }
+                text
+            }
+            Event::Contact {
+                username,
+                state,
+                online,
+            } => contact_state(username, *state, *online),
+            Event::ContactRemoved { username } => format!("{} is not a contact anymore", username),
+            Event::Directory { entries, total, next } => {
+                let mut text = format!("Users, {} of {}:", entries.len(), total);
+                for entry in entries {
+                    text.push_str(&format!("\n  {}", directory_entry(entry)));
+                }
+                if let Some(next) = next {
+                    text.push_str(&format!("\nMore with --after={}", next));
+                }
+                text
+            }
+            Event::Usernames { usernames, bots } => {
+                let shown: Vec<String> = usernames
+                    .iter()
+                    .map(|u| display_name(u, bots.contains(u)))
+                    .collect();
+                format!("Usernames: {:?}", shown)
+            }
+            Event::Response { ok: true, message } => message.clone(),
+            Event::Response { ok: false, message } => format!("Operation failed: {}", message),
+            Event::Notice { message } => message.clone(),
+            Event::Error {
+                message,
+                line: Some(line),
+            } => format!("line {}: {}", line, message),
+            Event::Error { message, line: None } => message.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Output {
+    pub json: bool,
+}
+
+impl Output {
+    pub fn format(&self, event: &Event) -> String {
+        if !self.json {
+            return event.text();
+        }
+        let record = Record {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            event,
+        };
+        serde_json::to_string(&record).unwrap()
+    }
+
+    // text mode keeps errors apart on stderr, JSON mode keeps every event in one stream
+    pub fn emit(&self, event: &Event) {
+        if !self.json && event.is_error() {
+            eprintln!("{}", self.format(event));
+        } else {
+            println!("{}", self.format(event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, Output};
+    use common::communication::common_message::{ReactionInfo, ReplyInfo, UserInfo};
+    use serde_json::Value;
+
+    #[test]
+    fn test_json_lines() {
+        let output = Output { json: true };
+        let line = output.format(&Event::Message {
+            id: Some(7),
+            from: "alice".to_string(),
+            name: None,
+            text: "hi\nthere".to_string(),
+            bot: false,
+            reply_to: None,
+        });
+        assert!(!line.contains('\n'));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["event"], "message");
+        assert_eq!(value["from"], "alice");
+        assert_eq!(value["text"], "hi\nthere");
+        assert!(value["timestamp"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(value["bot"], false);
+        assert_eq!(value["id"], 7);
+        assert!(value.get("reply_to").is_none());
+
+        let value: Value = serde_json::from_str(&output.format(&Event::error("nope"))).unwrap();
+        assert_eq!(value["event"], "error");
+        assert!(value.get("line").is_none());
+
+        let value: Value = serde_json::from_str(&output.format(&Event::Response {
+            ok: false,
+            message: "Recipient does not exist!".to_string(),
+        }))
+        .unwrap();
+        assert_eq!(value["ok"], false);
+    }
+
+    #[test]
+    fn test_text() {
+        let output = Output::default();
+        let users = vec![
+            UserInfo {
+                username: "remind".to_string(),
+                bot: true,
+                display_name: None,
+            },
+            UserInfo {
+                username: "alice".to_string(),
+                bot: false,
+                display_name: None,
+            },
+        ];
+        assert_eq!(
+            output.format(&Event::usernames(users)),
+            r#"Usernames: ["alice", "remind [bot]"]"#
+        );
+        assert_eq!(
+            output.format(&Event::Error {
+                message: "Unknown flag --x".to_string(),
+                line: Some(3),
+            }),
+            "line 3: Unknown flag --x"
+        );
+        assert_eq!(
+            output.format(&Event::Disconnected {
+                reason: "remote host closed the connection".to_string(),
+            }),
+            "Connection closed: remote host closed the connection"
+        );
+        let reactions = vec![
+            ReactionInfo {
+                emoji: "👍".to_string(),
+                users: vec!["alice".to_string(), "bob".to_string()],
+            },
+            ReactionInfo {
+                emoji: "🎉".to_string(),
+                users: vec!["bob".to_string()],
+            },
+        ];
+        assert_eq!(
+            output.format(&Event::Reactions { id: 4, reactions }),
+            "[4] Reactions: 👍 2, 🎉 1"
+        );
+        assert_eq!(
+            output.format(&Event::Reactions {
+                id: 4,
+                reactions: vec![]
+            }),
+            "[4] No reactions left"
+        );
+        let reply = Event::GroupMessage {
+            id: 5,
+            group: "#1".to_string(),
+            from: "bob".to_string(),
+            name: Some("Bob Ross".to_string()),
+            text: "friday".to_string(),
+            reply_to: Some(ReplyInfo {
+                id: 3,
+                from: "alice".to_string(),
+                excerpt: "which day?".to_string(),
+            }),
+        };
+        assert_eq!(
+            output.format(&reply),
+            "[5] Message in #1 from Bob Ross (bob) (re [3] alice: which day?): friday"
+        );
+    }
+}
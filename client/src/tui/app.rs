@@ -0,0 +1,570 @@
+use crate::commands::{parse_command, tui_commands, Avatar, ClientCommand, Export};
+use crate::output::{DisplayNames, Event, Output};
+use crate::tui::input_line::InputLine;
+use common::communication::avatar::parse_avatar_frame;
+use common::communication::common_message::{
+    ClientToServerMessage, CommandInfo, ContactState, ReactionInfo, ReplyInfo, ServerToClientMessage, GROUP_PREFIX,
+};
+use common::logic::command::{CommandSet, Invocation};
+use common::logic::input_parser::parse_input;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::collections::VecDeque;
+
+const SCROLL_STEP: usize = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected(String),
+}
+
+// what the event loop has to do after the app handled an event
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Send(ClientToServerMessage),
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryKind {
+    Incoming(String),
+    FromBot(String),
+    Outgoing(String),
+    Info,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub text: String,
+    // the server's id for chat messages, which edits and reactions refer to
+    pub id: Option<u64>,
+    pub reply_to: Option<ReplyInfo>,
+    pub edited: bool,
+    pub deleted: bool,
+    pub reactions: Vec<ReactionInfo>,
+}
+
+#[derive(Debug, Default)]
+pub struct Conversation {
+    // None for the server pane which collects responses and notices
+    pub peer: Option<String>,
+    pub entries: Vec<Entry>,
+    pub unread: usize,
+    // number of entries scrolled up from the bottom
+    pub scroll: usize,
+}
+
+pub struct SidebarEntry {
+    pub peer: Option<String>,
+    pub name: Option<String>,
+    pub unread: usize,
+    pub online: bool,
+    pub bot: bool,
+}
+
+// requests which the server answers with a Response, in the order they were sent
+enum Pending {
+    SetUsername(String),
+    Text(String),
+    // answered with the export instead, unless it failed
+    Export(Export),
+    // answered with a binary frame holding the avatar, unless it failed
+    Avatar(Avatar),
+}
+
+pub struct App {
+    pub server: String,
+    pub connection: ConnectionState,
+    pub username: Option<String>,
+    pub online_users: Vec<String>,
+    // the online users which are bot accounts
+    pub bots: Vec<String>,
+    pub conversations: Vec<Conversation>,
+    pub selected: Option<String>,
+    pub input: InputLine,
+    commands: CommandSet<ClientCommand>,
+    // the commands the server offers besides the built-in ones
+    server_commands: Vec<CommandInfo>,
+    pending: VecDeque<Pending>,
+    names: DisplayNames,
+}
+
+impl App {
+    pub fn new(server: String) -> Self {
+        let mut app = App {
+            server,
+            connection: ConnectionState::Connected,
+            username: None,
+            online_users: Vec::new(),
+            bots: Vec::new(),
+            conversations: vec![Conversation::default()],
+            selected: None,
+            input: InputLine::default(),
+            commands: tui_commands(),
+            server_commands: Vec::new(),
+            pending: VecDeque::new(),
+            names: DisplayNames::default(),
+        };
+        app.push_entry(
+            None,
+            EntryKind::Info,
+            "Connected. Use /set_name \"<username>\" to pick a name or /login \"<username>\" \"<password>\", /help for all commands"
+                .to_string(),
+        );
+        app
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection == ConnectionState::Connected
+    }
+
+    pub fn name_of(&self, username: &str) -> Option<String> {
+        self.names.get(username)
+    }
+
+    pub fn selected_conversation(&self) -> Option<&Conversation> {
+        self.conversations.iter().find(|c| c.peer == self.selected)
+    }
+
+    // server pane first, then open conversations, then online users without a conversation
+    pub fn sidebar_entries(&self) -> Vec<SidebarEntry> {
+        let mut entries: Vec<SidebarEntry> = self
+            .conversations
+            .iter()
+            .map(|c| SidebarEntry {
+                peer: c.peer.clone(),
+                name: c.peer.as_ref().and_then(|p| self.names.get(p)),
+                unread: c.unread,
+                online: c.peer.as_ref().is_some_and(|p| self.online_users.contains(p)),
+                bot: c.peer.as_ref().is_some_and(|p| self.bots.contains(p)),
+            })
+            .collect();
+
+        for user in &self.online_users {
+            if Some(user) == self.username.as_ref()
+                || self.conversations.iter().any(|c| c.peer.as_ref() == Some(user))
+            {
+                continue;
+            }
+            entries.push(SidebarEntry {
+                peer: Some(user.clone()),
+                name: self.names.get(user),
+                unread: 0,
+                online: true,
+                bot: self.bots.contains(user),
+            });
+        }
+
+        entries
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<Action> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if ctrl => return Some(Action::Quit),
+            KeyCode::Char('u') if ctrl => self.input.clear(),
+            KeyCode::Char('w') if ctrl => self.input.delete_word_before_cursor(),
+            KeyCode::Char('a') if ctrl => self.input.move_home(),
+            KeyCode::Char('e') if ctrl => self.input.move_end(),
+            KeyCode::Char(c) => self.input.insert(c),
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            KeyCode::Left => self.input.move_left(),
+            KeyCode::Right => self.input.move_right(),
+            KeyCode::Home => self.input.move_home(),
+            KeyCode::End => self.input.move_end(),
+            KeyCode::Up => self.input.history_prev(),
+            KeyCode::Down => self.input.history_next(),
+            KeyCode::Tab => self.select_relative(1),
+            KeyCode::BackTab => self.select_relative(-1),
+            KeyCode::PageUp => self.scroll(true),
+            KeyCode::PageDown => self.scroll(false),
+            KeyCode::Enter => return self.submit(),
+            _ => {}
+        }
+        None
+    }
+
+    pub fn handle_server_message(&mut self, message: ServerToClientMessage) -> Option<Action> {
+        match message {
+            ServerToClientMessage::TextFrom(id, username, text, reply_to) => {
+                let kind = EntryKind::Incoming(username.clone());
+                self.push_message(Some(&username), kind, Some(id), reply_to, text);
+                return self.fetch_name(username);
+            }
+            ServerToClientMessage::GroupTextFrom(id, group, from, text, reply_to) => {
+                if Some(&from) == self.username.as_ref() {
+                    self.push_message(Some(&group), EntryKind::Outgoing(from), Some(id), reply_to, text);
+                } else {
+                    self.push_message(Some(&group), EntryKind::Incoming(from.clone()), Some(id), reply_to, text);
+                    return self.fetch_name(from);
+                }
+            }
+            ServerToClientMessage::GroupUpdated(info) => {
+                let text = if self.username.as_ref().is_some_and(|u| info.members.contains(u)) {
+                    format!("Members: {}", info.members.join(", "))
+                } else {
+                    "You are no longer in this group".to_string()
+                };
+                self.push_entry(Some(&info.id), EntryKind::Info, text);
+            }
+            ServerToClientMessage::SentText(id, peer, text, reply_to) => {
+                let own_name = self.username.clone().unwrap_or_else(|| "me".to_string());
+                self.push_message(Some(&peer), EntryKind::Outgoing(own_name), Some(id), reply_to, text);
+            }
+            ServerToClientMessage::BotTextFrom(id, username, text, reply_to) => {
+                let kind = EntryKind::FromBot(username.clone());
+                self.push_message(Some(&username), kind, id, reply_to, text);
+            }
+            ServerToClientMessage::MessageEdited(id, text) => {
+                if let Some(entry) = self.entry_mut(id) {
+                    entry.text = text;
+                    entry.edited = true;
+                }
+            }
+            ServerToClientMessage::MessageDeleted(id) => {
+                if let Some(entry) = self.entry_mut(id) {
+                    entry.text = "message deleted".to_string();
+                    entry.deleted = true;
+                    entry.reactions.clear();
+                }
+            }
+            ServerToClientMessage::ReactionsUpdated(id, reactions) => {
+                if let Some(entry) = self.entry_mut(id) {
+                    entry.reactions = reactions;
+                }
+            }
+            ServerToClientMessage::SearchResults(results) => {
+                self.notice(
+                    EntryKind::Info,
+                    &format!("Found {} messages, page {} of {}:", results.total, results.page, results.pages),
+                );
+                for hit in results.hits {
+                    let message = hit.message;
+                    self.notice(
+                        EntryKind::Info,
+                        &format!(
+                            "  [{}] {} in {} at {}: {}",
+                            message.id, message.from, hit.conversation, hit.sent_at, message.text
+                        ),
+                    );
+                }
+            }
+            ServerToClientMessage::Exported { content, messages } => {
+                let position = self.pending.iter().position(|pending| matches!(pending, Pending::Export(_)));
+                if let Some(Pending::Export(export)) = position.and_then(|position| self.pending.remove(position)) {
+                    match export.write(&content, messages) {
+                        Ok(text) => self.notice(EntryKind::Info, &text),
+                        Err(e) => self.notice(EntryKind::Error, &e),
+                    }
+                }
+            }
+            ServerToClientMessage::Privacy(settings) => {
+                self.notice(EntryKind::Info, &Output::default().format(&Event::privacy(settings)));
+            }
+            ServerToClientMessage::Directory(page) => {
+                self.notice(EntryKind::Info, &Output::default().format(&Event::directory(page)));
+            }
+            // profiles fetched only for the display name are not shown
+            ServerToClientMessage::Profile(profile) => {
+                if !self.names.learn(&profile) {
+                    self.notice(EntryKind::Info, &Output::default().format(&Event::profile(profile)));
+                }
+            }
+            ServerToClientMessage::ProfileUpdated(profile) => {
+                self.names.learn(&profile);
+            }
+            ServerToClientMessage::Contacts(contacts) => {
+                self.notice(EntryKind::Info, &Output::default().format(&Event::Contacts { contacts }));
+            }
+            // contacts coming online or going offline keep the user list current without asking again
+            ServerToClientMessage::ContactUpdated(info) => {
+                if info.state == ContactState::Contact {
+                    self.online_users.retain(|user| *user != info.username);
+                    if info.online {
+                        self.online_users.push(info.username.clone());
+                        self.online_users.sort();
+                    }
+                }
+                self.notice(EntryKind::Info, &Output::default().format(&Event::contact(info)));
+            }
+            ServerToClientMessage::ContactRemoved(username) => {
+                self.notice(EntryKind::Info, &Output::default().format(&Event::ContactRemoved { username }));
+            }
+            // shown where the user asked for it, as a block of notices
+            ServerToClientMessage::Thread(messages) => {
+                self.notice(EntryKind::Info, "Thread:");
+                for message in messages {
+                    let edited = if message.edited { " (edited)" } else { "" };
+                    self.notice(
+                        EntryKind::Info,
+                        &format!("  [{}] {}: {}{}", message.id, message.from, message.text, edited),
+                    );
+                }
+            }
+            // asked for on connect and after picking a name, both times the users are worth refreshing
+            ServerToClientMessage::Commands(infos) => {
+                self.server_commands = infos;
+                return Some(Action::Send(ClientToServerMessage::GetUserList));
+            }
+            ServerToClientMessage::UserList(mut users) => {
+                self.names.learn_users(&users);
+                users.sort_by(|a, b| a.username.cmp(&b.username));
+                self.bots = users.iter().filter(|u| u.bot).map(|u| u.username.clone()).collect();
+                self.online_users = users.into_iter().map(|u| u.username).collect();
+            }
+            ServerToClientMessage::Response(result) => match (self.pending.pop_front(), result) {
+                (Some(Pending::SetUsername(username)), Ok(_)) => {
+                    self.push_entry(None, EntryKind::Info, format!("You are now {}", username));
+                    self.username = Some(username);
+                    // the answer to this refreshes the users as well
+                    return Some(Action::Send(ClientToServerMessage::GetCommands));
+                }
+                (Some(Pending::Text(peer)), Err(e)) => {
+                    self.push_entry(Some(&peer), EntryKind::Error, format!("Not delivered: {}", e));
+                }
+                (Some(Pending::Text(_)), Ok(_)) => {}
+                (Some(Pending::Export(_)), Err(e)) => self.notice(EntryKind::Error, &format!("Export failed: {}", e)),
+                (Some(Pending::Avatar(_)), Err(e)) => self.notice(EntryKind::Error, &e),
+                (_, Ok(text)) => self.push_entry(None, EntryKind::Info, text),
+                (_, Err(e)) => self.push_entry(None, EntryKind::Error, e),
+            },
+            // only sent to clients asking with GetUsernames, which this one does not
+            ServerToClientMessage::Usernames(_) | ServerToClientMessage::None => {}
+        }
+        None
+    }
+
+    // binary frames from the server carry avatars which were asked for
+    pub fn handle_avatar(&mut self, frame: &[u8]) {
+        let Some((username, image)) = parse_avatar_frame(frame) else {
+            self.notice(EntryKind::Error, "Received an invalid avatar");
+            return;
+        };
+        let position = self
+            .pending
+            .iter()
+            .position(|pending| matches!(pending, Pending::Avatar(avatar) if avatar.username == username));
+        if let Some(Pending::Avatar(avatar)) = position.and_then(|position| self.pending.remove(position)) {
+            match avatar.write(image) {
+                Ok(text) => self.notice(EntryKind::Info, &text),
+                Err(e) => self.notice(EntryKind::Error, &e),
+            }
+        }
+    }
+
+    pub fn handle_disconnect(&mut self, reason: String) {
+        self.push_entry(None, EntryKind::Error, format!("Disconnected: {}", reason));
+        self.connection = ConnectionState::Disconnected(reason);
+    }
+
+    fn submit(&mut self) -> Option<Action> {
+        let line = self.input.submit();
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+
+        if let Some(command) = line.strip_prefix('/') {
+            return self.run_command(command);
+        }
+
+        match self.selected.clone() {
+            Some(peer) => self.send_text(peer, line.to_string(), None),
+            None => {
+                self.notice(
+                    EntryKind::Error,
+                    "Select a conversation with Tab or use /send \"<username>\" \"<message>\"",
+                );
+                None
+            }
+        }
+    }
+
+    fn run_command(&mut self, command: &str) -> Option<Action> {
+        let tokens = match parse_input(command) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                self.notice(EntryKind::Error, &e.to_string());
+                // shifted by one column for the slash the command was typed after
+                let underline = e.underline(command);
+                let (input, carets) = underline.split_once('\n').unwrap();
+                self.notice(EntryKind::Error, &format!("/{}", input));
+                self.notice(EntryKind::Error, &format!(" {}", carets));
+                return None;
+            }
+        };
+
+        match parse_command(&self.commands, &self.server_commands, &tokens) {
+            Ok(Invocation::Help(help)) => {
+                for line in help.lines() {
+                    self.notice(EntryKind::Info, line);
+                }
+                self.notice(
+                    EntryKind::Info,
+                    "Text without a leading / is sent to the selected conversation.",
+                );
+                self.notice(
+                    EntryKind::Info,
+                    "Tab/Shift-Tab switch conversation, PgUp/PgDn scroll, Up/Down browse history, Ctrl-C quit",
+                );
+                None
+            }
+            Ok(Invocation::Command(ClientCommand::Send(send))) => {
+                self.select(Some(send.username.clone()));
+                self.send_text(send.username, send.message, send.reply_to)
+            }
+            Ok(Invocation::Command(ClientCommand::GroupSend(send))) => {
+                self.select(Some(send.group.clone()));
+                self.send_text(send.group, send.message, send.reply_to)
+            }
+            Ok(Invocation::Command(ClientCommand::SetName(set_name))) => {
+                self.pending.push_back(Pending::SetUsername(set_name.username.clone()));
+                Some(Action::Send(ClientToServerMessage::SetUsername(set_name.username)))
+            }
+            Ok(Invocation::Command(ClientCommand::Login(login))) => {
+                self.pending.push_back(Pending::SetUsername(login.username.clone()));
+                Some(Action::Send(ClientToServerMessage::Login(login.username, login.password)))
+            }
+            Ok(Invocation::Command(ClientCommand::Open(open))) => {
+                self.select(Some(open.username));
+                None
+            }
+            Ok(Invocation::Command(ClientCommand::Export(export))) => {
+                let message = ClientToServerMessage::Export {
+                    conversation: export.conversation.clone(),
+                    format: export.format,
+                };
+                self.pending.push_back(Pending::Export(export));
+                Some(Action::Send(message))
+            }
+            Ok(Invocation::Command(ClientCommand::SetAvatar(set_avatar))) => match set_avatar.read() {
+                Ok(message) => Some(Action::Send(message)),
+                Err(e) => {
+                    self.notice(EntryKind::Error, &e);
+                    None
+                }
+            },
+            Ok(Invocation::Command(ClientCommand::Avatar(avatar))) => {
+                let message = ClientToServerMessage::GetAvatar(avatar.username.clone());
+                self.pending.push_back(Pending::Avatar(avatar));
+                Some(Action::Send(message))
+            }
+            Ok(Invocation::Command(ClientCommand::Close(_))) => Some(Action::Quit),
+            Ok(Invocation::Command(command)) => command.to_message().map(Action::Send),
+            Err(e) => {
+                self.notice(EntryKind::Error, &e.to_string());
+                None
+            }
+        }
+    }
+
+    // the message shows up once the server sends it back with its id
+    fn send_text(&mut self, peer: String, text: String, reply_to: Option<u64>) -> Option<Action> {
+        self.pending.push_back(Pending::Text(peer.clone()));
+        if peer.starts_with(GROUP_PREFIX) {
+            Some(Action::Send(ClientToServerMessage::GroupTextTo(peer, text, reply_to)))
+        } else {
+            Some(Action::Send(ClientToServerMessage::TextTo(peer, text, reply_to)))
+        }
+    }
+
+    // asks once for the profile of a sender whose display name is not known yet
+    fn fetch_name(&mut self, username: String) -> Option<Action> {
+        self.names
+            .should_fetch(&username)
+            .then_some(Action::Send(ClientToServerMessage::GetProfile(username)))
+    }
+
+    fn select(&mut self, peer: Option<String>) {
+        let conversation = self.conversation_mut(peer.as_deref());
+        conversation.unread = 0;
+        conversation.scroll = 0;
+        self.selected = peer;
+    }
+
+    fn select_relative(&mut self, offset: isize) {
+        let entries = self.sidebar_entries();
+        let current = entries
+            .iter()
+            .position(|e| e.peer == self.selected)
+            .unwrap_or(0) as isize;
+        let next = (current + offset).rem_euclid(entries.len() as isize) as usize;
+        self.select(entries[next].peer.clone());
+    }
+
+    fn scroll(&mut self, up: bool) {
+        let selected = self.selected.clone();
+        let conversation = self.conversation_mut(selected.as_deref());
+        if up {
+            conversation.scroll =
+                (conversation.scroll + SCROLL_STEP).min(conversation.entries.len().saturating_sub(1));
+        } else {
+            conversation.scroll = conversation.scroll.saturating_sub(SCROLL_STEP);
+        }
+    }
+
+    // shows a local notice in whatever conversation is on screen
+    fn notice(&mut self, kind: EntryKind, text: &str) {
+        let selected = self.selected.clone();
+        self.push_entry(selected.as_deref(), kind, text.to_string());
+    }
+
+    fn push_entry(&mut self, peer: Option<&str>, kind: EntryKind, text: String) {
+        self.push_message(peer, kind, None, None, text);
+    }
+
+    fn push_message(
+        &mut self,
+        peer: Option<&str>,
+        kind: EntryKind,
+        id: Option<u64>,
+        reply_to: Option<ReplyInfo>,
+        text: String,
+    ) {
+        let is_selected = self.selected.as_deref() == peer;
+        let conversation = self.conversation_mut(peer);
+        conversation.entries.push(Entry {
+            kind,
+            text,
+            id,
+            reply_to,
+            edited: false,
+            deleted: false,
+            reactions: Vec::new(),
+        });
+        if !is_selected {
+            conversation.unread += 1;
+        } else if conversation.scroll > 0 {
+            // keep the viewport still while the user reads older messages
+            conversation.scroll += 1;
+        }
+    }
+
+    fn entry_mut(&mut self, id: u64) -> Option<&mut Entry> {
+        self.conversations
+            .iter_mut()
+            .flat_map(|c| c.entries.iter_mut())
+            .find(|entry| entry.id == Some(id))
+    }
+
+    fn conversation_mut(&mut self, peer: Option<&str>) -> &mut Conversation {
+        let idx = match self
+            .conversations
+            .iter()
+            .position(|c| c.peer.as_deref() == peer)
+        {
+            Some(idx) => idx,
+            None => {
+                self.conversations.push(Conversation {
+                    peer: peer.map(str::to_string),
+                    ..Default::default()
+                });
+                self.conversations.len() - 1
+            }
+        };
+        &mut self.conversations[idx]
+    }
+}
@@ -0,0 +1,164 @@
+// A single line editor with a cursor and a history of submitted lines
+#[derive(Debug, Default)]
+pub struct InputLine {
+    chars: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    // index into history while browsing it with up/down, None when editing a fresh line
+    history_pos: Option<usize>,
+    draft: Vec<char>,
+}
+
+impl InputLine {
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    // text before the cursor, used to compute where the terminal cursor goes
+    pub fn text_before_cursor(&self) -> String {
+        self.chars[..self.cursor].iter().collect()
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.chars.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+
+    pub fn delete_word_before_cursor(&mut self) {
+        let mut start = self.cursor;
+        while start > 0 && self.chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        while start > 0 && !self.chars[start - 1].is_whitespace() {
+            start -= 1;
+        }
+        self.chars.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    pub fn clear(&mut self) {
+        self.chars.clear();
+        self.cursor = 0;
+    }
+
+    pub fn move_left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn move_right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.chars.len());
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn move_end(&mut self) {
+        self.cursor = self.chars.len();
+    }
+
+    pub fn history_prev(&mut self) {
+        if self.history.is_empty() {
+            return;
+        }
+        let pos = match self.history_pos {
+            None => {
+                self.draft = std::mem::take(&mut self.chars);
+                self.history.len() - 1
+            }
+            Some(pos) => pos.saturating_sub(1),
+        };
+        self.history_pos = Some(pos);
+        self.chars = self.history[pos].chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    pub fn history_next(&mut self) {
+        let Some(pos) = self.history_pos else {
+            return;
+        };
+        if pos + 1 < self.history.len() {
+            self.history_pos = Some(pos + 1);
+            self.chars = self.history[pos + 1].chars().collect();
+        } else {
+            self.history_pos = None;
+            self.chars = std::mem::take(&mut self.draft);
+        }
+        self.cursor = self.chars.len();
+    }
+
+    // takes the current line out of the editor and remembers it in the history
+    pub fn submit(&mut self) -> String {
+        let line = self.text();
+        self.clear();
+        self.history_pos = None;
+        self.draft.clear();
+        if !line.trim().is_empty() && self.history.last() != Some(&line) {
+            self.history.push(line.clone());
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InputLine;
+
+    fn type_str(input: &mut InputLine, str: &str) {
+        for c in str.chars() {
+            input.insert(c);
+        }
+    }
+
+    #[test]
+    fn test_editing() {
+        let mut input = InputLine::default();
+        type_str(&mut input, "hllo");
+        input.move_home();
+        input.move_right();
+        input.insert('e');
+        assert_eq!(input.text(), "hello");
+        assert_eq!(input.text_before_cursor(), "he");
+        input.move_end();
+        type_str(&mut input, " wörld");
+        input.backspace();
+        assert_eq!(input.text(), "hello wörl");
+        input.delete_word_before_cursor();
+        assert_eq!(input.text(), "hello ");
+        input.move_home();
+        input.delete();
+        assert_eq!(input.text(), "ello ");
+    }
+
+    #[test]
+    fn test_history() {
+        let mut input = InputLine::default();
+        type_str(&mut input, "first");
+        assert_eq!(input.submit(), "first");
+        type_str(&mut input, "second");
+        input.submit();
+        type_str(&mut input, "draft");
+        input.history_prev();
+        assert_eq!(input.text(), "second");
+        input.history_prev();
+        input.history_prev();
+        assert_eq!(input.text(), "first");
+        input.history_next();
+        assert_eq!(input.text(), "second");
+        input.history_next();
+        assert_eq!(input.text(), "draft");
+    }
+}
@@ -0,0 +1,97 @@
+mod app;
+mod input_line;
+mod ui;
+
+use crate::tui::app::{Action, App};
+use common::communication::common_message::{ClientToServerMessage, ServerToClientMessage};
+use crossterm::event::{Event, EventStream, KeyEventKind};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+// how often the list of online users in the sidebar is refreshed
+const USERNAMES_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+pub async fn run(mut ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>, server: String) {
+    let mut terminal = ratatui::init();
+    let mut events = EventStream::new();
+    let mut refresh = tokio::time::interval(USERNAMES_REFRESH_INTERVAL);
+    let mut app = App::new(server);
+
+    if let Err(e) = ws_stream.send(crate::frame(&ClientToServerMessage::GetCommands)).await {
+        app.handle_disconnect(e.to_string());
+    }
+
+    loop {
+        if let Err(e) = terminal.draw(|frame| ui::draw(frame, &app)) {
+            ratatui::restore();
+            println!("Failed to draw the terminal: {}", e);
+            return;
+        }
+
+        let action = tokio::select! {
+            event = events.next() => {
+                match event {
+                    Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => app.handle_key(key),
+                    Some(Ok(_)) => None,
+                    Some(Err(_)) | None => Some(Action::Quit),
+                }
+            }
+            msg = ws_stream.next(), if app.is_connected() => {
+                match msg {
+                    Some(Ok(Message::Text(text))) => {
+                        match serde_json::from_str::<ServerToClientMessage>(&text) {
+                            Ok(message) => app.handle_server_message(message),
+                            Err(e) => {
+                                app.handle_disconnect(format!("unexpected message from server: {}", e));
+                                None
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Binary(frame))) => {
+                        app.handle_avatar(&frame);
+                        None
+                    }
+                    Some(Ok(Message::Close(_))) => {
+                        app.handle_disconnect("remote host closed the connection".to_string());
+                        None
+                    }
+                    Some(Ok(_)) => None,
+                    Some(Err(e)) => {
+                        app.handle_disconnect(e.to_string());
+                        None
+                    }
+                    None => {
+                        app.handle_disconnect("remote host closed abruptly".to_string());
+                        None
+                    }
+                }
+            }
+            _ = refresh.tick(), if app.is_connected() => {
+                Some(Action::Send(ClientToServerMessage::GetUserList))
+            }
+        };
+
+        match action {
+            Some(Action::Send(message)) => {
+                if !app.is_connected() {
+                    continue;
+                }
+                if let Err(e) = ws_stream.send(crate::frame(&message)).await {
+                    app.handle_disconnect(e.to_string());
+                }
+            }
+            Some(Action::Quit) => {
+                if app.is_connected() {
+                    let _ = ws_stream.close(None).await;
+                }
+                break;
+            }
+            None => {}
+        }
+    }
+
+    ratatui::restore();
+}
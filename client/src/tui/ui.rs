@@ -0,0 +1,165 @@
+use crate::output::{reaction_counts, sender};
+use crate::tui::app::{App, ConnectionState, Entry, EntryKind};
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::Frame;
+
+const SIDEBAR_WIDTH: u16 = 24;
+
+pub fn draw(frame: &mut Frame, app: &App) {
+    let [main_area, input_area, status_area] = Layout::vertical([
+        Constraint::Min(3),
+        Constraint::Length(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let [sidebar_area, messages_area] =
+        Layout::horizontal([Constraint::Length(SIDEBAR_WIDTH), Constraint::Min(10)])
+            .areas(main_area);
+
+    draw_sidebar(frame, app, sidebar_area);
+    draw_messages(frame, app, messages_area);
+    draw_input(frame, app, input_area);
+    draw_status(frame, app, status_area);
+}
+
+fn draw_sidebar(frame: &mut Frame, app: &App, area: ratatui::layout::Rect) {
+    let entries = app.sidebar_entries();
+    let selected = entries.iter().position(|e| e.peer == app.selected);
+
+    let items: Vec<ListItem> = entries
+        .iter()
+        .map(|entry| {
+            let mut spans = match &entry.peer {
+                None => vec![Span::raw("  server").italic()],
+                Some(peer) => {
+                    let marker = if entry.online { Span::raw("● ").green() } else { Span::raw("○ ").dark_gray() };
+                    vec![marker, Span::raw(entry.name.clone().unwrap_or_else(|| peer.clone()))]
+                }
+            };
+            if entry.bot {
+                spans.push(Span::raw(" [bot]").dark_gray());
+            }
+            if entry.unread > 0 {
+                spans.push(Span::raw(format!(" ({})", entry.unread)).yellow().bold());
+            }
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("Users"))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+    let mut state = ListState::default().with_selected(selected);
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+fn draw_messages(frame: &mut Frame, app: &App, area: ratatui::layout::Rect) {
+    let title = match &app.selected {
+        Some(peer) => format!("Conversation with {}", peer),
+        None => "Server".to_string(),
+    };
+    let block = Block::default().borders(Borders::ALL).title(title);
+    let height = block.inner(area).height as usize;
+
+    let mut lines: Vec<Line> = match app.selected_conversation() {
+        Some(conversation) => {
+            let end = conversation.entries.len().saturating_sub(conversation.scroll);
+            let start = end.saturating_sub(height);
+            conversation.entries[start..end]
+                .iter()
+                .flat_map(|entry| {
+                    // replies show what they reply to on a line of their own
+                    let quote = entry.reply_to.as_ref().map(|reply| {
+                        Line::from(format!("  ╭ [{}] {}: {}", reply.id, reply.from, reply.excerpt)).dark_gray()
+                    });
+                    quote.into_iter().chain(std::iter::once(entry_line(app, entry)))
+                })
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    // quotes take extra lines, the latest messages stay in view
+    if lines.len() > height {
+        lines.drain(..lines.len() - height);
+    }
+
+    frame.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+// other users show up by their display name once it is known
+fn entry_line(app: &App, entry: &Entry) -> Line<'static> {
+    match &entry.kind {
+        EntryKind::Incoming(from) => {
+            message_line(entry, Span::raw(format!("{}: ", sender(from, &app.name_of(from), false))).cyan().bold())
+        }
+        EntryKind::FromBot(from) => {
+            message_line(entry, Span::raw(format!("{}: ", sender(from, &app.name_of(from), true))).magenta().bold())
+        }
+        EntryKind::Outgoing(from) => message_line(entry, Span::raw(format!("{}: ", from)).green().bold()),
+        EntryKind::Info => Line::from(entry.text.clone()).dark_gray(),
+        EntryKind::Error => Line::from(entry.text.clone()).red(),
+    }
+}
+
+// "[12] alice: hi (edited) [👍 2]", the id is what /edit, /delete and /react take
+fn message_line<'a>(entry: &Entry, sender: Span<'a>) -> Line<'a> {
+    let mut spans = Vec::new();
+    if let Some(id) = entry.id {
+        spans.push(Span::raw(format!("[{}] ", id)).dark_gray());
+    }
+    spans.push(sender);
+    if entry.deleted {
+        spans.push(Span::raw(entry.text.clone()).dark_gray().italic());
+        return Line::from(spans);
+    }
+    spans.push(Span::raw(entry.text.clone()));
+    if entry.edited {
+        spans.push(Span::raw(" (edited)").dark_gray());
+    }
+    if !entry.reactions.is_empty() {
+        spans.push(Span::raw(format!(" [{}]", reaction_counts(&entry.reactions))).yellow());
+    }
+    Line::from(spans)
+}
+
+fn draw_input(frame: &mut Frame, app: &App, area: ratatui::layout::Rect) {
+    let block = Block::default().borders(Borders::ALL).title("Input");
+    let inner = block.inner(area);
+    let text = app.input.text();
+    let cursor_offset = Span::raw(app.input.text_before_cursor()).width() as u16;
+
+    // keep the cursor visible by scrolling the line horizontally
+    let horizontal_scroll = cursor_offset.saturating_sub(inner.width.saturating_sub(1));
+    frame.render_widget(
+        Paragraph::new(text)
+            .block(block)
+            .scroll((0, horizontal_scroll)),
+        area,
+    );
+    frame.set_cursor_position(Position::new(
+        inner.x + cursor_offset - horizontal_scroll,
+        inner.y,
+    ));
+}
+
+fn draw_status(frame: &mut Frame, app: &App, area: ratatui::layout::Rect) {
+    let (state, color) = match &app.connection {
+        ConnectionState::Connected => (format!("Connected to {}", app.server), Color::Green),
+        ConnectionState::Disconnected(reason) => (format!("Disconnected: {}", reason), Color::Red),
+    };
+    let username = match &app.username {
+        Some(username) => format!(" as {}", username),
+        None => " (no username)".to_string(),
+    };
+    let status = Line::from(vec![
+        Span::raw(state).fg(color).bold(),
+        Span::raw(username),
+        Span::raw(" | Tab: switch  PgUp/PgDn: scroll  /help  Ctrl-C: quit").dark_gray(),
+    ]);
+    frame.render_widget(Paragraph::new(status), area);
+}
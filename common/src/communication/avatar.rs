@@ -0,0 +1,80 @@
+use std::fmt;
+
+// avatars are small pictures, anything larger is turned away before it is sent
+pub const MAX_AVATAR_BYTES: usize = 64 * 1024;
+
+// the bytes of an avatar, printed by their size so logs stay readable
+#[derive(Clone, PartialEq, PartialOrd, Default)]
+pub struct Image(pub Vec<u8>);
+
+impl fmt::Debug for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Image({} bytes)", self.0.len())
+    }
+}
+
+// the format of an image by its first bytes, for the formats avatars may have
+pub fn image_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if bytes.starts_with(b"\xff\xd8\xff") {
+        Some("jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("gif")
+    } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        Some("webp")
+    } else {
+        None
+    }
+}
+
+// what is wrong with an image to be used as an avatar, if anything
+pub fn check_avatar(bytes: &[u8]) -> Result<&'static str, String> {
+    if bytes.len() > MAX_AVATAR_BYTES {
+        return Err(format!("Avatars may have at most {} KiB!", MAX_AVATAR_BYTES / 1024));
+    }
+    image_type(bytes).ok_or_else(|| "Avatars have to be PNG, JPEG, GIF or WebP images!".to_string())
+}
+
+// clients send a binary frame holding just the image; the server answers requests for an
+// avatar with one starting with the length of the username in two bytes and the username
+pub fn avatar_frame(username: &str, image: &[u8]) -> Result<Vec<u8>, String> {
+    let name = username.as_bytes();
+    let length = u16::try_from(name.len()).map_err(|_| "Username too long for an avatar frame!".to_string())?;
+    let mut frame = Vec::with_capacity(2 + name.len() + image.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(name);
+    frame.extend_from_slice(image);
+    Ok(frame)
+}
+
+pub fn parse_avatar_frame(frame: &[u8]) -> Option<(String, &[u8])> {
+    let (length, rest) = frame.split_first_chunk::<2>()?;
+    let length = u16::from_be_bytes(*length) as usize;
+    if rest.len() < length {
+        return None;
+    }
+    let (name, image) = rest.split_at(length);
+    Some((String::from_utf8(name.to_vec()).ok()?, image))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{avatar_frame, check_avatar, parse_avatar_frame, MAX_AVATAR_BYTES};
+
+    #[test]
+    fn test_avatar_frames() {
+        let png = b"\x89PNG\r\n\x1a\nrest of the image".to_vec();
+        assert_eq!(check_avatar(&png), Ok("png"));
+        assert!(check_avatar(b"plain text").is_err());
+        let mut large = png.clone();
+        large.resize(MAX_AVATAR_BYTES + 1, 0);
+        assert!(check_avatar(&large).is_err());
+
+        let frame = avatar_frame("zoë", &png).unwrap();
+        assert_eq!(parse_avatar_frame(&frame), Some(("zoë".to_string(), &png[..])));
+        assert_eq!(parse_avatar_frame(&frame[..3]), None);
+        assert_eq!(parse_avatar_frame(&[]), None);
+        assert!(avatar_frame(&"a".repeat(70_000), &png).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use crate::communication::avatar::Image;
+use crate::logic::input_parser::InputToken;
 use serde::{Deserialize, Serialize};
 
 // send and sync are required for the broadcast channel
@@ -5,16 +7,323 @@ use serde::{Deserialize, Serialize};
 pub enum ClientToServerMessage {
     #[default]
     None,
-    TextTo(String, String),
+    // recipient, text and the id of the message it replies to, which has to be from the same conversation
+    TextTo(String, String, Option<u64>),
     GetUsernames,
+    // like GetUsernames, answered with UserList which tells bots apart
+    GetUserList,
     SetUsername(String),
+    // bot name and API key, bots are shown as such to everyone else
+    AuthenticateBot(String, String),
+    // username and password, the first login creates the account;
+    // unlike a plain username an account can be used from several clients at once
+    Login(String, String),
+    // starts a group conversation of the sender and the given users
+    CreateGroup(Vec<String>),
+    // group id, text and the id of the message it replies to
+    GroupTextTo(String, String, Option<u64>),
+    // group id and the user to add or remove; members leave by removing themselves
+    AddToGroup(String, String),
+    RemoveFromGroup(String, String),
+    // message id and new text, only the sender may change a message and only for a while
+    EditMessage(u64, String),
+    DeleteMessage(u64),
+    // message id and emoji, reacting with the same emoji again takes the reaction back
+    React(u64, String),
+    // the thread the message belongs to, from its first message on
+    GetThread(u64),
+    Search(SearchQuery),
+    // a conversation of the sender, or all of them without one
+    Export { conversation: Option<String>, format: ExportFormat },
+    // moderator actions, mutes last the given number of minutes
+    Kick(String),
+    Mute(String, u32),
+    Unmute(String),
+    Block(String),
+    Unblock(String),
+    // settings left as None keep their value, answered with Privacy
+    SetPrivacy { contacts_only: Option<bool>, hide_presence: Option<bool> },
+    // asks the user to become a contact, or accepts if they asked first
+    AddContact(String),
+    // accepts or declines the request of the user
+    AnswerContact(String, bool),
+    // ends a contact or takes back a request
+    RemoveContact(String),
+    GetContacts,
+    // one page of the users the server knows, online or not
+    Directory(DirectoryQuery),
+    GetProfile(String),
+    // fields left as None keep their value, empty ones are cleared; answered with Profile
+    SetProfile { display_name: Option<String>, bio: Option<String>, timezone: Option<String> },
+    // travels as a binary frame holding just the image, never as JSON
+    #[serde(skip)]
+    SetAvatar(Image),
+    RemoveAvatar,
+    // answered with a binary frame, see avatar::avatar_frame
+    GetAvatar(String),
+    // a slash command run by the server, args are the tokens after the name
+    Command { name: String, args: Vec<InputToken> },
+    GetCommands,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct UserInfo {
+    pub username: String,
+    pub bot: bool,
+    #[serde(default)]
+    pub display_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Default)]
+pub enum DirectorySort {
+    #[default]
+    Name,
+    NameDescending,
+    // online users first, each part by name
+    OnlineFirst,
+}
+
+impl DirectorySort {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "name" => Some(DirectorySort::Name),
+            "name-desc" | "desc" => Some(DirectorySort::NameDescending),
+            "online" => Some(DirectorySort::OnlineFirst),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct DirectoryQuery {
+    // matched against the start of usernames regardless of case, an empty filter matches everyone
+    pub filter: String,
+    // match the filter anywhere in the username
+    pub substring: bool,
+    pub online_only: bool,
+    pub sort: DirectorySort,
+    // the cursor of the previous page, none for the first page
+    pub after: Option<String>,
+    // entries per page, 0 for the server's default; the server caps it
+    pub limit: u32,
+    // whether entries say if the user is online, and carry their profile
+    pub presence: bool,
+    pub profile: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct UserProfile {
+    pub bot: bool,
+    pub role: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub timezone: Option<String>,
+    // the image type of the avatar, if the user has one
+    pub avatar: Option<String>,
+}
+
+// what a user tells others about themselves, every field may be left out
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct ProfileInfo {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    // an IANA name like Europe/Berlin or an offset like +02:00
+    pub timezone: Option<String>,
+    // the image type of the avatar, like png, fetched with GetAvatar
+    pub avatar: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct DirectoryEntry {
+    pub username: String,
+    // only filled in when the query asked for them
+    pub online: Option<bool>,
+    pub profile: Option<UserProfile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct DirectoryPage {
+    pub entries: Vec<DirectoryEntry>,
+    // users matching the query across all pages
+    pub total: usize,
+    // passed as `after` for the next page, none on the last page
+    pub next: Option<String>,
+}
+
+// a command the server offers, so clients can show help for commands they do not know
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct CommandInfo {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub usage: String,
+    pub help: String,
+}
+
+// group ids start with this, usernames must not
+pub const GROUP_PREFIX: char = '#';
+
+// the longest username the server accepts, in bytes
+pub const MAX_USERNAME_BYTES: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct GroupInfo {
+    pub id: String,
+    pub members: Vec<String>,
+}
+
+// one emoji on a message and who reacted with it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct ReactionInfo {
+    pub emoji: String,
+    pub users: Vec<String>,
+}
+
+// the message a reply refers to, with the start of its text to quote
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct ReplyInfo {
+    pub id: u64,
+    pub from: String,
+    pub excerpt: String,
+}
+
+// a message as the server keeps it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct MessageInfo {
+    pub id: u64,
+    pub from: String,
+    pub text: String,
+    pub reply_to: Option<u64>,
+    pub edited: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Default)]
+pub enum ExportFormat {
+    // one JSON object per message, which the server can import again
+    #[default]
+    JsonLines,
+    Markdown,
+    // a single page with its styles inlined
+    Html,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "jsonl" | "json" => Some(ExportFormat::JsonLines),
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            "html" | "htm" => Some(ExportFormat::Html),
+            _ => None,
+        }
+    }
+
+    // the format a file name asks for by its extension
+    pub fn for_file(file: &str) -> Option<Self> {
+        Self::from_name(file.rsplit_once('.')?.1)
+    }
+}
+
+// what a user keeps from others
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct PrivacySettings {
+    // direct messages only from contacts
+    pub contacts_only: bool,
+    // left out of everyone else's username list
+    pub hide_presence: bool,
+    pub blocked: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum ContactState {
+    #[default]
+    Contact,
+    // asked by the user, waiting for the other side
+    Sent,
+    // waiting for the user to accept or decline
+    Received,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct ContactInfo {
+    pub username: String,
+    pub state: ContactState,
+    pub online: bool,
+}
+
+// finds messages containing all the words of `terms` in the conversations of the requester
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct SearchQuery {
+    pub terms: String,
+    pub from: Option<String>,
+    // a username for the direct conversation with that user, or a group id
+    pub conversation: Option<String>,
+    // RFC 3339 times or dates like 2024-05-31
+    pub after: Option<String>,
+    pub before: Option<String>,
+    // counted from 1, the newest hits come first
+    pub page: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct SearchHit {
+    // named like the requester would send to it
+    pub conversation: String,
+    pub sent_at: String,
+    pub message: MessageInfo,
+    // the messages right before and after it in the same conversation
+    pub before: Vec<MessageInfo>,
+    pub after: Vec<MessageInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    pub page: u32,
+    pub pages: u32,
+    pub total: usize,
+}
+
+// messages carry the id the server assigned them, which edits, deletions and reactions refer to
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, PartialOrd, Default)]
 pub enum ServerToClientMessage {
     #[default]
     None,
-    TextFrom(String, String),
+    // replies carry the message they refer to
+    TextFrom(u64, String, String, Option<ReplyInfo>),
+    // a message sent by a bot account, a webhook or a server plugin; plugin messages have no id
+    BotTextFrom(Option<u64>, String, String, Option<ReplyInfo>),
+    // recipient and text of a message the user sent, to each of its sessions
+    SentText(u64, String, String, Option<ReplyInfo>),
+    // group id, sender and text, the sender's sessions get it as well
+    GroupTextFrom(u64, String, String, String, Option<ReplyInfo>),
+    MessageEdited(u64, String),
+    MessageDeleted(u64),
+    // all reactions on a message after one of them changed
+    ReactionsUpdated(u64, Vec<ReactionInfo>),
+    // the messages of a thread in the order they were sent, its first message first
+    Thread(Vec<MessageInfo>),
+    SearchResults(SearchResults),
+    // the rendered export and the number of messages in it
+    Exported { content: String, messages: usize },
+    Privacy(PrivacySettings),
+    // the user's contacts and open requests
+    Contacts(Vec<ContactInfo>),
+    // a new request, an accepted one, or a contact coming online or going offline
+    ContactUpdated(ContactInfo),
+    // the contact ended or the request was declined or taken back
+    ContactRemoved(String),
+    // sent to everyone in or just removed from a group whenever its members change
+    GroupUpdated(GroupInfo),
+    // the names of every online user, in the shape older clients parse;
+    // UserList tells bots apart and Directory pages through all users
     Usernames(Vec<String>),
+    UserList(Vec<UserInfo>),
+    Directory(DirectoryPage),
+    // the answer to asking for or changing a profile
+    Profile(ProfileInfo),
+    // someone else changed their profile
+    ProfileUpdated(ProfileInfo),
+    // the commands the requesting client is allowed to run
+    Commands(Vec<CommandInfo>),
     Response(Result<String, String>),
 }
@@ -1 +1,2 @@
-pub mod common_message;
\ No newline at end of file
+pub mod avatar;
+pub mod common_message;
@@ -0,0 +1,734 @@
+use crate::communication::common_message::CommandInfo;
+use crate::logic::input_parser::InputToken;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    Text,
+    // text which names a user, consoles complete it from the known usernames
+    Username,
+    Integer,
+    Float,
+    Bool,
+    // a list of user names written as [alice, bob], never empty
+    Usernames,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool,
+}
+
+impl ArgSpec {
+    pub const fn required(name: &'static str, kind: ArgKind) -> Self {
+        ArgSpec {
+            name,
+            kind,
+            optional: false,
+        }
+    }
+
+    // optional arguments have to come after all required ones
+    pub const fn optional(name: &'static str, kind: ArgKind) -> Self {
+        ArgSpec {
+            name,
+            kind,
+            optional: true,
+        }
+    }
+
+    pub fn placeholder(&self) -> String {
+        let placeholder = match self.kind {
+            ArgKind::Text | ArgKind::Username => format!("\"<{}>\"", self.name),
+            ArgKind::Integer | ArgKind::Float | ArgKind::Bool => format!("<{}>", self.name),
+            ArgKind::Usernames => format!("[<{}>, ...]", self.name),
+        };
+        if self.optional {
+            format!("[{}]", placeholder)
+        } else {
+            placeholder
+        }
+    }
+
+    fn accepts(&self, token: &InputToken) -> bool {
+        self.kind.accepts(token)
+    }
+}
+
+impl ArgKind {
+    fn accepts(self, token: &InputToken) -> bool {
+        match (self, token) {
+            // text is whatever was written, numbers, booleans and brackets included
+            (ArgKind::Text, _) => token.source_text().is_some(),
+            // names are taken as written, so a user may well be called 007 or true
+            (
+                ArgKind::Username,
+                InputToken::String(_)
+                | InputToken::General(_)
+                | InputToken::Integer(..)
+                | InputToken::Float(..)
+                | InputToken::Bool(_),
+            ) => true,
+            (ArgKind::Integer, InputToken::Integer(..)) => true,
+            (ArgKind::Integer, InputToken::General(s)) => s.parse::<i64>().is_ok(),
+            (ArgKind::Float, InputToken::Float(..) | InputToken::Integer(..)) => true,
+            (ArgKind::Float, InputToken::General(s)) => s.parse::<f64>().is_ok(),
+            (ArgKind::Bool, InputToken::Bool(_)) => true,
+            (ArgKind::Usernames, InputToken::List(items)) => {
+                !items.is_empty() && items.iter().all(|item| ArgKind::Username.accepts(item))
+            }
+            _ => false,
+        }
+    }
+}
+
+// a boolean switch written as --name, or an option written as --name=value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlagSpec {
+    pub name: &'static str,
+    pub value: Option<ArgKind>,
+    pub help: &'static str,
+}
+
+impl FlagSpec {
+    pub const fn switch(name: &'static str, help: &'static str) -> Self {
+        FlagSpec {
+            name,
+            value: None,
+            help,
+        }
+    }
+
+    pub const fn with_value(name: &'static str, kind: ArgKind, help: &'static str) -> Self {
+        FlagSpec {
+            name,
+            value: Some(kind),
+            help,
+        }
+    }
+
+    pub fn placeholder(&self) -> String {
+        match self.value {
+            None => format!("--{}", self.name),
+            Some(kind) => format!("--{}={}", self.name, ArgSpec::required(self.name, kind).placeholder()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub args: &'static [ArgSpec],
+    pub flags: &'static [FlagSpec],
+    pub help: &'static str,
+}
+
+impl CommandSpec {
+    pub fn matches(&self, name: &str) -> bool {
+        self.name == name || self.aliases.contains(&name)
+    }
+
+    pub fn usage(&self) -> String {
+        let mut usage = self.name.to_string();
+        for arg in self.args {
+            usage.push(' ');
+            usage.push_str(&arg.placeholder());
+        }
+        for flag in self.flags {
+            usage.push_str(&format!(" [{}]", flag.placeholder()));
+        }
+        usage
+    }
+
+    // the spec as sent to clients, which have no static specs for server commands
+    pub fn info(&self) -> CommandInfo {
+        CommandInfo {
+            name: self.name.to_string(),
+            aliases: self.aliases.iter().map(|a| a.to_string()).collect(),
+            usage: self.usage(),
+            help: self.help.to_string(),
+        }
+    }
+
+    pub fn find_flag(&self, name: &str) -> Option<&FlagSpec> {
+        self.flags.iter().find(|f| f.name == name)
+    }
+
+    // checks the tokens after the command name against the spec
+    pub fn parse_args(&'static self, tokens: &[InputToken]) -> Result<CommandArgs, CommandError> {
+        let mut values = Vec::new();
+        let mut flags = Vec::new();
+        let mut positional = self.args.iter();
+
+        let mut tokens = tokens.iter();
+        while let Some(token) = tokens.next() {
+            if let InputToken::Flag(name) | InputToken::KeyValue(name, _) = token {
+                let Some(flag) = self.find_flag(name) else {
+                    return Err(CommandError::UnknownFlag {
+                        flag: name.clone(),
+                        usage: self.usage(),
+                    });
+                };
+                match (flag.value, token) {
+                    (None, InputToken::Flag(_)) => flags.push(flag.name),
+                    // --name value, the way options are written on a command line
+                    (Some(kind), InputToken::Flag(_)) => match tokens.next() {
+                        None | Some(InputToken::Flag(_) | InputToken::KeyValue(..)) => {
+                            return Err(CommandError::MissingArgument {
+                                arg: flag.name,
+                                usage: self.usage(),
+                            })
+                        }
+                        Some(value) if kind.accepts(value) => values.push((flag.name, value.clone())),
+                        Some(value) => {
+                            return Err(CommandError::InvalidArgument {
+                                arg: flag.name,
+                                token: value.clone(),
+                                usage: self.usage(),
+                            })
+                        }
+                    },
+                    (Some(kind), InputToken::KeyValue(_, value)) if kind.accepts(value) => {
+                        values.push((flag.name, value.as_ref().clone()))
+                    }
+                    _ => {
+                        return Err(CommandError::InvalidArgument {
+                            arg: flag.name,
+                            token: token.clone(),
+                            usage: self.usage(),
+                        })
+                    }
+                }
+                continue;
+            }
+
+            let Some(arg) = positional.next() else {
+                return Err(CommandError::TooManyArguments { usage: self.usage() });
+            };
+            if !arg.accepts(token) {
+                return Err(CommandError::InvalidArgument {
+                    arg: arg.name,
+                    token: token.clone(),
+                    usage: self.usage(),
+                });
+            }
+            values.push((arg.name, token.clone()));
+        }
+
+        if let Some(arg) = positional.find(|a| !a.optional) {
+            return Err(CommandError::MissingArgument {
+                arg: arg.name,
+                usage: self.usage(),
+            });
+        }
+
+        Ok(CommandArgs {
+            spec: self,
+            values,
+            flags,
+        })
+    }
+}
+
+// validated arguments of one command invocation
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandArgs {
+    spec: &'static CommandSpec,
+    values: Vec<(&'static str, InputToken)>,
+    flags: Vec<&'static str>,
+}
+
+impl CommandArgs {
+    fn get(&self, name: &'static str) -> Option<&InputToken> {
+        self.values.iter().find(|(n, _)| *n == name).map(|(_, t)| t)
+    }
+
+    fn required<T>(
+        &self,
+        name: &'static str,
+        value: Option<Option<T>>,
+    ) -> Result<T, CommandError> {
+        match value {
+            Some(Some(value)) => Ok(value),
+            Some(None) => Err(CommandError::InvalidArgument {
+                arg: name,
+                token: self.get(name).cloned().unwrap_or_default(),
+                usage: self.spec.usage(),
+            }),
+            None => Err(CommandError::MissingArgument {
+                arg: name,
+                usage: self.spec.usage(),
+            }),
+        }
+    }
+
+    pub fn optional_string(&self, name: &'static str) -> Option<String> {
+        self.get(name)?.source_text()
+    }
+
+    pub fn string(&self, name: &'static str) -> Result<String, CommandError> {
+        self.required(name, self.get(name).map(|_| self.optional_string(name)))
+    }
+
+    pub fn optional_integer(&self, name: &'static str) -> Option<i64> {
+        match self.get(name)? {
+            InputToken::Integer(i, _) => Some(*i),
+            InputToken::General(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn integer(&self, name: &'static str) -> Result<i64, CommandError> {
+        self.required(name, self.get(name).map(|_| self.optional_integer(name)))
+    }
+
+    pub fn optional_float(&self, name: &'static str) -> Option<f64> {
+        match self.get(name)? {
+            InputToken::Float(f, _) => Some(*f),
+            InputToken::Integer(i, _) => Some(*i as f64),
+            InputToken::General(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn float(&self, name: &'static str) -> Result<f64, CommandError> {
+        self.required(name, self.get(name).map(|_| self.optional_float(name)))
+    }
+
+    pub fn optional_bool(&self, name: &'static str) -> Option<bool> {
+        match self.get(name)? {
+            InputToken::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn bool(&self, name: &'static str) -> Result<bool, CommandError> {
+        self.required(name, self.get(name).map(|_| self.optional_bool(name)))
+    }
+
+    pub fn optional_strings(&self, name: &'static str) -> Option<Vec<String>> {
+        let InputToken::List(items) = self.get(name)? else {
+            return None;
+        };
+        items
+            .iter()
+            .map(InputToken::source_text)
+            .collect()
+    }
+
+    pub fn strings(&self, name: &'static str) -> Result<Vec<String>, CommandError> {
+        self.required(name, self.get(name).map(|_| self.optional_strings(name)))
+    }
+
+    pub fn flag(&self, name: &'static str) -> bool {
+        self.flags.contains(&name)
+    }
+}
+
+// a console command which knows its grammar and how to build itself from validated arguments
+pub trait Command: Sized {
+    const SPEC: CommandSpec;
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    Empty,
+    NotACommand(InputToken),
+    UnknownCommand { name: String, available: Vec<&'static str> },
+    MissingArgument { arg: &'static str, usage: String },
+    TooManyArguments { usage: String },
+    InvalidArgument { arg: &'static str, token: InputToken, usage: String },
+    UnknownFlag { flag: String, usage: String },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Empty => write!(f, "No command given"),
+            CommandError::NotACommand(token) => {
+                write!(f, "Expected a command name, found {:?}", token)
+            }
+            CommandError::UnknownCommand { name, available } => write!(
+                f,
+                "Invalid instruction {}, available instructions are: {}",
+                name,
+                available.join(", ")
+            ),
+            CommandError::MissingArgument { arg, usage } => {
+                write!(f, "Missing argument {}, should be: {}", arg, usage)
+            }
+            CommandError::TooManyArguments { usage } => {
+                write!(f, "Too many arguments, should be: {}", usage)
+            }
+            CommandError::InvalidArgument { arg, token, usage } => write!(
+                f,
+                "Invalid value {:?} for {}, should be: {}",
+                token, arg, usage
+            ),
+            CommandError::UnknownFlag { flag, usage } => {
+                write!(f, "Unknown flag --{}, should be: {}", flag, usage)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+pub enum Invocation<C> {
+    Command(C),
+    // the built-in help command, carrying the text to show
+    Help(String),
+}
+
+pub const HELP_SPEC: CommandSpec = CommandSpec {
+    name: "help",
+    aliases: &["?"],
+    args: &[ArgSpec::optional("command", ArgKind::Text)],
+    flags: &[],
+    help: "show all commands or the usage of one command",
+};
+
+type Constructor<C> = Box<dyn Fn(&CommandArgs) -> Result<C, CommandError> + Send + Sync>;
+
+// the commands a console understands, each turned into a value of the console's command type
+pub struct CommandSet<C> {
+    commands: Vec<(&'static CommandSpec, Constructor<C>)>,
+}
+
+impl<C> Default for CommandSet<C> {
+    fn default() -> Self {
+        CommandSet {
+            commands: Vec::new(),
+        }
+    }
+}
+
+impl<C> CommandSet<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<T: Command + 'static>(
+        mut self,
+        wrap: impl Fn(T) -> C + Send + Sync + 'static,
+    ) -> Self {
+        self.commands.push((
+            &T::SPEC,
+            Box::new(move |args| T::from_args(args).map(&wrap)),
+        ));
+        self
+    }
+
+    // every spec including the built-in help, in registration order
+    pub fn specs(&self) -> Vec<CommandSpec> {
+        let mut specs: Vec<CommandSpec> = self.commands.iter().map(|(s, _)| **s).collect();
+        specs.push(HELP_SPEC);
+        specs
+    }
+
+    pub fn find(&self, name: &str) -> Option<&'static CommandSpec> {
+        if HELP_SPEC.matches(name) {
+            return Some(&HELP_SPEC);
+        }
+        self.commands
+            .iter()
+            .map(|(s, _)| *s)
+            .find(|s| s.matches(name))
+    }
+
+    pub fn help(&self) -> String {
+        let specs = self.specs();
+        let usages: Vec<String> = specs.iter().map(CommandSpec::usage).collect();
+        let width = usages.iter().map(|u| u.chars().count()).max().unwrap_or(0);
+
+        let mut help = String::from("Available commands:");
+        for (spec, usage) in specs.iter().zip(usages) {
+            help.push_str(&format!("\n  {:width$}  {}", usage, spec.help, width = width));
+            if !spec.aliases.is_empty() {
+                help.push_str(&format!(" (aliases: {})", spec.aliases.join(", ")));
+            }
+        }
+        help
+    }
+
+    pub fn help_for(&self, spec: &CommandSpec) -> String {
+        let mut help = format!("{}\n  {}", spec.usage(), spec.help);
+        if !spec.aliases.is_empty() {
+            help.push_str(&format!("\n  aliases: {}", spec.aliases.join(", ")));
+        }
+        for flag in spec.flags {
+            help.push_str(&format!("\n  {}  {}", flag.placeholder(), flag.help));
+        }
+        help
+    }
+
+    pub fn parse(&self, tokens: &[InputToken]) -> Result<Invocation<C>, CommandError> {
+        let Some(first) = tokens.first() else {
+            return Err(CommandError::Empty);
+        };
+        let InputToken::General(name) = first else {
+            return Err(CommandError::NotACommand(first.clone()));
+        };
+
+        if HELP_SPEC.matches(name) {
+            let args = HELP_SPEC.parse_args(&tokens[1..])?;
+            return match args.optional_string("command") {
+                None => Ok(Invocation::Help(self.help())),
+                Some(command) => match self.find(&command) {
+                    Some(spec) => Ok(Invocation::Help(self.help_for(spec))),
+                    None => Err(self.unknown(command)),
+                },
+            };
+        }
+
+        let Some((spec, constructor)) = self.commands.iter().find(|(s, _)| s.matches(name)) else {
+            return Err(self.unknown(name.clone()));
+        };
+        let args = spec.parse_args(&tokens[1..])?;
+        constructor(&args).map(Invocation::Command)
+    }
+
+    fn unknown(&self, name: String) -> CommandError {
+        CommandError::UnknownCommand {
+            name,
+            available: self.specs().iter().map(|s| s.name).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::logic::input_parser::parse_input;
+
+    #[derive(Debug, PartialEq)]
+    struct Send {
+        username: String,
+        message: String,
+        urgent: bool,
+        repeat: Option<i64>,
+    }
+
+    impl Command for Send {
+        const SPEC: CommandSpec = CommandSpec {
+            name: "send",
+            aliases: &["msg"],
+            args: &[
+                ArgSpec::required("username", ArgKind::Username),
+                ArgSpec::required("message", ArgKind::Text),
+            ],
+            flags: &[
+                FlagSpec::switch("urgent", "mark the message as urgent"),
+                FlagSpec::with_value("repeat", ArgKind::Integer, "send the message several times"),
+            ],
+            help: "send a message to a user",
+        };
+
+        fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+            Ok(Send {
+                username: args.string("username")?,
+                message: args.string("message")?,
+                urgent: args.flag("urgent"),
+                repeat: args.optional_integer("repeat"),
+            })
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Remind {
+        minutes: i64,
+        text: Option<String>,
+    }
+
+    impl Command for Remind {
+        const SPEC: CommandSpec = CommandSpec {
+            name: "remind",
+            aliases: &[],
+            args: &[
+                ArgSpec::required("minutes", ArgKind::Integer),
+                ArgSpec::optional("text", ArgKind::Text),
+            ],
+            flags: &[],
+            help: "remind me later",
+        };
+
+        fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+            Ok(Remind {
+                minutes: args.integer("minutes")?,
+                text: args.optional_string("text"),
+            })
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum TestCommand {
+        Send(Send),
+        Remind(Remind),
+    }
+
+    fn commands() -> CommandSet<TestCommand> {
+        CommandSet::new()
+            .with(TestCommand::Send)
+            .with(TestCommand::Remind)
+    }
+
+    fn parse(input: &str) -> Result<TestCommand, CommandError> {
+        match commands().parse(&parse_input(input).unwrap())? {
+            Invocation::Command(command) => Ok(command),
+            Invocation::Help(help) => panic!("Unexpected help: {}", help),
+        }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse(r#"send "bob" "hi there""#),
+            Ok(TestCommand::Send(Send {
+                username: "bob".to_string(),
+                message: "hi there".to_string(),
+                urgent: false,
+                repeat: None,
+            }))
+        );
+        assert_eq!(
+            parse(r#"msg --urgent bob "hi""#),
+            Ok(TestCommand::Send(Send {
+                username: "bob".to_string(),
+                message: "hi".to_string(),
+                urgent: true,
+                repeat: None,
+            }))
+        );
+        assert_eq!(
+            parse(r#"send bob 'it''s "fine"' --repeat=3"#),
+            Ok(TestCommand::Send(Send {
+                username: "bob".to_string(),
+                message: "its \"fine\"".to_string(),
+                urgent: false,
+                repeat: Some(3),
+            }))
+        );
+        assert_eq!(
+            parse("send --repeat 2 bob hi"),
+            Ok(TestCommand::Send(Send {
+                username: "bob".to_string(),
+                message: "hi".to_string(),
+                urgent: false,
+                repeat: Some(2),
+            }))
+        );
+        assert_eq!(
+            parse("remind 5"),
+            Ok(TestCommand::Remind(Remind {
+                minutes: 5,
+                text: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_text_takes_any_token() {
+        let message = |input: &str| match parse(input) {
+            Ok(TestCommand::Send(send)) => send.message,
+            other => panic!("Unexpected {:?}", other),
+        };
+        assert_eq!(message("send bob true"), "true");
+        assert_eq!(message("send bob 12345678"), "12345678");
+        assert_eq!(message("send bob 1e234567"), "1e234567");
+        assert_eq!(message("send bob [wip]"), "[wip]");
+        assert_eq!(message(r#"send bob [a, "b c"]"#), r#"[a, "b c"]"#);
+        assert_eq!(parse("remind 007"), Ok(TestCommand::Remind(Remind { minutes: 7, text: None })));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse(""), Err(CommandError::Empty));
+        assert!(matches!(parse("\"send\""), Err(CommandError::NotACommand(_))));
+        assert!(matches!(parse("nope"), Err(CommandError::UnknownCommand { .. })));
+        assert_eq!(
+            parse("send bob"),
+            Err(CommandError::MissingArgument {
+                arg: "message",
+                usage: "send \"<username>\" \"<message>\" [--urgent] [--repeat=<repeat>]".to_string(),
+            })
+        );
+        assert!(matches!(parse("send bob hi there"), Err(CommandError::TooManyArguments { .. })));
+        assert!(matches!(parse("send bob hi --loud"), Err(CommandError::UnknownFlag { .. })));
+        assert!(matches!(
+            parse("send bob hi --repeat"),
+            Err(CommandError::MissingArgument { arg: "repeat", .. })
+        ));
+        assert!(matches!(
+            parse("send bob hi --repeat --urgent"),
+            Err(CommandError::MissingArgument { arg: "repeat", .. })
+        ));
+        assert!(matches!(
+            parse("send bob hi --repeat=often"),
+            Err(CommandError::InvalidArgument { arg: "repeat", .. })
+        ));
+        assert!(matches!(
+            parse("send bob hi --urgent=true"),
+            Err(CommandError::InvalidArgument { arg: "urgent", .. })
+        ));
+        assert!(matches!(
+            parse("remind soon"),
+            Err(CommandError::InvalidArgument { arg: "minutes", .. })
+        ));
+    }
+
+    #[test]
+    fn test_username_lists() {
+        const GROUP: CommandSpec = CommandSpec {
+            name: "group",
+            aliases: &[],
+            args: &[ArgSpec::required("members", ArgKind::Usernames)],
+            flags: &[],
+            help: "",
+        };
+        assert_eq!(GROUP.usage(), "group [<members>, ...]");
+
+        let tokens = parse_input(r#"group [alice, "bob smith"]"#).unwrap();
+        let args = GROUP.parse_args(&tokens[1..]).unwrap();
+        assert_eq!(
+            args.strings("members"),
+            Ok(vec!["alice".to_string(), "bob smith".to_string()])
+        );
+        let tokens = parse_input("group [007, 1e5, true]").unwrap();
+        let args = GROUP.parse_args(&tokens[1..]).unwrap();
+        assert_eq!(
+            args.strings("members"),
+            Ok(vec!["007".to_string(), "1e5".to_string(), "true".to_string()])
+        );
+
+        for input in ["group []", "group alice", "group [[alice]]", "group [alice, --admin]"] {
+            let tokens = parse_input(input).unwrap();
+            assert!(
+                matches!(GROUP.parse_args(&tokens[1..]), Err(CommandError::InvalidArgument { .. })),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_help() {
+        let commands = commands();
+        let Ok(Invocation::Help(help)) = commands.parse(&parse_input("help").unwrap()) else {
+            panic!("Expected help");
+        };
+        assert!(help.contains("send \"<username>\" \"<message>\" [--urgent] [--repeat=<repeat>]  send a message to a user (aliases: msg)"));
+        assert!(help.contains("remind <minutes> [\"<text>\"]"));
+        assert!(help.contains("help [\"<command>\"]"));
+
+        let Ok(Invocation::Help(help)) = commands.parse(&parse_input("help msg").unwrap()) else {
+            panic!("Expected help");
+        };
+        assert!(help.starts_with("send \"<username>\" \"<message>\" [--urgent] [--repeat=<repeat>]"));
+        assert!(help.contains("--urgent  mark the message as urgent"));
+    }
+}
@@ -1,5 +1,8 @@
-use std::*;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::CharIndices;
 
 #[derive(Debug, PartialEq, Clone, PartialOrd, Default, Serialize, Deserialize)]
 pub enum InputToken {
@@ -7,181 +10,436 @@ pub enum InputToken {
     None,
     General(String),
     String(String),
-    Integer(i64),
-    Float(f64),
+    // numbers keep the way they were written, like +5 or 1e5
+    Integer(i64, String),
+    Float(f64, String),
+    Bool(bool),
+    // --name
+    Flag(String),
+    // --name=value
+    KeyValue(String, Box<InputToken>),
+    // [a, b, c]
+    List(Vec<InputToken>),
 }
 
+impl InputToken {
+    // the token the way it was written, for arguments which take any text
+    pub fn source_text(&self) -> Option<String> {
+        match self {
+            InputToken::General(s) | InputToken::String(s) => Some(s.clone()),
+            InputToken::Integer(_, s) | InputToken::Float(_, s) => Some(s.clone()),
+            InputToken::Bool(b) => Some(b.to_string()),
+            InputToken::List(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| match item {
+                        InputToken::String(s) => Some(format!("{:?}", s)),
+                        _ => item.source_text(),
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(format!("[{}]", items.join(", ")))
+            }
+            InputToken::None | InputToken::Flag(_) | InputToken::KeyValue(..) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnexpectedQuote,
+    UnterminatedString,
+    UnterminatedList,
+    NestedTooDeep,
+    InvalidEscape,
+    InvalidUnicodeEscape,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ParseErrorKind::UnexpectedQuote => "Unexpected quote",
+            ParseErrorKind::UnterminatedString => "Unexpected end of input",
+            ParseErrorKind::UnterminatedList => "Missing closing bracket",
+            ParseErrorKind::NestedTooDeep => "Lists nested too deeply",
+            ParseErrorKind::InvalidEscape => "Invalid escape character",
+            ParseErrorKind::InvalidUnicodeEscape => "Invalid unicode escape",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    // byte offsets into the input
+    pub span: Range<usize>,
+    // the same range counted in characters, for pointing at it on a console
+    pub columns: Range<usize>,
+}
+
+impl ParseError {
+    fn new(input: &str, kind: ParseErrorKind, span: Range<usize>) -> Self {
+        let start = input[..span.start].chars().count();
+        let end = start + input[span.clone()].chars().count();
+        ParseError {
+            kind,
+            span,
+            columns: start..end,
+        }
+    }
+
+    // the input with a line of carets under the offending part
+    pub fn underline(&self, input: &str) -> String {
+        format!(
+            "{}\n{}{}",
+            input,
+            " ".repeat(self.columns.start),
+            "^".repeat((self.columns.end - self.columns.start).max(1))
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.kind, self.columns.start + 1)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// deeper lists are rejected rather than read recursively
+const MAX_LIST_DEPTH: usize = 16;
+
 fn parse_token(input: String) -> InputToken {
     // String was handled else where
-    let int_res = input.parse::<i64>();
-    if int_res.is_ok() {
-        return InputToken::Integer(int_res.unwrap());
+    // words without a digit, like inf or nan, are no numbers
+    if input.bytes().any(|b| b.is_ascii_digit()) {
+        if let Ok(int) = input.parse::<i64>() {
+            return InputToken::Integer(int, input);
+        }
+        if let Ok(float) = input.parse::<f64>() {
+            return InputToken::Float(float, input);
+        }
     }
-    let float_res = input.parse::<f64>();
-    if float_res.is_ok() {
-        return InputToken::Float(float_res.unwrap());
+    match input.as_str() {
+        "true" => InputToken::Bool(true),
+        "false" => InputToken::Bool(false),
+        _ => InputToken::General(input),
     }
-    InputToken::General(input)
 }
-pub fn parse_input(input: &str) -> Result<Vec<InputToken>, String> {
-    let mut tokens: Vec<InputToken> = Vec::new();
-    let mut buffer = String::new();
-    let mut idx = 0;
-    while idx < input.len() {
-        let c = input.chars().nth(idx);
-        if c.is_none() {
-            return Err("Unexpected end of input".to_string());
-        }
-        let c = c.unwrap();
-        match c {
-            '"' => {
-                if !buffer.is_empty() {
-                    return Err("Unexpected quote".to_string());
-                }
-                handle_string(&input, &mut idx, &mut buffer)?;
-                tokens.push(InputToken::String(std::mem::take(&mut buffer)));
+
+// characters which end a bare word; inside a list commas and the closing bracket do too
+fn ends_word(c: char, in_list: bool) -> bool {
+    c.is_whitespace() || (in_list && (c == ',' || c == ']'))
+}
+
+// splits console input into tokens in a single pass over its characters
+pub struct Tokenizer<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    // lists currently open
+    depth: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Tokenizer {
+            input,
+            chars: input.char_indices().peekable(),
+            depth: 0,
+        }
+    }
+
+    fn error(&self, kind: ParseErrorKind, span: Range<usize>) -> ParseError {
+        ParseError::new(self.input, kind, span)
+    }
+
+    // byte offset of the next character, or the end of the input
+    fn position(&mut self) -> usize {
+        self.chars.peek().map_or(self.input.len(), |(idx, _)| *idx)
+    }
+
+    fn read_token(&mut self, in_list: bool) -> Result<InputToken, ParseError> {
+        let position = self.position();
+        let rest = &self.input[position..];
+        if rest.starts_with('[') {
+            return self.read_list();
+        }
+        if let Some(name) = rest.strip_prefix("--") {
+            if name
+                .chars()
+                .next()
+                .is_some_and(|c| !ends_word(c, in_list) && !matches!(c, '=' | '"' | '\''))
+            {
+                return self.read_flag(in_list);
             }
-            c if c.is_whitespace() => {
-                if !buffer.is_empty() {
-                    tokens.push(parse_token(std::mem::take(&mut buffer)));
-                    buffer.clear();
+        }
+        self.read_word(in_list)
+    }
+
+    // a word may mix bare text and quoted parts, which are joined like in a shell
+    fn read_word(&mut self, in_list: bool) -> Result<InputToken, ParseError> {
+        let mut buffer = String::new();
+        let mut quoted = false;
+        while let Some(&(_, c)) = self.chars.peek() {
+            match c {
+                _ if ends_word(c, in_list) => break,
+                '"' => {
+                    quoted = true;
+                    self.read_string(&mut buffer)?;
+                }
+                '\'' => {
+                    quoted = true;
+                    self.read_raw_string(&mut buffer)?;
+                }
+                _ => {
+                    buffer.push(c);
+                    self.chars.next();
                 }
-                idx += c.len_utf8();
             }
-            c => {
-                buffer.push(c);
-                idx += c.len_utf8();
+        }
+        if quoted {
+            Ok(InputToken::String(buffer))
+        } else {
+            Ok(parse_token(buffer))
+        }
+    }
+
+    fn read_flag(&mut self, in_list: bool) -> Result<InputToken, ParseError> {
+        self.chars.next();
+        self.chars.next();
+        let mut name = String::new();
+        while let Some(&(idx, c)) = self.chars.peek() {
+            match c {
+                _ if ends_word(c, in_list) => break,
+                '=' => {
+                    self.chars.next();
+                    let value = match self.chars.peek() {
+                        Some(&(_, c)) if !ends_word(c, in_list) => self.read_token(in_list)?,
+                        _ => InputToken::String(String::new()),
+                    };
+                    return Ok(InputToken::KeyValue(name, Box::new(value)));
+                }
+                '"' | '\'' => {
+                    return Err(self.error(ParseErrorKind::UnexpectedQuote, idx..idx + 1));
+                }
+                _ => {
+                    name.push(c);
+                    self.chars.next();
+                }
             }
         }
+        Ok(InputToken::Flag(name))
     }
 
-    if !buffer.is_empty() {
-        tokens.push(parse_token(buffer));
+    fn read_list(&mut self) -> Result<InputToken, ParseError> {
+        let (start, bracket) = self.chars.next().unwrap();
+        assert_eq!(bracket, '[');
+        if self.depth == MAX_LIST_DEPTH {
+            return Err(self.error(ParseErrorKind::NestedTooDeep, start..start + 1));
+        }
+        self.depth += 1;
+        let list = self.read_items(start);
+        self.depth -= 1;
+        list
     }
 
-    Ok(tokens)
-}
+    fn read_items(&mut self, start: usize) -> Result<InputToken, ParseError> {
+        let mut items = Vec::new();
+        loop {
+            while self
+                .chars
+                .next_if(|&(_, c)| c.is_whitespace() || c == ',')
+                .is_some()
+            {}
+            match self.chars.peek() {
+                None => {
+                    return Err(self.error(ParseErrorKind::UnterminatedList, start..self.input.len()))
+                }
+                Some((_, ']')) => {
+                    self.chars.next();
+                    return Ok(InputToken::List(items));
+                }
+                Some(_) => items.push(self.read_token(true)?),
+            }
+        }
+    }
 
-fn handle_string(str: &&str, idx: &mut usize, buffer: &mut String) -> Result<(), String> {
-    let c = str.chars().nth(*idx).unwrap();
-    assert_eq!(c, '"');
+    // single quotes take everything up to the next single quote literally
+    fn read_raw_string(&mut self, buffer: &mut String) -> Result<(), ParseError> {
+        let (start, quote) = self.chars.next().unwrap();
+        assert_eq!(quote, '\'');
 
-    *idx += c.len_utf8();
-    while *idx < str.len() {
-        let c = str.chars().nth(*idx).unwrap();
-        match c {
-            '"' => {
-                *idx += c.len_utf8();
+        for (_, c) in self.chars.by_ref() {
+            if c == '\'' {
                 return Ok(());
             }
-            '\\' => {
-                handle_escape(str, idx, buffer)?;
-            }
-            _ => {
-                buffer.push(c);
-                *idx += c.len_utf8();
-            }
+            buffer.push(c);
         }
+
+        Err(self.error(ParseErrorKind::UnterminatedString, start..self.input.len()))
     }
 
-    Err("Unexpected end of input".to_string())
-}
+    fn read_string(&mut self, buffer: &mut String) -> Result<(), ParseError> {
+        let (start, quote) = self.chars.next().unwrap();
+        assert_eq!(quote, '"');
+
+        while let Some((idx, c)) = self.chars.next() {
+            match c {
+                '"' => return Ok(()),
+                '\\' => self.read_escape(idx, buffer)?,
+                _ => buffer.push(c),
+            }
+        }
+
+        Err(self.error(ParseErrorKind::UnterminatedString, start..self.input.len()))
+    }
 
-fn handle_escape(str: &&str, idx: &mut usize, buffer: &mut String) -> Result<(), String> {
-    assert_eq!(str.chars().nth(*idx).unwrap(), '\\');
-    *idx += '\\'.len_utf8();
-    let c = str.chars().nth(*idx).unwrap();
-    match c {
-        '"' => {
-            buffer.push('"');
-            *idx += c.len_utf8();
-        }
-        '\\' => {
-            buffer.push('\\');
-            *idx += c.len_utf8();
-        }
-        'n' => {
-            buffer.push('\n');
-            *idx += c.len_utf8();
-        }
-        't' => {
-            buffer.push('\t');
-            *idx += c.len_utf8();
-        }
-        'r' => {
-            buffer.push('\r');
-            *idx += c.len_utf8();
-        }
-        '0' => {
-            buffer.push('\0');
-            *idx += c.len_utf8();
-        }
-        'u' => {
-            let mut hex = String::new();
-            *idx += c.len_utf8();
-            for _ in 0..4 {
-                let c = str.chars().nth(*idx).unwrap();
-                if !c.is_ascii_hexdigit() {
-                    return Err("Invalid unicode escape".to_string());
+    // the backslash at `start` was already consumed
+    fn read_escape(&mut self, start: usize, buffer: &mut String) -> Result<(), ParseError> {
+        let Some((idx, c)) = self.chars.next() else {
+            return Err(self.error(ParseErrorKind::UnterminatedString, start..self.input.len()));
+        };
+        match c {
+            '"' => buffer.push('"'),
+            '\'' => buffer.push('\''),
+            '\\' => buffer.push('\\'),
+            'n' => buffer.push('\n'),
+            't' => buffer.push('\t'),
+            'r' => buffer.push('\r'),
+            '0' => buffer.push('\0'),
+            'u' => {
+                let c = self.read_unicode_escape();
+                match c {
+                    Some(c) => buffer.push(c),
+                    None => {
+                        let end = self.position();
+                        return Err(self.error(ParseErrorKind::InvalidUnicodeEscape, start..end));
+                    }
                 }
-                hex.push(c);
-                *idx += c.len_utf8();
             }
-            let code = u32::from_str_radix(&hex, 16);
-            if code.is_err() {
-                return Err("Invalid unicode escape".to_string());
+            _ => {
+                return Err(self.error(ParseErrorKind::InvalidEscape, start..idx + c.len_utf8()));
             }
-            let code = code.unwrap();
-            buffer.push(char::from_u32(code).unwrap());
         }
-        _ => {
-            *idx += c.len_utf8();
-            return Err("Invalid escape character".to_string());
+        Ok(())
+    }
+
+    // \u{1F600}, \u0041 or a UTF-16 surrogate pair like \uD83D\uDE00, after the \u
+    fn read_unicode_escape(&mut self) -> Option<char> {
+        if self.chars.next_if(|&(_, c)| c == '{').is_some() {
+            let code = self.read_hex(1, 6)?;
+            self.chars.next_if(|&(_, c)| c == '}')?;
+            return char::from_u32(code);
+        }
+
+        let code = self.read_hex(4, 4)?;
+        if !(0xD800..0xDC00).contains(&code) {
+            return char::from_u32(code);
+        }
+        let position = self.position();
+        if !self.input[position..].starts_with("\\u") {
+            return None;
+        }
+        self.chars.next();
+        self.chars.next();
+        let low = self.read_hex(4, 4)?;
+        if !(0xDC00..0xE000).contains(&low) {
+            return None;
+        }
+        char::from_u32(0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00))
+    }
+
+    fn read_hex(&mut self, min_digits: usize, max_digits: usize) -> Option<u32> {
+        let mut code = 0;
+        let mut digits = 0;
+        while digits < max_digits {
+            let Some(digit) = self.chars.peek().and_then(|(_, c)| c.to_digit(16)) else {
+                break;
+            };
+            self.chars.next();
+            code = code * 16 + digit;
+            digits += 1;
         }
+        (digits >= min_digits).then_some(code)
     }
+}
+
+impl Iterator for Tokenizer<'_> {
+    type Item = Result<InputToken, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        self.chars.peek()?;
+        Some(self.read_token(false))
+    }
+}
+
+pub fn parse_input(input: &str) -> Result<Vec<InputToken>, ParseError> {
+    Tokenizer::new(input).collect()
+}
 
-    Ok(())
+// tokens for arguments the shell already split: the first one names the command, the rest are
+// kept as strings apart from --flags, and everything after a bare -- is positional
+pub fn tokens_from_args<I: IntoIterator<Item = String>>(args: I) -> Vec<InputToken> {
+    let mut tokens = Vec::new();
+    let mut options_done = false;
+    for (idx, arg) in args.into_iter().enumerate() {
+        if idx == 0 {
+            tokens.push(InputToken::General(arg));
+            continue;
+        }
+        if options_done {
+            tokens.push(InputToken::String(arg));
+            continue;
+        }
+        match arg.strip_prefix("--") {
+            Some("") => options_done = true,
+            Some(option) => match option.split_once('=') {
+                Some((name, value)) => tokens.push(InputToken::KeyValue(
+                    name.to_string(),
+                    Box::new(InputToken::String(value.to_string())),
+                )),
+                None => tokens.push(InputToken::Flag(option.to_string())),
+            },
+            None => tokens.push(InputToken::String(arg)),
+        }
+    }
+    tokens
 }
 
 #[cfg(test)]
 mod test {
+    use super::{parse_input, ParseErrorKind, Tokenizer};
+    use proptest::prelude::*;
+
+    fn string_of(input: &str) -> String {
+        match Tokenizer::new(input).next() {
+            Some(Ok(super::InputToken::String(s))) => s,
+            other => panic!("Unexpected token {:?}", other),
+        }
+    }
+
     #[test]
     fn test_handle_escape() {
-        let str = r#""\n\t\r\0\"\\\u0041""#;
-        let mut idx = 1;
-        let mut buffer = String::new();
-        super::handle_escape(&str, &mut idx, &mut buffer).unwrap();
-        assert_eq!(buffer, "\n");
-        buffer.clear();
-        super::handle_escape(&str, &mut idx, &mut buffer).unwrap();
-        assert_eq!(buffer, "\t");
-        buffer.clear();
-        super::handle_escape(&str, &mut idx, &mut buffer).unwrap();
-        assert_eq!(buffer, "\r");
-        buffer.clear();
-        super::handle_escape(&str, &mut idx, &mut buffer).unwrap();
-        assert_eq!(buffer, "\0");
-        buffer.clear();
-        super::handle_escape(&str, &mut idx, &mut buffer).unwrap();
-        assert_eq!(buffer, "\"");
-        buffer.clear();
-        super::handle_escape(&str, &mut idx, &mut buffer).unwrap();
-        assert_eq!(buffer, "\\");
-        buffer.clear();
-        super::handle_escape(&str, &mut idx, &mut buffer).unwrap();
-        assert_eq!(buffer, "A");
+        assert_eq!(string_of(r#""\n""#), "\n");
+        assert_eq!(string_of(r#""\t""#), "\t");
+        assert_eq!(string_of(r#""\r""#), "\r");
+        assert_eq!(string_of(r#""\0""#), "\0");
+        assert_eq!(string_of(r#""\"""#), "\"");
+        assert_eq!(string_of(r#""\\""#), "\\");
+        assert_eq!(string_of(r#""\u0041""#), "A");
+        assert_eq!(string_of(r#""\n\t\r\0\"\\\u0041""#), "\n\t\r\0\"\\A");
     }
 
     #[test]
     fn test_handle_string() {
         let str = r#""Hello, \"world\"!\t\n\u0042\"""#;
-        let mut idx = 0;
-        let mut buffer = String::new();
-        super::handle_string(&str, &mut idx, &mut buffer).unwrap();
-        assert_eq!(buffer, "Hello, \"world\"!\t\nB\"");
+        assert_eq!(string_of(str), "Hello, \"world\"!\t\nB\"");
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_parse_input() {
         let input = r#"Hello, "world\u0042\"!" 42 3.14"#;
         let tokens = super::parse_input(input).unwrap();
@@ -195,12 +453,208 @@ mod test {
             _ => panic!("Unexpected token"),
         }
         match &tokens[2] {
-            super::InputToken::Integer(i) => assert_eq!(i, &42),
+            super::InputToken::Integer(i, _) => assert_eq!(i, &42),
             _ => panic!("Unexpected token"),
         }
         match &tokens[3] {
-            super::InputToken::Float(f) => assert_eq!(f, &3.14),
+            super::InputToken::Float(f, _) => assert_eq!(f, &3.14),
             _ => panic!("Unexpected token"),
         }
     }
+
+    #[test]
+    fn test_multi_byte_input() {
+        let tokens = parse_input("héllo \"wörld\\n\" ünïcode").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                super::InputToken::General("héllo".to_string()),
+                super::InputToken::String("wörld\n".to_string()),
+                super::InputToken::General("ünïcode".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let error = parse_input("héllo --na\"me").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnexpectedQuote);
+        assert_eq!(error.span, 11..12);
+        assert_eq!(error.columns, 10..11);
+        assert_eq!(error.underline("héllo --na\"me"), "héllo --na\"me\n          ^");
+        assert_eq!(error.to_string(), "Unexpected quote at column 11");
+
+        let error = parse_input("send \"ü\\x\"").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::InvalidEscape);
+        assert_eq!(error.columns, 7..9);
+
+        let error = parse_input("\"ü\\ud800 \"").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::InvalidUnicodeEscape);
+        assert_eq!(error.columns, 2..8);
+
+        let error = parse_input("\"\\u12").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::InvalidUnicodeEscape);
+        assert_eq!(error.columns, 1..5);
+
+        let error = parse_input("say \"unterminated").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnterminatedString);
+        assert_eq!(error.columns, 4..17);
+
+        let error = parse_input("\"\\").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnterminatedString);
+
+        let error = parse_input("say 'ünterminated").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnterminatedString);
+        assert_eq!(error.columns, 4..17);
+
+        let error = parse_input("to [ä, [b]").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnterminatedList);
+        assert_eq!(error.columns, 3..10);
+
+        let error = parse_input(&"[".repeat(100_000)).unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::NestedTooDeep);
+        assert_eq!(error.columns, 16..17);
+        assert!(parse_input(&format!("{}{}", "[".repeat(16), "]".repeat(16))).is_ok());
+
+        let error = parse_input("\"\\u{110000}\"").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::InvalidUnicodeEscape);
+
+        let error = parse_input("\"\\u{1F600\"").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::InvalidUnicodeEscape);
+
+        let error = parse_input("\"\\udc00\"").unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::InvalidUnicodeEscape);
+    }
+
+    #[test]
+    fn test_tokens_from_args() {
+        use super::InputToken::*;
+        let args = ["send", "--to", "bob", "--as=ci bot", "42", "--", "--not a flag"];
+        assert_eq!(
+            super::tokens_from_args(args.map(str::to_string)),
+            vec![
+                General("send".to_string()),
+                Flag("to".to_string()),
+                String("bob".to_string()),
+                KeyValue("as".to_string(), Box::new(String("ci bot".to_string()))),
+                String("42".to_string()),
+                String("--not a flag".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unicode_escapes() {
+        assert_eq!(string_of(r#""\u{1F600}""#), "😀");
+        assert_eq!(string_of(r#""\u{41}\u{e9}""#), "Aé");
+        assert_eq!(string_of(r#""\uD83D\uDE00""#), "😀");
+    }
+
+    #[test]
+    fn test_rich_tokens() {
+        use super::InputToken::*;
+        let tokens = parse_input(
+            r#"'raw \n "text"' name"quoted"'raw' true "false" --verbose --count=3 --to="a b" [1, 2.5 ,x ["y"]] []"#,
+        )
+        .unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                String(r#"raw \n "text""#.to_string()),
+                String("namequotedraw".to_string()),
+                Bool(true),
+                String("false".to_string()),
+                Flag("verbose".to_string()),
+                KeyValue("count".to_string(), Box::new(Integer(3, "3".to_string()))),
+                KeyValue("to".to_string(), Box::new(String("a b".to_string()))),
+                List(vec![
+                    Integer(1, "1".to_string()),
+                    Float(2.5, "2.5".to_string()),
+                    General("x".to_string()),
+                    List(vec![String("y".to_string())]),
+                ]),
+                List(vec![]),
+            ]
+        );
+
+        // numbers are typed however they are written, and give that back as their text
+        let tokens = parse_input("007 1e5 1.0 +5 -3 nan").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Integer(7, "007".to_string()),
+                Float(100000.0, "1e5".to_string()),
+                Float(1.0, "1.0".to_string()),
+                Integer(5, "+5".to_string()),
+                Integer(-3, "-3".to_string()),
+                General("nan".to_string()),
+            ]
+        );
+        let texts: Vec<_> = tokens.iter().map(|token| token.source_text().unwrap()).collect();
+        assert_eq!(texts, ["007", "1e5", "1.0", "+5", "-3", "nan"]);
+
+        assert_eq!(
+            parse_input("-- --= a,b] --list=[a,b] --empty=").unwrap(),
+            vec![
+                General("--".to_string()),
+                General("--=".to_string()),
+                General("a,b]".to_string()),
+                KeyValue(
+                    "list".to_string(),
+                    Box::new(List(vec![General("a".to_string()), General("b".to_string())]))
+                ),
+                KeyValue("empty".to_string(), Box::new(String(std::string::String::new()))),
+            ]
+        );
+    }
+
+    fn quote(text: &str) -> String {
+        let mut quoted = String::from('"');
+        for c in text.chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                _ => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    proptest! {
+        #[test]
+        fn test_never_panics(input in any::<String>()) {
+            match parse_input(&input) {
+                Ok(_) => {}
+                Err(error) => {
+                    prop_assert!(error.span.start <= error.span.end);
+                    prop_assert!(error.span.end <= input.len());
+                    prop_assert!(input.is_char_boundary(error.span.start));
+                    prop_assert!(input.is_char_boundary(error.span.end));
+                    prop_assert_eq!(error.columns.start, input[..error.span.start].chars().count());
+                }
+            }
+        }
+
+        #[test]
+        fn test_quoted_strings_round_trip(texts in prop::collection::vec(any::<String>(), 0..5)) {
+            let input: Vec<String> = texts.iter().map(|t| quote(t)).collect();
+            let tokens = parse_input(&input.join(" ")).unwrap();
+            let expected: Vec<super::InputToken> =
+                texts.into_iter().map(super::InputToken::String).collect();
+            prop_assert_eq!(tokens, expected);
+        }
+
+        #[test]
+        fn test_words_are_split_on_whitespace(words in prop::collection::vec("[^\\s\"'\\[]+", 0..5)) {
+            let tokens = parse_input(&words.join(" \t")).unwrap();
+            prop_assert_eq!(tokens.len(), words.len());
+            for (token, word) in tokens.iter().zip(&words) {
+                if let super::InputToken::General(s) = token {
+                    prop_assert_eq!(s, word);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,373 @@
+use crate::logic::command::{ArgKind, ArgSpec, CommandSpec};
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, ExternalPrinter, Helper};
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// usernames offered by tab completion, kept up to date by whoever owns the console
+pub type SharedUsernames = Arc<Mutex<Vec<String>>>;
+
+pub struct ConsoleHelper {
+    commands: Vec<CommandSpec>,
+    usernames: SharedUsernames,
+}
+
+impl ConsoleHelper {
+    pub fn new(commands: Vec<CommandSpec>, usernames: SharedUsernames) -> Self {
+        ConsoleHelper {
+            commands,
+            usernames,
+        }
+    }
+
+    fn find_command(&self, name: &str) -> Option<&CommandSpec> {
+        self.commands.iter().find(|c| c.matches(name))
+    }
+
+    // the command of the line and how many positional arguments come before the cursor word
+    fn command_and_position(
+        &self,
+        line: &str,
+        starts: &[usize],
+    ) -> Option<(&CommandSpec, usize)> {
+        let command = self.find_command(line[..starts[1]].trim())?;
+        let typed_args = starts
+            .windows(2)
+            .skip(1)
+            .filter(|w| !line[w[0]..w[1]].starts_with("--"))
+            .count();
+        Some((command, typed_args))
+    }
+
+    fn complete_words(&self, line: &str) -> (usize, Vec<Pair>) {
+        let starts = word_starts(line);
+        let current_start = *starts.last().unwrap();
+        let current = &line[current_start..];
+
+        if starts.len() == 1 {
+            let candidates = self
+                .commands
+                .iter()
+                .filter(|c| c.name.starts_with(current))
+                .map(|c| Pair {
+                    display: c.name.to_string(),
+                    replacement: format!("{} ", c.name),
+                })
+                .collect();
+            return (current_start, candidates);
+        }
+
+        let Some((command, position)) = self.command_and_position(line, &starts) else {
+            return (current_start, Vec::new());
+        };
+
+        if let Some(flag) = current.strip_prefix("--") {
+            let candidates = command
+                .flags
+                .iter()
+                .filter(|f| f.name.starts_with(flag))
+                .map(|f| Pair {
+                    display: f.placeholder(),
+                    // options continue with their value right after the =
+                    replacement: match f.value {
+                        None => format!("--{} ", f.name),
+                        Some(_) => format!("--{}=", f.name),
+                    },
+                })
+                .collect();
+            return (current_start, candidates);
+        }
+
+        if command.args.get(position).map(|a| a.kind) != Some(ArgKind::Username) {
+            return (current_start, Vec::new());
+        }
+
+        let typed = current.trim_start_matches(['"', '\'']);
+        let mut usernames: Vec<String> = self
+            .usernames
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|u| u.starts_with(typed))
+            .cloned()
+            .collect();
+        usernames.sort();
+        let candidates = usernames
+            .into_iter()
+            .map(|u| Pair {
+                replacement: format!("{} ", quote(&u)),
+                display: u,
+            })
+            .collect();
+        (current_start, candidates)
+    }
+
+    fn hint_for(&self, line: &str) -> Option<String> {
+        let starts = word_starts(line);
+        let current_start = *starts.last().unwrap();
+        let current = &line[current_start..];
+
+        if starts.len() == 1 {
+            if current.is_empty() {
+                return None;
+            }
+            let mut matches = self.commands.iter().filter(|c| c.name.starts_with(current));
+            let command = matches.next()?;
+            if matches.next().is_some() {
+                return None;
+            }
+            return Some(command.usage()[current.len()..].to_string());
+        }
+
+        let (command, mut next_arg) = self.command_and_position(line, &starts)?;
+        // skip the argument under the cursor if the user already started typing it
+        if !current.is_empty() && !current.starts_with("--") {
+            next_arg += 1;
+        }
+        let remaining: Vec<String> = command
+            .args
+            .iter()
+            .skip(next_arg)
+            .map(ArgSpec::placeholder)
+            .collect();
+        if remaining.is_empty() {
+            return None;
+        }
+        let separator = if current.is_empty() { "" } else { " " };
+        Some(format!("{}{}", separator, remaining.join(" ")))
+    }
+}
+
+// start of every word before the cursor, quotes group a word the way parse_input does;
+// the last entry is the start of the word under the cursor, which may be empty
+fn word_starts(line: &str) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut escaped = false;
+    for (idx, c) in line.char_indices() {
+        if let Some(open) = quote {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if open == '"' => escaped = true,
+                _ if c == open => quote = None,
+                _ => {}
+            }
+        } else if c.is_whitespace() {
+            in_word = false;
+        } else {
+            if !in_word {
+                starts.push(idx);
+                in_word = true;
+            }
+            if c == '"' || c == '\'' {
+                quote = Some(c);
+            }
+        }
+    }
+    if !in_word {
+        starts.push(line.len());
+    }
+    starts
+}
+
+fn quote(text: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Completer for ConsoleHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        Ok(self.complete_words(&line[..pos]))
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        self.hint_for(line)
+    }
+}
+
+impl Highlighter for ConsoleHelper {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("\x1b[2m{}\x1b[0m", hint))
+    }
+}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
+
+pub enum ConsoleInput {
+    Line(String),
+    // Ctrl-C
+    Interrupted,
+    // Ctrl-D or the end of piped input
+    Eof,
+}
+
+// readline-style console with completion, hints and a history file in the home directory
+pub struct LineEditor {
+    editor: Editor<ConsoleHelper, DefaultHistory>,
+    history_path: PathBuf,
+}
+
+impl LineEditor {
+    pub fn new(
+        commands: Vec<CommandSpec>,
+        usernames: SharedUsernames,
+        history_file_name: &str,
+    ) -> rustyline::Result<Self> {
+        let mut editor = Editor::new()?;
+        editor.set_helper(Some(ConsoleHelper::new(commands, usernames)));
+
+        let history_path = match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(history_file_name),
+            None => PathBuf::from(history_file_name),
+        };
+        // a missing history file just means this is the first run
+        let _ = editor.load_history(&history_path);
+
+        Ok(LineEditor {
+            editor,
+            history_path,
+        })
+    }
+
+    // printer that redraws the prompt around the printed text,
+    // None if the console is not a terminal and plain println! is fine
+    pub fn external_printer(&mut self) -> Option<Box<dyn ExternalPrinter + Send>> {
+        match self.editor.create_external_printer() {
+            Ok(printer) => Some(Box::new(printer)),
+            Err(_) => None,
+        }
+    }
+
+    pub fn read_line(&mut self, prompt: &str) -> ConsoleInput {
+        match self.editor.readline(prompt) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = self.editor.add_history_entry(line.as_str());
+                    let _ = self.editor.save_history(&self.history_path);
+                }
+                ConsoleInput::Line(line)
+            }
+            Err(ReadlineError::Interrupted) => ConsoleInput::Interrupted,
+            Err(ReadlineError::Eof) => ConsoleInput::Eof,
+            Err(e) => {
+                println!("Failed to read line: {}", e);
+                ConsoleInput::Eof
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConsoleHelper;
+    use crate::logic::command::{ArgKind, ArgSpec, CommandSpec, FlagSpec};
+    use std::sync::{Arc, Mutex};
+
+    const SEND: CommandSpec = CommandSpec {
+        name: "send",
+        aliases: &["msg"],
+        args: &[
+            ArgSpec::required("username", ArgKind::Username),
+            ArgSpec::required("message", ArgKind::Text),
+        ],
+        flags: &[FlagSpec::switch("urgent", "")],
+        help: "",
+    };
+
+    const SET_NAME: CommandSpec = CommandSpec {
+        name: "set_name",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Text)],
+        flags: &[],
+        help: "",
+    };
+
+    const USERNAMES: CommandSpec = CommandSpec {
+        name: "usernames",
+        aliases: &[],
+        args: &[],
+        flags: &[],
+        help: "",
+    };
+
+    fn helper() -> ConsoleHelper {
+        ConsoleHelper::new(
+            vec![SEND, SET_NAME, USERNAMES],
+            Arc::new(Mutex::new(vec!["bob".to_string(), "alice".to_string(), "al \"x\"".to_string()])),
+        )
+    }
+
+    fn replacements(line: &str) -> (usize, Vec<String>) {
+        let (start, pairs) = helper().complete_words(line);
+        (start, pairs.into_iter().map(|p| p.replacement).collect())
+    }
+
+    #[test]
+    fn test_complete_command() {
+        assert_eq!(replacements("se"), (0, vec!["send ".to_string(), "set_name ".to_string()]));
+        assert_eq!(replacements("  us"), (2, vec!["usernames ".to_string()]));
+    }
+
+    #[test]
+    fn test_complete_username() {
+        assert_eq!(
+            replacements("send \"al"),
+            (5, vec!["\"al \\\"x\\\"\" ".to_string(), "\"alice\" ".to_string()])
+        );
+        assert_eq!(replacements("msg b"), (4, vec!["\"bob\" ".to_string()]));
+        assert_eq!(replacements("send --urgent b"), (14, vec!["\"bob\" ".to_string()]));
+        // the message argument is free text
+        assert_eq!(replacements("send \"bob\" b"), (11, vec![]));
+        assert_eq!(replacements("set_name a"), (9, vec![]));
+    }
+
+    #[test]
+    fn test_complete_flag() {
+        assert_eq!(replacements("send --u"), (5, vec!["--urgent ".to_string()]));
+        assert_eq!(replacements("usernames --"), (10, vec![]));
+    }
+
+    #[test]
+    fn test_hint() {
+        let helper = helper();
+        assert_eq!(
+            helper.hint_for("sen"),
+            Some("d \"<username>\" \"<message>\" [--urgent]".to_string())
+        );
+        assert_eq!(helper.hint_for("se"), None);
+        assert_eq!(helper.hint_for("send "), Some("\"<username>\" \"<message>\"".to_string()));
+        assert_eq!(helper.hint_for("msg \"bob"), Some(" \"<message>\"".to_string()));
+        assert_eq!(helper.hint_for("send \"bob\" \"hi there\""), None);
+        assert_eq!(helper.hint_for("unknown "), None);
+    }
+}
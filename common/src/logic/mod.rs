@@ -1 +1,3 @@
-pub mod input_parser;
\ No newline at end of file
+pub mod command;
+pub mod input_parser;
+pub mod line_editor;
@@ -0,0 +1,235 @@
+mod pattern;
+mod report;
+mod simulated;
+
+use common::logic::command::{ArgKind, Command, CommandArgs, CommandError, CommandSet, CommandSpec, FlagSpec, Invocation};
+use common::logic::input_parser::{tokens_from_args, InputToken};
+use pattern::Pattern;
+use report::Report;
+use simulated::Settings;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Barrier;
+
+// the same exit codes as the client's one-shot commands
+const EXIT_FAILED: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+
+const SERVER_ENV: &str = "CHAT_SERVER";
+
+const SERVER: FlagSpec = FlagSpec::with_value(
+    "server",
+    ArgKind::Text,
+    "server address, ws:// may be left out; defaults to $CHAT_SERVER",
+);
+const CLIENTS: FlagSpec = FlagSpec::with_value("clients", ArgKind::Text, "number of simulated clients, 10 by default");
+const MESSAGES: FlagSpec = FlagSpec::with_value(
+    "messages",
+    ArgKind::Text,
+    "messages each client sends to each of its targets, 100 by default",
+);
+const RATE: FlagSpec = FlagSpec::with_value(
+    "rate",
+    ArgKind::Text,
+    "messages per second from each client, as fast as the server answers by default",
+);
+const IDLE: FlagSpec = FlagSpec::with_value(
+    "idle",
+    ArgKind::Text,
+    "seconds to wait for missing messages once nothing arrives anymore, 5 by default",
+);
+const PREFIX: FlagSpec = FlagSpec::with_value("prefix", ArgKind::Text, "usernames are the prefix and a number, load by default");
+const JSON: FlagSpec = FlagSpec::switch("json", "print the report as one JSON object");
+const ROOM_SIZE: FlagSpec = FlagSpec::with_value("room-size", ArgKind::Text, "members of each room, 5 by default");
+
+const FLAGS: &[FlagSpec] = &[SERVER, CLIENTS, MESSAGES, RATE, IDLE, PREFIX, JSON];
+
+struct LoadTest {
+    pattern: Pattern,
+    server: Option<String>,
+    clients: usize,
+    messages: usize,
+    rate: Option<f64>,
+    idle: f64,
+    prefix: String,
+    json: bool,
+}
+
+// numbers come from the command line as text
+fn number<T: FromStr>(args: &CommandArgs, spec: &CommandSpec, name: &'static str) -> Result<Option<T>, CommandError> {
+    match args.optional_string(name) {
+        None => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(|_| CommandError::InvalidArgument {
+            arg: name,
+            token: InputToken::String(value),
+            usage: spec.usage(),
+        }),
+    }
+}
+
+impl LoadTest {
+    fn from_args(args: &CommandArgs, spec: &CommandSpec, pattern: Pattern) -> Result<Self, CommandError> {
+        Ok(LoadTest {
+            pattern,
+            server: args.optional_string("server"),
+            clients: number(args, spec, "clients")?.unwrap_or(10),
+            messages: number(args, spec, "messages")?.unwrap_or(100),
+            rate: number(args, spec, "rate")?,
+            idle: number(args, spec, "idle")?.unwrap_or(5.0),
+            prefix: args.optional_string("prefix").unwrap_or_else(|| "load".to_string()),
+            json: args.flag("json"),
+        })
+    }
+
+    fn url(&self) -> Option<String> {
+        let server = self.server.clone().or_else(|| std::env::var(SERVER_ENV).ok())?;
+        if server.contains("://") {
+            Some(server)
+        } else {
+            Some(format!("ws://{}", server))
+        }
+    }
+}
+
+struct Pairs(LoadTest);
+
+impl Command for Pairs {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "pairs",
+        aliases: &[],
+        args: &[],
+        flags: FLAGS,
+        help: "every client messages its partner, who messages it back",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        LoadTest::from_args(args, &Self::SPEC, Pattern::Pairs).map(Pairs)
+    }
+}
+
+struct FanIn(LoadTest);
+
+impl Command for FanIn {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "fan-in",
+        aliases: &[],
+        args: &[],
+        flags: FLAGS,
+        help: "every client messages the first one",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        LoadTest::from_args(args, &Self::SPEC, Pattern::FanIn).map(FanIn)
+    }
+}
+
+struct FanOut(LoadTest);
+
+impl Command for FanOut {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "fan-out",
+        aliases: &[],
+        args: &[],
+        flags: FLAGS,
+        help: "the first client messages every other one",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        LoadTest::from_args(args, &Self::SPEC, Pattern::FanOut).map(FanOut)
+    }
+}
+
+struct Rooms(LoadTest);
+
+impl Command for Rooms {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "rooms",
+        aliases: &[],
+        args: &[],
+        flags: &[SERVER, CLIENTS, MESSAGES, RATE, IDLE, PREFIX, JSON, ROOM_SIZE],
+        help: "the clients are split into group chats which every member messages",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        let size = number(args, &Self::SPEC, "room-size")?.unwrap_or(5);
+        LoadTest::from_args(args, &Self::SPEC, Pattern::Rooms { size }).map(Rooms)
+    }
+}
+
+fn load_tests() -> CommandSet<LoadTest> {
+    CommandSet::new()
+        .with(|Pairs(test)| test)
+        .with(|FanIn(test)| test)
+        .with(|FanOut(test)| test)
+        .with(|Rooms(test)| test)
+}
+
+async fn run(test: LoadTest) -> Result<Report, String> {
+    let url = test.url().ok_or_else(|| format!("No server given, use --server or set {}", SERVER_ENV))?;
+    let (clients, messages) = (test.clients, test.messages);
+    if messages == 0 || test.rate.is_some_and(|rate| rate <= 0.0) || test.idle < 0.0 {
+        return Err("--messages and --rate have to be positive, --idle must not be negative".to_string());
+    }
+    let plans = pattern::plans(test.pattern, clients, messages, &test.prefix)?;
+    let settings = Arc::new(Settings {
+        url,
+        messages,
+        rate: test.rate,
+        idle: Duration::from_secs_f64(test.idle),
+    });
+
+    // the clients start sending together, once all of them logged in and joined their rooms
+    let barrier = Arc::new(Barrier::new(clients + 1));
+    let tasks: Vec<_> = plans.into_iter()
+        .map(|plan| tokio::spawn(simulated::simulate(plan, settings.clone(), barrier.clone())))
+        .collect();
+    barrier.wait().await;
+    eprintln!("{} clients done logging in", clients);
+    barrier.wait().await;
+    let start = Instant::now();
+    eprintln!("Sending");
+
+    let mut outcomes = Vec::new();
+    for task in tasks {
+        outcomes.push(task.await.map_err(|e| e.to_string())?);
+    }
+    let end = outcomes.iter().filter_map(|outcome| outcome.last_delivery).max().unwrap_or(start);
+    Ok(Report::new(outcomes, end.duration_since(start)))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let test = match load_tests().parse(&tokens_from_args(args)) {
+        Ok(Invocation::Command(test)) => test,
+        Ok(Invocation::Help(help)) => {
+            println!("{}", help);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    let json = test.json;
+    match run(test).await {
+        Ok(report) => {
+            if json {
+                println!("{}", report.to_json());
+            } else {
+                println!("{}", report.to_text());
+            }
+            if report.passed() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(EXIT_FAILED)
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(EXIT_USAGE)
+        }
+    }
+}
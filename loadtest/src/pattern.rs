@@ -0,0 +1,124 @@
+// who sends to whom
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pattern {
+    // every client and its partner message each other
+    Pairs,
+    // everyone messages the first client
+    FanIn,
+    // the first client messages everyone else
+    FanOut,
+    // the clients are split into groups which everyone in them messages
+    Rooms { size: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    User(String),
+    // the group of the client's room, named by the server once its creator made it
+    Room,
+}
+
+// what one simulated client does
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub username: String,
+    // each target gets every message
+    pub targets: Vec<Target>,
+    // the members a room creator makes its group with, everyone but itself
+    pub creates_room: Option<Vec<String>>,
+    // messages which should reach this client
+    pub expected: usize,
+}
+
+impl Plan {
+    fn new(username: String) -> Self {
+        Plan {
+            username,
+            targets: Vec::new(),
+            creates_room: None,
+            expected: 0,
+        }
+    }
+}
+
+pub fn username(prefix: &str, index: usize) -> String {
+    format!("{}{}", prefix, index)
+}
+
+// one plan per client, with `messages` sent to every target
+pub fn plans(pattern: Pattern, clients: usize, messages: usize, prefix: &str) -> Result<Vec<Plan>, String> {
+    if clients < 2 {
+        return Err("At least 2 clients are needed".to_string());
+    }
+    let mut plans: Vec<Plan> = (0..clients).map(|index| Plan::new(username(prefix, index))).collect();
+    match pattern {
+        Pattern::Pairs => {
+            if !clients.is_multiple_of(2) {
+                return Err("Pairs need an even number of clients".to_string());
+            }
+            for (index, plan) in plans.iter_mut().enumerate() {
+                plan.targets.push(Target::User(username(prefix, index ^ 1)));
+                plan.expected = messages;
+            }
+        }
+        Pattern::FanIn => {
+            for plan in plans.iter_mut().skip(1) {
+                plan.targets.push(Target::User(username(prefix, 0)));
+            }
+            plans[0].expected = messages * (clients - 1);
+        }
+        Pattern::FanOut => {
+            plans[0].targets = (1..clients).map(|index| Target::User(username(prefix, index))).collect();
+            for plan in plans.iter_mut().skip(1) {
+                plan.expected = messages;
+            }
+        }
+        Pattern::Rooms { size } => {
+            if size < 2 {
+                return Err("Rooms need at least 2 members".to_string());
+            }
+            // the last room takes the clients which are left over
+            let rooms = (clients / size).max(1);
+            for room in 0..rooms {
+                let end = if room == rooms - 1 { clients } else { (room + 1) * size };
+                let members = room * size..end;
+                // the sender gets its own group messages as well
+                for plan in &mut plans[members.clone()] {
+                    plan.targets.push(Target::Room);
+                    plan.expected = messages * members.len();
+                }
+                plans[room * size].creates_room = Some(members.skip(1).map(|index| username(prefix, index)).collect());
+            }
+        }
+    }
+    Ok(plans)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{plans, Pattern, Target};
+
+    #[test]
+    fn test_plans() {
+        let pairs = plans(Pattern::Pairs, 4, 10, "load").unwrap();
+        assert_eq!(pairs[2].targets, vec![Target::User("load3".to_string())]);
+        assert_eq!(pairs[3].targets, vec![Target::User("load2".to_string())]);
+        assert!(plans(Pattern::Pairs, 3, 10, "load").is_err());
+
+        let fan_in = plans(Pattern::FanIn, 4, 10, "load").unwrap();
+        assert_eq!(fan_in[0].expected, 30);
+        assert!(fan_in[0].targets.is_empty() && fan_in[3].expected == 0);
+
+        let fan_out = plans(Pattern::FanOut, 4, 10, "load").unwrap();
+        assert_eq!(fan_out[0].targets.len(), 3);
+        assert_eq!(fan_out[1].expected, 10);
+
+        let rooms = plans(Pattern::Rooms { size: 2 }, 5, 10, "load").unwrap();
+        assert_eq!(rooms[0].creates_room, Some(vec!["load1".to_string()]));
+        assert_eq!(rooms[2].creates_room, Some(vec!["load3".to_string(), "load4".to_string()]));
+        assert_eq!((rooms[1].expected, rooms[4].expected), (20, 30));
+        assert!(rooms.iter().all(|plan| plan.targets == vec![Target::Room]));
+        assert!(plans(Pattern::Rooms { size: 1 }, 5, 10, "load").is_err());
+        assert!(plans(Pattern::FanIn, 1, 10, "load").is_err());
+    }
+}
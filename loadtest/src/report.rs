@@ -0,0 +1,182 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// the time a message was sent, carried in its text since chat messages have no timestamp
+pub fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_micros() as u64)
+}
+
+pub fn stamped_text(sender: &str, sequence: usize) -> String {
+    format!("{} {} {}", now_micros(), sender, sequence)
+}
+
+// how long ago a stamped text was sent, None for any other text
+pub fn latency_of(text: &str) -> Option<Duration> {
+    let sent: u64 = text.split(' ').next()?.parse().ok()?;
+    Some(Duration::from_micros(now_micros().saturating_sub(sent)))
+}
+
+// what one simulated client saw
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub connected: bool,
+    pub sent: usize,
+    // messages the server answered with an error
+    pub rejected: usize,
+    pub expected: usize,
+    pub latencies: Vec<Duration>,
+    pub last_delivery: Option<Instant>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Report {
+    pub clients: usize,
+    pub connected: usize,
+    pub sent: usize,
+    pub rejected: usize,
+    pub expected: usize,
+    pub delivered: usize,
+    pub elapsed: Duration,
+    // by percentile, 50, 90, 99 and the maximum
+    pub latencies: Vec<(f64, Duration)>,
+}
+
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 100.0];
+
+// the nearest-rank percentile of sorted values
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+impl Report {
+    pub fn new(outcomes: Vec<Outcome>, elapsed: Duration) -> Self {
+        let mut latencies: Vec<Duration> = outcomes.iter().flat_map(|outcome| outcome.latencies.iter().copied()).collect();
+        latencies.sort();
+        Report {
+            clients: outcomes.len(),
+            connected: outcomes.iter().filter(|outcome| outcome.connected).count(),
+            sent: outcomes.iter().map(|outcome| outcome.sent).sum(),
+            rejected: outcomes.iter().map(|outcome| outcome.rejected).sum(),
+            expected: outcomes.iter().map(|outcome| outcome.expected).sum(),
+            delivered: latencies.len(),
+            elapsed,
+            latencies: PERCENTILES.iter().map(|percent| (*percent, percentile(&latencies, *percent))).collect(),
+        }
+    }
+
+    pub fn success_rate(&self) -> f64 {
+        self.connected as f64 / self.clients as f64 * 100.0
+    }
+
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.delivered as f64 / self.elapsed.as_secs_f64()
+    }
+
+    // everyone connected and everything arrived
+    pub fn passed(&self) -> bool {
+        self.connected == self.clients && self.rejected == 0 && self.delivered == self.expected
+    }
+
+    fn percentile_name(percent: f64) -> String {
+        if percent >= 100.0 {
+            return "max".to_string();
+        }
+        format!("p{}", percent)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "connections: {}/{} ({:.1}%)\nmessages: {} sent, {} rejected, {}/{} delivered\nthroughput: {:.0} messages/s over {:.2}s\nlatency:",
+            self.connected,
+            self.clients,
+            self.success_rate(),
+            self.sent,
+            self.rejected,
+            self.delivered,
+            self.expected,
+            self.throughput(),
+            self.elapsed.as_secs_f64()
+        );
+        for (percent, latency) in &self.latencies {
+            text.push_str(&format!(" {} {:.2}ms", Self::percentile_name(*percent), latency.as_secs_f64() * 1000.0));
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let latencies: serde_json::Map<String, serde_json::Value> = self.latencies.iter()
+            .map(|(percent, latency)| (Self::percentile_name(*percent), serde_json::json!(latency.as_secs_f64() * 1000.0)))
+            .collect();
+        serde_json::json!({
+            "clients": self.clients,
+            "connected": self.connected,
+            "success_rate": self.success_rate(),
+            "sent": self.sent,
+            "rejected": self.rejected,
+            "expected": self.expected,
+            "delivered": self.delivered,
+            "seconds": self.elapsed.as_secs_f64(),
+            "throughput": self.throughput(),
+            "latency_ms": latencies,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{latency_of, stamped_text, Outcome, Report};
+    use std::time::Duration;
+
+    #[test]
+    fn test_report() {
+        let millis = |values: &[u64]| values.iter().map(|value| Duration::from_millis(*value)).collect();
+        let outcomes = vec![
+            Outcome {
+                connected: true,
+                sent: 10,
+                rejected: 0,
+                expected: 6,
+                latencies: millis(&[5, 1, 3, 2, 4, 6]),
+                last_delivery: None,
+            },
+            Outcome {
+                connected: true,
+                sent: 10,
+                rejected: 1,
+                expected: 4,
+                latencies: millis(&[10, 7, 9, 8]),
+                last_delivery: None,
+            },
+            Outcome::default(),
+        ];
+        let report = Report::new(outcomes, Duration::from_secs(2));
+        assert_eq!((report.connected, report.sent, report.rejected, report.delivered), (2, 20, 1, 10));
+        assert_eq!(report.throughput(), 5.0);
+        assert!(!report.passed());
+        assert_eq!(report.latencies, vec![
+            (50.0, Duration::from_millis(5)),
+            (90.0, Duration::from_millis(9)),
+            (99.0, Duration::from_millis(10)),
+            (100.0, Duration::from_millis(10)),
+        ]);
+        assert_eq!(report.to_json()["latency_ms"]["p90"], 9.0);
+        assert!(report.to_text().starts_with("connections: 2/3 (66.7%)"));
+
+        let empty = Report::new(Vec::new(), Duration::ZERO);
+        assert_eq!((empty.throughput(), empty.latencies[0].1), (0.0, Duration::ZERO));
+    }
+
+    #[test]
+    fn test_stamped_text() {
+        let text = stamped_text("load1", 3);
+        assert!(text.ends_with(" load1 3"));
+        assert!(latency_of(&text).unwrap() < Duration::from_secs(1));
+        assert_eq!(latency_of("hello there"), None);
+    }
+}
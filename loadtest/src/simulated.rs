@@ -0,0 +1,181 @@
+use crate::pattern::{Plan, Target};
+use crate::report::{latency_of, stamped_text, Outcome};
+use common::communication::common_message::{ClientToServerMessage, ServerToClientMessage};
+use futures_util::{SinkExt, StreamExt};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::Barrier;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+use tokio_tungstenite::{connect_async_with_config, MaybeTlsStream, WebSocketStream};
+
+// how long to wait for the server while setting up
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// for the accounts of room members, only accounts may be in groups
+const PASSWORD: &str = "loadtest";
+
+pub struct Settings {
+    pub url: String,
+    pub messages: usize,
+    // messages per second from each client, as fast as the server answers without
+    pub rate: Option<f64>,
+    // how long a client waits for missing messages once nothing arrives anymore
+    pub idle: Duration,
+}
+
+struct Client {
+    ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    outcome: Outcome,
+}
+
+impl Client {
+    async fn send(&mut self, message: ClientToServerMessage) -> Result<(), String> {
+        let text = serde_json::to_string(&message).map_err(|e| e.to_string())?;
+        self.ws_stream.send(Message::Text(Utf8Bytes::from(text))).await.map_err(|e| e.to_string())
+    }
+
+    // the next message from the server, stamped texts are counted on the way
+    async fn next(&mut self) -> Result<ServerToClientMessage, String> {
+        loop {
+            let message = match self.ws_stream.next().await {
+                Some(Ok(Message::Text(text))) => serde_json::from_str(&text).map_err(|e| e.to_string())?,
+                Some(Ok(Message::Close(_))) | None => return Err("Server closed the connection".to_string()),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.to_string()),
+            };
+            if let ServerToClientMessage::TextFrom(_, _, text, _) | ServerToClientMessage::GroupTextFrom(_, _, _, text, _) = &message {
+                if let Some(latency) = latency_of(text) {
+                    self.outcome.latencies.push(latency);
+                    self.outcome.last_delivery = Some(Instant::now());
+                }
+            }
+            return Ok(message);
+        }
+    }
+
+    async fn response(&mut self) -> Result<Result<String, String>, String> {
+        loop {
+            if let ServerToClientMessage::Response(response) = self.next().await? {
+                return Ok(response);
+            }
+        }
+    }
+
+    async fn group(&mut self) -> Result<String, String> {
+        loop {
+            if let ServerToClientMessage::GroupUpdated(info) = self.next().await? {
+                return Ok(info.id);
+            }
+        }
+    }
+
+    async fn login(url: &str, plan: &Plan) -> Result<Client, String> {
+        // small messages go out at once, so the latency is the server's rather than the socket's
+        let (ws_stream, _) = connect_async_with_config(url, None, true).await.map_err(|e| e.to_string())?;
+        let mut client = Client {
+            ws_stream,
+            outcome: Outcome::default(),
+        };
+        let message = match plan.targets.contains(&Target::Room) {
+            true => ClientToServerMessage::Login(plan.username.clone(), PASSWORD.to_string()),
+            false => ClientToServerMessage::SetUsername(plan.username.clone()),
+        };
+        client.send(message).await?;
+        client.response().await??;
+        Ok(client)
+    }
+
+    // the room creator makes the group, every member learns its name from the server
+    async fn join_room(&mut self, plan: &Plan) -> Result<Option<String>, String> {
+        if !plan.targets.contains(&Target::Room) {
+            return Ok(None);
+        }
+        if let Some(members) = &plan.creates_room {
+            self.send(ClientToServerMessage::CreateGroup(members.clone())).await?;
+        }
+        self.group().await.map(Some)
+    }
+
+    async fn send_all(&mut self, plan: &Plan, room: Option<&str>, settings: &Settings) -> Result<(), String> {
+        let mut interval = settings.rate.map(|rate| tokio::time::interval(Duration::from_secs_f64(1.0 / rate)));
+        for sequence in 0..settings.messages {
+            if let Some(interval) = &mut interval {
+                interval.tick().await;
+            }
+            for target in &plan.targets {
+                let text = stamped_text(&plan.username, sequence);
+                let message = match (target, room) {
+                    (Target::User(user), _) => ClientToServerMessage::TextTo(user.clone(), text, None),
+                    (Target::Room, Some(room)) => ClientToServerMessage::GroupTextTo(room.to_string(), text, None),
+                    (Target::Room, None) => return Err("Joined no room".to_string()),
+                };
+                self.send(message).await?;
+                self.outcome.sent += 1;
+                // one message at a time, as a person would
+                if self.response().await?.is_err() {
+                    self.outcome.rejected += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // until everything expected arrived, or nothing did for a while
+    async fn drain(&mut self, idle: Duration) {
+        while self.outcome.latencies.len() < self.outcome.expected {
+            if !matches!(timeout(idle, self.next()).await, Ok(Ok(_))) {
+                break;
+            }
+        }
+    }
+}
+
+// one simulated client, which waits at the barrier once everyone logged in and once all rooms exist,
+// whether it got that far or not, so that the others are not held up
+pub async fn simulate(plan: Plan, settings: Arc<Settings>, barrier: Arc<Barrier>) -> Outcome {
+    let mut client = match timeout(SETUP_TIMEOUT, Client::login(&settings.url, &plan)).await {
+        Ok(Ok(client)) => Some(client),
+        Ok(Err(e)) => {
+            eprintln!("{} failed to log in: {}", plan.username, e);
+            None
+        }
+        Err(_) => {
+            eprintln!("{} timed out logging in", plan.username);
+            None
+        }
+    };
+    barrier.wait().await;
+
+    let room = match &mut client {
+        Some(connected) => match timeout(SETUP_TIMEOUT, connected.join_room(&plan)).await {
+            Ok(Ok(room)) => room,
+            Ok(Err(e)) => {
+                eprintln!("{} failed to join its room: {}", plan.username, e);
+                None
+            }
+            Err(_) => {
+                eprintln!("{} timed out joining its room", plan.username);
+                None
+            }
+        },
+        None => None,
+    };
+    barrier.wait().await;
+
+    let Some(mut client) = client else {
+        return Outcome {
+            expected: plan.expected,
+            ..Outcome::default()
+        };
+    };
+    client.outcome.connected = true;
+    client.outcome.expected = plan.expected;
+    if let Err(e) = client.send_all(&plan, room.as_deref(), &settings).await {
+        eprintln!("{} stopped sending: {}", plan.username, e);
+    }
+    client.drain(settings.idle).await;
+    let _ = client.ws_stream.close(None).await;
+    client.outcome
+}
@@ -0,0 +1,256 @@
+use crate::console::console_println;
+use crate::roles::Role;
+use crate::store;
+use chrono::{DateTime, SecondsFormat, Utc};
+use pbkdf2::pbkdf2_hmac;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+// hashing runs off the main loop, so it can afford to be slow
+#[cfg(not(test))]
+const HASH_ROUNDS: u32 = 600_000;
+// still above the legacy rounds, so tests see old accounts hashed again
+#[cfg(test)]
+const HASH_ROUNDS: u32 = 20_000;
+
+// what accounts were hashed with before the rounds were kept
+fn legacy_rounds() -> u32 {
+    10_000
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct Credentials {
+    salt: String,
+    password_hash: String,
+    #[serde(default = "legacy_rounds")]
+    rounds: u32,
+}
+
+impl Credentials {
+    fn new(password: &str) -> Self {
+        let salt = Uuid::new_v4().simple().to_string();
+        Credentials {
+            password_hash: hash(password, &salt, HASH_ROUNDS),
+            salt,
+            rounds: HASH_ROUNDS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Account {
+    #[serde(flatten)]
+    credentials: Credentials,
+    #[serde(default)]
+    role: Role,
+    // RFC 3339, unknown for accounts made before this was kept
+    #[serde(default)]
+    created_at: Option<String>,
+}
+
+// users which log in with a password, and may do so from several clients at once
+#[derive(Debug, Default)]
+pub struct Accounts {
+    // where the accounts are kept, none keeps them in memory only
+    path: Option<PathBuf>,
+    accounts: HashMap<String, Account>,
+}
+
+fn hash(password: &str, salt: &str, rounds: u32) -> String {
+    let mut hash = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), rounds, &mut hash);
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// the slow half of a login, run off the main loop
+#[derive(Debug)]
+pub struct PasswordCheck {
+    password: String,
+    // none for a name without an account yet
+    existing: Option<Credentials>,
+}
+
+impl PasswordCheck {
+    pub fn verify(self) -> Result<Verified, String> {
+        let Some(existing) = self.existing else {
+            return Ok(Verified {
+                created: true,
+                credentials: Some(Credentials::new(&self.password)),
+            });
+        };
+        let password_hash = hash(&self.password, &existing.salt, existing.rounds);
+        if !crate::webhook::same_token(&existing.password_hash, &password_hash) {
+            return Err("Wrong password!".to_string());
+        }
+        // hashed again with the current rounds while the password is at hand
+        Ok(Verified {
+            created: false,
+            credentials: (existing.rounds < HASH_ROUNDS).then(|| Credentials::new(&self.password)),
+        })
+    }
+}
+
+// a password which was found right, for Accounts::sign_in
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct Verified {
+    created: bool,
+    // for a new account, or to replace ones hashed with fewer rounds
+    credentials: Option<Credentials>,
+}
+
+impl Accounts {
+    // a missing file is fine, it is created with the first account
+    pub fn load(path: Option<&Path>) -> Result<Accounts, String> {
+        let Some(path) = path else {
+            return Ok(Accounts::default());
+        };
+        Ok(Accounts {
+            path: Some(path.to_path_buf()),
+            accounts: store::load(path, "accounts")?,
+        })
+    }
+
+    pub fn exists(&self, username: &str) -> bool {
+        self.accounts.contains_key(username)
+    }
+
+    pub fn usernames(&self) -> impl Iterator<Item = &String> {
+        self.accounts.keys()
+    }
+
+    // what the password has to be checked against, or creates the account with on its first login
+    pub fn check(&self, username: &str, password: &str) -> Result<PasswordCheck, String> {
+        let existing = self.accounts.get(username).map(|account| account.credentials.clone());
+        if existing.is_none() && password.is_empty() {
+            return Err("The password must not be empty!".to_string());
+        }
+        Ok(PasswordCheck {
+            password: password.to_string(),
+            existing,
+        })
+    }
+
+    // finishes a login once the password was verified; true if the account was created
+    pub fn sign_in(&mut self, username: &str, verified: Verified) -> Result<bool, String> {
+        let Some(credentials) = verified.credentials else {
+            return Ok(false);
+        };
+        if !verified.created {
+            // a failed save only means the stronger hash has to wait for the next login
+            if let Some(account) = self.accounts.get_mut(username) {
+                let previous = std::mem::replace(&mut account.credentials, credentials);
+                if let Err(e) = self.save() {
+                    console_println!("{}", e);
+                    self.accounts.get_mut(username).unwrap().credentials = previous;
+                }
+            }
+            return Ok(false);
+        }
+        // someone else got the name while the password was hashed
+        if self.accounts.contains_key(username) {
+            return Err("Username already exists!".to_string());
+        }
+
+        let account = Account {
+            credentials,
+            role: Role::default(),
+            created_at: Some(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+        };
+        self.accounts.insert(username.to_string(), account);
+        if let Err(e) = self.save() {
+            self.accounts.remove(username);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    // the whole login at once, hashing included
+    #[cfg(test)]
+    pub fn login(&mut self, username: &str, password: &str) -> Result<bool, String> {
+        let verified = self.check(username, password)?.verify()?;
+        self.sign_in(username, verified)
+    }
+
+    // a guest may have had the name before, so the account only sees the history from here on
+    pub fn created_at(&self, username: &str) -> Option<DateTime<Utc>> {
+        let created_at = self.accounts.get(username)?.created_at.as_deref()?;
+        DateTime::parse_from_rfc3339(created_at).ok().map(|time| time.with_timezone(&Utc))
+    }
+
+    // users without an account are members
+    pub fn role(&self, username: &str) -> Role {
+        self.accounts.get(username).map_or(Role::default(), |account| account.role)
+    }
+
+    pub fn set_role(&mut self, username: &str, role: Role) -> Result<(), String> {
+        let Some(account) = self.accounts.get_mut(username) else {
+            return Err(format!("There is no account {}!", username));
+        };
+        let previous = std::mem::replace(&mut account.role, role);
+        if let Err(e) = self.save() {
+            self.accounts.get_mut(username).unwrap().role = previous;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), String> {
+        match &self.path {
+            Some(path) => store::save(path, &self.accounts, "accounts"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash, Accounts, HASH_ROUNDS};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_login() {
+        let path = std::env::temp_dir().join(format!("accounts-{}.json", Uuid::new_v4()));
+        let mut accounts = Accounts::load(Some(&path)).unwrap();
+        assert!(!accounts.exists("alice"));
+        assert_eq!(accounts.created_at("alice"), None);
+        assert_eq!(accounts.login("alice", ""), Err("The password must not be empty!".to_string()));
+        assert_eq!(accounts.login("alice", "secret"), Ok(true));
+        assert_eq!(accounts.login("alice", "secret"), Ok(false));
+        assert_eq!(accounts.login("alice", "guess"), Err("Wrong password!".to_string()));
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(!text.contains("secret"));
+
+        let mut reloaded = Accounts::load(Some(&path)).unwrap();
+        assert!(reloaded.exists("alice"));
+        assert_eq!(reloaded.created_at("alice"), accounts.created_at("alice"));
+        assert!(reloaded.created_at("alice").is_some());
+        assert_eq!(reloaded.login("alice", "secret"), Ok(false));
+
+        // the first of two logins creating the same account wins
+        let (first, second) = (reloaded.check("bob", "one").unwrap(), reloaded.check("bob", "two").unwrap());
+        let (first, second) = (first.verify().unwrap(), second.verify().unwrap());
+        assert_eq!(reloaded.sign_in("bob", first), Ok(true));
+        assert_eq!(reloaded.sign_in("bob", second), Err("Username already exists!".to_string()));
+        assert_eq!(reloaded.login("bob", "two"), Err("Wrong password!".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // accounts hashed before the rounds were kept still log in, and are hashed again with the current rounds
+    #[test]
+    fn test_legacy_rounds() {
+        let path = std::env::temp_dir().join(format!("accounts-{}.json", Uuid::new_v4()));
+        let legacy = format!("{{\"alice\": {{\"salt\": \"pepper\", \"password_hash\": \"{}\"}}}}", hash("secret", "pepper", 10_000));
+        std::fs::write(&path, legacy).unwrap();
+
+        let mut accounts = Accounts::load(Some(&path)).unwrap();
+        assert_eq!(accounts.login("alice", "guess"), Err("Wrong password!".to_string()));
+        assert_eq!(accounts.login("alice", "secret"), Ok(false));
+        assert!(std::fs::read_to_string(&path).unwrap().contains(&format!("\"rounds\": {}", HASH_ROUNDS)));
+        assert_eq!(Accounts::load(Some(&path)).unwrap().login("alice", "secret"), Ok(false));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
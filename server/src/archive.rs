@@ -0,0 +1,469 @@
+use crate::console::console_println;
+use crate::group::Groups;
+use crate::history::{Conversation, History, StoredMessage};
+use crate::store;
+use chrono::{DateTime, SecondsFormat, Utc};
+use common::communication::common_message::ExportFormat;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// what an export contains
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd)]
+pub enum Scope {
+    Conversation(Conversation),
+    // every conversation the user is part of
+    User(String),
+}
+
+impl Scope {
+    pub fn heading(&self) -> String {
+        match self {
+            Scope::Conversation(conversation) => title(conversation),
+            Scope::User(user) => format!("History of {}", user),
+        }
+    }
+}
+
+// one line of a JSON Lines export, which is also what imports and the history file read
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ArchivedMessage {
+    id: u64,
+    conversation: Conversation,
+    from: String,
+    text: String,
+    sent_at: String,
+    #[serde(default)]
+    reply_to: Option<u64>,
+    #[serde(default)]
+    edited: bool,
+    #[serde(default)]
+    reactions: BTreeMap<String, Vec<String>>,
+    // only the history file keeps deleted messages, exports leave them out
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    deleted: bool,
+}
+
+impl ArchivedMessage {
+    fn new(message: &StoredMessage) -> Self {
+        ArchivedMessage {
+            id: message.id,
+            conversation: message.conversation.clone(),
+            from: message.from.clone(),
+            text: message.text.clone(),
+            sent_at: message.sent_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            reply_to: message.reply_to,
+            edited: message.edited,
+            reactions: message.reactions.clone(),
+            deleted: message.deleted,
+        }
+    }
+
+    fn into_stored(self) -> Result<StoredMessage, String> {
+        let sent_at = DateTime::parse_from_rfc3339(&self.sent_at)
+            .map_err(|e| format!("Invalid time {} of message {}: {}", self.sent_at, self.id, e))?;
+        Ok(StoredMessage {
+            id: self.id,
+            conversation: self.conversation,
+            from: self.from,
+            text: self.text,
+            sent_at: sent_at.with_timezone(&Utc),
+            reply_to: self.reply_to,
+            edited: self.edited,
+            deleted: self.deleted,
+            reactions: self.reactions,
+        })
+    }
+}
+
+// the messages of the scope sent since `since` if given, oldest first
+pub fn select<'a>(
+    history: &'a History,
+    groups: &Groups,
+    scope: &Scope,
+    since: Option<DateTime<Utc>>,
+) -> Vec<&'a StoredMessage> {
+    history
+        .messages()
+        .filter(|message| message.sent_since(since))
+        .filter(|message| match scope {
+            Scope::Conversation(conversation) => message.conversation == *conversation,
+            Scope::User(user) => message.conversation.participants(user, groups).is_some(),
+        })
+        .collect()
+}
+
+fn title(conversation: &Conversation) -> String {
+    match conversation {
+        Conversation::Direct(a, b) => format!("{} and {}", a, b),
+        Conversation::Group(id) => format!("Group {}", id),
+    }
+}
+
+fn time(message: &StoredMessage) -> String {
+    message.sent_at.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+// the messages grouped by conversation, each conversation in the order it was written
+fn by_conversation<'a>(messages: &[&'a StoredMessage]) -> Vec<&'a StoredMessage> {
+    let mut sorted = messages.to_vec();
+    sorted.sort_by(|a, b| (&a.conversation, a.id).cmp(&(&b.conversation, b.id)));
+    sorted
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn reactions(message: &StoredMessage) -> String {
+    message
+        .reactions
+        .iter()
+        .map(|(emoji, users)| format!("{} {}", emoji, users.len()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+const HTML_STYLE: &str = "body{font-family:sans-serif;max-width:50em;margin:2em auto;color:#222}\
+h2{border-bottom:1px solid #ccc}.message{margin:.6em 0}.meta{color:#777;font-size:.85em}\
+.from{font-weight:bold}.reply{color:#777;border-left:3px solid #ccc;padding-left:.5em}\
+.text{white-space:pre-wrap}";
+
+pub fn render(messages: &[&StoredMessage], format: ExportFormat, heading: &str) -> String {
+    let mut out = String::new();
+    match format {
+        ExportFormat::JsonLines => {
+            for message in messages {
+                out.push_str(&serde_json::to_string(&ArchivedMessage::new(message)).unwrap());
+                out.push('\n');
+            }
+        }
+        ExportFormat::Markdown => {
+            out.push_str(&format!("# {}\n", heading));
+            let mut current = None;
+            for message in by_conversation(messages) {
+                if current != Some(&message.conversation) {
+                    out.push_str(&format!("\n## {}\n\n", title(&message.conversation)));
+                    current = Some(&message.conversation);
+                }
+                out.push_str(&format!("**{}** · {} · #{}", message.from, time(message), message.id));
+                if let Some(parent) = message.reply_to {
+                    out.push_str(&format!(" · reply to #{}", parent));
+                }
+                if message.edited {
+                    out.push_str(" · edited");
+                }
+                out.push_str("\n\n");
+                for line in message.text.lines() {
+                    out.push_str(&format!("{}  \n", line));
+                }
+                if !message.reactions.is_empty() {
+                    out.push_str(&format!("*{}*\n", reactions(message)));
+                }
+                out.push('\n');
+            }
+        }
+        ExportFormat::Html => {
+            out.push_str(&format!(
+                "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title><style>{1}</style></head>\n<body><h1>{0}</h1>\n",
+                escape_html(heading),
+                HTML_STYLE
+            ));
+            let mut current = None;
+            for message in by_conversation(messages) {
+                if current != Some(&message.conversation) {
+                    out.push_str(&format!("<h2>{}</h2>\n", escape_html(&title(&message.conversation))));
+                    current = Some(&message.conversation);
+                }
+                out.push_str(&format!(
+                    "<div class=\"message\" id=\"m{0}\"><span class=\"from\">{1}</span> <span class=\"meta\">{2} · #{0}",
+                    message.id,
+                    escape_html(&message.from),
+                    time(message)
+                ));
+                if message.edited {
+                    out.push_str(" · edited");
+                }
+                out.push_str("</span>");
+                if let Some(parent) = message.reply_to {
+                    out.push_str(&format!(
+                        "<div class=\"reply\"><a href=\"#m{0}\">reply to #{0}</a></div>",
+                        parent
+                    ));
+                }
+                out.push_str(&format!("<div class=\"text\">{}</div>", escape_html(&message.text)));
+                if !message.reactions.is_empty() {
+                    out.push_str(&format!("<div class=\"meta\">{}</div>", escape_html(&reactions(message))));
+                }
+                out.push_str("</div>\n");
+            }
+            out.push_str("</body></html>\n");
+        }
+    }
+    out
+}
+
+// the messages of JSON Lines, each with the number of its line
+fn parse(text: &str) -> Result<Vec<(usize, StoredMessage)>, String> {
+    let mut messages = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let archived: ArchivedMessage =
+            serde_json::from_str(line).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+        let message = archived.into_stored().map_err(|e| format!("Line {}: {}", number + 1, e))?;
+        messages.push((number + 1, message));
+    }
+    Ok(messages)
+}
+
+// loads a JSON Lines export off the main loop, the history is only locked to add the messages
+pub async fn import_file(history: Arc<Mutex<History>>, path: PathBuf) -> Result<usize, String> {
+    let imported = tokio::task::spawn_blocking(move || {
+        let text = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        add(&mut history.lock().unwrap(), parse(&text)?)
+    });
+    imported.await.map_err(|e| format!("Failed to import: {}", e))?
+}
+
+// nothing is added unless every id is new
+fn add(history: &mut History, messages: Vec<(usize, StoredMessage)>) -> Result<usize, String> {
+    let mut ids = HashMap::new();
+    for (number, message) in &messages {
+        if history.contains(message.id) || ids.insert(message.id, message.conversation.clone()).is_some() {
+            return Err(format!("Line {}: message {} already exists", number, message.id));
+        }
+    }
+    // replies refer to an earlier message of their conversation, which exports may leave out if it was deleted
+    for (number, message) in &messages {
+        let Some(parent) = message.reply_to else { continue };
+        let same = history.conversation(parent).or(ids.get(&parent)).is_none_or(|conversation| *conversation == message.conversation);
+        if parent >= message.id || !same {
+            return Err(format!("Line {}: message {} replies to no earlier message of its conversation", number, message.id));
+        }
+    }
+    let count = messages.len();
+    for (_, message) in messages {
+        history.import(message)?;
+    }
+    Ok(count)
+}
+
+// selects and renders off the main loop, the history is only locked to copy the messages out;
+// the number of messages and the export
+pub async fn export(
+    history: Arc<Mutex<History>>,
+    groups: Arc<RwLock<Groups>>,
+    scope: Scope,
+    since: Option<DateTime<Utc>>,
+    format: ExportFormat,
+) -> Result<(usize, String), String> {
+    let rendered = tokio::task::spawn_blocking(move || {
+        let messages: Vec<StoredMessage> = {
+            let groups = groups.read().unwrap();
+            let history = history.lock().unwrap();
+            select(&history, &groups, &scope, since).into_iter().cloned().collect()
+        };
+        let messages: Vec<&StoredMessage> = messages.iter().collect();
+        (messages.len(), render(&messages, format, &scope.heading()))
+    });
+    rendered.await.map_err(|e| format!("Failed to export: {}", e))
+}
+
+// every message as JSON Lines, deleted ones included so their ids stay taken
+pub fn snapshot(history: &History) -> String {
+    let messages: Vec<&StoredMessage> = history.stored().collect();
+    render(&messages, ExportFormat::JsonLines, "")
+}
+
+// the history kept in `path`, empty if the file does not exist yet; the file only ever grows by the
+// messages changed since the last save, so a later line of a message replaces the earlier ones
+pub fn load(path: &Path) -> Result<History, String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(History::default()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    let invalid = |e| format!("Invalid history file {}: {}", path.display(), e);
+    // a line cut short by a crash while it was appended is left out
+    let complete = &text[..text.rfind('\n').map_or(0, |end| end + 1)];
+    let lines = parse(complete).map_err(invalid)?;
+    let count = lines.len();
+    let latest: BTreeMap<u64, (usize, StoredMessage)> =
+        lines.into_iter().map(|(number, message)| (message.id, (number, message))).collect();
+    let mut history = History::default();
+    add(&mut history, latest.into_values().collect()).map_err(invalid)?;
+    history.take_changed();
+
+    // written anew without the replaced lines, before anything is appended again
+    if count > history.stored().count() || complete.len() < text.len() {
+        store::write(path, snapshot(&history).as_bytes(), "history")?;
+    }
+    Ok(history)
+}
+
+// appends the changed messages to the history file from a task of its own, so the main loop
+// neither waits for the disk nor renders more than what changed
+pub struct HistoryFile {
+    // unbounded, as nothing piles up in it which the history does not hold anyway
+    changes: mpsc::UnboundedSender<Vec<StoredMessage>>,
+    task: JoinHandle<()>,
+}
+
+impl HistoryFile {
+    pub fn spawn(path: PathBuf) -> Self {
+        let (changes, mut receiver) = mpsc::unbounded_channel::<Vec<StoredMessage>>();
+        let task = tokio::spawn(async move {
+            let mut pending = Vec::new();
+            while let Some(changed) = receiver.recv().await {
+                // whatever came in while the last changes were written goes out at once,
+                // together with changes which failed to be written before
+                pending.extend(changed);
+                while let Ok(changed) = receiver.try_recv() {
+                    pending.extend(changed);
+                }
+                let changed = std::mem::take(&mut pending);
+                let path = path.clone();
+                let written = tokio::task::spawn_blocking(move || {
+                    let messages: Vec<&StoredMessage> = changed.iter().collect();
+                    let lines = render(&messages, ExportFormat::JsonLines, "");
+                    (store::append(&path, lines.as_bytes(), "history"), changed)
+                });
+                match written.await {
+                    Ok((Ok(()), _)) => {}
+                    Ok((Err(e), changed)) => {
+                        console_println!("{}", e);
+                        pending = changed;
+                    }
+                    Err(e) => console_println!("Failed to save the history: {}", e),
+                }
+            }
+        });
+        HistoryFile { changes, task }
+    }
+
+    pub fn save(&self, history: &mut History) {
+        let changed = history.take_changed();
+        if !changed.is_empty() {
+            let _ = self.changes.send(changed);
+        }
+    }
+
+    // waits until the last changes are written
+    pub async fn close(self) {
+        drop(self.changes);
+        let _ = self.task.await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{add, load, parse, render, select, HistoryFile, Scope};
+    use crate::group::Groups;
+    use crate::history::{Conversation, History};
+    use chrono::{TimeZone, Utc};
+    use common::communication::common_message::ExportFormat;
+    use uuid::Uuid;
+
+    fn import(history: &mut History, text: &str) -> Result<usize, String> {
+        parse(text).and_then(|messages| add(history, messages))
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let mut groups = Groups::default();
+        let group = groups.create("alice", &["carol".to_string()]).unwrap().id;
+        let mut history = History::default();
+        let time = Utc.with_ymd_and_hms(2024, 5, 31, 12, 0, 0).unwrap();
+        let direct = Conversation::direct("alice", "bob");
+        let first = history.add(direct.clone(), "alice", "<b>hi</b>", None, time).unwrap();
+        history.add(Conversation::Group(group.clone()), "carol", "lunch?", None, time).unwrap();
+        history.add(direct.clone(), "bob", "hello", Some(first), time).unwrap();
+        history.react(first, "bob", "👍").unwrap();
+
+        assert_eq!(select(&history, &groups, &Scope::User("alice".to_string()), None).len(), 3);
+        assert_eq!(select(&history, &groups, &Scope::User("bob".to_string()), None).len(), 2);
+        let conversation = select(&history, &groups, &Scope::Conversation(direct), None);
+
+        let markdown = render(&conversation, ExportFormat::Markdown, "Export");
+        assert!(markdown.contains("## alice and bob"));
+        assert!(markdown.contains("**bob** · 2024-05-31 12:00:00 UTC · #3 · reply to #1"));
+        let html = render(&conversation, ExportFormat::Html, "Export");
+        assert!(html.contains("&lt;b&gt;hi&lt;/b&gt;"));
+        assert!(html.contains("<a href=\"#m1\">"));
+
+        let lines = render(&conversation, ExportFormat::JsonLines, "Export");
+        assert_eq!(lines.lines().count(), 2);
+        let mut restored = History::default();
+        assert_eq!(import(&mut restored, &lines), Ok(2));
+        assert_eq!(restored.get(first), history.get(first));
+        assert_eq!(restored.matching("hello").count(), 1);
+        // ids continue after the imported ones
+        assert_eq!(restored.add(Conversation::direct("a", "b"), "a", "next", None, time), Ok(4));
+
+        assert!(import(&mut restored, &lines).is_err());
+        assert!(import(&mut History::default(), "{\"id\": 1}").is_err());
+        assert_eq!(restored.messages().count(), 3);
+
+        // replies to themselves, to later messages or across conversations would make threads endless or leak
+        let line = |id: u64, conversation: &Conversation, reply_to: u64| {
+            format!("{{\"id\":{},\"conversation\":{},\"from\":\"a\",\"text\":\"x\",\"sent_at\":\"2024-05-31T12:00:00Z\",\"reply_to\":{}}}\n",
+                id, serde_json::to_string(conversation).unwrap(), reply_to)
+        };
+        let other = Conversation::direct("a", "b");
+        assert!(import(&mut History::default(), &line(1, &other, 1)).is_err());
+        assert!(import(&mut History::default(), &(line(1, &other, 2) + &line(2, &other, 1))).is_err());
+        assert!(import(&mut restored, &line(5, &other, 1)).is_err());
+        assert_eq!(import(&mut restored, &line(5, &other, 4)), Ok(1));
+        // a parent left out of an export because it was deleted is fine
+        assert_eq!(import(&mut History::default(), &line(9, &other, 8)), Ok(1));
+    }
+
+    #[tokio::test]
+    async fn test_history_file() {
+        let path = std::env::temp_dir().join(format!("history-{}.jsonl", Uuid::new_v4()));
+        assert_eq!(load(&path).unwrap().messages().count(), 0);
+
+        let mut history = History::default();
+        let time = Utc.with_ymd_and_hms(2024, 5, 31, 12, 0, 0).unwrap();
+        let direct = Conversation::direct("alice", "bob");
+        let first = history.add(direct.clone(), "alice", "hi", None, time).unwrap();
+        let second = history.add(direct.clone(), "bob", "oops", Some(first), time).unwrap();
+        let file = HistoryFile::spawn(path.clone());
+        file.save(&mut history);
+        // only what changed since is appended, and replaces what came before
+        history.remove(second).unwrap();
+        history.react(first, "bob", "👍").unwrap();
+        file.save(&mut history);
+        file.save(&mut history);
+        file.close().await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 4);
+
+        let mut restored = load(&path).unwrap();
+        assert!(restored.take_changed().is_empty());
+        assert_eq!(restored.get(first), history.get(first));
+        // deleted messages stay deleted, and their ids are not handed out again
+        assert_eq!(restored.get(second), None);
+        assert_eq!(restored.add(direct, "alice", "again", None, time), Ok(3));
+        // exports still leave them out
+        let lines = render(&select(&history, &Groups::default(), &Scope::User("bob".to_string()), None), ExportFormat::JsonLines, "");
+        assert_eq!(lines.lines().count(), 1);
+        assert!(!lines.contains("deleted"));
+
+        // loading leaves the file with a line per message, and a line cut short by a crash is dropped
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        let mut text = std::fs::read_to_string(&path).unwrap();
+        text.push_str("{\"id\": 3, \"conv");
+        std::fs::write(&path, text).unwrap();
+        assert_eq!(load(&path).unwrap().get(first), history.get(first));
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,17 @@
+use crate::accounts::Verified;
+use crate::archive::Scope;
+use crate::cluster::ClusterMessage;
+use crate::federation::PeerMessage;
+use crate::roles::Role;
 use uuid::Uuid;
-use common::communication::common_message::{ClientToServerMessage, ServerToClientMessage};
+use common::communication::common_message::{ClientToServerMessage, ExportFormat, ServerToClientMessage};
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum Recipient {
+    Connection(Uuid),
+    User(String),
+}
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Default)]
 pub enum MainToThreadsMessage {
@@ -7,6 +19,11 @@ pub enum MainToThreadsMessage {
     Shutdown,
     SendToClient(ServerToClientMessage),
     Usernames(Vec<String>),
+    // an avatar, framed as avatar::avatar_frame describes
+    SendBinary(Vec<u8>),
+    SendToPeer(PeerMessage),
+    // not sent but read from an outbox which overflowed, the other end is to be cut off
+    FellBehind,
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Default)]
@@ -15,4 +32,25 @@ pub enum ThreadsToMainMessage {
     Shutdown,
     ReceivedFromClient(ClientToServerMessage, Uuid),
     ConnectionClosed(Uuid),
+    // closes one connection, asked for by its user from another session
+    RevokeSession(Uuid),
+    // a message posted through the incoming webhook of the named bot
+    ReceivedFromWebhook(String, ClientToServerMessage, Uuid),
+    // the password of a login to the connection, hashed off the main loop
+    LoggedIn(Uuid, String, Result<Verified, String>),
+    // sent by a plugin outside of handling a message, like a reminder which is due
+    Inject(Recipient, ServerToClientMessage),
+    // asked for on the console, the main loop owns the history
+    Export(Scope, ExportFormat, PathBuf),
+    Import(PathBuf),
+    // granted on the console, stored with the account
+    SetRole(String, Role),
+    // a link to another server was authenticated, or dropped
+    PeerConnected(Uuid, String),
+    PeerDisconnected(Uuid),
+    FromPeer(Uuid, PeerMessage),
+    // another node of the cluster came up or went away
+    NodeUp(String),
+    NodeDown(String),
+    FromNode(String, ClusterMessage),
 }
\ No newline at end of file
@@ -0,0 +1,460 @@
+use crate::channel_message::{MainToThreadsMessage, ThreadsToMainMessage};
+use crate::config::{ClusterConfig, ClusterNode};
+use crate::console::console_println;
+use crate::router::Router;
+use crate::webhook::same_token;
+use common::communication::common_message::ServerToClientMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::timeout;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the first line on every connection between nodes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Hello {
+    node: String,
+    secret: String,
+}
+
+// what the nodes of a cluster tell each other
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum ClusterMessage {
+    // everyone with a session on the sending node, replacing what was known of it
+    Users(Vec<String>),
+    UserChanged(String, bool),
+    // for the sessions of the user on the receiving node
+    Deliver(String, ServerToClientMessage),
+}
+
+// carries messages between the nodes of a cluster, whatever is sent to a node reaches
+// its main loop as ThreadsToMainMessage::FromNode, and nodes coming and going as NodeUp and NodeDown,
+// unless a backplane hands deliveries to the router of the node itself
+pub trait Backplane: Send + Sync {
+    fn node(&self) -> &str;
+    // the other nodes, whether they are up or not
+    fn nodes(&self) -> Vec<String>;
+    fn send(&self, node: &str, message: ClusterMessage) -> Result<(), String>;
+}
+
+// which node holds the sessions of which user, as far as the other nodes told; only the main loop
+// changes the directory, the connection tasks look it up as they route texts
+pub struct Cluster {
+    backplane: Option<Box<dyn Backplane>>,
+    directory: RwLock<HashMap<String, BTreeSet<String>>>,
+}
+
+impl Cluster {
+    // a server without a backplane is a cluster of one
+    pub fn new(backplane: Option<Box<dyn Backplane>>) -> Cluster {
+        Cluster {
+            backplane,
+            directory: RwLock::default(),
+        }
+    }
+
+    pub fn node(&self) -> Option<&str> {
+        self.backplane.as_ref().map(|backplane| backplane.node())
+    }
+
+    // online on another node
+    pub fn is_online(&self, user: &str) -> bool {
+        self.directory.read().unwrap().get(user).is_some_and(|nodes| !nodes.is_empty())
+    }
+
+    pub fn users(&self) -> Vec<String> {
+        let directory = self.directory.read().unwrap();
+        directory.iter().filter(|(_, nodes)| !nodes.is_empty()).map(|(user, _)| user.clone()).collect()
+    }
+
+    pub fn node_users(&self, node: &str, users: Vec<String>) {
+        let mut directory = self.directory.write().unwrap();
+        Self::forget_node(&mut directory, node);
+        for user in users {
+            directory.entry(user).or_default().insert(node.to_string());
+        }
+    }
+
+    pub fn user_changed(&self, node: &str, user: String, online: bool) {
+        let mut directory = self.directory.write().unwrap();
+        let nodes = directory.entry(user.clone()).or_default();
+        if online {
+            nodes.insert(node.to_string());
+        } else {
+            nodes.remove(node);
+            if nodes.is_empty() {
+                directory.remove(&user);
+            }
+        }
+    }
+
+    pub fn node_down(&self, node: &str) {
+        Self::forget_node(&mut self.directory.write().unwrap(), node);
+    }
+
+    fn forget_node(directory: &mut HashMap<String, BTreeSet<String>>, node: &str) {
+        directory.retain(|_, nodes| {
+            nodes.remove(node);
+            !nodes.is_empty()
+        });
+    }
+
+    pub fn send(&self, node: &str, message: ClusterMessage) {
+        if let Some(backplane) = &self.backplane {
+            backplane.send(node, message).unwrap_or_else(|e| console_println!("{}", e));
+        }
+    }
+
+    pub fn announce(&self, message: ClusterMessage) {
+        if let Some(backplane) = &self.backplane {
+            for node in backplane.nodes() {
+                backplane.send(&node, message.clone()).unwrap_or_else(|e| console_println!("{}", e));
+            }
+        }
+    }
+
+    // hands the message to every other node with a session of the user, false if there is none
+    pub fn forward(&self, user: &str, message: &ServerToClientMessage) -> bool {
+        let directory = self.directory.read().unwrap();
+        let Some(nodes) = directory.get(user).filter(|nodes| !nodes.is_empty()) else {
+            return false;
+        };
+        for node in nodes {
+            self.send(node, ClusterMessage::Deliver(user.to_string(), message.clone()));
+        }
+        true
+    }
+}
+
+// nodes on the same machine, each sending over its own connection to every other node
+// and listening for theirs; messages wait while a node is down
+pub struct TcpBackplane {
+    node: String,
+    outgoing: HashMap<String, UnboundedSender<ClusterMessage>>,
+}
+
+impl TcpBackplane {
+    pub fn start(
+        config: &ClusterConfig,
+        listener: TcpListener,
+        thread_to_main_tx: Sender<ThreadsToMainMessage>,
+        router: Arc<Router>,
+    ) -> TcpBackplane {
+        let config = Arc::new(config.clone());
+        tokio::spawn(accept_nodes(listener, config.clone(), thread_to_main_tx.clone(), router));
+        let mut outgoing = HashMap::new();
+        for other in config.nodes.iter().filter(|other| other.name != config.node) {
+            let (tx, rx) = unbounded_channel();
+            let hello = Hello {
+                node: config.node.clone(),
+                secret: config.secret.clone(),
+            };
+            tokio::spawn(dial_node(hello, other.clone(), rx, thread_to_main_tx.clone()));
+            outgoing.insert(other.name.clone(), tx);
+        }
+        TcpBackplane {
+            node: config.node.clone(),
+            outgoing,
+        }
+    }
+}
+
+impl Backplane for TcpBackplane {
+    fn node(&self) -> &str {
+        &self.node
+    }
+
+    fn nodes(&self) -> Vec<String> {
+        self.outgoing.keys().cloned().collect()
+    }
+
+    fn send(&self, node: &str, message: ClusterMessage) -> Result<(), String> {
+        self.outgoing
+            .get(node)
+            .ok_or_else(|| format!("Unknown node {}", node))?
+            .send(message)
+            .map_err(|_| format!("The link to node {} is gone", node))
+    }
+}
+
+async fn accept_nodes(
+    listener: TcpListener,
+    config: Arc<ClusterConfig>,
+    thread_to_main_tx: Sender<ThreadsToMainMessage>,
+    router: Arc<Router>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, address)) => {
+                tokio::spawn(read_node(stream, address, config.clone(), thread_to_main_tx.clone(), router.clone()));
+            }
+            Err(e) => console_println!("Failed to accept a node: {}", e),
+        }
+    }
+}
+
+// the first line is the hello of another node of the cluster, every further one is a message from it,
+// deliveries go straight to the sessions here without a detour through the main loop
+async fn read_node(
+    stream: TcpStream,
+    address: SocketAddr,
+    config: Arc<ClusterConfig>,
+    thread_to_main_tx: Sender<ThreadsToMainMessage>,
+    router: Arc<Router>,
+) {
+    let mut lines = BufReader::new(stream).lines();
+    let hello = match timeout(HANDSHAKE_TIMEOUT, lines.next_line()).await {
+        Ok(Ok(Some(line))) => serde_json::from_str::<Hello>(&line).ok(),
+        _ => None,
+    };
+    let node = match hello {
+        Some(Hello { node, secret }) => {
+            let known = node != config.node && config.nodes.iter().any(|other| other.name == node);
+            match known && same_token(&config.secret, &secret) {
+                true => node,
+                false => {
+                    console_println!("Refused node {} from {}: unknown node or wrong secret", node, address);
+                    return;
+                }
+            }
+        }
+        None => {
+            console_println!("Refused a node from {}: expected a hello", address);
+            return;
+        }
+    };
+    console_println!("Node {} joined", node);
+    thread_to_main_tx
+        .send(ThreadsToMainMessage::NodeUp(node.clone()))
+        .await.expect("Failed to send message to main thread");
+    while let Ok(Some(line)) = lines.next_line().await {
+        match serde_json::from_str(&line) {
+            Ok(ClusterMessage::Deliver(user, message)) => {
+                router.deliver_to_user(&user, MainToThreadsMessage::SendToClient(message));
+            }
+            Ok(message) => {
+                thread_to_main_tx
+                    .send(ThreadsToMainMessage::FromNode(node.clone(), message))
+                    .await.expect("Failed to send message to main thread");
+            }
+            Err(e) => console_println!("Invalid message from node {}: {}", node, e),
+        }
+    }
+    console_println!("Node {} left", node);
+    thread_to_main_tx
+        .send(ThreadsToMainMessage::NodeDown(node))
+        .await.expect("Failed to send message to main thread");
+}
+
+// a message which could not be written is sent again once the node is back
+async fn dial_node(
+    hello: Hello,
+    other: ClusterNode,
+    mut rx: UnboundedReceiver<ClusterMessage>,
+    thread_to_main_tx: Sender<ThreadsToMainMessage>,
+) {
+    let mut unsent = None;
+    loop {
+        if let Ok(stream) = TcpStream::connect(&other.address).await {
+            let (reader, mut writer) = stream.into_split();
+            let hello = format!("{}\n", serde_json::to_string(&hello).expect("Failed to serialize hello"));
+            if writer.write_all(hello.as_bytes()).await.is_ok() {
+                // the other node learns what this one holds, whatever got lost before
+                thread_to_main_tx
+                    .send(ThreadsToMainMessage::NodeUp(other.name.clone()))
+                    .await.expect("Failed to send message to main thread");
+                let mut closed = BufReader::new(reader).lines();
+                loop {
+                    let message = match unsent.take() {
+                        Some(message) => message,
+                        None => tokio::select! {
+                            message = rx.recv() => match message {
+                                Some(message) => message,
+                                None => return,
+                            },
+                            // the other node never writes here, so anything read means it went away
+                            _ = closed.next_line() => break,
+                        },
+                    };
+                    let line = format!("{}\n", serde_json::to_string(&message).expect("Failed to serialize message"));
+                    if writer.write_all(line.as_bytes()).await.is_err() {
+                        unsent = Some(message);
+                        break;
+                    }
+                }
+            }
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+// nodes within one process, for tests; like over TCP, messages queue up in front of a busy main loop
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MemoryHub {
+    nodes: std::sync::Arc<std::sync::Mutex<HashMap<String, UnboundedSender<ThreadsToMainMessage>>>>,
+}
+
+#[cfg(test)]
+pub struct MemoryBackplane {
+    node: String,
+    hub: MemoryHub,
+}
+
+#[cfg(test)]
+impl MemoryBackplane {
+    pub fn join(hub: &MemoryHub, node: &str, thread_to_main_tx: Sender<ThreadsToMainMessage>) -> MemoryBackplane {
+        let (tx, mut rx) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                if thread_to_main_tx.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut nodes = hub.nodes.lock().unwrap();
+        for (other, other_tx) in nodes.iter() {
+            let _ = other_tx.send(ThreadsToMainMessage::NodeUp(node.to_string()));
+            let _ = tx.send(ThreadsToMainMessage::NodeUp(other.clone()));
+        }
+        nodes.insert(node.to_string(), tx);
+        MemoryBackplane {
+            node: node.to_string(),
+            hub: hub.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for MemoryBackplane {
+    fn drop(&mut self) {
+        let mut nodes = self.hub.nodes.lock().unwrap();
+        nodes.remove(&self.node);
+        for other_tx in nodes.values() {
+            let _ = other_tx.send(ThreadsToMainMessage::NodeDown(self.node.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+impl Backplane for MemoryBackplane {
+    fn node(&self) -> &str {
+        &self.node
+    }
+
+    fn nodes(&self) -> Vec<String> {
+        self.hub.nodes.lock().unwrap().keys().filter(|node| **node != self.node).cloned().collect()
+    }
+
+    fn send(&self, node: &str, message: ClusterMessage) -> Result<(), String> {
+        let nodes = self.hub.nodes.lock().unwrap();
+        let tx = nodes.get(node).ok_or_else(|| format!("Unknown node {}", node))?;
+        tx.send(ThreadsToMainMessage::FromNode(self.node.clone(), message))
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backplane, Cluster, ClusterMessage, MemoryBackplane, MemoryHub, TcpBackplane};
+    use crate::channel_message::ThreadsToMainMessage;
+    use crate::config::{ClusterConfig, ClusterNode};
+    use crate::router::Router;
+    use common::communication::common_message::ServerToClientMessage;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_cluster_directory() {
+        let hub = MemoryHub::default();
+        let (tx, mut rx) = mpsc::channel(16);
+        let (other_tx, mut other_rx) = mpsc::channel(16);
+        let cluster = Cluster::new(Some(Box::new(MemoryBackplane::join(&hub, "one", tx))));
+        let other = MemoryBackplane::join(&hub, "two", other_tx);
+        assert_eq!(rx.recv().await.unwrap(), ThreadsToMainMessage::NodeUp("two".to_string()));
+        assert_eq!(other_rx.recv().await.unwrap(), ThreadsToMainMessage::NodeUp("one".to_string()));
+
+        cluster.node_users("two", vec!["bob".to_string(), "carol".to_string()]);
+        cluster.user_changed("two", "carol".to_string(), false);
+        cluster.user_changed("three", "bob".to_string(), true);
+        assert!(cluster.is_online("bob") && !cluster.is_online("carol"));
+        assert!(cluster.forward("bob", &ServerToClientMessage::None));
+        assert!(!cluster.forward("carol", &ServerToClientMessage::None));
+        assert_eq!(
+            other_rx.recv().await.unwrap(),
+            ThreadsToMainMessage::FromNode(
+                "one".to_string(),
+                ClusterMessage::Deliver("bob".to_string(), ServerToClientMessage::None)
+            )
+        );
+
+        drop(other);
+        assert_eq!(rx.recv().await.unwrap(), ThreadsToMainMessage::NodeDown("two".to_string()));
+        cluster.node_down("two");
+        cluster.node_down("three");
+        assert!(cluster.users().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_backplane() {
+        let (one_listener, two_listener) =
+            (TcpListener::bind("127.0.0.1:0").await.unwrap(), TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let nodes = vec![
+            ClusterNode {
+                name: "one".to_string(),
+                address: one_listener.local_addr().unwrap().to_string(),
+            },
+            ClusterNode {
+                name: "two".to_string(),
+                address: two_listener.local_addr().unwrap().to_string(),
+            },
+        ];
+        let two_address = two_listener.local_addr().unwrap();
+        let config = |node: &str| ClusterConfig {
+            node: node.to_string(),
+            nodes: nodes.clone(),
+            secret: "s3cret".to_string(),
+        };
+        let (one_tx, _one_rx) = mpsc::channel(16);
+        let (two_tx, mut two_rx) = mpsc::channel(16);
+        let _two = TcpBackplane::start(&config("two"), two_listener, two_tx, Arc::new(Router::default()));
+
+        // nodes which do not know the secret, or which are not part of the cluster, are hung up on
+        for hello in [r#"{"node":"one","secret":"guess"}"#, r#"{"node":"three","secret":"s3cret"}"#, "one"] {
+            let mut stream = TcpStream::connect(two_address).await.unwrap();
+            let forged = format!("{}\n{}\n", hello, serde_json::to_string(&ClusterMessage::UserChanged("mallory".to_string(), true)).unwrap());
+            stream.write_all(forged.as_bytes()).await.unwrap();
+            let closed = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut [0; 1])).await;
+            assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+        }
+
+        let one = TcpBackplane::start(&config("one"), one_listener, one_tx, Arc::new(Router::default()));
+        assert_eq!(one.nodes(), vec!["two"]);
+        one.send("two", ClusterMessage::UserChanged("alice".to_string(), true)).unwrap();
+        assert!(one.send("three", ClusterMessage::Users(Vec::new())).is_err());
+
+        let expected = ThreadsToMainMessage::FromNode("one".to_string(), ClusterMessage::UserChanged("alice".to_string(), true));
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let message = two_rx.recv().await.unwrap();
+                assert!(!matches!(&message, ThreadsToMainMessage::FromNode(_, ClusterMessage::UserChanged(user, _)) if user == "mallory"));
+                if message == expected {
+                    break;
+                }
+            }
+        });
+        assert!(received.await.is_ok());
+    }
+}
@@ -0,0 +1,127 @@
+use serde::Deserialize;
+use std::path::Path;
+
+// optional settings read from the file given with --config
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // where user accounts are stored, none keeps them until the server stops
+    pub accounts_file: Option<String>,
+    // where block lists and privacy settings are stored, none keeps them until the server stops
+    pub privacy_file: Option<String>,
+    // where contacts and contact requests are stored, none keeps them until the server stops
+    pub contacts_file: Option<String>,
+    // where groups and the last group id are stored, none keeps them until the server stops
+    pub groups_file: Option<String>,
+    // where profiles and avatar images are stored, none keeps them until the server stops
+    pub profiles_file: Option<String>,
+    pub avatar_dir: Option<String>,
+    // where the message history is kept as JSON Lines, none keeps it until the server stops
+    pub history_file: Option<String>,
+    // how long senders may edit or delete their messages, 15 minutes if not set
+    pub edit_window_minutes: Option<u32>,
+    pub webhooks: WebhookConfig,
+    pub bots: Vec<BotAccount>,
+    pub federation: FederationConfig,
+    // none runs the server on its own
+    pub cluster: Option<ClusterConfig>,
+}
+
+// accounts for helper programs, which log in with their API key instead of picking a name
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BotAccount {
+    pub name: String,
+    pub api_key: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    // address of the HTTP endpoint for incoming webhooks, none disables it
+    pub http_bind: Option<String>,
+    pub outgoing: Vec<OutgoingWebhook>,
+    pub incoming: Vec<IncomingWebhook>,
+}
+
+// messages sent to `target` are also POSTed to `url`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutgoingWebhook {
+    pub target: String,
+    pub url: String,
+}
+
+// lets whoever knows `token` post messages as `bot`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IncomingWebhook {
+    pub bot: String,
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FederationConfig {
+    // the name peers know this server by, the part after @ in the addresses of its users
+    pub name: Option<String>,
+    pub peers: Vec<PeerServer>,
+}
+
+// another server whose users may be messaged, both sides know the same secret
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerServer {
+    pub name: String,
+    // where to connect to, left out for peers which connect to this server instead
+    pub url: Option<String>,
+    pub secret: String,
+}
+
+// server processes on one machine which share their users, each listed with where it listens for the others
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    // which of the nodes this server is
+    pub node: String,
+    pub nodes: Vec<ClusterNode>,
+    // every node knows the same one, connections from nodes without it are refused
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterNode {
+    pub name: String,
+    pub address: String,
+}
+
+impl Config {
+    // names nobody can take with SetUsername or Login
+    pub fn is_reserved(&self, username: &str) -> bool {
+        self.bots.iter().any(|bot| bot.name == username)
+            || self.webhooks.incoming.iter().any(|hook| hook.bot == username)
+    }
+
+    pub fn edit_window(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.edit_window_minutes.unwrap_or(15).into())
+    }
+
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        serde_json::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    // the config file named after --config on the command line, if any
+    pub fn from_args() -> Result<Config, String> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let path = match args.as_slice() {
+            [] => return Ok(Config::default()),
+            [flag, path] if flag == "--config" => path.clone(),
+            [arg] if arg.starts_with("--config=") => arg["--config=".len()..].to_string(),
+            _ => return Err("Usage: server [--config <file>]".to_string()),
+        };
+        Config::load(Path::new(&path))
+    }
+}
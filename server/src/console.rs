@@ -0,0 +1,271 @@
+use crate::archive::Scope;
+use crate::channel_message::ThreadsToMainMessage;
+use crate::history::Conversation;
+use crate::roles::Role;
+use common::communication::common_message::{ExportFormat, GROUP_PREFIX};
+use common::logic::command::{
+    ArgKind, ArgSpec, Command, CommandArgs, CommandError, CommandSet, CommandSpec, FlagSpec, Invocation,
+};
+use common::logic::input_parser::{parse_input, InputToken};
+use std::path::PathBuf;
+use common::logic::line_editor::{ConsoleInput, LineEditor, SharedUsernames};
+use rustyline::ExternalPrinter;
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::mpsc::Sender;
+
+const HISTORY_FILE_NAME: &str = ".chat_server_history";
+
+// set once the console runs, from then on all output goes through the line editor
+static PRINTER: OnceLock<Mutex<Box<dyn ExternalPrinter + Send>>> = OnceLock::new();
+
+pub fn print(text: String) {
+    if let Some(printer) = PRINTER.get() {
+        if printer.lock().unwrap().print(text.clone()).is_ok() {
+            return;
+        }
+    }
+    println!("{}", text);
+}
+
+// println! that does not clobber the line being typed into the console
+macro_rules! console_println {
+    ($($arg:tt)*) => {
+        $crate::console::print(format!($($arg)*))
+    };
+}
+pub(crate) use console_println;
+
+struct Users;
+
+impl Command for Users {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "users",
+        aliases: &["list"],
+        args: &[],
+        flags: &[],
+        help: "list the users currently connected",
+    };
+
+    fn from_args(_args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Users)
+    }
+}
+
+struct Grant {
+    username: String,
+    role: Role,
+}
+
+impl Command for Grant {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "grant",
+        aliases: &[],
+        args: &[
+            ArgSpec::required("username", ArgKind::Username),
+            ArgSpec::required("role", ArgKind::Text),
+        ],
+        flags: &[],
+        help: "give an account the role admin, moderator, member or muted",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        let role = args.string("role")?;
+        Ok(Grant {
+            username: args.string("username")?,
+            role: Role::from_name(&role).ok_or_else(|| CommandError::InvalidArgument {
+                arg: "role",
+                token: InputToken::String(role),
+                usage: Self::SPEC.usage(),
+            })?,
+        })
+    }
+}
+
+struct Revoke {
+    username: String,
+}
+
+impl Command for Revoke {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "revoke",
+        aliases: &[],
+        args: &[ArgSpec::required("username", ArgKind::Username)],
+        flags: &[],
+        help: "make an account an ordinary member again",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Revoke {
+            username: args.string("username")?,
+        })
+    }
+}
+
+struct Export {
+    scope: Scope,
+    format: ExportFormat,
+    file: PathBuf,
+}
+
+impl Command for Export {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "export",
+        aliases: &[],
+        args: &[
+            ArgSpec::required("target", ArgKind::Username),
+            ArgSpec::required("file", ArgKind::Text),
+        ],
+        flags: &[
+            FlagSpec::with_value("with", ArgKind::Username, "only the conversation of the target with this user"),
+            FlagSpec::with_value("format", ArgKind::Text, "jsonl, md or html, by default taken from the file name"),
+        ],
+        help: "write the history of a user or a group to a file",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        let target = args.string("target")?;
+        let file = args.string("file")?;
+        let scope = match args.optional_string("with") {
+            Some(with) => Scope::Conversation(Conversation::direct(&target, &with)),
+            None if target.starts_with(GROUP_PREFIX) => Scope::Conversation(Conversation::Group(target)),
+            None => Scope::User(target),
+        };
+        let format = match args.optional_string("format") {
+            Some(name) => ExportFormat::from_name(&name).ok_or_else(|| CommandError::InvalidArgument {
+                arg: "format",
+                token: InputToken::String(name),
+                usage: Self::SPEC.usage(),
+            })?,
+            None => ExportFormat::for_file(&file).unwrap_or_default(),
+        };
+        Ok(Export {
+            scope,
+            format,
+            file: PathBuf::from(file),
+        })
+    }
+}
+
+struct Import {
+    file: PathBuf,
+}
+
+impl Command for Import {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "import",
+        aliases: &[],
+        args: &[ArgSpec::required("file", ArgKind::Text)],
+        flags: &[],
+        help: "load messages from a JSON Lines export",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Import {
+            file: PathBuf::from(args.string("file")?),
+        })
+    }
+}
+
+struct Close;
+
+impl Command for Close {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "close",
+        aliases: &["shutdown"],
+        args: &[],
+        flags: &[],
+        help: "disconnect everyone and shut the server down",
+    };
+
+    fn from_args(_args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Close)
+    }
+}
+
+enum ConsoleCommand {
+    Users(Users),
+    Grant(Grant),
+    Revoke(Revoke),
+    Export(Export),
+    Import(Import),
+    Close(Close),
+}
+
+fn console_commands() -> CommandSet<ConsoleCommand> {
+    CommandSet::new()
+        .with(ConsoleCommand::Users)
+        .with(ConsoleCommand::Grant)
+        .with(ConsoleCommand::Revoke)
+        .with(ConsoleCommand::Export)
+        .with(ConsoleCommand::Import)
+        .with(ConsoleCommand::Close)
+}
+
+pub fn spawn(thread_to_main_tx: Sender<ThreadsToMainMessage>, usernames: SharedUsernames) {
+    let commands = console_commands();
+    let mut editor = match LineEditor::new(commands.specs(), usernames.clone(), HISTORY_FILE_NAME) {
+        Ok(editor) => editor,
+        Err(e) => {
+            println!("Failed to initialize the console: {}", e);
+            return;
+        }
+    };
+    if let Some(printer) = editor.external_printer() {
+        let _ = PRINTER.set(Mutex::new(printer));
+    }
+
+    std::thread::spawn(move || loop {
+        let line = match editor.read_line("> ") {
+            ConsoleInput::Line(line) => line,
+            ConsoleInput::Interrupted => "close".to_string(),
+            ConsoleInput::Eof => break,
+        };
+
+        let tokens = match parse_input(&line) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                console_println!("Error: {}\n{}", e, e.underline(&line));
+                continue;
+            }
+        };
+        if tokens.is_empty() {
+            continue;
+        }
+
+        match commands.parse(&tokens) {
+            Ok(Invocation::Help(help)) => console_println!("{}", help),
+            Ok(Invocation::Command(ConsoleCommand::Users(_))) => {
+                let mut usernames = usernames.lock().unwrap().clone();
+                usernames.sort();
+                console_println!("Users: {:?}", usernames);
+            }
+            Ok(Invocation::Command(ConsoleCommand::Grant(grant))) => {
+                thread_to_main_tx
+                    .blocking_send(ThreadsToMainMessage::SetRole(grant.username, grant.role))
+                    .expect("Failed to send role signal");
+            }
+            Ok(Invocation::Command(ConsoleCommand::Revoke(revoke))) => {
+                thread_to_main_tx
+                    .blocking_send(ThreadsToMainMessage::SetRole(revoke.username, Role::Member))
+                    .expect("Failed to send role signal");
+            }
+            Ok(Invocation::Command(ConsoleCommand::Export(export))) => {
+                thread_to_main_tx
+                    .blocking_send(ThreadsToMainMessage::Export(export.scope, export.format, export.file))
+                    .expect("Failed to send export signal");
+            }
+            Ok(Invocation::Command(ConsoleCommand::Import(import))) => {
+                thread_to_main_tx
+                    .blocking_send(ThreadsToMainMessage::Import(import.file))
+                    .expect("Failed to send import signal");
+            }
+            Ok(Invocation::Command(ConsoleCommand::Close(_))) => {
+                thread_to_main_tx
+                    .blocking_send(ThreadsToMainMessage::Shutdown)
+                    .expect("Failed to send shutdown signal");
+                break;
+            }
+            Err(e) => console_println!("{}", e),
+        }
+    });
+}
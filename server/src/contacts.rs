@@ -0,0 +1,194 @@
+use crate::store;
+use common::communication::common_message::ContactState;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct UserContacts {
+    contacts: BTreeSet<String>,
+    // requests the user sent, waiting for the other side
+    sent: BTreeSet<String>,
+    // requests waiting for the user's answer
+    received: BTreeSet<String>,
+}
+
+// contacts are mutual, one side asks and the other accepts
+#[derive(Debug, Default)]
+pub struct Contacts {
+    // where the contacts are kept, none keeps them in memory only
+    path: Option<PathBuf>,
+    users: HashMap<String, UserContacts>,
+}
+
+impl Contacts {
+    pub fn load(path: Option<&Path>) -> Result<Contacts, String> {
+        let Some(path) = path else {
+            return Ok(Contacts::default());
+        };
+        Ok(Contacts {
+            path: Some(path.to_path_buf()),
+            users: store::load(path, "contacts")?,
+        })
+    }
+
+    // drops what was saved for names which are no account, older servers let guests have contacts too
+    pub fn retain_accounts(&mut self, is_account: impl Fn(&str) -> bool) {
+        self.users.retain(|user, _| is_account(user));
+        for contacts in self.users.values_mut() {
+            for names in [&mut contacts.contacts, &mut contacts.sent, &mut contacts.received] {
+                names.retain(|name| is_account(name));
+            }
+        }
+    }
+
+    pub fn are_contacts(&self, a: &str, b: &str) -> bool {
+        self.users.get(a).is_some_and(|user| user.contacts.contains(b))
+    }
+
+    pub fn of(&self, user: &str) -> impl Iterator<Item = &String> {
+        self.users.get(user).into_iter().flat_map(|user| user.contacts.iter())
+    }
+
+    // contacts and open requests of the user, by name
+    pub fn list(&self, user: &str) -> Vec<(String, ContactState)> {
+        let Some(user) = self.users.get(user) else {
+            return Vec::new();
+        };
+        let mut list: Vec<(String, ContactState)> = user
+            .contacts
+            .iter()
+            .map(|name| (name.clone(), ContactState::Contact))
+            .chain(user.sent.iter().map(|name| (name.clone(), ContactState::Sent)))
+            .chain(user.received.iter().map(|name| (name.clone(), ContactState::Received)))
+            .collect();
+        list.sort();
+        list
+    }
+
+    // true if `to` had already asked `from`, which makes them contacts right away
+    pub fn request(&mut self, from: &str, to: &str) -> Result<bool, String> {
+        if from == to {
+            return Err("You cannot add yourself!".to_string());
+        }
+        if self.are_contacts(from, to) {
+            return Err(format!("{} is already a contact!", to));
+        }
+        if self.users.get(from).is_some_and(|user| user.sent.contains(to)) {
+            return Err(format!("You already asked {}!", to));
+        }
+        if self.users.get(from).is_some_and(|user| user.received.contains(to)) {
+            self.answer(from, to, true)?;
+            return Ok(true);
+        }
+        self.change(from, to, |from_contacts, to_contacts, from, to| {
+            from_contacts.sent.insert(to.to_string());
+            to_contacts.received.insert(from.to_string());
+        })?;
+        Ok(false)
+    }
+
+    // `user` accepts or declines the request of `from`
+    pub fn answer(&mut self, user: &str, from: &str, accept: bool) -> Result<(), String> {
+        if !self.users.get(user).is_some_and(|contacts| contacts.received.contains(from)) {
+            return Err(format!("{} did not ask to be your contact!", from));
+        }
+        self.change(user, from, |user_contacts, from_contacts, user, from| {
+            user_contacts.received.remove(from);
+            from_contacts.sent.remove(user);
+            if accept {
+                user_contacts.contacts.insert(from.to_string());
+                from_contacts.contacts.insert(user.to_string());
+            }
+        })
+    }
+
+    // ends the contact, or takes back a request the user sent
+    pub fn remove(&mut self, user: &str, other: &str) -> Result<(), String> {
+        let known = self
+            .users
+            .get(user)
+            .is_some_and(|contacts| contacts.contacts.contains(other) || contacts.sent.contains(other));
+        if !known {
+            return Err(format!("{} is not a contact!", other));
+        }
+        self.change(user, other, |user_contacts, other_contacts, user, other| {
+            user_contacts.contacts.remove(other);
+            user_contacts.sent.remove(other);
+            other_contacts.contacts.remove(user);
+            other_contacts.received.remove(user);
+        })
+    }
+
+    // changes both sides at once, and undoes the change if it cannot be saved
+    fn change(
+        &mut self,
+        a: &str,
+        b: &str,
+        change: impl FnOnce(&mut UserContacts, &mut UserContacts, &str, &str),
+    ) -> Result<(), String> {
+        let previous = [a, b].map(|user| self.users.get(user).cloned());
+        let mut a_contacts = self.users.remove(a).unwrap_or_default();
+        let mut b_contacts = self.users.remove(b).unwrap_or_default();
+        change(&mut a_contacts, &mut b_contacts, a, b);
+        self.users.insert(a.to_string(), a_contacts);
+        self.users.insert(b.to_string(), b_contacts);
+        let result = self.save();
+        if result.is_err() {
+            for (user, previous) in [a, b].into_iter().zip(previous) {
+                match previous {
+                    Some(previous) => self.users.insert(user.to_string(), previous),
+                    None => self.users.remove(user),
+                };
+            }
+        }
+        result
+    }
+
+    fn save(&self) -> Result<(), String> {
+        match &self.path {
+            Some(path) => store::save(path, &self.users, "contacts"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Contacts;
+    use common::communication::common_message::ContactState;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_contacts() {
+        let path = std::env::temp_dir().join(format!("contacts-{}.json", Uuid::new_v4()));
+        let mut contacts = Contacts::load(Some(&path)).unwrap();
+        assert!(contacts.request("alice", "alice").is_err());
+        assert_eq!(contacts.request("alice", "bob"), Ok(false));
+        assert!(contacts.request("alice", "bob").is_err());
+        assert!(!contacts.are_contacts("alice", "bob"));
+        assert_eq!(contacts.list("bob"), vec![("alice".to_string(), ContactState::Received)]);
+        assert!(contacts.answer("alice", "bob", true).is_err());
+        assert_eq!(contacts.answer("bob", "alice", true), Ok(()));
+        assert!(contacts.are_contacts("alice", "bob") && contacts.are_contacts("bob", "alice"));
+
+        // asking someone who asked first accepts their request
+        contacts.request("carol", "alice").unwrap();
+        assert_eq!(contacts.request("alice", "carol"), Ok(true));
+        contacts.request("dave", "alice").unwrap();
+        assert_eq!(contacts.answer("alice", "dave", false), Ok(()));
+        assert_eq!(contacts.list("dave"), vec![]);
+
+        let mut reloaded = Contacts::load(Some(&path)).unwrap();
+        assert_eq!(reloaded.of("alice").collect::<Vec<_>>(), vec!["bob", "carol"]);
+        assert_eq!(reloaded.remove("bob", "alice"), Ok(()));
+        assert!(!reloaded.are_contacts("alice", "bob"));
+        assert!(reloaded.remove("bob", "alice").is_err());
+
+        reloaded.retain_accounts(|user| user != "carol");
+        assert_eq!(reloaded.of("alice").count(), 0);
+        assert_eq!(reloaded.list("carol"), vec![]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
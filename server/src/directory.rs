@@ -0,0 +1,199 @@
+use crate::roles::Role;
+use common::communication::common_message::{
+    DirectoryEntry, DirectoryPage, DirectoryQuery, DirectorySort, ProfileInfo, UserProfile,
+};
+use std::cmp::Ordering;
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 200;
+
+// what the directory knows of a user, as the one asking may see it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub username: String,
+    pub online: bool,
+    pub bot: bool,
+    pub role: Role,
+    pub profile: ProfileInfo,
+}
+
+// case only decides between names which differ in nothing else
+fn by_name(a: &str, b: &str) -> Ordering {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .cmp(b.chars().flat_map(char::to_lowercase))
+        .then_with(|| a.cmp(b))
+}
+
+fn compare(sort: DirectorySort, a: (&str, bool), b: (&str, bool)) -> Ordering {
+    match sort {
+        DirectorySort::Name => by_name(a.0, b.0),
+        DirectorySort::NameDescending => by_name(b.0, a.0),
+        DirectorySort::OnlineFirst => b.1.cmp(&a.1).then_with(|| by_name(a.0, b.0)),
+    }
+}
+
+// cursors name the last user of a page, so pages stay put when users come and go in between
+fn cursor(sort: DirectorySort, listing: &Listing) -> String {
+    match sort {
+        DirectorySort::OnlineFirst if listing.online => format!("+{}", listing.username),
+        DirectorySort::OnlineFirst => format!("-{}", listing.username),
+        _ => listing.username.clone(),
+    }
+}
+
+fn parse_cursor(sort: DirectorySort, cursor: &str) -> (&str, bool) {
+    match sort {
+        DirectorySort::OnlineFirst => match cursor.strip_prefix('+') {
+            Some(username) => (username, true),
+            None => (cursor.strip_prefix('-').unwrap_or(cursor), false),
+        },
+        _ => (cursor, false),
+    }
+}
+
+// the listings the query asks for in its order, ignoring the cursor and the page size
+pub fn matching(query: &DirectoryQuery, mut listings: Vec<Listing>) -> Vec<Listing> {
+    let filter = query.filter.to_lowercase();
+    let matches = |name: &str| {
+        let name = name.to_lowercase();
+        if query.substring {
+            name.contains(&filter)
+        } else {
+            name.starts_with(&filter)
+        }
+    };
+    // display names are searched as well, the order stays by username
+    listings.retain(|listing| {
+        let found = matches(&listing.username) || listing.profile.display_name.as_deref().is_some_and(matches);
+        found && (listing.online || !query.online_only)
+    });
+    listings.sort_by(|a, b| compare(query.sort, (&a.username, a.online), (&b.username, b.online)));
+    listings
+}
+
+pub fn page(query: &DirectoryQuery, listings: Vec<Listing>) -> DirectoryPage {
+    let listings = matching(query, listings);
+    let total = listings.len();
+    let start = match &query.after {
+        Some(after) => {
+            let after = parse_cursor(query.sort, after);
+            listings.partition_point(|listing| {
+                compare(query.sort, (&listing.username, listing.online), after) != Ordering::Greater
+            })
+        }
+        None => 0,
+    };
+    let limit = match query.limit {
+        0 => DEFAULT_LIMIT,
+        limit => limit.min(MAX_LIMIT),
+    } as usize;
+    let shown = &listings[start..total.min(start + limit)];
+    let next = match shown.last() {
+        Some(last) if start + shown.len() < total => Some(cursor(query.sort, last)),
+        _ => None,
+    };
+    DirectoryPage {
+        entries: shown
+            .iter()
+            .map(|listing| DirectoryEntry {
+                username: listing.username.clone(),
+                online: query.presence.then_some(listing.online),
+                profile: query.profile.then(|| UserProfile {
+                    bot: listing.bot,
+                    role: listing.role.name().to_string(),
+                    display_name: listing.profile.display_name.clone(),
+                    bio: listing.profile.bio.clone(),
+                    timezone: listing.profile.timezone.clone(),
+                    avatar: listing.profile.avatar.clone(),
+                }),
+            })
+            .collect(),
+        total,
+        next,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{page, Listing};
+    use crate::roles::Role;
+    use common::communication::common_message::{DirectoryQuery, DirectorySort, ProfileInfo};
+
+    fn listings() -> Vec<Listing> {
+        ["carol", "alice", "Albert", "bob", "alfred"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, username)| Listing {
+                username: username.to_string(),
+                online: i % 2 == 0,
+                bot: false,
+                role: Role::Member,
+                profile: ProfileInfo::default(),
+            })
+            .collect()
+    }
+
+    fn names(query: &DirectoryQuery) -> Vec<String> {
+        page(query, listings()).entries.into_iter().map(|entry| entry.username).collect()
+    }
+
+    #[test]
+    fn test_directory() {
+        let mut query = DirectoryQuery {
+            filter: "al".to_string(),
+            limit: 2,
+            ..DirectoryQuery::default()
+        };
+        let first = page(&query, listings());
+        assert_eq!(first.total, 3);
+        assert_eq!(first.entries[0].username, "Albert");
+        assert_eq!((first.entries[0].online, &first.entries[0].profile), (None, &None));
+
+        // a user coming along before the cursor does not shift the next page
+        let mut more = listings();
+        more.push(Listing {
+            username: "al".to_string(),
+            online: true,
+            bot: true,
+            role: Role::Member,
+            profile: ProfileInfo::default(),
+        });
+        query.after = first.next;
+        let second = page(&query, more);
+        assert_eq!(second.entries.len(), 1);
+        assert_eq!(second.entries[0].username, "alice");
+        assert_eq!(second.next, None);
+
+        let query = DirectoryQuery {
+            filter: "O".to_string(),
+            substring: true,
+            sort: DirectorySort::NameDescending,
+            presence: true,
+            ..DirectoryQuery::default()
+        };
+        assert_eq!(names(&query), vec!["carol", "bob"]);
+        assert_eq!(page(&query, listings()).entries[0].online, Some(true));
+
+        let mut named = listings();
+        named[3].profile.display_name = Some("Robert".to_string());
+        let query = DirectoryQuery {
+            filter: "rob".to_string(),
+            ..DirectoryQuery::default()
+        };
+        assert_eq!(page(&query, named).entries[0].username, "bob");
+
+        let mut query = DirectoryQuery {
+            sort: DirectorySort::OnlineFirst,
+            limit: 2,
+            ..DirectoryQuery::default()
+        };
+        assert_eq!(names(&query), vec!["Albert", "alfred"]);
+        query.after = page(&query, listings()).next;
+        assert_eq!(names(&query), vec!["carol", "alice"]);
+        query.online_only = true;
+        query.after = None;
+        query.limit = 0;
+        assert_eq!(names(&query), vec!["Albert", "alfred", "carol"]);
+    }
+}
@@ -0,0 +1,440 @@
+use crate::channel_message::{MainToThreadsMessage, ThreadsToMainMessage};
+use crate::config::{FederationConfig, PeerServer};
+use crate::console::console_println;
+use crate::router::{Inbox, Outbox};
+use crate::webhook::same_token;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+use tokio_tungstenite::{connect_async, WebSocketStream};
+use uuid::Uuid;
+
+// peers connect to the same address as clients, on this path
+pub const FEDERATION_PATH: &str = "/federation";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// what servers tell each other, usernames are the ones local to the sending server
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum PeerMessage {
+    // the first message of the server which connected, answered with Welcome or Rejected
+    Hello { server: String, secret: String },
+    Welcome { server: String },
+    Rejected(String),
+    // everyone online who does not hide, sent once the link is up
+    Presence(Vec<String>),
+    PresenceChanged(String, bool),
+    // answered with Delivered carrying the same ticket
+    Text { ticket: u64, from: String, to: String, text: String },
+    Delivered { ticket: u64, result: Result<(), String> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Local(String),
+    Remote { user: String, server: String },
+}
+
+struct Link {
+    main_to_thread_tx: Outbox,
+    // the peer once it is authenticated
+    server: Option<String>,
+    // links this server dialed are dialed again when they drop
+    dialed: bool,
+}
+
+// a direct message waiting for the peer to deliver it
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingText {
+    pub requester: Uuid,
+    pub from: String,
+    pub to: String,
+    pub text: String,
+}
+
+#[derive(Default)]
+pub struct Federation {
+    name: Option<String>,
+    links: HashMap<Uuid, Link>,
+    // the link each connected peer is reached over
+    servers: HashMap<String, Uuid>,
+    online: HashMap<String, BTreeSet<String>>,
+    pending: HashMap<u64, PendingText>,
+    last_ticket: u64,
+}
+
+// "bob@siteb" as the user and the server
+pub fn split_address(address: &str) -> Option<(&str, &str)> {
+    address.rsplit_once('@').filter(|(user, server)| !user.is_empty() && !server.is_empty())
+}
+
+impl Federation {
+    pub fn new(config: &FederationConfig) -> Result<Federation, String> {
+        if config.name.is_none() && !config.peers.is_empty() {
+            return Err("Federation needs a name for this server to go with its peers".to_string());
+        }
+        Ok(Federation {
+            name: config.name.clone(),
+            ..Federation::default()
+        })
+    }
+
+    // addresses naming this server are local ones
+    pub fn address(&self, address: &str) -> Address {
+        match split_address(address) {
+            Some((user, server)) if self.name.as_deref() != Some(server) => Address::Remote {
+                user: user.to_string(),
+                server: server.to_string(),
+            },
+            Some((user, _)) => Address::Local(user.to_string()),
+            None => Address::Local(address.to_string()),
+        }
+    }
+
+    pub fn add_link(&mut self, link: Uuid, main_to_thread_tx: Outbox, dialed: bool) {
+        self.links.insert(link, Link { main_to_thread_tx, server: None, dialed });
+    }
+
+    // a later link to the same peer takes over from an earlier one
+    pub fn connected(&mut self, link: Uuid, server: String) {
+        if let Some(entry) = self.links.get_mut(&link) {
+            entry.server = Some(server.clone());
+            self.servers.insert(server.clone(), link);
+            self.online.insert(server, BTreeSet::new());
+        }
+    }
+
+    // the messages waiting on the peer which went away
+    pub fn disconnected(&mut self, link: Uuid) -> Vec<PendingText> {
+        let server = match self.links.get_mut(&link) {
+            Some(entry) if entry.dialed => entry.server.take(),
+            Some(_) => self.links.remove(&link).and_then(|entry| entry.server),
+            None => None,
+        };
+        let Some(server) = server.filter(|server| self.servers.get(server) == Some(&link)) else {
+            return Vec::new();
+        };
+        self.servers.remove(&server);
+        self.online.remove(&server);
+        let tickets: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| split_address(&pending.to).is_some_and(|(_, to)| to == server))
+            .map(|(ticket, _)| *ticket)
+            .collect();
+        tickets.into_iter().filter_map(|ticket| self.pending.remove(&ticket)).collect()
+    }
+
+    // the peer on the other end of an authenticated link
+    pub fn server(&self, link: Uuid) -> Option<&str> {
+        self.links.get(&link).and_then(|entry| entry.server.as_deref())
+    }
+
+    pub fn set_online(&mut self, link: Uuid, users: Vec<String>) {
+        if let Some(server) = self.server(link).map(str::to_string) {
+            self.online.insert(server, users.into_iter().collect());
+        }
+    }
+
+    pub fn presence_changed(&mut self, link: Uuid, user: String, online: bool) {
+        let Some(server) = self.server(link).map(str::to_string) else {
+            return;
+        };
+        let users = self.online.entry(server).or_default();
+        if online {
+            users.insert(user);
+        } else {
+            users.remove(&user);
+        }
+    }
+
+    // the users of all peers as user@server
+    pub fn online_users(&self) -> impl Iterator<Item = String> + '_ {
+        self.online
+            .iter()
+            .flat_map(|(server, users)| users.iter().map(move |user| format!("{}@{}", user, server)))
+    }
+
+    pub fn send(&self, server: &str, message: PeerMessage) -> Result<(), String> {
+        let link = self
+            .servers
+            .get(server)
+            .and_then(|link| self.links.get(link))
+            .ok_or_else(|| format!("Server {} is not connected!", server))?;
+        link.main_to_thread_tx
+            .send(MainToThreadsMessage::SendToPeer(message))
+            .map_err(|e| format!("Failed to reach server {}: {}", server, e))
+    }
+
+    pub fn broadcast(&self, message: PeerMessage) {
+        for server in self.servers.keys() {
+            self.send(server, message.clone())
+                .unwrap_or_else(|e| console_println!("{}", e));
+        }
+    }
+
+    // the message is remembered until the peer tells whether it was delivered
+    pub fn send_text(&mut self, requester: Uuid, from: &str, user: &str, server: &str, text: &str) -> Result<(), String> {
+        self.last_ticket += 1;
+        let ticket = self.last_ticket;
+        let message = PeerMessage::Text {
+            ticket,
+            from: from.to_string(),
+            to: user.to_string(),
+            text: text.to_string(),
+        };
+        self.send(server, message)?;
+        self.pending.insert(ticket, PendingText {
+            requester,
+            from: from.to_string(),
+            to: format!("{}@{}", user, server),
+            text: text.to_string(),
+        });
+        Ok(())
+    }
+
+    pub fn delivered(&mut self, ticket: u64) -> Option<PendingText> {
+        self.pending.remove(&ticket)
+    }
+
+    pub fn shutdown(&self) {
+        for link in self.links.values() {
+            let _ = link.main_to_thread_tx.send(MainToThreadsMessage::Shutdown);
+        }
+    }
+}
+
+async fn send_peer_message<S>(ws_stream: &mut WebSocketStream<S>, message: &PeerMessage) -> Result<(), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let text = serde_json::to_string(message).expect("Failed to serialize peer message");
+    ws_stream.send(Message::Text(Utf8Bytes::from(text))).await.map_err(|e| e.to_string())
+}
+
+async fn receive_peer_message<S>(ws_stream: &mut WebSocketStream<S>) -> Result<PeerMessage, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        match ws_stream.next().await {
+            Some(Ok(Message::Text(text))) => {
+                return serde_json::from_str(&text).map_err(|e| format!("invalid peer message: {}", e))
+            }
+            Some(Ok(Message::Close(_))) | None => return Err("connection closed".to_string()),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.to_string()),
+        }
+    }
+}
+
+// passes messages between the peer and the main loop until either ends the link, true if it was the main loop
+async fn relay<S>(
+    mut ws_stream: WebSocketStream<S>,
+    link: Uuid,
+    main_to_thread_rx: &mut Inbox,
+    thread_to_main_tx: &Sender<ThreadsToMainMessage>,
+) -> bool
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        tokio::select! {
+            message = receive_peer_message(&mut ws_stream) => match message {
+                Ok(message) => {
+                    thread_to_main_tx
+                        .send(ThreadsToMainMessage::FromPeer(link, message))
+                        .await.expect("Failed to send message to main thread");
+                }
+                Err(e) => {
+                    console_println!("Peer link {} dropped: {}", link, e);
+                    return false;
+                }
+            },
+            channel_message = main_to_thread_rx.recv() => match channel_message {
+                Some(MainToThreadsMessage::SendToPeer(message)) => {
+                    if let Err(e) = send_peer_message(&mut ws_stream, &message).await {
+                        console_println!("Peer link {} dropped: {}", link, e);
+                        return false;
+                    }
+                }
+                Some(MainToThreadsMessage::Shutdown) | None => {
+                    let _ = ws_stream.close(None).await;
+                    return true;
+                }
+                Some(MainToThreadsMessage::FellBehind) => {
+                    console_println!("Peer link {} does not keep up", link);
+                    let _ = ws_stream.close(None).await;
+                    return false;
+                }
+                Some(_) => {}
+            },
+        }
+    }
+}
+
+// a peer which connected to this server, it has to name itself and know the secret first
+pub async fn handle_peer(
+    mut ws_stream: WebSocketStream<TcpStream>,
+    link: Uuid,
+    mut main_to_thread_rx: Inbox,
+    thread_to_main_tx: Sender<ThreadsToMainMessage>,
+    config: Arc<FederationConfig>,
+) {
+    let hello = timeout(HANDSHAKE_TIMEOUT, receive_peer_message(&mut ws_stream)).await;
+    let accepted = match (&config.name, hello) {
+        (None, _) => Err("This server does not federate".to_string()),
+        (Some(name), Ok(Ok(PeerMessage::Hello { server, secret }))) => {
+            let known = config.peers.iter().any(|peer| peer.name == server && same_token(&peer.secret, &secret));
+            match known {
+                true => Ok((name.clone(), server)),
+                false => Err("Unknown server or wrong secret".to_string()),
+            }
+        }
+        (Some(_), _) => Err("Expected a hello".to_string()),
+    };
+    match accepted {
+        Ok((name, server)) => {
+            if send_peer_message(&mut ws_stream, &PeerMessage::Welcome { server: name }).await.is_ok() {
+                console_println!("Peer {} connected on link {}", server, link);
+                thread_to_main_tx
+                    .send(ThreadsToMainMessage::PeerConnected(link, server))
+                    .await.expect("Failed to send message to main thread");
+                if !relay(ws_stream, link, &mut main_to_thread_rx, &thread_to_main_tx).await {
+                    thread_to_main_tx
+                        .send(ThreadsToMainMessage::PeerDisconnected(link))
+                        .await.expect("Failed to send message to main thread");
+                }
+                return;
+            }
+        }
+        Err(e) => {
+            console_println!("Refused peer on link {}: {}", link, e);
+            let _ = send_peer_message(&mut ws_stream, &PeerMessage::Rejected(e)).await;
+            let _ = ws_stream.close(None).await;
+        }
+    }
+    thread_to_main_tx
+        .send(ThreadsToMainMessage::ConnectionClosed(link))
+        .await.expect("Failed to send message to main thread");
+}
+
+// keeps a link to a peer with an address, connecting again whenever it drops
+pub async fn dial(
+    peer: PeerServer,
+    name: String,
+    link: Uuid,
+    mut main_to_thread_rx: Inbox,
+    thread_to_main_tx: Sender<ThreadsToMainMessage>,
+) {
+    let Some(url) = peer.url.clone() else {
+        return;
+    };
+    loop {
+        match connect_async(url.as_str()).await {
+            Ok((mut ws_stream, _)) => {
+                let hello = PeerMessage::Hello {
+                    server: name.clone(),
+                    secret: peer.secret.clone(),
+                };
+                let welcome = match send_peer_message(&mut ws_stream, &hello).await {
+                    Ok(()) => timeout(HANDSHAKE_TIMEOUT, receive_peer_message(&mut ws_stream))
+                        .await
+                        .unwrap_or_else(|_| Err("no answer".to_string())),
+                    Err(e) => Err(e),
+                };
+                match welcome {
+                    Ok(PeerMessage::Welcome { server }) if server == peer.name => {
+                        console_println!("Connected to peer {}", peer.name);
+                        thread_to_main_tx
+                            .send(ThreadsToMainMessage::PeerConnected(link, peer.name.clone()))
+                            .await.expect("Failed to send message to main thread");
+                        if relay(ws_stream, link, &mut main_to_thread_rx, &thread_to_main_tx).await {
+                            return;
+                        }
+                        thread_to_main_tx
+                            .send(ThreadsToMainMessage::PeerDisconnected(link))
+                            .await.expect("Failed to send message to main thread");
+                    }
+                    Ok(PeerMessage::Welcome { server }) => {
+                        console_println!("Expected peer {} at {}, found {}", peer.name, url, server);
+                    }
+                    Ok(PeerMessage::Rejected(reason)) => console_println!("Peer {} refused the link: {}", peer.name, reason),
+                    Ok(other) => console_println!("Unexpected answer from peer {}: {:?}", peer.name, other),
+                    Err(e) => console_println!("Failed to connect to peer {}: {}", peer.name, e),
+                }
+            }
+            Err(e) => console_println!("Failed to connect to peer {}: {}", peer.name, e),
+        }
+        // messages for the peer are dropped until it is back
+        let retry = tokio::time::sleep(RETRY_INTERVAL);
+        tokio::pin!(retry);
+        loop {
+            tokio::select! {
+                _ = &mut retry => break,
+                channel_message = main_to_thread_rx.recv() => match channel_message {
+                    Some(MainToThreadsMessage::Shutdown) | None => return,
+                    Some(_) => {}
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_address, Address, Federation};
+    use crate::config::FederationConfig;
+    use crate::router::outbox;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_federation() {
+        assert_eq!(split_address("bob@siteb"), Some(("bob", "siteb")));
+        assert_eq!(split_address("bob@"), None);
+        assert_eq!(split_address("bob"), None);
+
+        let config = FederationConfig {
+            name: Some("sitea".to_string()),
+            peers: Vec::new(),
+        };
+        let mut federation = Federation::new(&config).unwrap();
+        assert_eq!(federation.address("alice@sitea"), Address::Local("alice".to_string()));
+        assert_eq!(
+            federation.address("bob@siteb"),
+            Address::Remote {
+                user: "bob".to_string(),
+                server: "siteb".to_string()
+            }
+        );
+
+        let (tx, _rx) = outbox();
+        let link = Uuid::new_v4();
+        federation.add_link(link, tx, true);
+        federation.connected(link, "siteb".to_string());
+        federation.set_online(link, vec!["bob".to_string(), "carol".to_string()]);
+        federation.presence_changed(link, "carol".to_string(), false);
+        assert_eq!(federation.online_users().collect::<Vec<_>>(), vec!["bob@siteb"]);
+        // a dialed link stays to be dialed again, without what the peer told
+        assert!(federation.disconnected(link).is_empty());
+        assert_eq!(federation.online_users().count(), 0);
+        assert_eq!(federation.server(link), None);
+
+        let config = FederationConfig {
+            name: None,
+            peers: vec![crate::config::PeerServer {
+                name: "siteb".to_string(),
+                url: None,
+                secret: "s3cret".to_string(),
+            }],
+        };
+        assert!(Federation::new(&config).is_err());
+    }
+}
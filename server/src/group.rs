@@ -0,0 +1,207 @@
+use crate::store;
+use common::communication::common_message::{GroupInfo, GROUP_PREFIX};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct SavedGroups {
+    next_id: u64,
+    groups: HashMap<String, Vec<String>>,
+}
+
+// ad-hoc conversations between several accounts, kept until the last member leaves; ids are never
+// given out twice, as the history keeps what was said in a group under its id
+#[derive(Debug, Default)]
+pub struct Groups {
+    // where the groups are kept, none keeps them in memory only
+    path: Option<PathBuf>,
+    next_id: u64,
+    groups: HashMap<String, Vec<String>>,
+}
+
+impl Groups {
+    pub fn load(path: Option<&Path>) -> Result<Groups, String> {
+        let Some(path) = path else {
+            return Ok(Groups::default());
+        };
+        let saved: SavedGroups = store::load(path, "groups")?;
+        Ok(Groups {
+            path: Some(path.to_path_buf()),
+            next_id: saved.next_id,
+            groups: saved.groups,
+        })
+    }
+
+    // drops members which are no account, and groups left without any
+    pub fn retain_accounts(&mut self, is_account: impl Fn(&str) -> bool) {
+        for members in self.groups.values_mut() {
+            members.retain(|member| is_account(member));
+        }
+        self.groups.retain(|_, members| !members.is_empty());
+    }
+
+    // new ids start past every group id in `used`, like those of the history, in case the groups were lost
+    pub fn continue_after<'a>(&mut self, used: impl IntoIterator<Item = &'a str>) {
+        let used = used.into_iter()
+            .filter_map(|id| id.strip_prefix(GROUP_PREFIX))
+            .filter_map(|number| number.parse().ok());
+        self.next_id = used.fold(self.next_id, u64::max);
+    }
+
+    pub fn create(&mut self, creator: &str, others: &[String]) -> Result<GroupInfo, String> {
+        let mut members = vec![creator.to_string()];
+        for member in others {
+            if !members.contains(member) {
+                members.push(member.clone());
+            }
+        }
+        if members.len() < 2 {
+            return Err("A group needs at least one other member!".to_string());
+        }
+
+        let id = format!("{}{}", GROUP_PREFIX, self.next_id + 1);
+        self.change(&id, |next_id, group| {
+            *next_id += 1;
+            *group = Some(members.clone());
+        })?;
+        Ok(GroupInfo { id, members })
+    }
+
+    // the members of a group, as long as the user asking is one of them
+    pub fn members(&self, id: &str, member: &str) -> Result<&[String], String> {
+        match self.groups.get(id) {
+            Some(members) if members.iter().any(|m| m == member) => Ok(members),
+            _ => Err(format!("You are not in a group {}!", id)),
+        }
+    }
+
+    // the members of a group, for moderators who need not be one of them
+    pub fn members_of(&self, id: &str) -> Option<&[String]> {
+        self.groups.get(id).map(Vec::as_slice)
+    }
+
+    pub fn add(&mut self, id: &str, by: &str, username: &str) -> Result<GroupInfo, String> {
+        if self.members(id, by)?.iter().any(|m| m == username) {
+            return Err(format!("{} is already in {}!", username, id));
+        }
+        self.change(id, |_, group| group.as_mut().unwrap().push(username.to_string()))?;
+        Ok(GroupInfo {
+            id: id.to_string(),
+            members: self.groups[id].clone(),
+        })
+    }
+
+    // a group left without members is gone for good
+    pub fn remove(&mut self, id: &str, by: &str, username: &str) -> Result<GroupInfo, String> {
+        let Some(position) = self.members(id, by)?.iter().position(|m| m == username) else {
+            return Err(format!("{} is not in {}!", username, id));
+        };
+        let mut members = Vec::new();
+        self.change(id, |_, group| {
+            let mut remaining = group.take().unwrap();
+            remaining.remove(position);
+            members = remaining.clone();
+            *group = Some(remaining).filter(|remaining| !remaining.is_empty());
+        })?;
+        Ok(GroupInfo {
+            id: id.to_string(),
+            members,
+        })
+    }
+
+    // changes one group, none if it is gone, and undoes the change if it cannot be saved
+    fn change(&mut self, id: &str, change: impl FnOnce(&mut u64, &mut Option<Vec<String>>)) -> Result<(), String> {
+        let previous = (self.next_id, self.groups.get(id).cloned());
+        let mut group = previous.1.clone();
+        change(&mut self.next_id, &mut group);
+        match group {
+            Some(members) => self.groups.insert(id.to_string(), members),
+            None => self.groups.remove(id),
+        };
+        let result = self.save();
+        if result.is_err() {
+            self.next_id = previous.0;
+            match previous.1 {
+                Some(members) => self.groups.insert(id.to_string(), members),
+                None => self.groups.remove(id),
+            };
+        }
+        result
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let saved = SavedGroups {
+            next_id: self.next_id,
+            groups: self.groups.clone(),
+        };
+        store::save(path, &saved, "groups")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Groups;
+    use uuid::Uuid;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut groups = Groups::default();
+        assert!(groups.create("alice", &names(&["alice"])).is_err());
+
+        let group = groups.create("alice", &names(&["bob", "carol", "bob"])).unwrap();
+        assert_eq!(group.id, "#1");
+        assert_eq!(group.members, names(&["alice", "bob", "carol"]));
+        assert_eq!(groups.create("bob", &names(&["carol"])).unwrap().id, "#2");
+
+        assert!(groups.members("#1", "dave").is_err());
+        assert!(groups.add("#1", "dave", "dave").is_err());
+        assert_eq!(
+            groups.add("#1", "bob", "dave").unwrap().members,
+            names(&["alice", "bob", "carol", "dave"])
+        );
+        assert!(groups.add("#1", "bob", "dave").is_err());
+
+        assert_eq!(
+            groups.remove("#1", "alice", "carol").unwrap().members,
+            names(&["alice", "bob", "dave"])
+        );
+        assert!(groups.remove("#1", "alice", "carol").is_err());
+        for member in ["alice", "bob", "dave"] {
+            groups.remove("#1", member, member).unwrap();
+        }
+        assert!(groups.members("#1", "alice").is_err());
+        assert!(groups.members("#2", "carol").is_ok());
+    }
+
+    // a group id stays taken after a restart, even when the groups themselves were lost
+    #[test]
+    fn test_groups_file() {
+        let path = std::env::temp_dir().join(format!("groups-{}.json", Uuid::new_v4()));
+        let mut groups = Groups::load(Some(&path)).unwrap();
+        groups.create("alice", &names(&["bob"])).unwrap();
+        groups.create("alice", &names(&["carol"])).unwrap();
+        groups.remove("#2", "carol", "carol").unwrap();
+
+        let mut reloaded = Groups::load(Some(&path)).unwrap();
+        assert_eq!(reloaded.members("#1", "bob").unwrap(), names(&["alice", "bob"]));
+        assert_eq!(reloaded.members("#2", "alice").unwrap(), names(&["alice"]));
+        assert_eq!(reloaded.create("bob", &names(&["carol"])).unwrap().id, "#3");
+        reloaded.retain_accounts(|user| user != "alice");
+        assert!(reloaded.members("#2", "alice").is_err() && reloaded.members_of("#2").is_none());
+        assert_eq!(reloaded.members_of("#1").unwrap(), names(&["bob"]));
+        std::fs::remove_file(&path).unwrap();
+
+        let mut lost = Groups::default();
+        lost.continue_after(["#7", "#12", "#x", "bob"]);
+        assert_eq!(lost.create("alice", &names(&["bob"])).unwrap().id, "#13");
+    }
+}
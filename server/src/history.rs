@@ -0,0 +1,463 @@
+use crate::group::Groups;
+use crate::search::SearchIndex;
+use chrono::{DateTime, Duration, Utc};
+use common::communication::common_message::{MessageInfo, ReactionInfo, ReplyInfo, GROUP_PREFIX};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+// how much of a message replies quote
+const EXCERPT_CHARS: usize = 40;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Conversation {
+    // the two users in alphabetical order, so both directions are the same conversation
+    Direct(String, String),
+    Group(String),
+}
+
+impl Conversation {
+    pub fn direct(a: &str, b: &str) -> Self {
+        if a <= b {
+            Conversation::Direct(a.to_string(), b.to_string())
+        } else {
+            Conversation::Direct(b.to_string(), a.to_string())
+        }
+    }
+
+    // a group id, or the user the conversation of `user` is with
+    pub fn named(user: &str, name: &str) -> Self {
+        if name.starts_with(GROUP_PREFIX) {
+            Conversation::Group(name.to_string())
+        } else {
+            Conversation::direct(user, name)
+        }
+    }
+
+    // everyone who sees the conversation, as long as the user asking is one of them
+    pub fn participants(&self, user: &str, groups: &Groups) -> Option<Vec<String>> {
+        match self {
+            Conversation::Direct(a, b) if a == user || b == user => Some(vec![a.clone(), b.clone()]),
+            Conversation::Direct(..) => None,
+            Conversation::Group(id) => groups.members(id, user).ok().map(<[String]>::to_vec),
+        }
+    }
+
+    // everyone who sees the conversation, whoever asks
+    pub fn members(&self, groups: &Groups) -> Vec<String> {
+        match self {
+            Conversation::Direct(a, b) => vec![a.clone(), b.clone()],
+            Conversation::Group(id) => groups.members_of(id).map(<[String]>::to_vec).unwrap_or_default(),
+        }
+    }
+
+    // what `user` calls the conversation when sending to it
+    pub fn name_for(&self, user: &str) -> String {
+        match self {
+            Conversation::Direct(a, b) if a == user => b.clone(),
+            Conversation::Direct(a, _) => a.clone(),
+            Conversation::Group(id) => id.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredMessage {
+    pub id: u64,
+    pub conversation: Conversation,
+    pub from: String,
+    pub text: String,
+    pub sent_at: DateTime<Utc>,
+    pub reply_to: Option<u64>,
+    pub edited: bool,
+    pub deleted: bool,
+    // emoji and the users who reacted with it, in the order they did
+    pub reactions: BTreeMap<String, Vec<String>>,
+}
+
+impl StoredMessage {
+    pub fn info(&self) -> MessageInfo {
+        MessageInfo {
+            id: self.id,
+            from: self.from.clone(),
+            text: self.text.clone(),
+            reply_to: self.reply_to,
+            edited: self.edited,
+        }
+    }
+
+    // what replies to this message show of it
+    pub fn reply_info(&self) -> ReplyInfo {
+        let mut excerpt: String = self.text.chars().take(EXCERPT_CHARS).collect();
+        if excerpt.len() < self.text.len() {
+            excerpt.push('…');
+        }
+        ReplyInfo {
+            id: self.id,
+            from: self.from.clone(),
+            excerpt,
+        }
+    }
+
+    pub fn reaction_infos(&self) -> Vec<ReactionInfo> {
+        self.reactions
+            .iter()
+            .map(|(emoji, users)| ReactionInfo {
+                emoji: emoji.clone(),
+                users: users.clone(),
+            })
+            .collect()
+    }
+
+    // whether a user who sees the history from `since` on, or all of it for none, sees this message
+    pub fn sent_since(&self, since: Option<DateTime<Utc>>) -> bool {
+        since.is_none_or(|since| self.sent_at >= since)
+    }
+}
+
+// reactions are single emoji, not words
+fn is_emoji(reaction: &str) -> bool {
+    let count = reaction.chars().count();
+    (1..=8).contains(&count) && reaction.chars().all(|c| !c.is_ascii() && !c.is_whitespace())
+}
+
+// every message delivered to a user or a group, by id
+#[derive(Debug, Default)]
+pub struct History {
+    next_id: u64,
+    messages: BTreeMap<u64, StoredMessage>,
+    index: SearchIndex,
+    // the ids of the messages changed since the history file last took them
+    changed: BTreeSet<u64>,
+}
+
+impl History {
+    // replies have to stay within the conversation of the message they refer to
+    pub fn add(
+        &mut self,
+        conversation: Conversation,
+        from: &str,
+        text: &str,
+        reply_to: Option<u64>,
+        sent_at: DateTime<Utc>,
+    ) -> Result<u64, String> {
+        if let Some(parent) = reply_to {
+            match self.get(parent) {
+                Some(parent) if parent.conversation == conversation => {}
+                _ => return Err(format!("There is no message {} in this conversation!", parent)),
+            }
+        }
+        self.next_id += 1;
+        self.changed.insert(self.next_id);
+        self.index.insert(self.next_id, text);
+        self.messages.insert(
+            self.next_id,
+            StoredMessage {
+                id: self.next_id,
+                conversation,
+                from: from.to_string(),
+                text: text.to_string(),
+                sent_at,
+                reply_to,
+                edited: false,
+                deleted: false,
+                reactions: BTreeMap::new(),
+            },
+        );
+        Ok(self.next_id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&StoredMessage> {
+        self.messages.get(&id).filter(|message| !message.deleted)
+    }
+
+    // every message which was not deleted, oldest first
+    pub fn messages(&self) -> impl Iterator<Item = &StoredMessage> {
+        self.messages.values().filter(|message| !message.deleted)
+    }
+
+    // deleted messages included, so their ids stay taken after a restart
+    pub fn stored(&self) -> impl Iterator<Item = &StoredMessage> {
+        self.messages.values()
+    }
+
+    // deleted messages included, their ids are taken for good
+    pub fn contains(&self, id: u64) -> bool {
+        self.messages.contains_key(&id)
+    }
+
+    // where a message was written, deleted messages included
+    pub fn conversation(&self, id: u64) -> Option<&Conversation> {
+        self.messages.get(&id).map(|message| &message.conversation)
+    }
+
+    // stores a message under the id it had before, like one of an export
+    pub fn import(&mut self, message: StoredMessage) -> Result<(), String> {
+        if self.messages.contains_key(&message.id) {
+            return Err(format!("Message {} already exists!", message.id));
+        }
+        self.next_id = self.next_id.max(message.id);
+        self.changed.insert(message.id);
+        if !message.deleted {
+            self.index.insert(message.id, &message.text);
+        }
+        self.messages.insert(message.id, message);
+        Ok(())
+    }
+
+    // the messages containing every word of `terms`, oldest first
+    pub fn matching(&self, terms: &str) -> impl DoubleEndedIterator<Item = &StoredMessage> + '_ {
+        self.index
+            .matching(terms)
+            .into_iter()
+            .filter_map(|id| self.get(id))
+    }
+
+    // up to `count` messages of the same conversation right before and right after a message,
+    // of those sent since `since` if given
+    pub fn context(
+        &self,
+        message: &StoredMessage,
+        count: usize,
+        since: Option<DateTime<Utc>>,
+    ) -> (Vec<&StoredMessage>, Vec<&StoredMessage>) {
+        let same_conversation = |other: &&StoredMessage| {
+            other.conversation == message.conversation && !other.deleted && other.sent_since(since)
+        };
+        let mut before: Vec<&StoredMessage> = self
+            .messages
+            .range(..message.id)
+            .rev()
+            .map(|(_, other)| other)
+            .filter(same_conversation)
+            .take(count)
+            .collect();
+        before.reverse();
+        let after = self
+            .messages
+            .range(message.id + 1..)
+            .map(|(_, other)| other)
+            .filter(same_conversation)
+            .take(count)
+            .collect();
+        (before, after)
+    }
+
+    // the first message of the thread and every reply to it, directly or not
+    pub fn thread(&self, id: u64) -> Vec<&StoredMessage> {
+        let mut root = id;
+        // only ever walking to older messages, so no broken reply_to can send it round in circles
+        while let Some(parent) = self.messages.get(&root).and_then(|message| message.reply_to).filter(|parent| *parent < root) {
+            root = parent;
+        }
+        // replies always come after what they reply to, so one pass finds them all
+        let mut members = HashSet::from([root]);
+        self.messages
+            .range(root..)
+            .filter(|(id, message)| {
+                let member = **id == root || message.reply_to.is_some_and(|parent| members.contains(&parent));
+                if member {
+                    members.insert(**id);
+                }
+                member
+            })
+            .map(|(_, message)| message)
+            .filter(|message| !message.deleted)
+            .collect()
+    }
+
+    // a message its sender may still change
+    fn own_recent<'a>(
+        messages: &'a mut BTreeMap<u64, StoredMessage>,
+        id: u64,
+        by: &str,
+        now: DateTime<Utc>,
+        window: Duration,
+    ) -> Result<&'a mut StoredMessage, String> {
+        let message = messages
+            .get_mut(&id)
+            .filter(|message| !message.deleted)
+            .ok_or_else(|| format!("There is no message {}!", id))?;
+        if message.from != by {
+            return Err("You can only change your own messages!".to_string());
+        }
+        if now - message.sent_at > window {
+            return Err(format!(
+                "Messages can only be changed within {} minutes!",
+                window.num_minutes()
+            ));
+        }
+        Ok(message)
+    }
+
+    pub fn edit(
+        &mut self,
+        id: u64,
+        by: &str,
+        text: &str,
+        now: DateTime<Utc>,
+        window: Duration,
+    ) -> Result<&StoredMessage, String> {
+        if text.is_empty() {
+            return Err("The text must not be empty, delete the message instead!".to_string());
+        }
+        let message = Self::own_recent(&mut self.messages, id, by, now, window)?;
+        self.changed.insert(id);
+        self.index.remove(id, &message.text);
+        self.index.insert(id, text);
+        message.text = text.to_string();
+        message.edited = true;
+        Ok(message)
+    }
+
+    // the message stays known as deleted, so late edits and reactions fail properly
+    pub fn delete(
+        &mut self,
+        id: u64,
+        by: &str,
+        now: DateTime<Utc>,
+        window: Duration,
+    ) -> Result<&StoredMessage, String> {
+        Self::own_recent(&mut self.messages, id, by, now, window)?;
+        self.remove(id)
+    }
+
+    // deletes anyone's message at any time, as moderators do
+    pub fn remove(&mut self, id: u64) -> Result<&StoredMessage, String> {
+        let message = self
+            .messages
+            .get_mut(&id)
+            .filter(|message| !message.deleted)
+            .ok_or_else(|| format!("There is no message {}!", id))?;
+        self.changed.insert(id);
+        self.index.remove(id, &message.text);
+        message.text.clear();
+        message.reactions.clear();
+        message.deleted = true;
+        Ok(message)
+    }
+
+    // reacting twice with the same emoji takes the reaction back
+    pub fn react(&mut self, id: u64, by: &str, emoji: &str) -> Result<&StoredMessage, String> {
+        if !is_emoji(emoji) {
+            return Err(format!("{} is not an emoji!", emoji));
+        }
+        let message = self
+            .messages
+            .get_mut(&id)
+            .filter(|message| !message.deleted)
+            .ok_or_else(|| format!("There is no message {}!", id))?;
+        self.changed.insert(id);
+        let users = message.reactions.entry(emoji.to_string()).or_default();
+        match users.iter().position(|user| user == by) {
+            Some(position) => {
+                users.remove(position);
+                if users.is_empty() {
+                    message.reactions.remove(emoji);
+                }
+            }
+            None => users.push(by.to_string()),
+        }
+        Ok(message)
+    }
+
+    // the messages changed since the last call, deleted ones included
+    pub fn take_changed(&mut self) -> Vec<StoredMessage> {
+        let changed = std::mem::take(&mut self.changed);
+        changed.into_iter().filter_map(|id| self.messages.get(&id)).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Conversation, History};
+    use crate::group::Groups;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn test_edit_and_delete() {
+        let mut history = History::default();
+        let now = Utc::now();
+        let window = Duration::minutes(15);
+        let id = history.add(Conversation::direct("bob", "alice"), "alice", "helo", None, now).unwrap();
+        assert_eq!(history.get(id).unwrap().conversation, Conversation::direct("alice", "bob"));
+        assert_eq!(history.take_changed().len(), 1);
+
+        assert!(history.edit(id, "bob", "hello", now, window).is_err());
+        assert!(history.edit(id, "alice", "", now, window).is_err());
+        assert!(history.edit(id, "alice", "hello", now + Duration::minutes(16), window).is_err());
+        assert!(history.take_changed().is_empty());
+        let edited = history.edit(id, "alice", "hello", now + Duration::minutes(1), window).unwrap();
+        assert_eq!((edited.text.as_str(), edited.edited), ("hello", true));
+        assert_eq!(history.take_changed().len(), 1);
+        assert_eq!(history.matching("helo").count(), 0);
+        assert_eq!(history.matching("hello").count(), 1);
+
+        assert!(history.delete(id, "bob", now, window).is_err());
+        assert!(history.delete(id, "alice", now, window).unwrap().deleted);
+        assert_eq!(history.get(id), None);
+        assert_eq!(history.matching("hello").count(), 0);
+        assert!(history.edit(id, "alice", "again", now, window).is_err());
+        assert!(history.react(id, "bob", "👍").is_err());
+        assert!(history.delete(id + 1, "alice", now, window).is_err());
+    }
+
+    #[test]
+    fn test_reactions() {
+        let mut groups = Groups::default();
+        let group = groups.create("alice", &["bob".to_string(), "carol".to_string()]).unwrap();
+        let conversation = Conversation::Group(group.id);
+        assert_eq!(conversation.participants("bob", &groups), Some(group.members));
+        assert_eq!(conversation.participants("dave", &groups), None);
+        assert_eq!(Conversation::direct("alice", "bob").participants("carol", &groups), None);
+
+        let mut history = History::default();
+        let id = history.add(conversation, "alice", "lunch?", None, Utc::now()).unwrap();
+        assert!(history.react(id, "bob", "yes").is_err());
+        assert!(history.react(id, "bob", "").is_err());
+
+        history.react(id, "bob", "👍").unwrap();
+        history.react(id, "carol", "👍").unwrap();
+        let message = history.react(id, "carol", "🎉").unwrap();
+        let counts: Vec<(String, usize)> = message
+            .reaction_infos()
+            .into_iter()
+            .map(|info| (info.emoji, info.users.len()))
+            .collect();
+        assert_eq!(counts, vec![("🎉".to_string(), 1), ("👍".to_string(), 2)]);
+
+        let message = history.react(id, "carol", "🎉").unwrap();
+        let infos = message.reaction_infos();
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].users, vec!["bob".to_string(), "carol".to_string()]);
+    }
+
+    #[test]
+    fn test_threads() {
+        let mut history = History::default();
+        let now = Utc::now();
+        let direct = Conversation::direct("alice", "bob");
+        let root = history.add(direct.clone(), "alice", "Which day suits everyone for the retrospective?", None, now).unwrap();
+        let other = history.add(direct.clone(), "bob", "unrelated", None, now).unwrap();
+        let reply = history.add(direct.clone(), "bob", "friday", Some(root), now).unwrap();
+        let nested = history.add(direct.clone(), "alice", "ok", Some(reply), now).unwrap();
+
+        assert!(history.add(Conversation::direct("alice", "carol"), "alice", "hi", Some(root), now).is_err());
+        assert!(history.add(direct.clone(), "alice", "hi", Some(99), now).is_err());
+
+        let ids = |thread: Vec<&super::StoredMessage>| thread.iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(ids(history.thread(nested)), vec![root, reply, nested]);
+        assert_eq!(ids(history.thread(root)), vec![root, reply, nested]);
+        assert_eq!(ids(history.thread(other)), vec![other]);
+
+        // a message replying to itself is a thread of its own rather than an endless walk
+        let mut looped = history.get(other).unwrap().clone();
+        (looped.id, looped.reply_to) = (nested + 1, Some(nested + 1));
+        history.import(looped).unwrap();
+        assert_eq!(ids(history.thread(nested + 1)), vec![nested + 1]);
+
+        let quoted = history.get(root).unwrap().reply_info();
+        assert_eq!(quoted.excerpt, "Which day suits everyone for the retrosp…");
+        assert_eq!(history.get(reply).unwrap().info().reply_to, Some(root));
+    }
+}
@@ -1,21 +1,333 @@
+mod accounts;
+mod archive;
 mod channel_message;
+mod cluster;
+mod config;
+mod console;
+mod contacts;
+mod directory;
+mod federation;
+mod group;
+mod history;
+mod plugin;
+mod privacy;
+mod profiles;
+mod roles;
+mod router;
+mod search;
+mod store;
+mod text;
+mod webhook;
 
-use crate::channel_message::{MainToThreadsMessage, ThreadsToMainMessage};
-use common::communication::common_message::{ClientToServerMessage, ServerToClientMessage};
-use common::logic::input_parser::{parse_input, InputToken};
+use crate::accounts::Accounts;
+use crate::archive::{HistoryFile, Scope};
+use crate::channel_message::{MainToThreadsMessage, Recipient, ThreadsToMainMessage};
+use crate::cluster::{Backplane, Cluster, ClusterMessage, TcpBackplane};
+use crate::config::{Config, FederationConfig};
+use crate::console::console_println;
+use crate::contacts::Contacts;
+use crate::directory::Listing;
+use crate::federation::{Address, Federation, PeerMessage, FEDERATION_PATH};
+use crate::group::Groups;
+use crate::history::{Conversation, History, StoredMessage};
+use crate::plugin::{Flow, Injector, PluginContext, SessionInfo};
+use crate::privacy::Privacy;
+use crate::profiles::Profiles;
+use crate::roles::{Moderation, Role};
+use crate::router::{outbox, Identity, Inbox, Outbox, Router};
+use crate::text::Texts;
+use chrono::{DateTime, Utc};
+use common::communication::avatar::{avatar_frame, Image, MAX_AVATAR_BYTES};
+use common::communication::common_message::{
+    ClientToServerMessage, ContactInfo, ContactState, DirectoryQuery, ProfileInfo, ServerToClientMessage, UserInfo, GROUP_PREFIX,
+    MAX_USERNAME_BYTES,
+};
+use common::logic::line_editor::SharedUsernames;
 use futures_util::{SinkExt, StreamExt};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::io;
-use std::io::BufRead;
-use tokio::net::TcpListener;
-use tokio::sync::broadcast;
-use tokio::sync::broadcast::Sender;
-use tokio::sync::mpsc::Receiver;
-use tokio_tungstenite::accept_async;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::Sender;
+use tokio_tungstenite::accept_hdr_async_with_config;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
 use uuid::Uuid;
 
+// connection tasks, webhook requests and plugins all report to the main loop through one channel,
+// and wait for room in it when the main loop falls behind rather than have anything dropped
+const THREADS_TO_MAIN_CAPACITY: usize = 64;
+
+// the largest message a client or peer may send, rather than tungstenite's 64 MiB
+const MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+
+// how often the changes to the history are appended to the history file
+const HISTORY_SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+struct UserEssential {
+    uuid: Uuid,
+    // the same outbox the router delivers to
+    main_to_thread_tx: Outbox,
+    username: Option<String>,
+    bot: bool,
+    // logged in with a password, so the username may have further sessions
+    account: bool,
+    address: SocketAddr,
+    connected_at: DateTime<Utc>,
+    // when the current username was taken
+    named_at: DateTime<Utc>,
+}
+
+impl UserEssential {
+    fn new(uuid: Uuid, main_to_thread_tx: Outbox, address: SocketAddr, bot: bool) -> Self {
+        UserEssential {
+            uuid,
+            main_to_thread_tx,
+            username: None,
+            bot,
+            account: false,
+            address,
+            connected_at: Utc::now(),
+            named_at: Utc::now(),
+        }
+    }
+
+    // where the history the user may see starts; guest names are taken over by others,
+    // so a guest does not see what was said to an earlier holder of the name
+    fn history_since(&self, accounts: &Accounts) -> Option<DateTime<Utc>> {
+        match (&self.username, self.account) {
+            _ if self.bot => None,
+            (Some(username), true) => accounts.created_at(username),
+            _ => Some(self.named_at),
+        }
+    }
+
+    // what the connection task is told about its client, to route texts on its own
+    fn identity(&self, accounts: &Accounts, moderation: &Moderation) -> Option<Identity> {
+        let username = self.username.clone()?;
+        Some(Identity {
+            role: accounts.role(&username),
+            muted_until: moderation.muted_until(accounts, Some(&username), self.uuid, Utc::now()),
+            since: self.history_since(accounts),
+            bot: self.bot,
+            username,
+        })
+    }
+}
+
+// tells the connection tasks of the user what changed about them, like a role or a mute
+fn identify_user(
+    username: &str,
+    accounts: &Accounts,
+    moderation: &Moderation,
+    router: &Router,
+    uuid_to_user_essential_map: &HashMap<Uuid, UserEssential>,
+) {
+    for uuid in router.sessions(username) {
+        if let Some(user_essential) = uuid_to_user_essential_map.get(&uuid) {
+            router.identify(uuid, user_essential.identity(accounts, moderation));
+        }
+    }
+}
+
+// a name held by a guest or a bot cannot be logged in on in addition
+fn held_by_guest(username: &str, router: &Router, map: &HashMap<Uuid, UserEssential>) -> bool {
+    router.sessions(username)
+        .first()
+        .and_then(|uuid| map.get(uuid))
+        .is_some_and(|user_essential| !user_essential.account)
+}
+
+// the name of the user if it is an account, for what is kept by name for good
+fn account_name(user_essential: Option<&UserEssential>, what: &str) -> Result<String, String> {
+    match user_essential {
+        Some(UserEssential { username: Some(username), account: true, .. }) => Ok(username.clone()),
+        Some(UserEssential { username: Some(_), .. }) => Err(format!("Only accounts have {}, log in first!", what)),
+        _ => Err("You must set a username first!".to_string()),
+    }
+}
+
+// other nodes learn of a name only once its UserChanged arrives, so two of them may grant the same
+// guest name before either hears of the other; both then see the collision, and the node whose name
+// sorts last takes the name back from its guest. true if it did
+fn resolve_collision(
+    user: &str,
+    node: &str,
+    cluster: &Cluster,
+    router: &Router,
+    uuid_to_user_essential_map: &mut HashMap<Uuid, UserEssential>,
+    privacy: &mut Privacy,
+) -> bool {
+    if cluster.node().is_none_or(|own| own <= node) {
+        return false;
+    }
+    // accounts may have sessions on several nodes
+    let Some(uuid) = router.sessions(user).into_iter()
+        .find(|uuid| uuid_to_user_essential_map.get(uuid).is_some_and(|user_essential| !user_essential.account)) else {
+        return false;
+    };
+    let user_essential = uuid_to_user_essential_map.get_mut(&uuid)
+        .expect("Failed to find user essential");
+    router.forget_session(user, uuid);
+    router.identify(uuid, None);
+    privacy.forget_guest(user);
+    user_essential.username = None;
+    user_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(ServerToClientMessage::BotTextFrom(None,
+        "server".to_string(), format!("{} was taken on another server at the same time, pick another name", user), None)))
+        .unwrap_or_else(|e|
+            console_println!("Failed to send message to client: {}", e));
+    true
+}
+
+// closes every session of the user, false if the user is not online
+fn kick(
+    username: &str,
+    router: &Router,
+    uuid_to_user_essential_map: &mut HashMap<Uuid, UserEssential>,
+) -> bool {
+    let uuids = router.remove_user(username);
+    if uuids.is_empty() {
+        return false;
+    }
+    // the connection task does not report back after a shutdown, so forget it here
+    for uuid in uuids {
+        let user_essential = uuid_to_user_essential_map.remove(&uuid)
+            .expect("Failed to find user essential");
+        router.close(uuid);
+        user_essential.main_to_thread_tx.send(MainToThreadsMessage::Shutdown)
+            .unwrap_or_else(|e|
+                console_println!("Failed to send message to client: {}", e));
+    }
+    true
+}
+
+// refreshes the console's list of users, and tells the other nodes, the contacts and the peers of everyone who came online or went offline
+fn users_changed(
+    shared_usernames: &SharedUsernames,
+    contacts: &Contacts,
+    privacy: &Privacy,
+    federation: &Federation,
+    cluster: &Cluster,
+    router: &Router,
+) {
+    let online = router.usernames();
+    let previous = std::mem::replace(&mut *shared_usernames.lock().unwrap(), online.clone());
+    let previous: HashSet<String> = previous.into_iter().collect();
+    let came = online.iter().filter(|username| !previous.contains(*username)).map(|username| (username, true));
+    let left = previous.iter().filter(|username| !router.is_online(username)).map(|username| (username, false));
+    for (username, online) in came.chain(left) {
+        cluster.announce(ClusterMessage::UserChanged(username.clone(), online));
+        if !privacy.settings(username).hide_presence {
+            federation.broadcast(PeerMessage::PresenceChanged(username.clone(), online));
+        }
+        let watching = contacts.of(username)
+            .filter(|contact| router.is_online(contact))
+            .filter(|contact| privacy.shows(username, contact));
+        for contact in watching {
+            let info = ContactInfo {
+                username: username.clone(),
+                state: ContactState::Contact,
+                online,
+            };
+            send_to_recipient(Recipient::User(contact.clone()), ServerToClientMessage::ContactUpdated(info),
+                router, cluster);
+        }
+    }
+}
+
+// the users online which peers may know about
+fn public_presence(privacy: &Privacy, router: &Router) -> Vec<String> {
+    router.usernames()
+        .into_iter()
+        .filter(|username| !privacy.settings(username).hide_presence)
+        .collect()
+}
+
+// everyone online on any node and everyone with an account, as `viewer` may see them, and the users of peers
+#[allow(clippy::too_many_arguments)]
+fn listings(
+    viewer: &str,
+    accounts: &Accounts,
+    privacy: &Privacy,
+    profiles: &Profiles,
+    federation: &Federation,
+    cluster: &Cluster,
+    router: &Router,
+    uuid_to_user_essential_map: &HashMap<Uuid, UserEssential>,
+) -> Vec<Listing> {
+    let mut listings = Vec::new();
+    let local = router.usernames();
+    let remote = cluster.users();
+    let usernames: BTreeSet<&String> = accounts.usernames().chain(&local).chain(&remote).collect();
+    for username in usernames {
+        let sessions = router.sessions(username);
+        let online = (!sessions.is_empty() || cluster.is_online(username)) && privacy.shows(username, viewer);
+        // hidden guests are nowhere to be found, blocked users not even with an account
+        if privacy.blocks(viewer, username) || (!online && !accounts.exists(username)) {
+            continue;
+        }
+        listings.push(Listing {
+            username: username.clone(),
+            online,
+            bot: online && sessions.iter().any(|uuid|
+                uuid_to_user_essential_map.get(uuid).is_some_and(|u| u.bot)),
+            role: accounts.role(username),
+            profile: profiles.info(username),
+        });
+    }
+    for username in federation.online_users().filter(|username| !privacy.blocks(viewer, username)) {
+        listings.push(Listing {
+            profile: ProfileInfo {
+                username: username.clone(),
+                ..ProfileInfo::default()
+            },
+            username,
+            online: true,
+            bot: false,
+            role: Role::Member,
+        });
+    }
+    listings
+}
+
+// queued for the connections without waiting for them, so a slow client holds up nobody else
+fn send_to_recipient(recipient: Recipient, message: ServerToClientMessage, router: &Router, cluster: &Cluster) {
+    match recipient {
+        Recipient::Connection(uuid) => {
+            router.deliver(uuid, MainToThreadsMessage::SendToClient(message));
+        }
+        Recipient::User(username) => {
+            // sessions on other nodes get it through the backplane
+            let forwarded = cluster.forward(&username, &message);
+            if !router.deliver_to_user(&username, MainToThreadsMessage::SendToClient(message)) && !forwarded {
+                console_println!("Dropped message for {} who is not online", username);
+            }
+        }
+    }
+}
+
+// pending forever without a listener, so a disabled endpoint never wins the select
+async fn accept_optional(listener: &Option<TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
     let mut input = String::new();
     println!("Enter the address to bind to: ");
     io::stdin()
@@ -28,200 +340,1117 @@ async fn main() {
 
     println!("Listening on: {}", input);
 
-    println!("Please follow the instructions to interact with the server.");
+    if let Err(e) = serve(config, listener, true).await {
+        println!("{}", e);
+    }
+}
 
-    input.clear();
-
-    let (thread_to_main_tx, mut thread_to_main_rx) = broadcast::channel(1);
-
-    let thread_to_main_tx_clone = thread_to_main_tx.clone();
-    tokio::spawn(async move {
-        let stdin = io::stdin();
-        for line in stdin.lock().lines() {
-            if let Ok(line) = line {
-                let tokens = parse_input(&line);
-                match tokens {
-                    Ok(mut tokens) => match std::mem::take(&mut tokens[0]) {
-                        InputToken::General(str) if str == "close" => {
-                            thread_to_main_tx_clone
-                                .send(ThreadsToMainMessage::Shutdown)
-                                .expect("Failed to send shutdown signal");
-                            break;
-                        }
-                        _ => {
-                            println!("Invalid command: {:?}", tokens[0]);
-                        }
-                    },
-                    Err(e) => {
-                        println!("Error: {}", e);
-                    }
-                }
-            }
+// runs the server until it is shut down, with the console reading commands from stdin if asked for
+async fn serve(config: Config, listener: TcpListener, console: bool) -> Result<(), String> {
+    let (thread_to_main_tx, thread_to_main_rx) = mpsc::channel(THREADS_TO_MAIN_CAPACITY);
+    let router = Arc::new(Router::default());
+    let backplane: Option<Box<dyn Backplane>> = match &config.cluster {
+        Some(cluster) => {
+            let own = cluster.nodes.iter()
+                .find(|node| node.name == cluster.node)
+                .ok_or_else(|| format!("Node {} is not among the nodes of the cluster", cluster.node))?;
+            let node_listener = TcpListener::bind(&own.address).await
+                .map_err(|e| format!("Failed to bind {}: {}", own.address, e))?;
+            println!("Cluster listening on: {}", own.address);
+            Some(Box::new(TcpBackplane::start(cluster, node_listener, thread_to_main_tx.clone(), router.clone())))
+        }
+        None => None,
+    };
+    run(config, listener, console, (thread_to_main_tx, thread_to_main_rx), Cluster::new(backplane), router).await
+}
+
+// the main loop, which owns the state of this node
+async fn run(
+    config: Config,
+    listener: TcpListener,
+    console: bool,
+    (thread_to_main_tx, mut thread_to_main_rx): (Sender<ThreadsToMainMessage>, mpsc::Receiver<ThreadsToMainMessage>),
+    cluster: Cluster,
+    // shared with the tasks which deliver on their own, only the main loop changes who is online
+    router: Arc<Router>,
+) -> Result<(), String> {
+    let incoming_webhooks = Arc::new(config.webhooks.incoming.clone());
+    let federation_config = Arc::new(config.federation.clone());
+    let mut accounts = Accounts::load(config.accounts_file.as_deref().map(Path::new))?;
+    let mut privacy = Privacy::load(config.privacy_file.as_deref().map(Path::new))?;
+    privacy.retain_accounts(|user| accounts.exists(user));
+    let privacy = Arc::new(RwLock::new(privacy));
+    let mut contacts = Contacts::load(config.contacts_file.as_deref().map(Path::new))?;
+    contacts.retain_accounts(|user| accounts.exists(user));
+    let contacts = Arc::new(RwLock::new(contacts));
+    let mut profiles = Profiles::load(config.profiles_file.as_deref().map(Path::new),
+        config.avatar_dir.as_deref().map(Path::new))?;
+    profiles.retain_accounts(|user| accounts.exists(user));
+    let mut federation = Federation::new(&config.federation)?;
+
+    let webhook_listener = match &config.webhooks.http_bind {
+        Some(address) => {
+            let listener = TcpListener::bind(address).await.expect("Failed to bind webhook endpoint");
+            println!("Webhooks listening on: http://{}/hooks/<bot>", address);
+            Some(listener)
         }
-    });
+        None => None,
+    };
 
-    let mut username_to_uuid_map: std::collections::HashMap<String, Uuid> =
-        std::collections::HashMap::new();
+    println!("Please follow the instructions to interact with the server.");
 
-    struct UserEssential {
-        uuid: Uuid,
-        main_to_thread_tx: tokio::sync::mpsc::Sender<MainToThreadsMessage>,
-        username: Option<String>,
+    let injector = Injector::new(thread_to_main_tx.clone());
+    let mut plugins = plugin::builtin_plugins();
+    let plugin_names: Vec<&str> = plugins.iter().map(|p| p.name()).collect();
+    println!("Plugins: {}", plugin_names.join(", "));
+    if let Some(node) = cluster.node() {
+        println!("Running as node {} of a cluster", node);
     }
 
-    let mut uuid_to_user_essential_map: std::collections::HashMap<Uuid, UserEssential> =
-        std::collections::HashMap::new();
+    let shared_usernames: SharedUsernames = Arc::new(Mutex::new(Vec::new()));
+    if console {
+        console::spawn(thread_to_main_tx.clone(), shared_usernames.clone());
+    }
+
+    // peers with an address are dialed, the others dial this server
+    for peer in config.federation.peers.iter().filter(|peer| peer.url.is_some()) {
+        let link = Uuid::new_v4();
+        let (main_to_thread_tx, main_to_thread_rx) = outbox();
+        federation.add_link(link, main_to_thread_tx, true);
+        let name = config.federation.name.clone().expect("Federation without a name");
+        tokio::spawn(federation::dial(peer.clone(), name, link,
+            main_to_thread_rx, thread_to_main_tx.clone()));
+    }
+
+    let mut uuid_to_user_essential_map: HashMap<Uuid, UserEssential> = HashMap::new();
+
+    let history = match &config.history_file {
+        Some(path) => archive::load(Path::new(path))?,
+        None => History::default(),
+    };
+    let mut groups = Groups::load(config.groups_file.as_deref().map(Path::new))?;
+    groups.retain_accounts(|user| accounts.exists(user));
+    // a group id names the same conversation in the history for good
+    groups.continue_after(history.stored().filter_map(|stored| match &stored.conversation {
+        Conversation::Group(id) => Some(id.as_str()),
+        Conversation::Direct(..) => None,
+    }));
+    let groups = Arc::new(RwLock::new(groups));
+    let history = Arc::new(Mutex::new(history));
+    let history_file = config.history_file.as_ref().map(|path| HistoryFile::spawn(path.into()));
+    let mut save_history = tokio::time::interval(HISTORY_SAVE_INTERVAL);
+
+    let mut moderation = Moderation::default();
+
+    let cluster = Arc::new(cluster);
+    // what the connection tasks route texts with; the main loop takes the locks an arm at a time
+    let texts = Texts {
+        router: router.clone(),
+        cluster: cluster.clone(),
+        history: history.clone(),
+        privacy: privacy.clone(),
+        contacts: contacts.clone(),
+        groups: groups.clone(),
+        hooks: Arc::new(config.webhooks.outgoing.clone()),
+    };
 
     loop {
         tokio::select! {
-            Ok((stream, _)) = listener.accept() => {
+            Ok((stream, address)) = listener.accept() => {
                 let connection_id = Uuid::new_v4();
-                let (main_to_thread_tx, main_to_thread_rx) = tokio::sync::mpsc::channel(1);
-                uuid_to_user_essential_map.insert(connection_id, UserEssential {
-                    uuid : connection_id,
-                    main_to_thread_tx,
-                    username : None,
-                });
+                let (main_to_thread_tx, main_to_thread_rx) = outbox();
+                router.open(connection_id, main_to_thread_tx.clone());
+                uuid_to_user_essential_map.insert(connection_id,
+                    UserEssential::new(connection_id, main_to_thread_tx, address, false));
                 tokio::spawn(handle_connection(stream, connection_id,
-                    main_to_thread_rx, thread_to_main_tx.clone()));
+                    main_to_thread_rx, thread_to_main_tx.clone(), federation_config.clone(), texts.clone()));
+            },
+
+            Ok((stream, address)) = accept_optional(&webhook_listener) => {
+                let connection_id = Uuid::new_v4();
+                let (main_to_thread_tx, main_to_thread_rx) = outbox();
+                router.open(connection_id, main_to_thread_tx.clone());
+                uuid_to_user_essential_map.insert(connection_id,
+                    UserEssential::new(connection_id, main_to_thread_tx, address, true));
+                tokio::spawn(webhook::handle_request(stream, connection_id,
+                    main_to_thread_rx, thread_to_main_tx.clone(), incoming_webhooks.clone()));
+            },
+
+            _ = save_history.tick() => {
+                if let Some(file) = &history_file {
+                    file.save(&mut history.lock().unwrap());
+                }
             },
 
             message = thread_to_main_rx.recv() => {
+                // the connection task routes texts itself again once the main loop is done with this
+                let _handling = match &message {
+                    Some(ThreadsToMainMessage::ReceivedFromClient(_, uuid)) => Some(router.handling(*uuid)),
+                    _ => None,
+                };
+                // a webhook request acts as a short lived client named after its bot,
+                // bot names are reserved so it never collides with a connected user
+                let message = match message {
+                    Some(ThreadsToMainMessage::ReceivedFromWebhook(bot, message, uuid)) => {
+                        if let Some(user_essential) = uuid_to_user_essential_map.get_mut(&uuid) {
+                            user_essential.username = Some(bot);
+                        }
+                        Some(ThreadsToMainMessage::ReceivedFromClient(message, uuid))
+                    }
+                    other => other,
+                };
                 match message {
-                    Ok(ThreadsToMainMessage::Shutdown) => {
-                        for (uuid, user_essential) in uuid_to_user_essential_map.iter() {
+                    Some(ThreadsToMainMessage::Shutdown) => {
+                        for user_essential in uuid_to_user_essential_map.values() {
                             user_essential.main_to_thread_tx.send(MainToThreadsMessage::Shutdown)
-                                .await.expect("Failed to send shutdown signal");
+                                .unwrap_or_else(|e|
+                                    console_println!("Failed to send message to client: {}", e));
+                        }
+                        federation.shutdown();
+                        console_println!("Shutting down server");
+                        if let Some(file) = history_file {
+                            file.save(&mut history.lock().unwrap());
+                            file.close().await;
                         }
-                        println!("Shutting down server");
                         break;
                     }
-                    Ok(ThreadsToMainMessage::ReceivedFromClient(message, requester_uuid)) => {
+                    Some(ThreadsToMainMessage::ReceivedFromClient(message, requester_uuid)) => {
 
-                        println!("Received message from {}: {:?}", requester_uuid, message);
+                        console_println!("Received message from {}: {:?}", requester_uuid, message);
+
+                        let sender = uuid_to_user_essential_map.get(&requester_uuid)
+                            .and_then(|user_essential| user_essential.username.clone());
+                        let sessions: Vec<SessionInfo> = sender.as_ref()
+                            .map(|username| router.sessions(username))
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|uuid| uuid_to_user_essential_map.get(&uuid).map(|user_essential| SessionInfo {
+                                id: uuid,
+                                address: user_essential.address,
+                                connected_at: user_essential.connected_at,
+                            }))
+                            .collect();
+                        // the plugins check roles and mutes for their commands themselves
+                        let role = moderation.role(&accounts, sender.as_deref(), requester_uuid, Utc::now());
+                        let mut context = PluginContext::new(requester_uuid, sender.as_deref(), role, &sessions, &injector);
+                        let flow = plugins.iter_mut()
+                            .map(|plugin| plugin.on_message(&mut context, &message))
+                            .find(|flow| *flow == Flow::Handled)
+                            .unwrap_or(Flow::Continue);
+                        for (recipient, message) in context.into_outbox() {
+                            send_to_recipient(recipient, message,
+                                &router, &cluster);
+                        }
+                        if flow == Flow::Handled {
+                            continue;
+                        }
+                        if let Err(e) = moderation.check(&accounts, sender.as_deref(), requester_uuid, &message, Utc::now()) {
+                            send_to_recipient(Recipient::Connection(requester_uuid), ServerToClientMessage::Response(Err(e)),
+                                &router, &cluster);
+                            continue;
+                        }
 
                         match message {
 
                             ClientToServerMessage::SetUsername(username) => {
+                                let mut privacy = privacy.write().unwrap();
+                                let contacts = contacts.read().unwrap();
                                 let requester_essential : &mut UserEssential =
                                     uuid_to_user_essential_map.get_mut(&requester_uuid)
                                     .expect("Failed to find user essential");
 
-                                if username_to_uuid_map.contains_key(&username) {
+                                // a name taken on another node at the same moment is not known here yet, resolve_collision sorts that out
+                                if router.is_online(&username) || cluster.is_online(&username) || config.is_reserved(&username) {
                                     requester_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
                                     ServerToClientMessage::Response(Err("Username already exists!".to_string()))))
-                                    .await
                                     .unwrap_or_else(|e|
-                                        println!("Failed to send message to client: {}", e));
+                                        console_println!("Failed to send message to client: {}", e));
+
+                                } else if username.starts_with(GROUP_PREFIX) {
+                                    requester_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
+                                    ServerToClientMessage::Response(Err(format!("Usernames must not start with {}!", GROUP_PREFIX)))))
+                                    .unwrap_or_else(|e|
+                                        console_println!("Failed to send message to client: {}", e));
+
+                                } else if username.contains('@') {
+                                    requester_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
+                                    ServerToClientMessage::Response(Err("Usernames must not contain @!".to_string()))))
+                                    .unwrap_or_else(|e|
+                                        console_println!("Failed to send message to client: {}", e));
+
+                                } else if username.len() > MAX_USERNAME_BYTES {
+                                    requester_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
+                                    ServerToClientMessage::Response(Err(format!("Usernames may have at most {} bytes!", MAX_USERNAME_BYTES)))))
+                                    .unwrap_or_else(|e|
+                                        console_println!("Failed to send message to client: {}", e));
+
+                                } else if accounts.exists(&username) {
+                                    requester_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
+                                    ServerToClientMessage::Response(Err("Username belongs to an account, log in with its password!".to_string()))))
+                                    .unwrap_or_else(|e|
+                                        console_println!("Failed to send message to client: {}", e));
 
                                 } else {
-                                    if requester_essential.username.is_some() {
-                                        username_to_uuid_map.remove(&requester_essential.username.clone().unwrap());
+                                    if let Some(old_username) = &requester_essential.username {
+                                        router.forget_session(old_username, requester_uuid);
+                                        privacy.forget_guest(old_username);
                                     }
 
-                                    username_to_uuid_map.insert(username.clone(), requester_uuid);
+                                    router.add_session(&username, requester_uuid);
 
-                                    (*requester_essential).username = Some(username.clone());
+                                    requester_essential.username = Some(username.clone());
+                                    requester_essential.account = false;
+                                    requester_essential.named_at = Utc::now();
+                                    router.identify(requester_uuid, requester_essential.identity(&accounts, &moderation));
 
                                     requester_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
                                     ServerToClientMessage::Response(Ok(format!("Set username {} successfully!",
                                         username)))))
-                                    .await
                                     .unwrap_or_else(|e|
-                                        println!("Failed to send message to client: {}", e));
+                                        console_println!("Failed to send message to client: {}", e));
+                                    users_changed(&shared_usernames, &contacts, &privacy, &federation, &cluster, &router);
                                 }
                             }
 
-                            ClientToServerMessage::GetUsernames => {
-                                let mut usernames = Vec::new();
+                            ClientToServerMessage::AuthenticateBot(name, api_key) => {
+                                let known = config.bots.iter()
+                                    .any(|bot| bot.name == name && webhook::same_token(&bot.api_key, &api_key));
+                                let result = if !known {
+                                    Err("Unknown bot or wrong API key!".to_string())
+                                } else if router.is_online(&name) || cluster.is_online(&name) {
+                                    Err("Bot is already connected!".to_string())
+                                } else {
+                                    Ok(format!("Logged in as bot {}", name))
+                                };
+
+                                let mut privacy = privacy.write().unwrap();
+                                let contacts = contacts.read().unwrap();
+                                let requester_essential = uuid_to_user_essential_map.get_mut(&requester_uuid)
+                                    .expect("Failed to find user essential");
+                                if result.is_ok() {
+                                    if let Some(old_username) = requester_essential.username.replace(name.clone()) {
+                                        router.forget_session(&old_username, requester_uuid);
+                                        privacy.forget_guest(&old_username);
+                                    }
+                                    requester_essential.bot = true;
+                                    requester_essential.account = false;
+                                    requester_essential.named_at = Utc::now();
+                                    router.add_session(&name, requester_uuid);
+                                    router.identify(requester_uuid, requester_essential.identity(&accounts, &moderation));
+                                }
+                                let signed_in = result.is_ok();
+                                requester_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
+                                    ServerToClientMessage::Response(result)))
+                                    .unwrap_or_else(|e|
+                                        console_println!("Failed to send message to client: {}", e));
+                                if signed_in {
+                                    users_changed(&shared_usernames, &contacts, &privacy, &federation, &cluster, &router);
+                                }
+                            }
 
-                                for (username, _) in username_to_uuid_map.iter() {
-                                    usernames.push(username.clone());
+                            ClientToServerMessage::Login(username, password) => {
+                                let checked = if held_by_guest(&username, &router, &uuid_to_user_essential_map)
+                                    || config.is_reserved(&username) {
+                                    Err("Username already exists!".to_string())
+                                } else if username.starts_with(GROUP_PREFIX) {
+                                    Err(format!("Usernames must not start with {}!", GROUP_PREFIX))
+                                } else if username.contains('@') {
+                                    Err("Usernames must not contain @!".to_string())
+                                } else if username.len() > MAX_USERNAME_BYTES {
+                                    Err(format!("Usernames may have at most {} bytes!", MAX_USERNAME_BYTES))
+                                } else {
+                                    accounts.check(&username, &password)
+                                };
+                                match checked {
+                                    // the login is finished once the password is hashed, which takes a while
+                                    Ok(check) => {
+                                        let thread_to_main_tx = thread_to_main_tx.clone();
+                                        tokio::spawn(async move {
+                                            let verified = tokio::task::spawn_blocking(move || check.verify()).await
+                                                .unwrap_or_else(|e| Err(format!("Failed to check the password: {}", e)));
+                                            let _ = thread_to_main_tx
+                                                .send(ThreadsToMainMessage::LoggedIn(requester_uuid, username, verified)).await;
+                                        });
+                                    }
+                                    Err(e) => send_to_recipient(Recipient::Connection(requester_uuid),
+                                        ServerToClientMessage::Response(Err(e)), &router, &cluster),
                                 }
+                            }
 
-                                let user_essential = uuid_to_user_essential_map.get(&requester_uuid)
-                                    .expect("Failed to find user essential");
+                            ClientToServerMessage::CreateGroup(_)
+                            | ClientToServerMessage::AddToGroup(..)
+                            | ClientToServerMessage::RemoveFromGroup(..) => {
+                                // groups are kept by name, which only accounts hold for good
+                                let sender = account_name(uuid_to_user_essential_map.get(&requester_uuid), "groups");
+                                let mut groups = groups.write().unwrap();
+                                let joining = |username: &String| match accounts.exists(username) {
+                                    false => Err(format!("{} has no account!", username)),
+                                    true if !router.is_online(username) && !cluster.is_online(username) => {
+                                        Err(format!("{} is not online!", username))
+                                    }
+                                    true => Ok(()),
+                                };
+                                // members which are removed learn about it as well
+                                let mut removed = None;
+                                let result = match (sender, message) {
+                                    (Err(e), _) => Err(e),
+                                    (Ok(sender), ClientToServerMessage::CreateGroup(members)) => {
+                                        members.iter().try_for_each(joining).and_then(|_| groups.create(&sender, &members)).map(|info| {
+                                            let text = format!("Created group {} with {}", info.id, info.members.join(", "));
+                                            (info, text)
+                                        })
+                                    }
+                                    (Ok(sender), ClientToServerMessage::AddToGroup(id, username)) => {
+                                        joining(&username)
+                                            .and_then(|_| groups.add(&id, &sender, &username))
+                                            .map(|info| (info, format!("Added {} to {}", username, id)))
+                                    }
+                                    (Ok(sender), ClientToServerMessage::RemoveFromGroup(id, username)) => {
+                                        groups.remove(&id, &sender, &username).map(|info| {
+                                            removed = Some(username.clone());
+                                            (info, format!("Removed {} from {}", username, id))
+                                        })
+                                    }
+                                    _ => unreachable!("only group messages get here"),
+                                };
 
-                                user_essential.main_to_thread_tx
-                                    .send(MainToThreadsMessage::SendToClient(ServerToClientMessage::Usernames(usernames)))
-                                    .await
-                                    .expect("Failed to send message to client");
+                                let response = match result {
+                                    Ok((info, text)) => {
+                                        for member in info.members.iter().chain(removed.iter()) {
+                                            send_to_recipient(Recipient::User(member.clone()),
+                                                ServerToClientMessage::GroupUpdated(info.clone()),
+                                                &router, &cluster);
+                                        }
+                                        Ok(text)
+                                    }
+                                    Err(e) => Err(e),
+                                };
+                                send_to_recipient(Recipient::Connection(requester_uuid),
+                                    ServerToClientMessage::Response(response),
+                                    &router, &cluster);
                             }
 
-                            ClientToServerMessage::TextTo(username, text) => {
+                            // the main loop only gets those sent while earlier messages of the client were queued for it
+                            ClientToServerMessage::GroupTextTo(id, text, reply_to) => {
+                                let identity = uuid_to_user_essential_map.get(&requester_uuid)
+                                    .and_then(|user_essential| user_essential.identity(&accounts, &moderation));
+                                let response = match identity {
+                                    None => Err("You must set a username first!".to_string()),
+                                    Some(identity) => texts.send_to_group(&identity, &id, &text, reply_to),
+                                };
+                                send_to_recipient(Recipient::Connection(requester_uuid),
+                                    ServerToClientMessage::Response(response),
+                                    &router, &cluster);
+                            }
 
-                                let user_essential = uuid_to_user_essential_map
-                                    .get(&requester_uuid)
-                                    .expect("Failed to find user essential");
+                            ClientToServerMessage::EditMessage(..)
+                            | ClientToServerMessage::DeleteMessage(_)
+                            | ClientToServerMessage::React(..) => {
+                                let user_essential = uuid_to_user_essential_map.get(&requester_uuid);
+                                let sender = user_essential.and_then(|user_essential| user_essential.username.clone());
+                                let since = user_essential.and_then(|user_essential| user_essential.history_since(&accounts));
+                                let window = config.edit_window();
+                                let mut history = history.lock().unwrap();
+                                let groups = groups.read().unwrap();
+                                let result = match sender {
+                                    None => Err("You must set a username first!".to_string()),
+                                    Some(sender) => {
+                                        let id = match &message {
+                                            ClientToServerMessage::EditMessage(id, _)
+                                            | ClientToServerMessage::DeleteMessage(id)
+                                            | ClientToServerMessage::React(id, _) => *id,
+                                            _ => unreachable!("only message changes get here"),
+                                        };
+                                        // moderators delete the messages of those they outrank, wherever they were sent
+                                        let moderating = matches!(message, ClientToServerMessage::DeleteMessage(_))
+                                            && history.get(id).is_some_and(|stored| stored.from != sender
+                                                && moderation.outranks(&accounts, &sender, requester_uuid, &stored.from, Utc::now()).is_ok());
+                                        // messages of conversations the user is not part of do not exist for them
+                                        let participants = history.get(id)
+                                            .and_then(|stored| match moderating {
+                                                true => Some(stored.conversation.members(&groups)),
+                                                false if stored.sent_since(since) => stored.conversation.participants(&sender, &groups),
+                                                false => None,
+                                            })
+                                            .ok_or_else(|| format!("There is no message {}!", id));
+                                        participants.and_then(|participants| {
+                                            let (update, text) = match message {
+                                                ClientToServerMessage::EditMessage(_, text) => history
+                                                    .edit(id, &sender, &text, Utc::now(), window)
+                                                    .map(|stored| ServerToClientMessage::MessageEdited(id, stored.text.clone()))
+                                                    .map(|update| (update, format!("Edited message {}", id))),
+                                                ClientToServerMessage::DeleteMessage(_) if moderating => history
+                                                    .remove(id)
+                                                    .map(|_| (ServerToClientMessage::MessageDeleted(id), format!("Deleted message {} as a moderator", id))),
+                                                ClientToServerMessage::DeleteMessage(_) => history
+                                                    .delete(id, &sender, Utc::now(), window)
+                                                    .map(|_| (ServerToClientMessage::MessageDeleted(id), format!("Deleted message {}", id))),
+                                                ClientToServerMessage::React(_, emoji) => history
+                                                    .react(id, &sender, &emoji)
+                                                    .map(|stored| ServerToClientMessage::ReactionsUpdated(id, stored.reaction_infos()))
+                                                    .map(|update| (update, format!("Reacted to message {}", id))),
+                                                _ => unreachable!("only message changes get here"),
+                                            }?;
+                                            Ok((participants, update, text))
+                                        })
+                                    }
+                                };
 
-                                if user_essential.username.is_none() {
-                                    user_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
-                                    ServerToClientMessage::Response(Err("You must set a username first!".to_string()))))
-                                    .await
-                                    .unwrap_or_else(|e|
-                                        println!("Failed to send message to client: {}", e));
+                                let response = match result {
+                                    Ok((participants, update, text)) => {
+                                        for participant in participants {
+                                            if router.is_online(&participant) {
+                                                send_to_recipient(Recipient::User(participant), update.clone(),
+                                                    &router, &cluster);
+                                            }
+                                        }
+                                        Ok(text)
+                                    }
+                                    Err(e) => Err(e),
+                                };
+                                send_to_recipient(Recipient::Connection(requester_uuid),
+                                    ServerToClientMessage::Response(response),
+                                    &router, &cluster);
+                            }
+
+                            ClientToServerMessage::Kick(_)
+                            | ClientToServerMessage::Mute(..)
+                            | ClientToServerMessage::Unmute(_) => {
+                                // the permission check lets nobody without a name get here
+                                let Some(by) = sender else {
                                     continue;
+                                };
+                                let now = Utc::now();
+                                let result = match &message {
+                                    ClientToServerMessage::Kick(user) => moderation.outranks(&accounts, &by, requester_uuid, user, now)
+                                        .and_then(|_| match router.is_online(user) {
+                                            true => Ok((user, format!("You were kicked by {}", by), format!("Kicked {}", user))),
+                                            false => Err(format!("{} is not online!", user)),
+                                        }),
+                                    ClientToServerMessage::Mute(user, minutes) => moderation
+                                        .mute(&accounts, &by, requester_uuid, user, &router.sessions(user), *minutes, now)
+                                        .map(|until| until.format("%Y-%m-%d %H:%M UTC"))
+                                        .map(|until| (user, format!("You were muted by {} until {}", by, until),
+                                            format!("Muted {} until {}", user, until))),
+                                    ClientToServerMessage::Unmute(user) => moderation
+                                        .unmute(&accounts, &by, requester_uuid, user, &router.sessions(user), now)
+                                        .map(|_| (user, format!("You were unmuted by {}", by), format!("Unmuted {}", user))),
+                                    _ => unreachable!("only moderator actions get here"),
+                                };
+
+                                let response = match result {
+                                    Ok((user, notice, text)) => {
+                                        identify_user(user, &accounts, &moderation, &router, &uuid_to_user_essential_map);
+                                        if router.is_online(user) {
+                                            send_to_recipient(Recipient::User(user.clone()),
+                                                ServerToClientMessage::BotTextFrom(None, "moderation".to_string(), notice, None),
+                                                &router, &cluster);
+                                        }
+                                        if matches!(message, ClientToServerMessage::Kick(_)) {
+                                            kick(user, &router, &mut uuid_to_user_essential_map);
+                                            let mut privacy = privacy.write().unwrap();
+                                            privacy.forget_guest(user);
+                                            users_changed(&shared_usernames, &contacts.read().unwrap(), &privacy, &federation, &cluster, &router);
+                                        }
+                                        console_println!("{}, asked for by {}", text, by);
+                                        Ok(text)
+                                    }
+                                    Err(e) => Err(e),
+                                };
+                                send_to_recipient(Recipient::Connection(requester_uuid),
+                                    ServerToClientMessage::Response(response),
+                                    &router, &cluster);
+                            }
+
+                            ClientToServerMessage::GetThread(id) => {
+                                let user_essential = uuid_to_user_essential_map.get(&requester_uuid);
+                                let sender = user_essential.and_then(|user_essential| user_essential.username.clone());
+                                let since = user_essential.and_then(|user_essential| user_essential.history_since(&accounts));
+                                let history = history.lock().unwrap();
+                                let groups = groups.read().unwrap();
+                                let visible = sender.is_some_and(|sender| history.get(id)
+                                    .filter(|stored| stored.sent_since(since))
+                                    .and_then(|stored| stored.conversation.participants(&sender, &groups))
+                                    .is_some());
+                                let message = if visible {
+                                    ServerToClientMessage::Thread(history.thread(id).into_iter()
+                                        .filter(|stored| stored.sent_since(since))
+                                        .map(StoredMessage::info)
+                                        .collect())
+                                } else {
+                                    ServerToClientMessage::Response(Err(format!("There is no message {}!", id)))
+                                };
+                                send_to_recipient(Recipient::Connection(requester_uuid), message,
+                                    &router, &cluster);
+                            }
+
+                            ClientToServerMessage::Search(query) => {
+                                let user_essential = uuid_to_user_essential_map.get(&requester_uuid);
+                                let sender = user_essential.and_then(|user_essential| user_essential.username.clone());
+                                let since = user_essential.and_then(|user_essential| user_essential.history_since(&accounts));
+                                let results = match sender {
+                                    None => Err("You must set a username first!".to_string()),
+                                    Some(sender) => search::search(&history.lock().unwrap(), &groups.read().unwrap(), &sender, since, &query),
+                                };
+                                let message = match results {
+                                    Ok(results) => ServerToClientMessage::SearchResults(results),
+                                    Err(e) => ServerToClientMessage::Response(Err(e)),
+                                };
+                                send_to_recipient(Recipient::Connection(requester_uuid), message,
+                                    &router, &cluster);
+                            }
+
+                            ClientToServerMessage::Export { conversation, format } => {
+                                let user_essential = uuid_to_user_essential_map.get(&requester_uuid);
+                                let sender = user_essential.and_then(|user_essential| user_essential.username.clone());
+                                let since = user_essential.and_then(|user_essential| user_essential.history_since(&accounts));
+                                let scope = match (sender, conversation) {
+                                    (None, _) => Err("You must set a username first!".to_string()),
+                                    (Some(sender), Some(name)) => {
+                                        let conversation = Conversation::named(&sender, &name);
+                                        match conversation.participants(&sender, &groups.read().unwrap()) {
+                                            Some(_) => Ok(Scope::Conversation(conversation)),
+                                            None => Err(format!("You are not in a group {}!", name)),
+                                        }
+                                    }
+                                    (Some(sender), None) => Ok(Scope::User(sender)),
+                                };
+                                match scope {
+                                    Ok(scope) => {
+                                        // rendered off the main loop, and sent whenever it is done
+                                        let (history, groups, router) = (history.clone(), groups.clone(), router.clone());
+                                        tokio::spawn(async move {
+                                            let message = match archive::export(history, groups, scope, since, format).await {
+                                                Ok((messages, content)) => ServerToClientMessage::Exported { content, messages },
+                                                Err(e) => ServerToClientMessage::Response(Err(e)),
+                                            };
+                                            router.deliver(requester_uuid, MainToThreadsMessage::SendToClient(message));
+                                        });
+                                    }
+                                    Err(e) => send_to_recipient(Recipient::Connection(requester_uuid),
+                                        ServerToClientMessage::Response(Err(e)), &router, &cluster),
+                                }
+                            }
+
+                            ClientToServerMessage::Block(_)
+                            | ClientToServerMessage::Unblock(_)
+                            | ClientToServerMessage::SetPrivacy { .. } => {
+                                let user_essential = uuid_to_user_essential_map.get(&requester_uuid);
+                                let sender = user_essential.and_then(|user_essential| user_essential.username.clone());
+                                // only accounts keep their settings past the session
+                                let account = user_essential.is_some_and(|user_essential| user_essential.account);
+                                let mut privacy = privacy.write().unwrap();
+                                let message = match (sender, message) {
+                                    (None, _) => ServerToClientMessage::Response(Err("You must set a username first!".to_string())),
+                                    (Some(sender), ClientToServerMessage::Block(user)) => ServerToClientMessage::Response(
+                                        privacy.block(&sender, account, &user).map(|_| format!("Blocked {}", user))),
+                                    (Some(sender), ClientToServerMessage::Unblock(user)) => ServerToClientMessage::Response(
+                                        privacy.unblock(&sender, account, &user).map(|_| format!("Unblocked {}", user))),
+                                    (Some(sender), ClientToServerMessage::SetPrivacy { contacts_only, hide_presence }) => {
+                                        match privacy.update(&sender, account, contacts_only, hide_presence) {
+                                            Ok(settings) => ServerToClientMessage::Privacy(settings),
+                                            Err(e) => ServerToClientMessage::Response(Err(e)),
+                                        }
+                                    }
+                                    _ => unreachable!("only privacy changes get here"),
+                                };
+                                send_to_recipient(Recipient::Connection(requester_uuid), message,
+                                    &router, &cluster);
+                            }
+
+                            ClientToServerMessage::AddContact(_)
+                            | ClientToServerMessage::AnswerContact(..)
+                            | ClientToServerMessage::RemoveContact(_)
+                            | ClientToServerMessage::GetContacts => {
+                                let user = match account_name(uuid_to_user_essential_map.get(&requester_uuid), "contacts") {
+                                    Ok(user) => user,
+                                    Err(e) => {
+                                        send_to_recipient(Recipient::Connection(requester_uuid),
+                                            ServerToClientMessage::Response(Err(e)),
+                                            &router, &cluster);
+                                        continue;
+                                    }
+                                };
+                                let privacy = privacy.read().unwrap();
+                                let mut contacts = contacts.write().unwrap();
+                                let online = |name: &str, viewer: &str|
+                                    router.is_online(name) && privacy.shows(name, viewer);
+                                let contact = |name: &str, state: ContactState, viewer: &str| ContactInfo {
+                                    username: name.to_string(),
+                                    state,
+                                    online: online(name, viewer),
+                                };
+                                // messages for the other side of the change
+                                let mut notices = Vec::new();
+                                let response = match message {
+                                    ClientToServerMessage::AddContact(other) => {
+                                        let known = accounts.exists(&other);
+                                        // to those who block them, users look like they do not exist
+                                        if !known || privacy.blocks(&other, &user) {
+                                            ServerToClientMessage::Response(Err("Recipient does not exist!".to_string()))
+                                        } else {
+                                            match contacts.request(&user, &other) {
+                                                Ok(true) => {
+                                                    notices.push((other.clone(), ServerToClientMessage::ContactUpdated(
+                                                        contact(&user, ContactState::Contact, &other))));
+                                                    notices.push((user.clone(), ServerToClientMessage::ContactUpdated(
+                                                        contact(&other, ContactState::Contact, &user))));
+                                                    ServerToClientMessage::Response(Ok(format!("{} is now a contact", other)))
+                                                }
+                                                Ok(false) => {
+                                                    notices.push((other.clone(), ServerToClientMessage::ContactUpdated(
+                                                        contact(&user, ContactState::Received, &other))));
+                                                    ServerToClientMessage::Response(Ok(format!("Sent a contact request to {}", other)))
+                                                }
+                                                Err(e) => ServerToClientMessage::Response(Err(e)),
+                                            }
+                                        }
+                                    }
+                                    ClientToServerMessage::AnswerContact(other, accept) => {
+                                        match contacts.answer(&user, &other, accept) {
+                                            Ok(()) if accept => {
+                                                notices.push((other.clone(), ServerToClientMessage::ContactUpdated(
+                                                    contact(&user, ContactState::Contact, &other))));
+                                                notices.push((user.clone(), ServerToClientMessage::ContactUpdated(
+                                                    contact(&other, ContactState::Contact, &user))));
+                                                ServerToClientMessage::Response(Ok(format!("Accepted {}", other)))
+                                            }
+                                            Ok(()) => {
+                                                notices.push((other.clone(), ServerToClientMessage::ContactRemoved(user.clone())));
+                                                ServerToClientMessage::Response(Ok(format!("Declined {}", other)))
+                                            }
+                                            Err(e) => ServerToClientMessage::Response(Err(e)),
+                                        }
+                                    }
+                                    ClientToServerMessage::RemoveContact(other) => match contacts.remove(&user, &other) {
+                                        Ok(()) => {
+                                            notices.push((other.clone(), ServerToClientMessage::ContactRemoved(user.clone())));
+                                            ServerToClientMessage::Response(Ok(format!("Removed {}", other)))
+                                        }
+                                        Err(e) => ServerToClientMessage::Response(Err(e)),
+                                    },
+                                    ClientToServerMessage::GetContacts => ServerToClientMessage::Contacts(
+                                        contacts.list(&user).into_iter()
+                                            .map(|(name, state)| contact(&name, state, &user))
+                                            .collect()),
+                                    _ => unreachable!("only contact changes get here"),
+                                };
+                                for (recipient, notice) in notices {
+                                    if router.is_online(&recipient) {
+                                        send_to_recipient(Recipient::User(recipient), notice,
+                                            &router, &cluster);
+                                    }
                                 }
+                                send_to_recipient(Recipient::Connection(requester_uuid), response,
+                                    &router, &cluster);
+                            }
 
-                                let recipient_uuid = username_to_uuid_map.get(&username);
+                            ClientToServerMessage::GetProfile(username) => {
+                                let viewer = uuid_to_user_essential_map.get(&requester_uuid)
+                                    .and_then(|user_essential| user_essential.username.clone())
+                                    .unwrap_or_default();
+                                // unknown users and those blocking the viewer have an empty profile alike
+                                let profile = match privacy.read().unwrap().blocks(&username, &viewer) {
+                                    true => ProfileInfo { username, ..ProfileInfo::default() },
+                                    false => profiles.info(&username),
+                                };
+                                send_to_recipient(Recipient::Connection(requester_uuid), ServerToClientMessage::Profile(profile),
+                                    &router, &cluster);
+                            }
 
-                                if recipient_uuid.is_none() {
-                                    user_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
-                                    ServerToClientMessage::Response(Err("Recipient does not exist!".to_string()))))
-                                    .await
-                                    .unwrap_or_else(|e|
-                                        println!("Failed to send message to client: {}", e));
-                                    continue;
+                            ClientToServerMessage::GetAvatar(username) => {
+                                let viewer = uuid_to_user_essential_map.get(&requester_uuid)
+                                    .and_then(|user_essential| user_essential.username.clone())
+                                    .unwrap_or_default();
+                                let avatar = match privacy.read().unwrap().blocks(&username, &viewer) {
+                                    true => Err(format!("{} has no avatar!", username)),
+                                    false => profiles.avatar(&username),
+                                };
+                                let message = match avatar.and_then(|image| avatar_frame(&username, &image)) {
+                                    Ok(frame) => MainToThreadsMessage::SendBinary(frame),
+                                    Err(e) => MainToThreadsMessage::SendToClient(ServerToClientMessage::Response(Err(e))),
+                                };
+                                if let Some(user_essential) = uuid_to_user_essential_map.get(&requester_uuid) {
+                                    user_essential.main_to_thread_tx.send(message)
+                                        .unwrap_or_else(|e| console_println!("Failed to send message to client: {}", e));
+                                }
+                            }
+
+                            ClientToServerMessage::SetProfile { .. }
+                            | ClientToServerMessage::SetAvatar(_)
+                            | ClientToServerMessage::RemoveAvatar => {
+                                let user = match account_name(uuid_to_user_essential_map.get(&requester_uuid), "profiles") {
+                                    Ok(user) => user,
+                                    Err(e) => {
+                                        send_to_recipient(Recipient::Connection(requester_uuid),
+                                            ServerToClientMessage::Response(Err(e)),
+                                            &router, &cluster);
+                                        continue;
+                                    }
+                                };
+                                let result = match message {
+                                    ClientToServerMessage::SetProfile { display_name, bio, timezone } => {
+                                        profiles.update(&user, display_name, bio, timezone)
+                                    }
+                                    ClientToServerMessage::SetAvatar(Image(image)) => profiles.set_avatar(&user, image),
+                                    ClientToServerMessage::RemoveAvatar => profiles.remove_avatar(&user),
+                                    _ => unreachable!("only profile changes get here"),
+                                };
+                                let info = match result {
+                                    Ok(info) => info,
+                                    Err(e) => {
+                                        send_to_recipient(Recipient::Connection(requester_uuid),
+                                            ServerToClientMessage::Response(Err(e)),
+                                            &router, &cluster);
+                                        continue;
+                                    }
+                                };
+                                // everyone online keeps the names they show up to date, apart from those the user blocked
+                                let privacy = privacy.read().unwrap();
+                                let others: Vec<String> = router.usernames()
+                                    .into_iter()
+                                    .filter(|other| *other != user && !privacy.blocks(&user, other))
+                                    .collect();
+                                for other in others {
+                                    send_to_recipient(Recipient::User(other), ServerToClientMessage::ProfileUpdated(info.clone()),
+                                        &router, &cluster);
                                 }
+                                send_to_recipient(Recipient::Connection(requester_uuid), ServerToClientMessage::Profile(info),
+                                    &router, &cluster);
+                            }
 
-                                let recipient_uuid = recipient_uuid.unwrap();
+                            ClientToServerMessage::Directory(query) => {
+                                let viewer = uuid_to_user_essential_map.get(&requester_uuid)
+                                    .and_then(|user_essential| user_essential.username.clone())
+                                    .unwrap_or_default();
+                                let listings = listings(&viewer, &accounts, &privacy.read().unwrap(), &profiles, &federation, &cluster,
+                                    &router, &uuid_to_user_essential_map);
+                                send_to_recipient(Recipient::Connection(requester_uuid),
+                                    ServerToClientMessage::Directory(directory::page(&query, listings)),
+                                    &router, &cluster);
+                            }
 
-                                let recipient_user_essential = uuid_to_user_essential_map.get(recipient_uuid)
-                                    .expect("Failed to find recipient user essential");
+                            // the whole directory of online users at once, for clients which do not page
+                            ClientToServerMessage::GetUsernames | ClientToServerMessage::GetUserList => {
+                                let viewer = uuid_to_user_essential_map.get(&requester_uuid)
+                                    .and_then(|user_essential| user_essential.username.clone())
+                                    .unwrap_or_default();
+                                let online = DirectoryQuery {
+                                    online_only: true,
+                                    ..DirectoryQuery::default()
+                                };
+                                let listings = listings(&viewer, &accounts, &privacy.read().unwrap(), &profiles, &federation, &cluster,
+                                    &router, &uuid_to_user_essential_map);
+                                let users: Vec<UserInfo> = directory::matching(&online, listings).into_iter()
+                                    .map(|listing| UserInfo {
+                                        username: listing.username,
+                                        bot: listing.bot,
+                                        display_name: listing.profile.display_name,
+                                    })
+                                    .collect();
+                                let response = match message {
+                                    ClientToServerMessage::GetUsernames => MainToThreadsMessage::Usernames(
+                                        users.into_iter().map(|user| user.username).collect(),
+                                    ),
+                                    _ => MainToThreadsMessage::SendToClient(ServerToClientMessage::UserList(users)),
+                                };
 
-                                recipient_user_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
-                                    ServerToClientMessage::TextFrom(user_essential.username.clone().unwrap(), text)))
-                                    .await
+                                let user_essential = uuid_to_user_essential_map.get(&requester_uuid)
+                                    .expect("Failed to find user essential");
+
+                                user_essential.main_to_thread_tx
+                                    .send(response)
                                     .unwrap_or_else(|e|
-                                        println!("Failed to send message to client: {}", e));
+                                        console_println!("Failed to send message to client: {}", e));
+                            }
 
+                            // webhooks, texts for other servers and those the connection task left to the main loop
+                            ClientToServerMessage::TextTo(username, text, reply_to) => {
+                                let Some(user_essential) = uuid_to_user_essential_map.get(&requester_uuid) else {
+                                    continue;
+                                };
+                                let response = match user_essential.identity(&accounts, &moderation) {
+                                    None => Err("You must set a username first!".to_string()),
+                                    Some(identity) => match federation.address(&username) {
+                                        Address::Local(username) => texts.send(&identity, &username, &text, reply_to),
+                                        // answered once the peer tells whether the message was delivered
+                                        Address::Remote { user, server } => {
+                                            match federation.send_text(requester_uuid, &identity.username, &user, &server, &text) {
+                                                Ok(()) => continue,
+                                                Err(e) => Err(e),
+                                            }
+                                        }
+                                    },
+                                };
+                                send_to_recipient(Recipient::Connection(requester_uuid),
+                                    ServerToClientMessage::Response(response),
+                                    &router, &cluster);
+                            }
+                            // known commands were run by the command plugin
+                            ClientToServerMessage::Command { name, .. } => {
+                                let user_essential = uuid_to_user_essential_map.get(&requester_uuid)
+                                    .expect("Failed to find user essential");
                                 user_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
-                                    ServerToClientMessage::Response(Ok(format!("Sent message to {}", username)))))
-                                    .await
+                                    ServerToClientMessage::Response(Err(format!("Unknown command /{}", name)))))
                                     .unwrap_or_else(|e|
-                                        println!("Failed to send message to client: {}", e));
-
+                                        console_println!("Failed to send message to client: {}", e));
                             }
                             _ => {}
                         }
                     }
 
-                    Ok(ThreadsToMainMessage::ConnectionClosed(uuid)) => {
-                        let user_essential = uuid_to_user_essential_map.remove(&uuid)
+                    Some(ThreadsToMainMessage::ConnectionClosed(uuid)) => {
+                        // a kicked client which did not keep up closes on its own, after it was forgotten
+                        let Some(user_essential) = uuid_to_user_essential_map.remove(&uuid) else {
+                            continue;
+                        };
+                        router.close(uuid);
+                        if let Some(username) = user_essential.username {
+                            router.forget_session(&username, uuid);
+                            let mut privacy = privacy.write().unwrap();
+                            privacy.forget_guest(&username);
+                            users_changed(&shared_usernames, &contacts.read().unwrap(), &privacy, &federation, &cluster, &router);
+                        }
+                    }
+
+                    Some(ThreadsToMainMessage::SetRole(username, role)) => {
+                        match accounts.set_role(&username, role) {
+                            Ok(()) => {
+                                identify_user(&username, &accounts, &moderation, &router, &uuid_to_user_essential_map);
+                                console_println!("{} is now {}", username, role.name())
+                            }
+                            Err(e) => console_println!("{}", e),
+                        }
+                    }
+
+                    Some(ThreadsToMainMessage::Export(scope, format, path)) => {
+                        let (history, groups) = (history.clone(), groups.clone());
+                        tokio::spawn(async move {
+                            let exported = archive::export(history, groups, scope, None, format).await;
+                            match exported {
+                                Ok((count, content)) => match tokio::fs::write(&path, content).await {
+                                    Ok(()) => console_println!("Exported {} messages to {}", count, path.display()),
+                                    Err(e) => console_println!("Failed to write {}: {}", path.display(), e),
+                                },
+                                Err(e) => console_println!("{}", e),
+                            }
+                        });
+                    }
+
+                    Some(ThreadsToMainMessage::Import(path)) => {
+                        let history = history.clone();
+                        tokio::spawn(async move {
+                            match archive::import_file(history, path.clone()).await {
+                                Ok(count) => console_println!("Imported {} messages from {}", count, path.display()),
+                                Err(e) => console_println!("Nothing imported: {}", e),
+                            }
+                        });
+                    }
+
+                    Some(ThreadsToMainMessage::LoggedIn(requester_uuid, username, verified)) => {
+                        // the connection may have closed while the password was hashed,
+                        // and a guest may have taken the name
+                        if !uuid_to_user_essential_map.contains_key(&requester_uuid) {
+                            continue;
+                        }
+                        let result = if held_by_guest(&username, &router, &uuid_to_user_essential_map) {
+                            Err("Username already exists!".to_string())
+                        } else {
+                            verified.and_then(|verified| accounts.sign_in(&username, verified))
+                        };
+
+                        let mut privacy = privacy.write().unwrap();
+                        let contacts = contacts.read().unwrap();
+                        let requester_essential = uuid_to_user_essential_map.get_mut(&requester_uuid)
                             .expect("Failed to find user essential");
-                        if user_essential.username.is_some() {
-                            username_to_uuid_map.remove(&user_essential.username.unwrap());
+                        let result = result.map(|created| {
+                            if let Some(old_username) = requester_essential.username.replace(username.clone()) {
+                                router.forget_session(&old_username, requester_uuid);
+                                privacy.forget_guest(&old_username);
+                            }
+                            requester_essential.account = true;
+                            requester_essential.named_at = Utc::now();
+                            router.add_session(&username, requester_uuid);
+                            router.identify(requester_uuid, requester_essential.identity(&accounts, &moderation));
+
+                            match (created, router.sessions(&username).len()) {
+                                (true, _) => format!("Created account {} and logged in", username),
+                                (false, 1) => format!("Logged in as {}", username),
+                                (false, sessions) => format!("Logged in as {}, {} sessions active", username, sessions),
+                            }
+                        });
+                        let signed_in = result.is_ok();
+                        requester_essential.main_to_thread_tx.send(MainToThreadsMessage::SendToClient(
+                            ServerToClientMessage::Response(result)))
+                            .unwrap_or_else(|e|
+                                console_println!("Failed to send message to client: {}", e));
+                        if signed_in {
+                            users_changed(&shared_usernames, &contacts, &privacy, &federation, &cluster, &router);
                         }
                     }
 
-                    Err(e) => {
-                        println!("Error: {}", e);
+                    Some(ThreadsToMainMessage::RevokeSession(uuid)) => {
+                        // the session may have closed by itself in the meantime
+                        let Some(user_essential) = uuid_to_user_essential_map.remove(&uuid) else {
+                            continue;
+                        };
+                        router.close(uuid);
+                        if let Some(username) = &user_essential.username {
+                            router.forget_session(username, uuid);
+                            let mut privacy = privacy.write().unwrap();
+                            privacy.forget_guest(username);
+                            users_changed(&shared_usernames, &contacts.read().unwrap(), &privacy, &federation, &cluster, &router);
+                        }
+                        user_essential.main_to_thread_tx.send(MainToThreadsMessage::Shutdown)
+                            .unwrap_or_else(|e|
+                                console_println!("Failed to send message to client: {}", e));
+                        console_println!("Revoked session {}", uuid);
                     }
+
+                    Some(ThreadsToMainMessage::ReceivedFromWebhook(..)) => unreachable!("handled as a client message"),
+
+                    Some(ThreadsToMainMessage::Inject(recipient, message)) => {
+                        send_to_recipient(recipient, message,
+                            &router, &cluster);
+                    }
+
+                    Some(ThreadsToMainMessage::PeerConnected(link, server)) => {
+                        // peers which connected to this server started out like clients
+                        if let Some(user_essential) = uuid_to_user_essential_map.remove(&link) {
+                            router.close(link);
+                            federation.add_link(link, user_essential.main_to_thread_tx, false);
+                        }
+                        federation.connected(link, server.clone());
+                        let online = public_presence(&privacy.read().unwrap(), &router);
+                        federation.send(&server, PeerMessage::Presence(online))
+                            .unwrap_or_else(|e| console_println!("{}", e));
+                    }
+
+                    Some(ThreadsToMainMessage::PeerDisconnected(link)) => {
+                        for pending in federation.disconnected(link) {
+                            send_to_recipient(Recipient::Connection(pending.requester),
+                                ServerToClientMessage::Response(Err(format!("Lost the connection to the server of {}!", pending.to))),
+                                &router, &cluster);
+                        }
+                    }
+
+                    Some(ThreadsToMainMessage::FromPeer(link, message)) => {
+                        let Some(server) = federation.server(link).map(str::to_string) else {
+                            continue;
+                        };
+                        match message {
+                            PeerMessage::Presence(users) => federation.set_online(link, users),
+                            PeerMessage::PresenceChanged(user, online) => federation.presence_changed(link, user, online),
+                            // remote senders are known by their full address here
+                            PeerMessage::Text { ticket, from, to, text } => {
+                                let from = format!("{}@{}", from, server);
+                                let delivered = privacy.read().unwrap().may_message(&from, &to, false)
+                                    .and_then(|_| match router.is_online(&to) || cluster.is_online(&to) {
+                                        true => history.lock().unwrap().add(Conversation::direct(&from, &to), &from, &text, None, Utc::now()),
+                                        false => Err("Recipient does not exist!".to_string()),
+                                    });
+                                let result = match delivered {
+                                    Ok(message_id) => {
+                                        send_to_recipient(Recipient::User(to),
+                                            ServerToClientMessage::TextFrom(message_id, from, text, None),
+                                            &router, &cluster);
+                                        Ok(())
+                                    }
+                                    Err(e) => Err(e),
+                                };
+                                federation.send(&server, PeerMessage::Delivered { ticket, result })
+                                    .unwrap_or_else(|e| console_println!("{}", e));
+                            }
+                            PeerMessage::Delivered { ticket, result } => {
+                                let Some(pending) = federation.delivered(ticket) else {
+                                    continue;
+                                };
+                                let stored = result.and_then(|_| history.lock().unwrap().add(Conversation::direct(&pending.from, &pending.to),
+                                    &pending.from, &pending.text, None, Utc::now()));
+                                let response = match stored {
+                                    Ok(message_id) => {
+                                        send_to_recipient(Recipient::User(pending.from.clone()),
+                                            ServerToClientMessage::SentText(message_id, pending.to.clone(), pending.text, None),
+                                            &router, &cluster);
+                                        Ok(format!("Sent message to {}", pending.to))
+                                    }
+                                    Err(e) => Err(e),
+                                };
+                                send_to_recipient(Recipient::Connection(pending.requester),
+                                    ServerToClientMessage::Response(response),
+                                    &router, &cluster);
+                            }
+                            PeerMessage::Hello { .. } | PeerMessage::Welcome { .. } | PeerMessage::Rejected(_) => {}
+                        }
+                    }
+
+                    // a node which comes up learns who is here, one which goes away takes its users along
+                    Some(ThreadsToMainMessage::NodeUp(node)) => {
+                        cluster.send(&node, ClusterMessage::Users(router.usernames()));
+                    }
+
+                    Some(ThreadsToMainMessage::NodeDown(node)) => cluster.node_down(&node),
+
+                    Some(ThreadsToMainMessage::FromNode(node, message)) => match message {
+                        ClusterMessage::Users(users) => {
+                            let mut privacy = privacy.write().unwrap();
+                            cluster.node_users(&node, users.clone());
+                            let mut released = false;
+                            for user in users {
+                                released |= resolve_collision(&user, &node, &cluster, &router, &mut uuid_to_user_essential_map, &mut privacy);
+                            }
+                            if released {
+                                users_changed(&shared_usernames, &contacts.read().unwrap(), &privacy, &federation, &cluster, &router);
+                            }
+                        }
+                        ClusterMessage::UserChanged(user, online) => {
+                            cluster.user_changed(&node, user.clone(), online);
+                            let mut privacy = privacy.write().unwrap();
+                            if online && resolve_collision(&user, &node, &cluster, &router, &mut uuid_to_user_essential_map, &mut privacy) {
+                                users_changed(&shared_usernames, &contacts.read().unwrap(), &privacy, &federation, &cluster, &router);
+                            }
+                        }
+                        // only for the sessions here, the sending node told the others itself
+                        ClusterMessage::Deliver(user, message) => {
+                            router.deliver_to_user(&user, MainToThreadsMessage::SendToClient(message));
+                        }
+                    },
+
+                    // the main loop holds a sender itself, so the channel never closes
+                    None => unreachable!("the channel to the main loop closed"),
                 }
             }
         }
     }
+    Ok(())
 }
 
+// the handshake callback has to return tungstenite's large error response
+#[allow(clippy::result_large_err)]
 async fn handle_connection(
     stream: tokio::net::TcpStream,
     connection_id: Uuid,
-    mut main_to_thread_rx: Receiver<MainToThreadsMessage>,
-    mut thread_to_main_tx: Sender<ThreadsToMainMessage>,
+    mut main_to_thread_rx: Inbox,
+    thread_to_main_tx: Sender<ThreadsToMainMessage>,
+    federation_config: Arc<FederationConfig>,
+    texts: Texts,
 ) {
-    match accept_async(stream).await {
+    // peers ask for their own path, clients for any other
+    let mut path = String::new();
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(MAX_MESSAGE_BYTES))
+        .max_frame_size(Some(MAX_MESSAGE_BYTES));
+    let handshake = accept_hdr_async_with_config(stream, |request: &Request, response: Response| {
+        path = request.uri().path().to_string();
+        Ok(response)
+    }, Some(config)).await;
+    match handshake {
+        Ok(ws_stream) if path == FEDERATION_PATH => {
+            federation::handle_peer(ws_stream, connection_id, main_to_thread_rx, thread_to_main_tx, federation_config).await;
+            return;
+        }
         Ok(ws_stream) => {
-            println!("New WebSocket connection: {}", connection_id);
+            console_println!("New WebSocket connection: {}", connection_id);
 
             let (mut write, mut read) = ws_stream.split();
 
@@ -232,25 +1461,40 @@ async fn handle_connection(
                             Some(Ok(msg)) => {
                                 match msg {
                                     Message::Close(_) => {
-                                        println!("Connection {} closing", connection_id);
+                                        console_println!("Connection {} closing", connection_id);
                                         break;
                                     }
                                     Message::Text(text) => {
                                         let message : ClientToServerMessage = serde_json::from_str(&text)
                                             .expect("Failed to parse message");
-                                        thread_to_main_tx.send(ThreadsToMainMessage::ReceivedFromClient(message, connection_id)).expect("Failed to send message to main thread");
+                                        // texts go straight to their recipients, everything else through the main loop
+                                        if let Some(response) = texts.route(connection_id, &message) {
+                                            texts.router.deliver(connection_id,
+                                                MainToThreadsMessage::SendToClient(ServerToClientMessage::Response(response)));
+                                            continue;
+                                        }
+                                        texts.router.queue(connection_id);
+                                        thread_to_main_tx.send(ThreadsToMainMessage::ReceivedFromClient(message, connection_id)).await.expect("Failed to send message to main thread");
+                                    }
+                                    // the only binary frames clients send are avatar images; larger ones are turned
+                                    // away by their size alone, so no more than that goes to the main loop
+                                    Message::Binary(bytes) => {
+                                        let bytes = &bytes[..bytes.len().min(MAX_AVATAR_BYTES + 1)];
+                                        let message = ClientToServerMessage::SetAvatar(Image(bytes.to_vec()));
+                                        texts.router.queue(connection_id);
+                                        thread_to_main_tx.send(ThreadsToMainMessage::ReceivedFromClient(message, connection_id)).await.expect("Failed to send message to main thread");
                                     }
                                 _ => {
-                                    println!("Received non-text message from connection {}", connection_id);
+                                    console_println!("Received non-text message from connection {}", connection_id);
                                     }
                                 }
                             }
                             Some(Err(e)) => {
-                                println!("Error on connection {}: {}", connection_id, e);
+                                console_println!("Error on connection {}: {}", connection_id, e);
                                 break;
                             }
                             None => {
-                                println!("Connection {} closed by client", connection_id);
+                                console_println!("Connection {} closed by client", connection_id);
                                 break;
                             }
                         }
@@ -258,22 +1502,36 @@ async fn handle_connection(
                     channel_message = main_to_thread_rx.recv() => {
                         match channel_message {
                             Some(MainToThreadsMessage::Shutdown) => {
-                                println!("Shutting down connection {}", connection_id);
+                                console_println!("Shutting down connection {}", connection_id);
                                 write.send(Message::Close(None)).await.expect("Failed to send close message");
                                 return;
                             }
                             Some(MainToThreadsMessage::SendToClient(message)) => {
-                                println!("Sending message to client: {:?}", message);
+                                console_println!("Sending message to client: {:?}", message);
                                 write.send(Message::Text(Utf8Bytes::from(serde_json::to_string(&message).expect("Failed to serialize message")))).await.expect("Failed to send message to client");
                             }
-                            _ => {}
+                            Some(MainToThreadsMessage::Usernames(usernames)) => {
+                                let message = ServerToClientMessage::Usernames(usernames);
+                                console_println!("Sending message to client: {:?}", message);
+                                write.send(Message::Text(Utf8Bytes::from(serde_json::to_string(&message).expect("Failed to serialize message")))).await.expect("Failed to send message to client");
+                            }
+                            Some(MainToThreadsMessage::SendBinary(bytes)) => {
+                                console_println!("Sending {} bytes to client", bytes.len());
+                                write.send(Message::Binary(bytes.into())).await.expect("Failed to send message to client");
+                            }
+                            Some(MainToThreadsMessage::FellBehind) | None => {
+                                console_println!("Connection {} does not keep up, closing", connection_id);
+                                let _ = write.send(Message::Close(None)).await;
+                                break;
+                            }
+                            Some(_) => {}
                         }
                     }
                 }
             }
         }
         Err(e) => {
-            println!(
+            console_println!(
                 "Error during the websocket handshake for connection {}: {:?}",
                 connection_id, e
             );
@@ -281,7 +1539,267 @@ async fn handle_connection(
     }
     thread_to_main_tx
         .send(ThreadsToMainMessage::ConnectionClosed(connection_id))
-        .expect("Failed to send shutdown signal");
-    println!("Connection {} closed", connection_id);
-    return;
+        .await.expect("Failed to send shutdown signal");
+    console_println!("Connection {} closed", connection_id);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run, serve, THREADS_TO_MAIN_CAPACITY};
+    use crate::channel_message::ThreadsToMainMessage;
+    use crate::cluster::{Cluster, ClusterMessage, MemoryBackplane, MemoryHub};
+    use crate::config::{Config, FederationConfig, PeerServer};
+    use crate::router::Router;
+    use common::communication::common_message::{ClientToServerMessage, SearchQuery, ServerToClientMessage};
+    use futures_util::{SinkExt, StreamExt};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn start(name: &str, peers: Vec<PeerServer>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let config = Config {
+            federation: FederationConfig {
+                name: Some(name.to_string()),
+                peers,
+            },
+            ..Config::default()
+        };
+        tokio::spawn(serve(config, listener, false));
+        address
+    }
+
+    async fn start_node(hub: &MemoryHub, node: &str) -> SocketAddr {
+        start_node_with(hub, node, Config::default()).await
+    }
+
+    async fn start_node_with(hub: &MemoryHub, node: &str, config: Config) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (thread_to_main_tx, thread_to_main_rx) = mpsc::channel(THREADS_TO_MAIN_CAPACITY);
+        let cluster = Cluster::new(Some(Box::new(MemoryBackplane::join(hub, node, thread_to_main_tx.clone()))));
+        tokio::spawn(run(config, listener, false, (thread_to_main_tx, thread_to_main_rx), cluster, Arc::new(Router::default())));
+        address
+    }
+
+    async fn send(client: &mut Client, message: ClientToServerMessage) {
+        let text = serde_json::to_string(&message).unwrap();
+        client.send(Message::Text(Utf8Bytes::from(text))).await.unwrap();
+    }
+
+    async fn receive(client: &mut Client) -> ServerToClientMessage {
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(5), client.next()).await.unwrap();
+            if let Some(Ok(Message::Text(text))) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    async fn connect(address: SocketAddr, username: &str) -> Client {
+        let (mut client, _) = connect_async(format!("ws://{}", address)).await.unwrap();
+        send(&mut client, ClientToServerMessage::SetUsername(username.to_string())).await;
+        assert!(matches!(receive(&mut client).await, ServerToClientMessage::Response(Ok(_))));
+        client
+    }
+
+    // asks until the user list shows `username` the way `present` says
+    async fn wait_for(client: &mut Client, username: &str, present: bool) {
+        for _ in 0..100 {
+            send(client, ClientToServerMessage::GetUsernames).await;
+            if let ServerToClientMessage::Usernames(users) = receive(client).await {
+                if users.iter().any(|user| user == username) == present {
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("{} never became {}", username, if present { "present" } else { "absent" });
+    }
+
+    // older clients ask with GetUsernames and parse nothing but names
+    #[tokio::test]
+    async fn test_get_usernames_keeps_its_shape() {
+        let address = start("site", Vec::new()).await;
+        let mut alice = connect(address, "alice").await;
+        alice.send(Message::Text(Utf8Bytes::from(r#""GetUsernames""#))).await.unwrap();
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(5), alice.next()).await.unwrap();
+            if let Some(Ok(Message::Text(text))) = message {
+                if text.starts_with(r#"{"Usernames""#) {
+                    assert_eq!(text.as_str(), r#"{"Usernames":["alice"]}"#);
+                    return;
+                }
+            }
+        }
+    }
+
+    // a guest taking a name over does not get to read what was said to the one before
+    #[tokio::test]
+    async fn test_guests_do_not_inherit_history() {
+        let address = start("site", Vec::new()).await;
+        let mut alice = connect(address, "alice").await;
+        let mut bob = connect(address, "bob").await;
+        send(&mut alice, ClientToServerMessage::TextTo("bob".to_string(), "secret plans".to_string(), None)).await;
+        let ServerToClientMessage::TextFrom(id, ..) = receive(&mut bob).await else {
+            panic!("bob got no message");
+        };
+        bob.close(None).await.unwrap();
+        wait_for(&mut alice, "bob", false).await;
+
+        let mut other = connect(address, "bob").await;
+        send(&mut other, ClientToServerMessage::GetThread(id)).await;
+        assert_eq!(receive(&mut other).await, ServerToClientMessage::Response(Err(format!("There is no message {}!", id))));
+        let query = SearchQuery { terms: "plans".to_string(), page: 1, ..SearchQuery::default() };
+        send(&mut other, ClientToServerMessage::Search(query)).await;
+        assert!(matches!(receive(&mut other).await, ServerToClientMessage::SearchResults(results) if results.total == 0));
+        send(&mut other, ClientToServerMessage::React(id, "👍".to_string())).await;
+        assert_eq!(receive(&mut other).await, ServerToClientMessage::Response(Err(format!("There is no message {}!", id))));
+        send(&mut other, ClientToServerMessage::TextTo("alice".to_string(), "what plans?".to_string(), Some(id))).await;
+        assert_eq!(receive(&mut other).await,
+            ServerToClientMessage::Response(Err(format!("There is no message {} in this conversation!", id))));
+    }
+
+    #[tokio::test]
+    async fn test_federation_between_two_servers() {
+        let secret = "s3cret".to_string();
+        let a = start("sitea", vec![PeerServer { name: "siteb".to_string(), url: None, secret: secret.clone() }]).await;
+        let b = start("siteb", vec![PeerServer {
+            name: "sitea".to_string(),
+            url: Some(format!("ws://{}/federation", a)),
+            secret,
+        }])
+        .await;
+        // a server knowing the wrong secret is turned away
+        start("sitec", vec![PeerServer {
+            name: "sitea".to_string(),
+            url: Some(format!("ws://{}/federation", a)),
+            secret: "guess".to_string(),
+        }])
+        .await;
+
+        let mut alice = connect(a, "alice").await;
+        let mut bob = connect(b, "bob").await;
+        wait_for(&mut alice, "bob@siteb", true).await;
+        wait_for(&mut bob, "alice@sitea", true).await;
+
+        send(&mut alice, ClientToServerMessage::TextTo("bob@siteb".to_string(), "hi bob".to_string(), None)).await;
+        assert!(matches!(receive(&mut bob).await,
+            ServerToClientMessage::TextFrom(_, from, text, None) if from == "alice@sitea" && text == "hi bob"));
+        assert!(matches!(receive(&mut alice).await,
+            ServerToClientMessage::SentText(_, to, _, None) if to == "bob@siteb"));
+        assert!(matches!(receive(&mut alice).await, ServerToClientMessage::Response(Ok(_))));
+
+        // the answer goes back the same way, and the server's own name may be left on
+        send(&mut bob, ClientToServerMessage::TextTo("alice@sitea".to_string(), "hi alice".to_string(), None)).await;
+        assert!(matches!(receive(&mut alice).await,
+            ServerToClientMessage::TextFrom(_, from, _, None) if from == "bob@siteb"));
+        assert!(matches!(receive(&mut bob).await, ServerToClientMessage::SentText(..)));
+        assert!(matches!(receive(&mut bob).await, ServerToClientMessage::Response(Ok(_))));
+        send(&mut alice, ClientToServerMessage::TextTo("alice@sitea".to_string(), "me".to_string(), None)).await;
+        assert!(matches!(receive(&mut alice).await, ServerToClientMessage::SentText(_, to, _, None) if to == "alice"));
+        assert!(matches!(receive(&mut alice).await, ServerToClientMessage::TextFrom(_, from, _, None) if from == "alice"));
+        assert!(matches!(receive(&mut alice).await, ServerToClientMessage::Response(Ok(_))));
+
+        send(&mut alice, ClientToServerMessage::TextTo("carol@siteb".to_string(), "hi".to_string(), None)).await;
+        assert_eq!(receive(&mut alice).await, ServerToClientMessage::Response(Err("Recipient does not exist!".to_string())));
+        send(&mut alice, ClientToServerMessage::TextTo("carol@sitec".to_string(), "hi".to_string(), None)).await;
+        assert_eq!(receive(&mut alice).await, ServerToClientMessage::Response(Err("Server sitec is not connected!".to_string())));
+
+        send(&mut bob, ClientToServerMessage::SetUsername("eve@sitea".to_string())).await;
+        assert!(matches!(receive(&mut bob).await, ServerToClientMessage::Response(Err(_))));
+        bob.close(None).await.unwrap();
+        wait_for(&mut alice, "bob@siteb", false).await;
+    }
+
+    #[tokio::test]
+    async fn test_cluster_of_two_nodes() {
+        let hub = MemoryHub::default();
+        let one = start_node(&hub, "one").await;
+        let two = start_node(&hub, "two").await;
+
+        let mut alice = connect(one, "alice").await;
+        let mut bob = connect(two, "bob").await;
+        wait_for(&mut alice, "bob", true).await;
+
+        // names are taken across the whole cluster
+        let (mut other, _) = connect_async(format!("ws://{}", one)).await.unwrap();
+        send(&mut other, ClientToServerMessage::SetUsername("bob".to_string())).await;
+        assert_eq!(receive(&mut other).await, ServerToClientMessage::Response(Err("Username already exists!".to_string())));
+
+        send(&mut alice, ClientToServerMessage::TextTo("bob".to_string(), "hi bob".to_string(), None)).await;
+        assert!(matches!(receive(&mut bob).await,
+            ServerToClientMessage::TextFrom(_, from, text, None) if from == "alice" && text == "hi bob"));
+        assert!(matches!(receive(&mut alice).await, ServerToClientMessage::SentText(..)));
+        assert!(matches!(receive(&mut alice).await, ServerToClientMessage::Response(Ok(_))));
+
+        bob.close(None).await.unwrap();
+        wait_for(&mut alice, "bob", false).await;
+        send(&mut alice, ClientToServerMessage::TextTo("bob".to_string(), "still there?".to_string(), None)).await;
+        assert_eq!(receive(&mut alice).await, ServerToClientMessage::Response(Err("Recipient does not exist!".to_string())));
+    }
+
+    // two nodes granted bob at once, the one whose name sorts last takes it back
+    #[tokio::test]
+    async fn test_name_collision_between_nodes() {
+        let hub = MemoryHub::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (thread_to_main_tx, thread_to_main_rx) = mpsc::channel(THREADS_TO_MAIN_CAPACITY);
+        let cluster = Cluster::new(Some(Box::new(MemoryBackplane::join(&hub, "two", thread_to_main_tx.clone()))));
+        tokio::spawn(run(Config::default(), listener, false, (thread_to_main_tx.clone(), thread_to_main_rx), cluster,
+            Arc::new(Router::default())));
+        let mut bob = connect(address, "bob").await;
+        let claimed = |node: &str| ThreadsToMainMessage::FromNode(node.to_string(), ClusterMessage::UserChanged("bob".to_string(), true));
+
+        thread_to_main_tx.send(claimed("zulu")).await.unwrap();
+        send(&mut bob, ClientToServerMessage::GetUsernames).await;
+        assert!(matches!(receive(&mut bob).await, ServerToClientMessage::Usernames(_)));
+
+        thread_to_main_tx.send(claimed("one")).await.unwrap();
+        assert!(matches!(receive(&mut bob).await, ServerToClientMessage::BotTextFrom(None, from, ..) if from == "server"));
+        send(&mut bob, ClientToServerMessage::TextTo("alice".to_string(), "hi".to_string(), None)).await;
+        assert_eq!(receive(&mut bob).await, ServerToClientMessage::Response(Err("You must set a username first!".to_string())));
+    }
+
+    // a peer's message for a user on another node of the cluster is handed on to that node
+    #[tokio::test]
+    async fn test_federated_text_to_another_node() {
+        let hub = MemoryHub::default();
+        let secret = "s3cret".to_string();
+        let config = Config {
+            federation: FederationConfig {
+                name: Some("sitea".to_string()),
+                peers: vec![PeerServer { name: "siteb".to_string(), url: None, secret: secret.clone() }],
+            },
+            ..Config::default()
+        };
+        let one = start_node_with(&hub, "one", config).await;
+        let two = start_node(&hub, "two").await;
+        let b = start("siteb", vec![PeerServer {
+            name: "sitea".to_string(),
+            url: Some(format!("ws://{}/federation", one)),
+            secret,
+        }])
+        .await;
+
+        let mut carol = connect(one, "carol").await;
+        let mut bob = connect(two, "bob").await;
+        let mut alice = connect(b, "alice").await;
+        wait_for(&mut carol, "bob", true).await;
+        wait_for(&mut alice, "carol@sitea", true).await;
+
+        send(&mut alice, ClientToServerMessage::TextTo("bob@sitea".to_string(), "hi bob".to_string(), None)).await;
+        assert!(matches!(receive(&mut bob).await,
+            ServerToClientMessage::TextFrom(_, from, text, None) if from == "alice@siteb" && text == "hi bob"));
+        assert!(matches!(receive(&mut alice).await, ServerToClientMessage::SentText(..)));
+        assert!(matches!(receive(&mut alice).await, ServerToClientMessage::Response(Ok(_))));
+    }
 }
@@ -0,0 +1,311 @@
+use crate::plugin::{Flow, Plugin, PluginContext};
+use crate::roles::Role;
+use common::communication::common_message::{ClientToServerMessage, CommandInfo, ServerToClientMessage};
+use common::logic::command::{Command, CommandArgs, CommandSpec};
+use common::logic::input_parser::{parse_input, InputToken};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Anyone,
+    // only clients which picked a username or logged in as a bot, muted ones included
+    SignedIn,
+    // signed in with at least the role, which a mute takes away while it lasts
+    Role(Role),
+}
+
+impl Permission {
+    fn check(self, context: &PluginContext) -> Result<(), String> {
+        match self {
+            Permission::Anyone => Ok(()),
+            _ if context.sender.is_none() => Err("You must set a username first!".to_string()),
+            Permission::Role(role) if context.role < role => match context.role {
+                Role::Muted => Err("You are muted!".to_string()),
+                _ if role == Role::Admin => Err("Only admins may do that!".to_string()),
+                _ => Err("Only moderators may do that!".to_string()),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+// a command clients run with Command { name, args } or a text message "/name args..."
+pub trait ServerCommand: Command {
+    const PERMISSION: Permission;
+
+    // runs inside the main loop, so it must never block; Ok is the text of the response
+    fn run(self, context: &mut PluginContext) -> Result<String, String>;
+}
+
+type Handler = Box<dyn Fn(&mut PluginContext, &CommandArgs) -> Result<String, String> + Send>;
+
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: Vec<(&'static CommandSpec, Permission, Handler)>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<T: ServerCommand + 'static>(mut self) -> Self {
+        self.commands.push((
+            &T::SPEC,
+            T::PERMISSION,
+            Box::new(|context, args| {
+                T::from_args(args)
+                    .map_err(|e| e.to_string())?
+                    .run(context)
+            }),
+        ));
+        self
+    }
+
+    // the commands the sender of the current message may run
+    pub fn infos(&self, context: &PluginContext) -> Vec<CommandInfo> {
+        self.commands
+            .iter()
+            .filter(|(_, permission, _)| permission.check(context).is_ok())
+            .map(|(spec, _, _)| spec.info())
+            .collect()
+    }
+
+    fn knows(&self, name: &str) -> bool {
+        self.commands.iter().any(|(spec, _, _)| spec.matches(name))
+    }
+
+    // None if no command is registered under the name
+    pub fn run(
+        &self,
+        context: &mut PluginContext,
+        name: &str,
+        args: &[InputToken],
+    ) -> Option<Result<String, String>> {
+        let (spec, permission, handler) = self.commands.iter().find(|(spec, _, _)| spec.matches(name))?;
+        Some(permission.check(context).and_then(|_| {
+            spec.parse_args(args)
+                .map_err(|e| e.to_string())
+                .and_then(|args| handler(context, &args))
+        }))
+    }
+}
+
+// splits a text message "/name args..." into the name and the tokenized arguments
+fn slash_command(text: &str) -> Option<(&str, Result<Vec<InputToken>, String>)> {
+    let line = text.strip_prefix('/')?;
+    let name = line.split_whitespace().next()?;
+    let args = parse_input(line)
+        .map(|mut tokens| tokens.split_off(1))
+        .map_err(|e| e.to_string());
+    Some((name, args))
+}
+
+impl Plugin for CommandRegistry {
+    fn name(&self) -> &'static str {
+        "commands"
+    }
+
+    fn on_message(&mut self, context: &mut PluginContext, message: &ClientToServerMessage) -> Flow {
+        let result = match message {
+            ClientToServerMessage::GetCommands => {
+                let infos = self.infos(context);
+                context.reply(ServerToClientMessage::Commands(infos));
+                return Flow::Handled;
+            }
+            ClientToServerMessage::Command { name, args } => self.run(context, name, args),
+            // texts starting with an unknown /name are ordinary messages
+            ClientToServerMessage::TextTo(_, text, _) => match slash_command(text) {
+                Some((name, Ok(args))) => self.run(context, name, &args),
+                Some((name, Err(e))) if self.knows(name) => Some(Err(e)),
+                _ => None,
+            },
+            _ => None,
+        };
+        match result {
+            None => Flow::Continue,
+            Some(response) => {
+                context.reply(ServerToClientMessage::Response(response));
+                Flow::Handled
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CommandRegistry, Permission, ServerCommand};
+    use crate::plugin::{Flow, Injector, Plugin, PluginContext};
+    use crate::roles::Role;
+    use common::communication::common_message::{ClientToServerMessage, ServerToClientMessage};
+    use common::logic::command::{ArgKind, ArgSpec, Command, CommandArgs, CommandError, CommandSpec};
+    use common::logic::input_parser::InputToken;
+    use uuid::Uuid;
+
+    struct Shout {
+        text: String,
+    }
+
+    impl Command for Shout {
+        const SPEC: CommandSpec = CommandSpec {
+            name: "shout",
+            aliases: &["yell"],
+            args: &[ArgSpec::required("text", ArgKind::Text)],
+            flags: &[],
+            help: "",
+        };
+
+        fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+            Ok(Shout {
+                text: args.string("text")?,
+            })
+        }
+    }
+
+    impl ServerCommand for Shout {
+        const PERMISSION: Permission = Permission::SignedIn;
+
+        fn run(self, _context: &mut PluginContext) -> Result<String, String> {
+            Ok(self.text.to_uppercase())
+        }
+    }
+
+    struct Silence;
+
+    impl Command for Silence {
+        const SPEC: CommandSpec = CommandSpec {
+            name: "silence",
+            aliases: &[],
+            args: &[],
+            flags: &[],
+            help: "",
+        };
+
+        fn from_args(_args: &CommandArgs) -> Result<Self, CommandError> {
+            Ok(Silence)
+        }
+    }
+
+    impl ServerCommand for Silence {
+        const PERMISSION: Permission = Permission::Role(Role::Moderator);
+
+        fn run(self, _context: &mut PluginContext) -> Result<String, String> {
+            Ok("Quiet please".to_string())
+        }
+    }
+
+    // the flow and the replies of the registry for one message
+    fn handle(
+        sender: Option<&str>,
+        message: ClientToServerMessage,
+    ) -> (Flow, Vec<ServerToClientMessage>) {
+        handle_as(sender, Role::Member, message)
+    }
+
+    fn handle_as(
+        sender: Option<&str>,
+        role: Role,
+        message: ClientToServerMessage,
+    ) -> (Flow, Vec<ServerToClientMessage>) {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let injector = Injector::new(tx);
+        let mut context = PluginContext::new(Uuid::new_v4(), sender, role, &[], &injector);
+        let flow = CommandRegistry::new()
+            .with::<Shout>()
+            .with::<Silence>()
+            .on_message(&mut context, &message);
+        let replies = context.into_outbox().into_iter().map(|(_, m)| m).collect();
+        (flow, replies)
+    }
+
+    fn text(text: &str) -> ClientToServerMessage {
+        ClientToServerMessage::TextTo("anyone".to_string(), text.to_string(), None)
+    }
+
+    fn response(result: Result<&str, &str>) -> Vec<ServerToClientMessage> {
+        vec![ServerToClientMessage::Response(
+            result.map(str::to_string).map_err(str::to_string),
+        )]
+    }
+
+    #[test]
+    fn test_commands() {
+        let command = ClientToServerMessage::Command {
+            name: "yell".to_string(),
+            args: vec![InputToken::String("hi".to_string())],
+        };
+        assert_eq!(handle(Some("alice"), command.clone()), (Flow::Handled, response(Ok("HI"))));
+        assert_eq!(
+            handle(None, command),
+            (Flow::Handled, response(Err("You must set a username first!")))
+        );
+
+        assert_eq!(
+            handle(Some("alice"), text("/shout \"hi there\"")),
+            (Flow::Handled, response(Ok("HI THERE")))
+        );
+        let (flow, replies) = handle(Some("alice"), text("/shout"));
+        assert_eq!(flow, Flow::Handled);
+        assert!(matches!(replies[..], [ServerToClientMessage::Response(Err(_))]));
+        let (flow, _) = handle(Some("alice"), text("/shout \"hi"));
+        assert_eq!(flow, Flow::Handled);
+
+        // unknown commands are left to the server, texts stay texts
+        assert_eq!(handle(Some("alice"), text("/whisper \"hi")), (Flow::Continue, vec![]));
+        assert_eq!(handle(Some("alice"), text("shout hi")), (Flow::Continue, vec![]));
+        let unknown = ClientToServerMessage::Command {
+            name: "whisper".to_string(),
+            args: vec![],
+        };
+        assert_eq!(handle(Some("alice"), unknown), (Flow::Continue, vec![]));
+    }
+
+    #[test]
+    fn test_permissions() {
+        assert_eq!(
+            handle(Some("alice"), text("/silence")),
+            (Flow::Handled, response(Err("Only moderators may do that!")))
+        );
+        assert_eq!(
+            handle_as(Some("mod"), Role::Moderator, text("/silence")),
+            (Flow::Handled, response(Ok("Quiet please")))
+        );
+        assert_eq!(
+            handle_as(Some("admin"), Role::Admin, text("/silence")),
+            (Flow::Handled, response(Ok("Quiet please")))
+        );
+        // a mute takes any role away while it lasts, but leaves commands which only need a name
+        assert_eq!(
+            handle_as(Some("mod"), Role::Muted, text("/silence")),
+            (Flow::Handled, response(Err("You are muted!")))
+        );
+        assert_eq!(
+            handle_as(Some("alice"), Role::Muted, text("/shout hi")),
+            (Flow::Handled, response(Ok("HI")))
+        );
+
+        // the list only has what the sender may run
+        let names = |role| match handle_as(Some("alice"), role, ClientToServerMessage::GetCommands).1.pop() {
+            Some(ServerToClientMessage::Commands(infos)) => infos.into_iter().map(|info| info.name).collect::<Vec<_>>(),
+            other => panic!("expected the command list, got {:?}", other),
+        };
+        assert_eq!(names(Role::Member), ["shout"]);
+        assert_eq!(names(Role::Moderator), ["shout", "silence"]);
+    }
+
+    #[test]
+    fn test_advertised_commands() {
+        let (flow, replies) = handle(Some("alice"), ClientToServerMessage::GetCommands);
+        assert_eq!(flow, Flow::Handled);
+        let [ServerToClientMessage::Commands(infos)] = &replies[..] else {
+            panic!("expected the command list, got {:?}", replies);
+        };
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].usage, "shout \"<text>\"");
+        assert_eq!(infos[0].aliases, vec!["yell".to_string()]);
+
+        assert_eq!(
+            handle(None, ClientToServerMessage::GetCommands),
+            (Flow::Handled, vec![ServerToClientMessage::Commands(vec![])])
+        );
+    }
+}
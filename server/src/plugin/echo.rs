@@ -0,0 +1,32 @@
+use crate::plugin::{Permission, PluginContext, ServerCommand};
+use common::communication::common_message::ServerToClientMessage;
+use common::logic::command::{ArgKind, ArgSpec, Command, CommandArgs, CommandError, CommandSpec};
+
+pub struct Echo {
+    text: String,
+}
+
+impl Command for Echo {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "echo",
+        aliases: &[],
+        args: &[ArgSpec::required("text", ArgKind::Text)],
+        flags: &[],
+        help: "send the text back to you",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Echo {
+            text: args.string("text")?,
+        })
+    }
+}
+
+impl ServerCommand for Echo {
+    const PERMISSION: Permission = Permission::Anyone;
+
+    fn run(self, context: &mut PluginContext) -> Result<String, String> {
+        context.reply(ServerToClientMessage::BotTextFrom(None, Self::SPEC.name.to_string(), self.text, None));
+        Ok("Echoed".to_string())
+    }
+}
@@ -0,0 +1,180 @@
+mod commands;
+mod echo;
+mod remind;
+mod sessions;
+
+use crate::channel_message::{Recipient, ThreadsToMainMessage};
+use crate::roles::Role;
+use chrono::{DateTime, Utc};
+use common::communication::common_message::{ClientToServerMessage, ServerToClientMessage};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+// messages plugins want sent at a later time, by one task which sleeps until the next is due
+#[derive(Default)]
+struct Schedule {
+    // keyed by when they are due, and in the order they came in for the same time
+    due: BTreeMap<(Instant, u64), (Recipient, ServerToClientMessage)>,
+    added: u64,
+    running: bool,
+}
+
+// lets plugins send messages later on, outside of on_message
+#[derive(Clone)]
+pub struct Injector {
+    thread_to_main_tx: Sender<ThreadsToMainMessage>,
+    schedule: Arc<Mutex<Schedule>>,
+    // woken when a message is scheduled, which may be due before the one slept for
+    scheduled: Arc<Notify>,
+}
+
+impl Injector {
+    pub fn new(thread_to_main_tx: Sender<ThreadsToMainMessage>) -> Self {
+        Injector {
+            thread_to_main_tx,
+            schedule: Default::default(),
+            scheduled: Default::default(),
+        }
+    }
+
+    pub fn send_later(&self, delay: Duration, recipient: Recipient, message: ServerToClientMessage) {
+        let mut schedule = self.schedule.lock().unwrap();
+        schedule.added += 1;
+        let key = (Instant::now() + delay, schedule.added);
+        schedule.due.insert(key, (recipient, message));
+        if !std::mem::replace(&mut schedule.running, true) {
+            tokio::spawn(self.clone().send_when_due());
+        }
+        self.scheduled.notify_one();
+    }
+
+    // how many messages wait to be sent to the recipient
+    pub fn pending(&self, recipient: &Recipient) -> usize {
+        let schedule = self.schedule.lock().unwrap();
+        schedule.due.values().filter(|(to, _)| to == recipient).count()
+    }
+
+    async fn send_when_due(self) {
+        loop {
+            let next = self.schedule.lock().unwrap().due.keys().next().map(|(at, _)| *at);
+            match next {
+                Some(at) => tokio::select! {
+                    _ = tokio::time::sleep_until(at) => {}
+                    _ = self.scheduled.notified() => continue,
+                },
+                None => {
+                    self.scheduled.notified().await;
+                    continue;
+                }
+            }
+            let due = {
+                let mut schedule = self.schedule.lock().unwrap();
+                let later = schedule.due.split_off(&(Instant::now(), u64::MAX));
+                std::mem::replace(&mut schedule.due, later)
+            };
+            for (recipient, message) in due.into_values() {
+                self.send(recipient, message);
+            }
+        }
+    }
+
+    pub fn send(&self, recipient: Recipient, message: ServerToClientMessage) {
+        self.queue(ThreadsToMainMessage::Inject(recipient, message));
+    }
+
+    pub fn revoke(&self, session: Uuid) {
+        self.queue(ThreadsToMainMessage::RevokeSession(session));
+    }
+
+    // plugins run inside the main loop, which must not wait for room in its own channel;
+    // sending fails only once the server shuts down, then nobody is left to tell
+    fn queue(&self, message: ThreadsToMainMessage) {
+        if let Err(TrySendError::Full(message)) = self.thread_to_main_tx.try_send(message) {
+            let tx = self.thread_to_main_tx.clone();
+            tokio::spawn(async move {
+                let _ = tx.send(message).await;
+            });
+        }
+    }
+}
+
+// one of the connections the sender of a message is logged in on
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub address: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+}
+
+pub struct PluginContext<'a> {
+    pub connection: Uuid,
+    // username of the client the message came from, if it picked one
+    pub sender: Option<&'a str>,
+    // what the sender may do, muted while a mute lasts
+    pub role: Role,
+    // every session of the sender, including the one the message came from
+    pub sessions: &'a [SessionInfo],
+    pub injector: &'a Injector,
+    outbox: Vec<(Recipient, ServerToClientMessage)>,
+}
+
+impl<'a> PluginContext<'a> {
+    pub fn new(
+        connection: Uuid,
+        sender: Option<&'a str>,
+        role: Role,
+        sessions: &'a [SessionInfo],
+        injector: &'a Injector,
+    ) -> Self {
+        PluginContext {
+            connection,
+            sender,
+            role,
+            sessions,
+            injector,
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn reply(&mut self, message: ServerToClientMessage) {
+        self.outbox.push((Recipient::Connection(self.connection), message));
+    }
+
+    // messages to deliver once the plugins are done with the current message
+    pub fn into_outbox(self) -> Vec<(Recipient, ServerToClientMessage)> {
+        self.outbox
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    // the plugin answered the message itself, it is not routed any further
+    Handled,
+}
+
+// runs inside the main loop, so it must never block; texts are mostly routed by the connection tasks
+// without reaching it, apart from those starting with a slash, which may be commands
+pub trait Plugin: Send {
+    fn name(&self) -> &'static str;
+
+    fn on_message(&mut self, context: &mut PluginContext, message: &ClientToServerMessage) -> Flow;
+}
+
+pub use commands::{CommandRegistry, Permission, ServerCommand};
+
+pub fn builtin_plugins() -> Vec<Box<dyn Plugin>> {
+    let commands = CommandRegistry::new()
+        .with::<echo::Echo>()
+        .with::<remind::Remind>()
+        .with::<sessions::Sessions>()
+        .with::<sessions::Revoke>();
+    vec![Box::new(commands)]
+}
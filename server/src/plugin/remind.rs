@@ -0,0 +1,101 @@
+use crate::channel_message::Recipient;
+use crate::plugin::{Permission, PluginContext, ServerCommand};
+use crate::roles::Role;
+use common::communication::common_message::ServerToClientMessage;
+use common::logic::command::{ArgKind, ArgSpec, Command, CommandArgs, CommandError, CommandSpec};
+use std::time::Duration;
+
+const MAX_MINUTES: f64 = 7.0 * 24.0 * 60.0;
+// reminders one user may have waiting at once
+const MAX_PENDING: usize = 20;
+
+pub struct Remind {
+    minutes: f64,
+    text: String,
+}
+
+impl Command for Remind {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "remind",
+        aliases: &[],
+        args: &[
+            ArgSpec::required("minutes", ArgKind::Float),
+            ArgSpec::required("text", ArgKind::Text),
+        ],
+        flags: &[],
+        help: "send you the text after the given number of minutes",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Remind {
+            minutes: args.float("minutes")?,
+            text: args.string("text")?,
+        })
+    }
+}
+
+impl ServerCommand for Remind {
+    const PERMISSION: Permission = Permission::Role(Role::Member);
+
+    fn run(self, context: &mut PluginContext) -> Result<String, String> {
+        let Remind { minutes, text } = self;
+        if !(0.0..=MAX_MINUTES).contains(&minutes) {
+            return Err(format!("Minutes have to be between 0 and {}", MAX_MINUTES));
+        }
+        let username = context.sender.expect("checked by the permission");
+
+        // the reminder follows the name, so it also reaches a reconnected client
+        let recipient = Recipient::User(username.to_string());
+        if context.injector.pending(&recipient) >= MAX_PENDING {
+            return Err(format!("You already have {} reminders waiting!", MAX_PENDING));
+        }
+        context.injector.send_later(
+            Duration::from_secs_f64(minutes * 60.0),
+            recipient,
+            ServerToClientMessage::BotTextFrom(None, Self::SPEC.name.to_string(), text, None),
+        );
+        Ok(format!("Will remind you in {} minutes", minutes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Remind, MAX_PENDING};
+    use crate::channel_message::{Recipient, ThreadsToMainMessage};
+    use crate::plugin::{CommandRegistry, Injector, PluginContext};
+    use crate::roles::Role;
+    use common::communication::common_message::ServerToClientMessage;
+    use common::logic::input_parser::parse_input;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_reminders() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+        let injector = Injector::new(tx);
+        let registry = CommandRegistry::new().with::<Remind>();
+        let remind = |args: &str| {
+            let mut context = PluginContext::new(Uuid::new_v4(), Some("alice"), Role::Member, &[], &injector);
+            registry.run(&mut context, "remind", &parse_input(args).unwrap()).unwrap()
+        };
+
+        remind("0.002 later").unwrap();
+        remind("0.001 sooner").unwrap();
+        for _ in 2..MAX_PENDING {
+            remind("60 tomorrow").unwrap();
+        }
+        assert_eq!(remind("60 \"one too many\""), Err(format!("You already have {} reminders waiting!", MAX_PENDING)));
+
+        // one task sends them all, each once it is due
+        for expected in ["sooner", "later"] {
+            let Some(ThreadsToMainMessage::Inject(Recipient::User(user), ServerToClientMessage::BotTextFrom(_, bot, text, _))) =
+                rx.recv().await else {
+                panic!("Expected the reminder {}", expected);
+            };
+            assert_eq!((user.as_str(), bot.as_str(), text.as_str()), ("alice", "remind", expected));
+        }
+        // which makes room for new ones
+        remind("60 again").unwrap();
+        remind("60 again").unwrap();
+        assert_eq!(remind("60 \"one too many\""), Err(format!("You already have {} reminders waiting!", MAX_PENDING)));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::plugin::{Permission, PluginContext, ServerCommand};
+use chrono::SecondsFormat;
+use common::logic::command::{ArgKind, ArgSpec, Command, CommandArgs, CommandError, CommandSpec};
+use uuid::Uuid;
+
+// sessions are named by the start of their id, which is unique enough among one user's sessions
+fn short_id(id: &Uuid) -> String {
+    id.simple().to_string()[..8].to_string()
+}
+
+pub struct Sessions;
+
+impl Command for Sessions {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "sessions",
+        aliases: &[],
+        args: &[],
+        flags: &[],
+        help: "list the clients you are logged in on",
+    };
+
+    fn from_args(_args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Sessions)
+    }
+}
+
+impl ServerCommand for Sessions {
+    const PERMISSION: Permission = Permission::SignedIn;
+
+    fn run(self, context: &mut PluginContext) -> Result<String, String> {
+        let mut lines = vec![format!("{} active sessions:", context.sessions.len())];
+        for session in context.sessions {
+            let mut line = format!(
+                "  {}  {}  since {}",
+                short_id(&session.id),
+                session.address,
+                session.connected_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+            );
+            if session.id == context.connection {
+                line.push_str("  (this session)");
+            }
+            lines.push(line);
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+pub struct Revoke {
+    session: String,
+}
+
+impl Command for Revoke {
+    const SPEC: CommandSpec = CommandSpec {
+        name: "revoke",
+        aliases: &[],
+        args: &[ArgSpec::required("session", ArgKind::Text)],
+        flags: &[],
+        help: "log out one of your other sessions, as listed by sessions",
+    };
+
+    fn from_args(args: &CommandArgs) -> Result<Self, CommandError> {
+        Ok(Revoke {
+            session: args.string("session")?,
+        })
+    }
+}
+
+impl ServerCommand for Revoke {
+    const PERMISSION: Permission = Permission::SignedIn;
+
+    fn run(self, context: &mut PluginContext) -> Result<String, String> {
+        let Some(session) = context
+            .sessions
+            .iter()
+            .find(|session| short_id(&session.id) == self.session)
+        else {
+            return Err(format!("You have no session {}", self.session));
+        };
+        if session.id == context.connection {
+            return Err("That is this session, close it instead".to_string());
+        }
+        context.injector.revoke(session.id);
+        Ok(format!("Revoked session {}", self.session))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Revoke;
+    use common::logic::command::Command;
+    use common::logic::input_parser::parse_input;
+
+    // session ids are hex, so some of them read like numbers
+    #[test]
+    fn test_revoke_takes_numeric_ids() {
+        for id in ["12345678", "00123456", "1e234567", "0e000000", "a1b2c3d4"] {
+            let tokens = parse_input(&format!("revoke {}", id)).unwrap();
+            let args = Revoke::SPEC.parse_args(&tokens[1..]).unwrap();
+            assert_eq!(Revoke::from_args(&args).unwrap().session, id);
+        }
+    }
+}
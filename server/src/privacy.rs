@@ -0,0 +1,194 @@
+use crate::store;
+use common::communication::common_message::PrivacySettings;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct UserPrivacy {
+    blocked: BTreeSet<String>,
+    // direct messages only from contacts
+    contacts_only: bool,
+    // left out of everyone's username list
+    hide_presence: bool,
+}
+
+// what each user keeps from others, by username
+#[derive(Debug, Default)]
+pub struct Privacy {
+    // where the settings of accounts are kept, none keeps them in memory only
+    path: Option<PathBuf>,
+    users: HashMap<String, UserPrivacy>,
+    // guests keep theirs only while they hold the name, the next guest taking it starts afresh
+    guests: HashMap<String, UserPrivacy>,
+}
+
+impl Privacy {
+    pub fn load(path: Option<&Path>) -> Result<Privacy, String> {
+        let Some(path) = path else {
+            return Ok(Privacy::default());
+        };
+        Ok(Privacy {
+            path: Some(path.to_path_buf()),
+            users: store::load(path, "privacy")?,
+            guests: HashMap::new(),
+        })
+    }
+
+    // drops what was saved for names which are no account, older servers kept guests' settings too
+    pub fn retain_accounts(&mut self, is_account: impl Fn(&str) -> bool) {
+        self.users.retain(|user, _| is_account(user));
+    }
+
+    // called whenever a guest gives up their name
+    pub fn forget_guest(&mut self, user: &str) {
+        self.guests.remove(user);
+    }
+
+    fn get(&self, user: &str) -> Option<&UserPrivacy> {
+        self.guests.get(user).or_else(|| self.users.get(user))
+    }
+
+    pub fn blocks(&self, user: &str, other: &str) -> bool {
+        self.get(user).is_some_and(|privacy| privacy.blocked.contains(other))
+    }
+
+    // whether `from` may send `to` a direct message; blocked senders are told nothing more
+    pub fn may_message(&self, from: &str, to: &str, is_contact: bool) -> Result<(), String> {
+        if self.blocks(to, from) {
+            return Err("Recipient does not exist!".to_string());
+        }
+        if !is_contact && self.get(to).is_some_and(|privacy| privacy.contacts_only) {
+            return Err(format!("{} only accepts direct messages from contacts!", to));
+        }
+        Ok(())
+    }
+
+    // whether `viewer` finds `user` among those online
+    pub fn shows(&self, user: &str, viewer: &str) -> bool {
+        user == viewer
+            || !(self.get(user).is_some_and(|privacy| privacy.hide_presence) || self.blocks(viewer, user))
+    }
+
+    pub fn settings(&self, user: &str) -> PrivacySettings {
+        let privacy = self.get(user).cloned().unwrap_or_default();
+        PrivacySettings {
+            contacts_only: privacy.contacts_only,
+            hide_presence: privacy.hide_presence,
+            blocked: privacy.blocked.into_iter().collect(),
+        }
+    }
+
+    pub fn block(&mut self, user: &str, account: bool, other: &str) -> Result<(), String> {
+        if user == other {
+            return Err("You cannot block yourself!".to_string());
+        }
+        self.change(user, account, |privacy| match privacy.blocked.insert(other.to_string()) {
+            true => Ok(()),
+            false => Err(format!("{} is already blocked!", other)),
+        })
+    }
+
+    pub fn unblock(&mut self, user: &str, account: bool, other: &str) -> Result<(), String> {
+        self.change(user, account, |privacy| match privacy.blocked.remove(other) {
+            true => Ok(()),
+            false => Err(format!("{} is not blocked!", other)),
+        })
+    }
+
+    // settings left as None keep their value
+    pub fn update(
+        &mut self,
+        user: &str,
+        account: bool,
+        contacts_only: Option<bool>,
+        hide_presence: Option<bool>,
+    ) -> Result<PrivacySettings, String> {
+        self.change(user, account, |privacy| {
+            privacy.contacts_only = contacts_only.unwrap_or(privacy.contacts_only);
+            privacy.hide_presence = hide_presence.unwrap_or(privacy.hide_presence);
+            Ok(())
+        })?;
+        Ok(self.settings(user))
+    }
+
+    // only the settings of accounts are saved, the change is undone if that fails
+    fn change(
+        &mut self,
+        user: &str,
+        account: bool,
+        change: impl FnOnce(&mut UserPrivacy) -> Result<(), String>,
+    ) -> Result<(), String> {
+        if !account {
+            return change(self.guests.entry(user.to_string()).or_default());
+        }
+        let previous = self.users.get(user).cloned();
+        let privacy = self.users.entry(user.to_string()).or_default();
+        let result = change(privacy).and_then(|_| self.save());
+        if result.is_err() {
+            match previous {
+                Some(previous) => self.users.insert(user.to_string(), previous),
+                None => self.users.remove(user),
+            };
+        }
+        result
+    }
+
+    fn save(&self) -> Result<(), String> {
+        match &self.path {
+            Some(path) => store::save(path, &self.users, "privacy settings"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Privacy;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_privacy() {
+        let path = std::env::temp_dir().join(format!("privacy-{}.json", Uuid::new_v4()));
+        let mut privacy = Privacy::load(Some(&path)).unwrap();
+        assert_eq!(privacy.block("alice", true, "alice"), Err("You cannot block yourself!".to_string()));
+        assert_eq!(privacy.block("alice", true, "mallory"), Ok(()));
+        assert!(privacy.block("alice", true, "mallory").is_err());
+
+        // blocked senders get the same answer as for someone who does not exist
+        assert_eq!(privacy.may_message("mallory", "alice", false), Err("Recipient does not exist!".to_string()));
+        assert_eq!(privacy.may_message("alice", "mallory", false), Ok(()));
+        assert!(!privacy.shows("mallory", "alice"));
+        assert!(privacy.shows("alice", "mallory"));
+
+        let settings = privacy.update("alice", true, Some(true), None).unwrap();
+        assert!(settings.contacts_only && !settings.hide_presence);
+        assert_eq!(settings.blocked, vec!["mallory".to_string()]);
+        assert!(privacy.may_message("bob", "alice", false).is_err());
+        assert_eq!(privacy.may_message("bob", "alice", true), Ok(()));
+
+        privacy.update("bob", true, None, Some(true)).unwrap();
+        assert!(!privacy.shows("bob", "alice"));
+        assert!(privacy.shows("bob", "bob"));
+
+        let mut reloaded = Privacy::load(Some(&path)).unwrap();
+        assert_eq!(reloaded.settings("alice"), settings);
+        assert_eq!(reloaded.unblock("alice", true, "mallory"), Ok(()));
+        assert!(reloaded.unblock("alice", true, "mallory").is_err());
+        assert_eq!(reloaded.may_message("mallory", "alice", true), Ok(()));
+
+        // guests' settings hold until they give up the name, and are never saved
+        reloaded.block("carol", false, "mallory").unwrap();
+        reloaded.update("carol", false, Some(true), None).unwrap();
+        assert!(reloaded.may_message("mallory", "carol", true).is_err());
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("carol"));
+        reloaded.forget_guest("carol");
+        assert_eq!(reloaded.may_message("mallory", "carol", false), Ok(()));
+        assert!(Privacy::load(Some(&path)).unwrap().settings("bob").hide_presence);
+        reloaded.retain_accounts(|user| user == "alice");
+        reloaded.block("alice", true, "mallory").unwrap();
+        assert!(!Privacy::load(Some(&path)).unwrap().settings("bob").hide_presence);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
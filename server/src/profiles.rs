@@ -0,0 +1,242 @@
+use crate::store;
+use common::communication::avatar::check_avatar;
+use common::communication::common_message::ProfileInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const MAX_DISPLAY_NAME_CHARS: usize = 40;
+const MAX_BIO_CHARS: usize = 280;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Profile {
+    display_name: Option<String>,
+    bio: Option<String>,
+    timezone: Option<String>,
+    // the image type of the avatar, the image itself is kept apart
+    avatar: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Profiles {
+    // where the profiles are kept, none keeps them in memory only
+    path: Option<PathBuf>,
+    // one file per user, named after the username in hex
+    avatar_dir: Option<PathBuf>,
+    users: HashMap<String, Profile>,
+    // the avatars of a server without an avatar directory
+    avatars: HashMap<String, Vec<u8>>,
+}
+
+// trimmed, and None once nothing is left
+fn clean(value: &str, what: &str, max_chars: usize) -> Result<Option<String>, String> {
+    let value = value.trim();
+    if value.chars().count() > max_chars {
+        return Err(format!("The {} may have at most {} characters!", what, max_chars));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(format!("The {} may not contain control characters!", what));
+    }
+    Ok(Some(value.to_string()).filter(|value| !value.is_empty()))
+}
+
+// an offset like +02:00 or UTC-5, or what looks like an IANA name such as America/New_York
+fn valid_timezone(timezone: &str) -> bool {
+    let offset = timezone.strip_prefix("UTC").or_else(|| timezone.strip_prefix("GMT")).unwrap_or(timezone);
+    if offset.is_empty() {
+        return true;
+    }
+    if let Some(offset) = offset.strip_prefix('+').or_else(|| offset.strip_prefix('-')) {
+        let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "00"));
+        let two_digits = |part: &str| (1..=2).contains(&part.len()) && part.bytes().all(|b| b.is_ascii_digit());
+        return two_digits(hours)
+            && two_digits(minutes)
+            && hours.parse::<u8>().is_ok_and(|hours| hours <= 14)
+            && minutes.parse::<u8>().is_ok_and(|minutes| minutes < 60);
+    }
+    let parts: Vec<&str> = timezone.split('/').collect();
+    parts.len() >= 2
+        && parts.iter().all(|part| {
+            part.starts_with(|c: char| c.is_ascii_uppercase())
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || "_-+".contains(c))
+        })
+}
+
+fn hex(username: &str) -> String {
+    username.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Profiles {
+    pub fn load(path: Option<&Path>, avatar_dir: Option<&Path>) -> Result<Profiles, String> {
+        if let Some(avatar_dir) = avatar_dir {
+            std::fs::create_dir_all(avatar_dir)
+                .map_err(|e| format!("Failed to create {}: {}", avatar_dir.display(), e))?;
+        }
+        Ok(Profiles {
+            path: path.map(Path::to_path_buf),
+            avatar_dir: avatar_dir.map(Path::to_path_buf),
+            users: match path {
+                Some(path) => store::load(path, "profiles")?,
+                None => HashMap::new(),
+            },
+            avatars: HashMap::new(),
+        })
+    }
+
+    // drops what was saved for names which are no account, older servers let guests have profiles too
+    pub fn retain_accounts(&mut self, is_account: impl Fn(&str) -> bool) {
+        let guests: Vec<String> = self.users.keys().filter(|user| !is_account(user)).cloned().collect();
+        for guest in guests {
+            if let (Some(_), Some(avatar_dir)) = (self.users.remove(&guest).and_then(|profile| profile.avatar), &self.avatar_dir) {
+                let _ = std::fs::remove_file(avatar_dir.join(hex(&guest)));
+            }
+        }
+    }
+
+    // users who never set anything have an empty profile
+    pub fn info(&self, username: &str) -> ProfileInfo {
+        let profile = self.users.get(username).cloned().unwrap_or_default();
+        ProfileInfo {
+            username: username.to_string(),
+            display_name: profile.display_name,
+            bio: profile.bio,
+            timezone: profile.timezone,
+            avatar: profile.avatar,
+        }
+    }
+
+    // fields left as None keep their value, empty ones are cleared
+    pub fn update(
+        &mut self,
+        user: &str,
+        display_name: Option<String>,
+        bio: Option<String>,
+        timezone: Option<String>,
+    ) -> Result<ProfileInfo, String> {
+        let display_name = display_name
+            .map(|name| clean(&name, "display name", MAX_DISPLAY_NAME_CHARS))
+            .transpose()?;
+        let bio = bio.map(|bio| clean(&bio, "bio", MAX_BIO_CHARS)).transpose()?;
+        let timezone = timezone.map(|timezone| clean(&timezone, "timezone", MAX_DISPLAY_NAME_CHARS)).transpose()?;
+        if let Some(Some(timezone)) = &timezone {
+            if !valid_timezone(timezone) {
+                return Err(format!(
+                    "Unknown timezone {}, use a name like Europe/Berlin or an offset like +02:00!",
+                    timezone
+                ));
+            }
+        }
+        self.change(user, |profile| {
+            profile.display_name = display_name.unwrap_or(profile.display_name.take());
+            profile.bio = bio.unwrap_or(profile.bio.take());
+            profile.timezone = timezone.unwrap_or(profile.timezone.take());
+        })?;
+        Ok(self.info(user))
+    }
+
+    pub fn set_avatar(&mut self, user: &str, image: Vec<u8>) -> Result<ProfileInfo, String> {
+        let kind = check_avatar(&image)?;
+        match &self.avatar_dir {
+            Some(avatar_dir) => store::write(&avatar_dir.join(hex(user)), &image, "avatar")?,
+            None => {
+                self.avatars.insert(user.to_string(), image);
+            }
+        }
+        self.change(user, |profile| profile.avatar = Some(kind.to_string()))?;
+        Ok(self.info(user))
+    }
+
+    pub fn remove_avatar(&mut self, user: &str) -> Result<ProfileInfo, String> {
+        if self.users.get(user).is_none_or(|profile| profile.avatar.is_none()) {
+            return Err("You have no avatar!".to_string());
+        }
+        self.change(user, |profile| profile.avatar = None)?;
+        match &self.avatar_dir {
+            Some(avatar_dir) => {
+                let _ = std::fs::remove_file(avatar_dir.join(hex(user)));
+            }
+            None => {
+                self.avatars.remove(user);
+            }
+        }
+        Ok(self.info(user))
+    }
+
+    pub fn avatar(&self, user: &str) -> Result<Vec<u8>, String> {
+        let missing = || format!("{} has no avatar!", user);
+        if self.users.get(user).is_none_or(|profile| profile.avatar.is_none()) {
+            return Err(missing());
+        }
+        match &self.avatar_dir {
+            Some(avatar_dir) => std::fs::read(avatar_dir.join(hex(user)))
+                .map_err(|e| format!("Failed to read the avatar of {}: {}", user, e)),
+            None => self.avatars.get(user).cloned().ok_or_else(missing),
+        }
+    }
+
+    // the change is undone if it cannot be saved
+    fn change(&mut self, user: &str, change: impl FnOnce(&mut Profile)) -> Result<(), String> {
+        let previous = self.users.get(user).cloned();
+        change(self.users.entry(user.to_string()).or_default());
+        let result = self.save();
+        if result.is_err() {
+            match previous {
+                Some(previous) => self.users.insert(user.to_string(), previous),
+                None => self.users.remove(user),
+            };
+        }
+        result
+    }
+
+    fn save(&self) -> Result<(), String> {
+        match &self.path {
+            Some(path) => store::save(path, &self.users, "profiles"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{valid_timezone, Profiles};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_profiles() {
+        let dir = std::env::temp_dir().join(format!("profiles-{}", Uuid::new_v4()));
+        let path = dir.join("profiles.json");
+        let mut profiles = Profiles::load(Some(&path), Some(&dir.join("avatars"))).unwrap();
+        let alice = profiles
+            .update("alice", Some(" Alice Liddell ".to_string()), None, Some("Europe/London".to_string()))
+            .unwrap();
+        assert_eq!(alice.display_name.as_deref(), Some("Alice Liddell"));
+        assert!(profiles.update("alice", None, Some("x".repeat(281)), None).is_err());
+        assert!(profiles.update("alice", Some("tab\there".to_string()), None, None).is_err());
+        assert!(profiles.update("alice", None, None, Some("Mars".to_string())).is_err());
+        // empty fields are cleared, the others stay
+        let alice = profiles.update("alice", None, None, Some(String::new())).unwrap();
+        assert_eq!((alice.display_name.as_deref(), alice.timezone), (Some("Alice Liddell"), None));
+
+        let png = b"\x89PNG\r\n\x1a\navatar".to_vec();
+        assert!(profiles.set_avatar("alice", b"not an image".to_vec()).is_err());
+        assert_eq!(profiles.set_avatar("alice", png.clone()).unwrap().avatar.as_deref(), Some("png"));
+        assert!(profiles.avatar("bob").is_err());
+
+        let mut reloaded = Profiles::load(Some(&path), Some(&dir.join("avatars"))).unwrap();
+        assert_eq!(reloaded.info("alice"), profiles.info("alice"));
+        assert_eq!(reloaded.avatar("alice"), Ok(png));
+        assert_eq!(reloaded.remove_avatar("alice").unwrap().avatar, None);
+        assert!(reloaded.avatar("alice").is_err());
+        assert!(reloaded.remove_avatar("alice").is_err());
+
+        reloaded.set_avatar("alice", b"\x89PNG\r\n\x1a\nagain".to_vec()).unwrap();
+        reloaded.retain_accounts(|user| user != "alice");
+        assert_eq!(reloaded.info("alice").display_name, None);
+        assert!(!dir.join("avatars").join("616c696365").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(["UTC", "+02:00", "UTC-5", "America/Argentina/Buenos_Aires"].into_iter().all(valid_timezone));
+        assert!(!["+15:00", "UTC+2:75", "europe/berlin", "Berlin"].into_iter().any(valid_timezone));
+    }
+}
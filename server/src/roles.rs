@@ -0,0 +1,261 @@
+use crate::accounts::Accounts;
+use chrono::{DateTime, Duration, Utc};
+use common::communication::common_message::ClientToServerMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// ordered by privilege, each role may do what the ones before it may
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // may read and look around, but not post
+    Muted,
+    #[default]
+    Member,
+    // may kick and mute members and delete their messages
+    Moderator,
+    // may moderate moderators as well
+    Admin,
+}
+
+impl Role {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "muted" => Some(Role::Muted),
+            "member" => Some(Role::Member),
+            "moderator" | "mod" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Muted => "muted",
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+// the role a client needs for the message to be dispatched at all
+fn required(message: &ClientToServerMessage) -> Role {
+    match message {
+        ClientToServerMessage::TextTo(..)
+        | ClientToServerMessage::GroupTextTo(..)
+        | ClientToServerMessage::EditMessage(..)
+        | ClientToServerMessage::React(..)
+        | ClientToServerMessage::CreateGroup(_)
+        | ClientToServerMessage::AddToGroup(..)
+        | ClientToServerMessage::AddContact(_)
+        | ClientToServerMessage::SetProfile { .. }
+        | ClientToServerMessage::SetAvatar(_) => Role::Member,
+        ClientToServerMessage::Kick(_) | ClientToServerMessage::Mute(..) | ClientToServerMessage::Unmute(_) => {
+            Role::Moderator
+        }
+        _ => Role::Muted,
+    }
+}
+
+// the check for a session of `role`, muted until `muted_until` if at all, for those which do not
+// look the user up in Moderation themselves
+pub fn permit(
+    role: Role,
+    muted_until: Option<DateTime<Utc>>,
+    message: &ClientToServerMessage,
+    now: DateTime<Utc>,
+) -> Result<(), String> {
+    let muted_until = muted_until.filter(|until| *until > now);
+    let role = match muted_until {
+        Some(_) => Role::Muted,
+        None => role,
+    };
+    if role >= required(message) {
+        return Ok(());
+    }
+    if role > Role::Muted {
+        return Err("Only moderators may do that!".to_string());
+    }
+    match muted_until {
+        Some(until) => Err(format!("You are muted until {}!", until.format("%Y-%m-%d %H:%M UTC"))),
+        None => Err("You are muted!".to_string()),
+    }
+}
+
+// roles are kept with the accounts, mutes for a while only in memory
+#[derive(Debug, Default)]
+pub struct Moderation {
+    // accounts stay muted on every session, new ones included
+    accounts_muted_until: HashMap<String, DateTime<Utc>>,
+    // the sessions open when the mute was given, so renaming does not lift it; guests have nothing else
+    sessions_muted_until: HashMap<Uuid, DateTime<Utc>>,
+}
+
+impl Moderation {
+    // until when the session of `user` is muted, if it is
+    pub fn muted_until(&self, accounts: &Accounts, user: Option<&str>, session: Uuid, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let account = user
+            .filter(|user| accounts.exists(user))
+            .and_then(|user| self.accounts_muted_until.get(user));
+        account
+            .into_iter()
+            .chain(self.sessions_muted_until.get(&session))
+            .filter(|until| **until > now)
+            .max()
+            .copied()
+    }
+
+    // guests and bots are members, unless they are muted
+    pub fn role(&self, accounts: &Accounts, user: Option<&str>, session: Uuid, now: DateTime<Utc>) -> Role {
+        match self.muted_until(accounts, user, session, now) {
+            Some(_) => Role::Muted,
+            None => user.map_or(Role::default(), |user| accounts.role(user)),
+        }
+    }
+
+    // consulted before each message of the session of `user` is dispatched
+    pub fn check(
+        &self,
+        accounts: &Accounts,
+        user: Option<&str>,
+        session: Uuid,
+        message: &ClientToServerMessage,
+        now: DateTime<Utc>,
+    ) -> Result<(), String> {
+        let role = user.map(|user| accounts.role(user)).unwrap_or_default();
+        permit(role, self.muted_until(accounts, user, session, now), message, now)
+    }
+
+    // moderators only act on users of a lower role than their own
+    pub fn outranks(
+        &self,
+        accounts: &Accounts,
+        by: &str,
+        session: Uuid,
+        user: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), String> {
+        let role = self.role(accounts, Some(by), session, now);
+        if role >= Role::Moderator && role > accounts.role(user) {
+            Ok(())
+        } else {
+            Err(format!("You may not moderate {}!", user))
+        }
+    }
+
+    // mutes `user` on `sessions`, the ones they have open, and accounts on any they open later
+    #[allow(clippy::too_many_arguments)]
+    pub fn mute(
+        &mut self,
+        accounts: &Accounts,
+        by: &str,
+        by_session: Uuid,
+        user: &str,
+        sessions: &[Uuid],
+        minutes: u32,
+        now: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>, String> {
+        self.outranks(accounts, by, by_session, user, now)?;
+        if minutes == 0 {
+            return Err("Mute for at least a minute!".to_string());
+        }
+        if sessions.is_empty() && !accounts.exists(user) {
+            return Err(format!("{} is not online!", user));
+        }
+        self.accounts_muted_until.retain(|_, until| *until > now);
+        self.sessions_muted_until.retain(|_, until| *until > now);
+        let until = now + Duration::minutes(minutes.into());
+        if accounts.exists(user) {
+            self.accounts_muted_until.insert(user.to_string(), until);
+        }
+        for session in sessions {
+            self.sessions_muted_until.insert(*session, until);
+        }
+        Ok(until)
+    }
+
+    pub fn unmute(
+        &mut self,
+        accounts: &Accounts,
+        by: &str,
+        by_session: Uuid,
+        user: &str,
+        sessions: &[Uuid],
+        now: DateTime<Utc>,
+    ) -> Result<(), String> {
+        self.outranks(accounts, by, by_session, user, now)?;
+        let account = self.accounts_muted_until.remove(user);
+        let removed: Vec<_> = sessions.iter().filter_map(|session| self.sessions_muted_until.remove(session)).collect();
+        match account.into_iter().chain(removed).any(|until| until > now) {
+            true => Ok(()),
+            false => Err(format!("{} is not muted!", user)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Moderation, Role};
+    use crate::accounts::Accounts;
+    use chrono::{Duration, TimeZone, Utc};
+    use common::communication::common_message::ClientToServerMessage;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_moderation() {
+        let mut accounts = Accounts::load(None).unwrap();
+        for user in ["admin", "mod", "alice"] {
+            accounts.login(user, "secret").unwrap();
+        }
+        accounts.set_role("admin", Role::Admin).unwrap();
+        accounts.set_role("mod", Role::Moderator).unwrap();
+        assert!(accounts.set_role("guest", Role::Moderator).is_err());
+        let [admin, moderator, alice, guest] = [(); 4].map(|_| Uuid::new_v4());
+
+        let mut moderation = Moderation::default();
+        let now = Utc.with_ymd_and_hms(2024, 5, 31, 12, 0, 0).unwrap();
+        let text = ClientToServerMessage::TextTo("mod".to_string(), "hi".to_string(), None);
+        let kick = ClientToServerMessage::Kick("guest".to_string());
+        assert_eq!(moderation.check(&accounts, Some("guest"), guest, &text, now), Ok(()));
+        assert!(moderation.check(&accounts, Some("alice"), alice, &kick, now).is_err());
+        assert_eq!(moderation.check(&accounts, Some("mod"), moderator, &kick, now), Ok(()));
+
+        assert!(moderation.mute(&accounts, "mod", moderator, "nobody", &[], 10, now).is_err());
+        assert_eq!(moderation.mute(&accounts, "mod", moderator, "guest", &[guest], 10, now), Ok(now + Duration::minutes(10)));
+        assert_eq!(moderation.role(&accounts, Some("guest"), guest, now), Role::Muted);
+        assert_eq!(moderation.role(&accounts, None, guest, now), Role::Muted);
+        assert_eq!(
+            moderation.check(&accounts, Some("guest"), guest, &text, now),
+            Err("You are muted until 2024-05-31 12:10 UTC!".to_string())
+        );
+        // the mute stays with the session of the guest, whatever name it takes, and not with the name
+        assert!(moderation.check(&accounts, Some("renamed"), guest, &text, now).is_err());
+        let add = ClientToServerMessage::AddContact("alice".to_string());
+        assert!(moderation.check(&accounts, Some("guest"), guest, &add, now).is_err());
+        assert!(moderation.check(&accounts, None, guest, &text, now).is_err());
+        assert_eq!(moderation.check(&accounts, Some("guest"), Uuid::new_v4(), &text, now), Ok(()));
+        let search = ClientToServerMessage::GetThread(1);
+        assert_eq!(moderation.check(&accounts, Some("guest"), guest, &search, now), Ok(()));
+        // mutes run out by themselves
+        let later = now + Duration::minutes(10);
+        assert_eq!(moderation.check(&accounts, Some("guest"), guest, &text, later), Ok(()));
+        assert!(moderation.unmute(&accounts, "mod", moderator, "guest", &[guest], later).is_err());
+
+        // nobody moderates their equals or betters
+        assert!(moderation.mute(&accounts, "mod", moderator, "admin", &[admin], 5, now).is_err());
+        assert!(moderation.mute(&accounts, "mod", moderator, "mod", &[moderator], 5, now).is_err());
+        assert!(moderation.mute(&accounts, "alice", alice, "guest", &[guest], 5, now).is_err());
+        assert!(moderation.mute(&accounts, "admin", admin, "mod", &[moderator], 5, now).is_ok());
+        assert!(moderation.check(&accounts, Some("mod"), moderator, &kick, now).is_err());
+        // accounts stay muted on sessions they open later, and on the muted one under a guest name
+        assert!(moderation.check(&accounts, Some("mod"), Uuid::new_v4(), &text, now).is_err());
+        assert!(moderation.check(&accounts, Some("guest"), moderator, &text, now).is_err());
+        assert_eq!(moderation.unmute(&accounts, "admin", admin, "mod", &[moderator], now), Ok(()));
+        assert_eq!(moderation.check(&accounts, Some("mod"), moderator, &kick, now), Ok(()));
+
+        accounts.set_role("alice", Role::Muted).unwrap();
+        assert_eq!(moderation.check(&accounts, Some("alice"), alice, &text, now), Err("You are muted!".to_string()));
+    }
+}
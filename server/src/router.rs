@@ -0,0 +1,495 @@
+use crate::channel_message::MainToThreadsMessage;
+use crate::roles::Role;
+use chrono::{DateTime, Utc};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+// enough for the locks to be rarely contended on a machine with many cores
+const SHARDS: usize = 32;
+
+// how far a client may fall behind before it is disconnected, so that a client which stops
+// reading cannot make the server hold on to everything sent to it
+pub const OUTBOX_CAPACITY: usize = 1024;
+
+// the queue of a connection task, filled without waiting for the client to keep up
+#[derive(Clone, Debug)]
+pub struct Outbox {
+    tx: Sender<MainToThreadsMessage>,
+    overflowed: Arc<Notify>,
+}
+
+// what the connection task reads its outbox through
+#[derive(Debug)]
+pub struct Inbox {
+    rx: Receiver<MainToThreadsMessage>,
+    overflowed: Arc<Notify>,
+}
+
+pub fn outbox() -> (Outbox, Inbox) {
+    let (tx, rx) = channel(OUTBOX_CAPACITY);
+    let overflowed = Arc::new(Notify::new());
+    (Outbox { tx, overflowed: overflowed.clone() }, Inbox { rx, overflowed })
+}
+
+impl Outbox {
+    // never waits, a full queue tells the connection task to close the connection instead
+    pub fn send(&self, message: MainToThreadsMessage) -> Result<(), String> {
+        match self.tx.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.overflowed.notify_one();
+                Err("the client does not keep up".to_string())
+            }
+            Err(TrySendError::Closed(_)) => Err("the connection is closed".to_string()),
+        }
+    }
+}
+
+impl Inbox {
+    // FellBehind ahead of anything still queued once the outbox overflowed, None once every outbox is gone
+    pub async fn recv(&mut self) -> Option<MainToThreadsMessage> {
+        tokio::select! {
+            biased;
+            _ = self.overflowed.notified() => Some(MainToThreadsMessage::FellBehind),
+            message = self.rx.recv() => message,
+        }
+    }
+}
+
+// who is behind a connection, as far as its task needs to know to route texts on its own
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub username: String,
+    pub bot: bool,
+    // where the history the user may see starts
+    pub since: Option<DateTime<Utc>>,
+    pub role: Role,
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+struct Connection {
+    outbox: Outbox,
+    // kept up to date by the main loop, none until the client picked a name
+    identity: Option<Identity>,
+    // messages the connection task handed to the main loop which the main loop is not done with
+    queued: AtomicUsize,
+}
+
+// who is online with which sessions, and where to deliver to each session, split into shards
+// so that lookups and deliveries from many tasks at once only contend on the same shard
+pub struct Router {
+    hasher: RandomState,
+    // a username has several sessions when its account is logged in from several clients
+    sessions: Vec<RwLock<HashMap<String, Vec<Uuid>>>>,
+    connections: Vec<RwLock<HashMap<Uuid, Connection>>>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router {
+            hasher: RandomState::new(),
+            sessions: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            connections: (0..SHARDS).map(|_| RwLock::default()).collect(),
+        }
+    }
+}
+
+// held by the main loop while it handles a message a connection task handed to it
+pub struct Handling<'a> {
+    router: &'a Router,
+    connection: Uuid,
+}
+
+impl Drop for Handling<'_> {
+    fn drop(&mut self) {
+        let connections = self.router.connections[self.router.shard(&self.connection)].read().unwrap();
+        if let Some(connection) = connections.get(&self.connection) {
+            let _ = connection.queued.fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| queued.checked_sub(1));
+        }
+    }
+}
+
+impl Router {
+    fn shard<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        self.hasher.hash_one(key) as usize % SHARDS
+    }
+
+    pub fn open(&self, connection: Uuid, outbox: Outbox) {
+        let entry = Connection {
+            outbox,
+            identity: None,
+            queued: AtomicUsize::new(0),
+        };
+        self.connections[self.shard(&connection)].write().unwrap().insert(connection, entry);
+    }
+
+    pub fn close(&self, connection: Uuid) -> Option<Outbox> {
+        let removed = self.connections[self.shard(&connection)].write().unwrap().remove(&connection);
+        removed.map(|connection| connection.outbox)
+    }
+
+    // false once the connection is gone
+    pub fn deliver(&self, connection: Uuid, message: MainToThreadsMessage) -> bool {
+        let connections = self.connections[self.shard(&connection)].read().unwrap();
+        connections.get(&connection).is_some_and(|connection| connection.outbox.send(message).is_ok())
+    }
+
+    pub fn identify(&self, connection: Uuid, identity: Option<Identity>) {
+        if let Some(entry) = self.connections[self.shard(&connection)].write().unwrap().get_mut(&connection) {
+            entry.identity = identity;
+        }
+    }
+
+    pub fn identity(&self, connection: Uuid) -> Option<Identity> {
+        let connections = self.connections[self.shard(&connection)].read().unwrap();
+        connections.get(&connection).and_then(|connection| connection.identity.clone())
+    }
+
+    // counted by the connection task before it hands a message to the main loop, until handling is dropped
+    pub fn queue(&self, connection: Uuid) {
+        if let Some(connection) = self.connections[self.shard(&connection)].read().unwrap().get(&connection) {
+            connection.queued.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    pub fn handling(&self, connection: Uuid) -> Handling<'_> {
+        Handling { router: self, connection }
+    }
+
+    // whether the main loop is done with everything the connection task handed to it
+    pub fn caught_up(&self, connection: Uuid) -> bool {
+        let connections = self.connections[self.shard(&connection)].read().unwrap();
+        connections.get(&connection).is_some_and(|connection| connection.queued.load(Ordering::Acquire) == 0)
+    }
+
+    // to every session of the user, false if the user has none
+    pub fn deliver_to_user(&self, username: &str, message: MainToThreadsMessage) -> bool {
+        let sessions = self.sessions(username);
+        for connection in &sessions {
+            self.deliver(*connection, message.clone());
+        }
+        !sessions.is_empty()
+    }
+
+    pub fn is_online(&self, username: &str) -> bool {
+        self.sessions[self.shard(username)].read().unwrap().contains_key(username)
+    }
+
+    // empty for a user who is not online
+    pub fn sessions(&self, username: &str) -> Vec<Uuid> {
+        let sessions = self.sessions[self.shard(username)].read().unwrap();
+        sessions.get(username).cloned().unwrap_or_default()
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        self.sessions.iter()
+            .flat_map(|shard| shard.read().unwrap().keys().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn add_session(&self, username: &str, connection: Uuid) {
+        let mut sessions = self.sessions[self.shard(username)].write().unwrap();
+        sessions.entry(username.to_string()).or_default().push(connection);
+    }
+
+    // drops one session of a user, and the user once no session is left
+    pub fn forget_session(&self, username: &str, connection: Uuid) {
+        let mut sessions = self.sessions[self.shard(username)].write().unwrap();
+        if let Some(connections) = sessions.get_mut(username) {
+            connections.retain(|session| *session != connection);
+            if connections.is_empty() {
+                sessions.remove(username);
+            }
+        }
+    }
+
+    // all sessions of the user, who is offline afterwards
+    pub fn remove_user(&self, username: &str) -> Vec<Uuid> {
+        self.sessions[self.shard(username)].write().unwrap().remove(username).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{outbox, Identity, Router, OUTBOX_CAPACITY};
+    use crate::channel_message::MainToThreadsMessage;
+    use crate::roles::Role;
+    use common::communication::common_message::{ClientToServerMessage, ServerToClientMessage};
+    use futures_util::FutureExt;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::sync::mpsc::{channel, unbounded_channel, Sender, UnboundedReceiver, UnboundedSender};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_router() {
+        let router = Router::default();
+        let (alice, bob, bob_again) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let (alice_tx, mut alice_rx) = outbox();
+        let (bob_tx, mut bob_rx) = outbox();
+        router.open(alice, alice_tx);
+        router.open(bob, bob_tx);
+        router.add_session("alice", alice);
+        router.add_session("bob", bob);
+        router.add_session("bob", bob_again);
+        assert_eq!(router.sessions("bob"), vec![bob, bob_again]);
+        let mut usernames = router.usernames();
+        usernames.sort();
+        assert_eq!(usernames, vec!["alice", "bob"]);
+
+        // the second session of bob has no outbox yet and is skipped
+        assert!(router.deliver_to_user("bob", MainToThreadsMessage::Shutdown));
+        assert_eq!(bob_rx.recv().now_or_never(), Some(Some(MainToThreadsMessage::Shutdown)));
+        assert!(!router.deliver_to_user("carol", MainToThreadsMessage::Shutdown));
+
+        router.forget_session("bob", bob);
+        router.forget_session("bob", bob_again);
+        assert!(!router.is_online("bob") && router.is_online("alice"));
+        assert_eq!(router.remove_user("alice"), vec![alice]);
+        assert!(router.usernames().is_empty());
+
+        assert!(router.close(alice).is_some());
+        assert!(!router.deliver(alice, MainToThreadsMessage::Shutdown));
+        assert_eq!(alice_rx.recv().now_or_never(), Some(None));
+    }
+
+    // the main loop tells the connection task who its client is and when it is done with its messages
+    #[test]
+    fn test_router_identity_and_queue() {
+        let router = Router::default();
+        let alice = Uuid::new_v4();
+        let (tx, _rx) = outbox();
+        router.open(alice, tx);
+        assert_eq!(router.identity(alice), None);
+        let identity = Identity {
+            username: "alice".to_string(),
+            bot: false,
+            since: None,
+            role: Role::Member,
+            muted_until: None,
+        };
+        router.identify(alice, Some(identity.clone()));
+        assert_eq!(router.identity(alice), Some(identity));
+
+        assert!(router.caught_up(alice));
+        router.queue(alice);
+        router.queue(alice);
+        drop(router.handling(alice));
+        assert!(!router.caught_up(alice));
+        drop(router.handling(alice));
+        assert!(router.caught_up(alice));
+        // never below nothing queued, whatever the main loop handles for the connection
+        drop(router.handling(alice));
+        router.queue(alice);
+        assert!(!router.caught_up(alice));
+
+        router.close(alice);
+        assert!(!router.caught_up(alice));
+        assert_eq!(router.identity(alice), None);
+    }
+
+    // a client which does not read is cut off instead of having its queue grow
+    #[test]
+    fn test_router_disconnects_slow_clients() {
+        let router = Router::default();
+        let alice = Uuid::new_v4();
+        let (tx, mut rx) = outbox();
+        router.open(alice, tx);
+        for sequence in 0..OUTBOX_CAPACITY {
+            assert!(router.deliver(alice, MainToThreadsMessage::SendToClient(text(0, sequence))));
+        }
+        assert!(!router.deliver(alice, MainToThreadsMessage::SendToClient(text(0, OUTBOX_CAPACITY))));
+        assert_eq!(rx.recv().now_or_never(), Some(Some(MainToThreadsMessage::FellBehind)));
+    }
+
+    const USERS: usize = 256;
+    // each client also gets a response per message it sends, together no more than an outbox holds,
+    // as a client which falls further behind is disconnected
+    const MESSAGES_PER_USER: usize = 300;
+
+    fn text(from: usize, sequence: usize) -> ServerToClientMessage {
+        ServerToClientMessage::TextFrom(sequence as u64, format!("user{}", from), "hello there".to_string(), None)
+    }
+
+    // what the main loop is told
+    type ToMain = UnboundedSender<(String, Uuid, ClientToServerMessage)>;
+
+    // every user sends to the next one, as the frames a client would write to the socket
+    fn frames(user: usize) -> UnboundedReceiver<String> {
+        let (tx, rx) = unbounded_channel();
+        let message = ClientToServerMessage::TextTo(format!("user{}", (user + 1) % USERS), "hello there".to_string(), None);
+        for _ in 0..MESSAGES_PER_USER {
+            tx.send(serde_json::to_string(&message).unwrap()).unwrap();
+        }
+        rx
+    }
+
+    // what a connection task does with a frame of its client before the main loop sees it
+    fn parse(frame: &str) -> ClientToServerMessage {
+        serde_json::from_str(frame).unwrap()
+    }
+
+    // and with each message before writing it to the socket
+    fn write(message: &ServerToClientMessage) {
+        std::hint::black_box(serde_json::to_string(message).unwrap());
+    }
+
+    // the main loop owns the maps, looks up every recipient and waits on each connection's bounded channel,
+    // as it used to
+    async fn route_through_one_loop() -> Duration {
+        let (to_main_tx, mut to_main_rx): (ToMain, _) = unbounded_channel();
+        let mut username_to_uuid_map = HashMap::new();
+        let mut uuid_to_tx: HashMap<Uuid, Sender<MainToThreadsMessage>> = HashMap::new();
+        let mut connections = Vec::new();
+        for user in 0..USERS {
+            let (tx, rx) = channel(1);
+            let uuid = Uuid::new_v4();
+            username_to_uuid_map.insert(format!("user{}", user), vec![uuid]);
+            uuid_to_tx.insert(uuid, tx);
+            connections.push((user, uuid, rx, frames(user)));
+        }
+        let main = tokio::spawn(async move {
+            let mut message_id = 0;
+            while let Some((from, uuid, message)) = to_main_rx.recv().await {
+                let ClientToServerMessage::TextTo(to, text, _) = message else {
+                    continue;
+                };
+                message_id += 1;
+                let message = ServerToClientMessage::TextFrom(message_id, from, text, None);
+                for recipient in &username_to_uuid_map[&to] {
+                    uuid_to_tx[recipient].send(MainToThreadsMessage::SendToClient(message.clone())).await.unwrap();
+                }
+                let response = ServerToClientMessage::Response(Ok(format!("Sent message to {}", to)));
+                uuid_to_tx[&uuid].send(MainToThreadsMessage::SendToClient(response)).await.unwrap();
+            }
+        });
+        let start = Instant::now();
+        let connections: Vec<_> = connections.into_iter().map(|(user, uuid, mut rx, mut frames)| {
+            let to_main_tx = to_main_tx.clone();
+            tokio::spawn(async move {
+                let mut written = 0;
+                while written < 2 * MESSAGES_PER_USER {
+                    tokio::select! {
+                        Some(frame) = frames.recv() => to_main_tx.send((format!("user{}", user), uuid, parse(&frame))).unwrap(),
+                        Some(MainToThreadsMessage::SendToClient(message)) = rx.recv() => {
+                            write(&message);
+                            written += 1;
+                        }
+                    }
+                }
+            })
+        }).collect();
+        drop(to_main_tx);
+        for connection in connections {
+            connection.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+        main.await.unwrap();
+        elapsed
+    }
+
+    // the same traffic, routed by the sender's connection task itself, which looks up the recipient and fills
+    // its outbox through the router, as the server does; ids still come from one place, like the history
+    async fn route_through_router() -> Duration {
+        let router = Arc::new(Router::default());
+        let message_id = Arc::new(Mutex::new(0));
+        let mut connections = Vec::new();
+        for user in 0..USERS {
+            let (tx, rx) = outbox();
+            let uuid = Uuid::new_v4();
+            router.open(uuid, tx);
+            router.add_session(&format!("user{}", user), uuid);
+            connections.push((user, uuid, rx, frames(user)));
+        }
+        let start = Instant::now();
+        let connections: Vec<_> = connections.into_iter().map(|(user, uuid, mut rx, mut frames)| {
+            let (router, message_id) = (router.clone(), message_id.clone());
+            tokio::spawn(async move {
+                let mut written = 0;
+                while written < 2 * MESSAGES_PER_USER {
+                    tokio::select! {
+                        Some(frame) = frames.recv() => {
+                            let ClientToServerMessage::TextTo(to, text, _) = parse(&frame) else {
+                                continue;
+                            };
+                            let mut message_id = message_id.lock().unwrap();
+                            *message_id += 1;
+                            let message = ServerToClientMessage::TextFrom(*message_id, format!("user{}", user), text, None);
+                            router.deliver_to_user(&to, MainToThreadsMessage::SendToClient(message));
+                            drop(message_id);
+                            let response = ServerToClientMessage::Response(Ok(format!("Sent message to {}", to)));
+                            router.deliver(uuid, MainToThreadsMessage::SendToClient(response));
+                        }
+                        message = rx.recv() => match message {
+                            Some(MainToThreadsMessage::SendToClient(message)) => {
+                                write(&message);
+                                written += 1;
+                            }
+                            other => panic!("Unexpected {:?}", other),
+                        },
+                    }
+                }
+            })
+        }).collect();
+        for connection in connections {
+            connection.await.unwrap();
+        }
+        start.elapsed()
+    }
+
+    // the messages of each sender arrive in the order they were sent, with many senders at once
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_router_keeps_order_per_sender() {
+        let router = Arc::new(Router::default());
+        let (tx, mut rx) = outbox();
+        let uuid = Uuid::new_v4();
+        router.open(uuid, tx);
+        router.add_session("bob", uuid);
+        let senders: Vec<_> = (0..8).map(|user| {
+            let router = router.clone();
+            tokio::spawn(async move {
+                for sequence in 0..100 {
+                    router.deliver_to_user("bob", MainToThreadsMessage::SendToClient(text(user, sequence)));
+                }
+            })
+        }).collect();
+        for sender in senders {
+            sender.await.unwrap();
+        }
+        let mut last: HashMap<String, u64> = HashMap::new();
+        for _ in 0..8 * 100 {
+            let Some(MainToThreadsMessage::SendToClient(ServerToClientMessage::TextFrom(sequence, from, _, _))) = rx.recv().await else {
+                panic!("Unexpected message");
+            };
+            if let Some(previous) = last.insert(from, sequence) {
+                assert_eq!(previous + 1, sequence);
+            }
+        }
+    }
+
+    // a benchmark rather than a test, run with
+    // cargo test --release -p server bench_routing -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_routing() {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        let messages = (USERS * MESSAGES_PER_USER) as f64;
+        for _ in 0..3 {
+            let one_loop = runtime.block_on(route_through_one_loop());
+            let router = runtime.block_on(route_through_router());
+            println!(
+                "one loop: {:>10.0} messages/s, sharded router: {:>10.0} messages/s",
+                messages / one_loop.as_secs_f64(),
+                messages / router.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use crate::group::Groups;
+use crate::history::{Conversation, History, StoredMessage};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use common::communication::common_message::{SearchHit, SearchQuery, SearchResults};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+const PAGE_SIZE: usize = 10;
+// messages shown around each hit, on either side
+const CONTEXT_MESSAGES: usize = 1;
+
+// words are compared in lowercase, anything but letters and digits separates them
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// the ids of the messages containing each word, kept up to date as messages are stored and changed
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    postings: HashMap<String, BTreeSet<u64>>,
+}
+
+impl SearchIndex {
+    pub fn insert(&mut self, id: u64, text: &str) {
+        for word in words(text) {
+            self.postings.entry(word).or_default().insert(id);
+        }
+    }
+
+    pub fn remove(&mut self, id: u64, text: &str) {
+        for word in words(text) {
+            if let Some(ids) = self.postings.get_mut(&word) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+    }
+
+    // the messages containing every word of `terms`
+    pub fn matching(&self, terms: &str) -> BTreeSet<u64> {
+        let mut postings: Vec<&BTreeSet<u64>> = Vec::new();
+        for word in words(terms) {
+            match self.postings.get(&word) {
+                Some(ids) => postings.push(ids),
+                None => return BTreeSet::new(),
+            }
+        }
+        // intersecting from the rarest word on keeps the sets small
+        postings.sort_by_key(|ids| ids.len());
+        let Some((rarest, others)) = postings.split_first() else {
+            return BTreeSet::new();
+        };
+        rarest
+            .iter()
+            .filter(|id| others.iter().all(|ids| ids.contains(id)))
+            .copied()
+            .collect()
+    }
+}
+
+fn parse_time(text: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| format!("{} is neither a date like 2024-05-31 nor an RFC 3339 time!", text))
+}
+
+// runs a query of `requester`, who only finds messages of conversations they are part of,
+// sent since `since` if given
+pub fn search(
+    history: &History,
+    groups: &Groups,
+    requester: &str,
+    since: Option<DateTime<Utc>>,
+    query: &SearchQuery,
+) -> Result<SearchResults, String> {
+    if words(&query.terms).is_empty() {
+        return Err("Search for at least one word!".to_string());
+    }
+    let after = query.after.as_deref().map(parse_time).transpose()?;
+    let before = query.before.as_deref().map(parse_time).transpose()?;
+    let conversation = query.conversation.as_deref().map(|name| Conversation::named(requester, name));
+
+    let visible = |message: &StoredMessage| {
+        message.sent_since(since) && message.conversation.participants(requester, groups).is_some()
+    };
+    let hits: Vec<&StoredMessage> = history
+        .matching(&query.terms)
+        .rev()
+        .filter(|message| query.from.as_ref().is_none_or(|from| message.from == *from))
+        .filter(|message| conversation.as_ref().is_none_or(|c| message.conversation == *c))
+        .filter(|message| after.is_none_or(|after| message.sent_at >= after))
+        .filter(|message| before.is_none_or(|before| message.sent_at < before))
+        .filter(|message| visible(message))
+        .collect();
+
+    let total = hits.len();
+    let pages = total.div_ceil(PAGE_SIZE).max(1) as u32;
+    let page = query.page.max(1);
+    let hits = hits
+        .into_iter()
+        .skip((page as usize - 1) * PAGE_SIZE)
+        .take(PAGE_SIZE)
+        .map(|message| {
+            let (before, after) = history.context(message, CONTEXT_MESSAGES, since);
+            SearchHit {
+                conversation: message.conversation.name_for(requester),
+                sent_at: message.sent_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                message: message.info(),
+                before: before.into_iter().map(StoredMessage::info).collect(),
+                after: after.into_iter().map(StoredMessage::info).collect(),
+            }
+        })
+        .collect();
+    Ok(SearchResults {
+        hits,
+        page,
+        pages,
+        total,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{search, SearchIndex};
+    use crate::group::Groups;
+    use crate::history::{Conversation, History};
+    use chrono::{Duration, TimeZone, Utc};
+    use common::communication::common_message::SearchQuery;
+
+    #[test]
+    fn test_index() {
+        let mut index = SearchIndex::default();
+        index.insert(1, "Lunch at noon?");
+        index.insert(2, "lunch, then the release");
+        assert_eq!(index.matching("LUNCH").into_iter().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(index.matching("lunch release").into_iter().collect::<Vec<_>>(), vec![2]);
+        assert!(index.matching("dinner lunch").is_empty());
+        assert!(index.matching("?!").is_empty());
+
+        index.remove(2, "lunch, then the release");
+        assert_eq!(index.matching("lunch").into_iter().collect::<Vec<_>>(), vec![1]);
+        assert!(index.matching("release").is_empty());
+    }
+
+    fn query(terms: &str) -> SearchQuery {
+        SearchQuery {
+            terms: terms.to_string(),
+            page: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let mut groups = Groups::default();
+        let group = groups.create("bob", &["carol".to_string()]).unwrap().id;
+        let mut history = History::default();
+        let start = Utc.with_ymd_and_hms(2024, 5, 31, 12, 0, 0).unwrap();
+        let direct = Conversation::direct("alice", "bob");
+        for minute in 0..12 {
+            let time = start + Duration::minutes(minute);
+            history.add(direct.clone(), "alice", &format!("deploy number {}", minute), None, time).unwrap();
+        }
+        history.add(direct.clone(), "bob", "no", None, start + Duration::days(1)).unwrap();
+        history.add(Conversation::Group(group.clone()), "bob", "deploy secret", None, start).unwrap();
+
+        let results = search(&history, &groups, "alice", None, &query("Deploy")).unwrap();
+        assert_eq!((results.total, results.pages, results.hits.len()), (12, 2, 10));
+        let newest = &results.hits[0];
+        assert_eq!(newest.message.text, "deploy number 11");
+        assert_eq!(newest.conversation, "bob");
+        assert_eq!(newest.sent_at, "2024-05-31T12:11:00Z");
+        assert_eq!(newest.before[0].text, "deploy number 10");
+        assert_eq!(newest.after[0].text, "no");
+
+        let second = search(&history, &groups, "alice", None, &SearchQuery { page: 2, ..query("deploy") }).unwrap();
+        assert_eq!(second.hits.last().unwrap().message.text, "deploy number 0");
+        assert!(second.hits.last().unwrap().before.is_empty());
+
+        let filtered = SearchQuery {
+            after: Some("2024-05-31T12:05:00Z".to_string()),
+            before: Some("2024-05-31T12:07:00+00:00".to_string()),
+            ..query("number")
+        };
+        assert_eq!(search(&history, &groups, "bob", None, &filtered).unwrap().total, 2);
+        let by_bob = SearchQuery {
+            from: Some("bob".to_string()),
+            ..query("deploy")
+        };
+        assert_eq!(search(&history, &groups, "bob", None, &by_bob).unwrap().hits[0].conversation, group);
+        let in_group = SearchQuery {
+            conversation: Some(group.clone()),
+            ..query("deploy")
+        };
+        assert_eq!(search(&history, &groups, "carol", None, &in_group).unwrap().total, 1);
+
+        // nobody finds what was said where they are not part of the conversation
+        assert_eq!(search(&history, &groups, "carol", None, &query("number")).unwrap().total, 0);
+        assert_eq!(search(&history, &groups, "alice", None, &in_group).unwrap().total, 0);
+        // nor what was said before they could see it
+        let since = Some(start + Duration::minutes(6));
+        let recent = search(&history, &groups, "alice", since, &query("deploy")).unwrap();
+        assert_eq!(recent.total, 6);
+        assert_eq!(recent.hits[5].message.text, "deploy number 6");
+        assert!(recent.hits[5].before.is_empty());
+
+        assert!(search(&history, &groups, "alice", None, &query("")).is_err());
+        let invalid = SearchQuery {
+            after: Some("yesterday".to_string()),
+            ..query("deploy")
+        };
+        assert!(search(&history, &groups, "alice", None, &invalid).is_err());
+    }
+}
@@ -0,0 +1,35 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+
+// a missing file is fine, it is created with the first change
+pub fn load<T: DeserializeOwned + Default>(path: &Path, what: &str) -> Result<T, String> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Invalid {} file {}: {}", what, path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+pub fn save<T: Serialize>(path: &Path, value: &T, what: &str) -> Result<(), String> {
+    write(path, serde_json::to_string_pretty(value).unwrap().as_bytes(), what)
+}
+
+// for files which only ever grow; a crash may cut the appended bytes short
+pub fn append(path: &Path, bytes: &[u8], what: &str) -> Result<(), String> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(bytes))
+        .map_err(|e| format!("Failed to save the {}: {}", what, e))
+}
+
+// written to a temporary file first, so a crash never leaves half a file behind
+pub fn write(path: &Path, bytes: &[u8], what: &str) -> Result<(), String> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, bytes)
+        .and_then(|_| std::fs::rename(&temporary, path))
+        .map_err(|e| format!("Failed to save the {}: {}", what, e))
+}
@@ -0,0 +1,256 @@
+use crate::channel_message::MainToThreadsMessage;
+use crate::cluster::Cluster;
+use crate::config::OutgoingWebhook;
+use crate::contacts::Contacts;
+use crate::group::Groups;
+use crate::history::{Conversation, History, StoredMessage};
+use crate::privacy::Privacy;
+use crate::roles;
+use crate::router::{Identity, Router};
+use crate::webhook;
+use chrono::{DateTime, Utc};
+use common::communication::common_message::{ClientToServerMessage, ServerToClientMessage};
+use std::sync::{Arc, Mutex, RwLock};
+use uuid::Uuid;
+
+// what it takes to route a text, shared between the main loop and the connection tasks, so that texts
+// are checked, stored and delivered by the task of their sender without a detour through the main loop;
+// only the main loop changes any of it apart from the history
+#[derive(Clone)]
+pub struct Texts {
+    pub router: Arc<Router>,
+    pub cluster: Arc<Cluster>,
+    pub history: Arc<Mutex<History>>,
+    pub privacy: Arc<RwLock<Privacy>>,
+    pub contacts: Arc<RwLock<Contacts>>,
+    pub groups: Arc<RwLock<Groups>>,
+    pub hooks: Arc<Vec<OutgoingWebhook>>,
+}
+
+// replies may only refer to messages the sender can see
+fn check_reply(history: &History, reply_to: Option<u64>, since: Option<DateTime<Utc>>) -> Result<(), String> {
+    match reply_to {
+        Some(parent) if !history.get(parent).is_some_and(|stored| stored.sent_since(since)) => {
+            Err(format!("There is no message {} in this conversation!", parent))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Texts {
+    // the response to a text the connection task routed itself, None for what is left to the main loop:
+    // anything but texts, texts for other servers or which may be commands for the plugins, and every text
+    // while the main loop still has messages of the connection to handle, which the text would overtake
+    pub fn route(&self, connection: Uuid, message: &ClientToServerMessage) -> Option<Result<String, String>> {
+        let own = match message {
+            ClientToServerMessage::TextTo(to, text, _) => !to.contains('@') && !text.starts_with('/'),
+            ClientToServerMessage::GroupTextTo(..) => true,
+            _ => false,
+        };
+        if !own || !self.router.caught_up(connection) {
+            return None;
+        }
+        let identity = self.router.identity(connection)?;
+        if let Err(e) = roles::permit(identity.role, identity.muted_until, message, Utc::now()) {
+            return Some(Err(e));
+        }
+        match message {
+            ClientToServerMessage::TextTo(to, text, reply_to) => Some(self.send(&identity, to, text, *reply_to)),
+            ClientToServerMessage::GroupTextTo(id, text, reply_to) => {
+                Some(self.send_to_group(&identity, id, text, *reply_to))
+            }
+            _ => None,
+        }
+    }
+
+    // to a user of this cluster, or whoever listens on the outgoing webhooks for the name
+    pub fn send(&self, sender: &Identity, to: &str, text: &str, reply_to: Option<u64>) -> Result<String, String> {
+        let is_contact = self.contacts.read().unwrap().are_contacts(to, &sender.username);
+        self.privacy.read().unwrap().may_message(&sender.username, to, is_contact)?;
+        let hooks: Vec<_> = self.hooks.iter().filter(|hook| hook.target == to).collect();
+        if !self.router.is_online(to) && !self.cluster.is_online(to) && hooks.is_empty() {
+            return Err("Recipient does not exist!".to_string());
+        }
+
+        // delivered before the history is let go, so that everyone gets messages in the order of their ids
+        let mut history = self.history.lock().unwrap();
+        check_reply(&history, reply_to, sender.since)?;
+        let conversation = Conversation::direct(&sender.username, to);
+        let message_id = history.add(conversation, &sender.username, text, reply_to, Utc::now())?;
+        let reply = reply_to.and_then(|parent| history.get(parent)).map(StoredMessage::reply_info);
+
+        for hook in hooks {
+            webhook::post_message(hook.url.clone(), &sender.username, to, text);
+        }
+
+        let message = if sender.bot {
+            ServerToClientMessage::BotTextFrom(Some(message_id), sender.username.clone(), text.to_string(), reply.clone())
+        } else {
+            ServerToClientMessage::TextFrom(message_id, sender.username.clone(), text.to_string(), reply.clone())
+        };
+        // all clients of the sender show the message as sent by them, with its id
+        let sent = ServerToClientMessage::SentText(message_id, to.to_string(), text.to_string(), reply);
+        self.router.deliver_to_user(&sender.username, MainToThreadsMessage::SendToClient(sent));
+        self.cluster.forward(to, &message);
+        self.router.deliver_to_user(to, MainToThreadsMessage::SendToClient(message));
+        Ok(format!("Sent message to {}", to))
+    }
+
+    pub fn send_to_group(&self, sender: &Identity, id: &str, text: &str, reply_to: Option<u64>) -> Result<String, String> {
+        let members = self.groups.read().unwrap().members(id, &sender.username)?.to_vec();
+        let recipients: Vec<String> = {
+            let privacy = self.privacy.read().unwrap();
+            members.into_iter().filter(|member| !privacy.blocks(member, &sender.username)).collect()
+        };
+
+        let mut history = self.history.lock().unwrap();
+        check_reply(&history, reply_to, sender.since)?;
+        let conversation = Conversation::Group(id.to_string());
+        let message_id = history.add(conversation, &sender.username, text, reply_to, Utc::now())?;
+        let reply = reply_to.and_then(|parent| history.get(parent)).map(StoredMessage::reply_info);
+
+        // the sender's sessions get the message too, so they learn its id;
+        // sessions on other nodes get it through the backplane
+        let message = ServerToClientMessage::GroupTextFrom(message_id, id.to_string(), sender.username.clone(), text.to_string(), reply);
+        for member in recipients {
+            self.cluster.forward(&member, &message);
+            self.router.deliver_to_user(&member, MainToThreadsMessage::SendToClient(message.clone()));
+        }
+        Ok(format!("Sent message to {}", id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Texts;
+    use crate::channel_message::MainToThreadsMessage;
+    use crate::cluster::Cluster;
+    use crate::router::{outbox, Identity, Router, OUTBOX_CAPACITY};
+    use crate::roles::Role;
+    use chrono::{Duration, Utc};
+    use common::communication::common_message::{ClientToServerMessage, ServerToClientMessage};
+    use futures_util::FutureExt;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn identity(username: &str) -> Identity {
+        Identity {
+            username: username.to_string(),
+            bot: false,
+            since: None,
+            role: Role::Member,
+            muted_until: None,
+        }
+    }
+
+    fn texts() -> Texts {
+        Texts {
+            router: Arc::new(Router::default()),
+            cluster: Arc::new(Cluster::new(None)),
+            history: Default::default(),
+            privacy: Default::default(),
+            contacts: Default::default(),
+            groups: Default::default(),
+            hooks: Default::default(),
+        }
+    }
+
+    // the connection task of the sender routes texts itself, unless the main loop has to see them first
+    #[test]
+    fn test_route() {
+        let texts = texts();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let (alice_tx, mut alice_rx) = outbox();
+        let (bob_tx, mut bob_rx) = outbox();
+        texts.router.open(alice, alice_tx);
+        texts.router.open(bob, bob_tx);
+        texts.router.add_session("bob", bob);
+        let text = |to: &str, text: &str| ClientToServerMessage::TextTo(to.to_string(), text.to_string(), None);
+
+        // nameless clients are told off by the main loop
+        assert_eq!(texts.route(alice, &text("bob", "hi")), None);
+        texts.router.add_session("alice", alice);
+        texts.router.identify(alice, Some(identity("alice")));
+        assert_eq!(texts.route(alice, &ClientToServerMessage::GetContacts), None);
+        assert_eq!(texts.route(alice, &text("bob@elsewhere", "hi")), None);
+        assert_eq!(texts.route(alice, &text("bob", "/echo hi")), None);
+
+        assert_eq!(texts.route(alice, &text("bob", "hi")), Some(Ok("Sent message to bob".to_string())));
+        assert_eq!(bob_rx.recv().now_or_never(), Some(Some(MainToThreadsMessage::SendToClient(
+            ServerToClientMessage::TextFrom(1, "alice".to_string(), "hi".to_string(), None)))));
+        assert_eq!(alice_rx.recv().now_or_never(), Some(Some(MainToThreadsMessage::SendToClient(
+            ServerToClientMessage::SentText(1, "bob".to_string(), "hi".to_string(), None)))));
+        assert_eq!(texts.route(alice, &text("carol", "hi")), Some(Err("Recipient does not exist!".to_string())));
+
+        // a text must not overtake what the main loop was handed before it
+        texts.router.queue(alice);
+        assert_eq!(texts.route(alice, &text("bob", "hi")), None);
+        drop(texts.router.handling(alice));
+
+        let muted = Identity {
+            muted_until: Some(Utc::now() + Duration::minutes(5)),
+            ..identity("alice")
+        };
+        texts.router.identify(alice, Some(muted));
+        assert!(texts.route(alice, &text("bob", "hi")).unwrap().is_err_and(|e| e.starts_with("You are muted")));
+        assert!(bob_rx.recv().now_or_never().is_none());
+    }
+
+    // the sender falling behind or going away costs the recipients nothing, texts go straight into their outboxes
+    #[test]
+    fn test_texts_survive_their_sender() {
+        let texts = texts();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let (alice_tx, alice_rx) = outbox();
+        let (bob_tx, mut bob_rx) = outbox();
+        texts.router.open(alice, alice_tx);
+        texts.router.open(bob, bob_tx);
+        texts.router.add_session("alice", alice);
+        texts.router.add_session("bob", bob);
+        texts.router.identify(alice, Some(identity("alice")));
+
+        // alice never reads, her own copies overflow her outbox halfway through
+        for _ in 0..OUTBOX_CAPACITY / 2 {
+            texts.router.deliver(alice, MainToThreadsMessage::SendToClient(ServerToClientMessage::None));
+        }
+        let count = OUTBOX_CAPACITY - 1;
+        for sequence in 0..count {
+            let message = ClientToServerMessage::TextTo("bob".to_string(), sequence.to_string(), None);
+            assert_eq!(texts.route(alice, &message), Some(Ok("Sent message to bob".to_string())));
+        }
+        // and then she is gone
+        drop(alice_rx);
+        texts.router.close(alice);
+        texts.send(&identity("alice"), "bob", "last", None).unwrap();
+
+        for sequence in 0..count {
+            let Some(Some(MainToThreadsMessage::SendToClient(ServerToClientMessage::TextFrom(_, _, text, _)))) =
+                bob_rx.recv().now_or_never() else {
+                panic!("Expected text {}", sequence);
+            };
+            assert_eq!(text, sequence.to_string());
+        }
+        assert!(matches!(bob_rx.recv().now_or_never(),
+            Some(Some(MainToThreadsMessage::SendToClient(ServerToClientMessage::TextFrom(_, _, text, _)))) if text == "last"));
+    }
+
+    #[test]
+    fn test_send_to_group() {
+        let texts = texts();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let (alice_tx, mut alice_rx) = outbox();
+        let (bob_tx, mut bob_rx) = outbox();
+        texts.router.open(alice, alice_tx);
+        texts.router.open(bob, bob_tx);
+        texts.router.add_session("alice", alice);
+        texts.router.add_session("bob", bob);
+        let group = texts.groups.write().unwrap().create("alice", &["bob".to_string()]).unwrap();
+
+        assert!(texts.send_to_group(&identity("carol"), &group.id, "hi", None).is_err());
+        assert_eq!(texts.send_to_group(&identity("alice"), &group.id, "hi", None), Ok(format!("Sent message to {}", group.id)));
+        let expected = ServerToClientMessage::GroupTextFrom(1, group.id.clone(), "alice".to_string(), "hi".to_string(), None);
+        assert_eq!(bob_rx.recv().now_or_never(), Some(Some(MainToThreadsMessage::SendToClient(expected.clone()))));
+        assert_eq!(alice_rx.recv().now_or_never(), Some(Some(MainToThreadsMessage::SendToClient(expected))));
+        assert!(texts.send_to_group(&identity("alice"), &group.id, "hi", Some(7)).is_err());
+    }
+}
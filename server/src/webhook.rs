@@ -0,0 +1,389 @@
+use crate::channel_message::{MainToThreadsMessage, ThreadsToMainMessage};
+use crate::config::IncomingWebhook;
+use crate::console::console_println;
+use crate::router::Inbox;
+use chrono::{SecondsFormat, Utc};
+use common::communication::common_message::{ClientToServerMessage, ServerToClientMessage};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+const MAX_HEADERS: usize = 32;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+struct HttpRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        HttpError {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+// body of a POST to the incoming endpoint
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IncomingMessage {
+    to: String,
+    text: String,
+}
+
+// Ok(None) while the request is still incomplete
+fn parse_request(buffer: &[u8]) -> Result<Option<HttpRequest>, HttpError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    let header_length = match request.parse(buffer) {
+        Ok(httparse::Status::Complete(length)) => length,
+        Ok(httparse::Status::Partial) => return Ok(None),
+        Err(e) => return Err(HttpError::new(400, format!("Malformed request: {}", e))),
+    };
+
+    let headers: Vec<(String, String)> = request
+        .headers
+        .iter()
+        .map(|h| {
+            (
+                h.name.to_string(),
+                String::from_utf8_lossy(h.value).trim().to_string(),
+            )
+        })
+        .collect();
+    let content_length = match headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+    {
+        Some((_, value)) => value
+            .parse::<usize>()
+            .map_err(|_| HttpError::new(400, "Invalid Content-Length"))?,
+        None => 0,
+    };
+    if header_length + content_length > MAX_REQUEST_SIZE {
+        return Err(HttpError::new(413, "Request too large"));
+    }
+    if buffer.len() < header_length + content_length {
+        return Ok(None);
+    }
+
+    Ok(Some(HttpRequest {
+        method: request.method.unwrap_or_default().to_string(),
+        path: request.path.unwrap_or_default().to_string(),
+        headers,
+        body: buffer[header_length..header_length + content_length].to_vec(),
+    }))
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, HttpError> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(request) = parse_request(&buffer)? {
+            return Ok(request);
+        }
+        if buffer.len() >= MAX_REQUEST_SIZE {
+            return Err(HttpError::new(413, "Request too large"));
+        }
+        let read = stream
+            .read(&mut chunk)
+            .await
+            .map_err(|e| HttpError::new(400, e.to_string()))?;
+        if read == 0 {
+            return Err(HttpError::new(400, "Incomplete request"));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+// compares without stopping at the first difference, so response times do not leak the token
+pub fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// POST /hooks/<bot> with "Authorization: Bearer <token>"
+fn authenticate<'a>(
+    hooks: &'a [IncomingWebhook],
+    request: &HttpRequest,
+) -> Result<&'a IncomingWebhook, HttpError> {
+    let Some(bot) = request.path.strip_prefix("/hooks/") else {
+        return Err(HttpError::new(404, "Not found"));
+    };
+    if request.method != "POST" {
+        return Err(HttpError::new(405, "Only POST is supported"));
+    }
+    let token = request
+        .header("authorization")
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    hooks
+        .iter()
+        .find(|hook| hook.bot == bot && same_token(&hook.token, token))
+        .ok_or_else(|| HttpError::new(401, "Unknown hook or wrong token"))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+async fn write_response(stream: &mut TcpStream, status: u16, body: serde_json::Value) {
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        console_println!("Failed to answer webhook request: {}", e);
+    }
+    let _ = stream.shutdown().await;
+}
+
+// checks the request here and lets the main loop deliver the message like one sent by a client
+async fn deliver(
+    stream: &mut TcpStream,
+    connection_id: Uuid,
+    main_to_thread_rx: &mut Inbox,
+    thread_to_main_tx: &Sender<ThreadsToMainMessage>,
+    hooks: &[IncomingWebhook],
+) -> Result<String, HttpError> {
+    let request = timeout(TIMEOUT, read_request(stream))
+        .await
+        .map_err(|_| HttpError::new(408, "Timed out reading the request"))??;
+    let hook = authenticate(hooks, &request)?;
+    let message: IncomingMessage = serde_json::from_slice(&request.body)
+        .map_err(|e| HttpError::new(400, format!("Invalid body: {}", e)))?;
+
+    console_println!("Webhook {} posts to {}", hook.bot, message.to);
+    thread_to_main_tx
+        .send(ThreadsToMainMessage::ReceivedFromWebhook(
+            hook.bot.clone(),
+            ClientToServerMessage::TextTo(message.to, message.text, None),
+            connection_id,
+        ))
+        .await.expect("Failed to send message to main thread");
+
+    // anything sent before the response, like a copy of the message, is of no interest here
+    loop {
+        match main_to_thread_rx.recv().await {
+            Some(MainToThreadsMessage::SendToClient(ServerToClientMessage::Response(result))) => {
+                return result.map_err(|e| HttpError::new(422, e));
+            }
+            Some(MainToThreadsMessage::SendToClient(_)) => continue,
+            _ => return Err(HttpError::new(503, "Server is shutting down")),
+        }
+    }
+}
+
+pub async fn handle_request(
+    mut stream: TcpStream,
+    connection_id: Uuid,
+    mut main_to_thread_rx: Inbox,
+    thread_to_main_tx: Sender<ThreadsToMainMessage>,
+    hooks: Arc<Vec<IncomingWebhook>>,
+) {
+    let result = deliver(
+        &mut stream,
+        connection_id,
+        &mut main_to_thread_rx,
+        &thread_to_main_tx,
+        &hooks,
+    )
+    .await;
+    match result {
+        Ok(message) => write_response(&mut stream, 200, json!({ "ok": true, "message": message })).await,
+        Err(e) => {
+            console_println!("Rejected webhook request {}: {}", connection_id, e.message);
+            write_response(&mut stream, e.status, json!({ "ok": false, "error": e.message })).await
+        }
+    }
+    thread_to_main_tx
+        .send(ThreadsToMainMessage::ConnectionClosed(connection_id))
+        .await.expect("Failed to send shutdown signal");
+}
+
+// splits http://host[:port]/path into the address to connect to, the Host header and the path
+fn parse_url(url: &str) -> Result<(String, String, String), String> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Err(format!("Only http:// webhook URLs are supported, got {}", url));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(format!("Missing host in {}", url));
+    }
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Ok((address, authority.to_string(), path.to_string()))
+}
+
+async fn post_json(url: &str, body: &str) -> Result<(), String> {
+    let (address, host, path) = parse_url(url)?;
+    let mut stream = TcpStream::connect(&address)
+        .await
+        .map_err(|e| e.to_string())?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+    loop {
+        let read = stream.read(&mut chunk).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Err("Connection closed before a response".to_string());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        match response.parse(&buffer) {
+            Ok(httparse::Status::Complete(_)) => {
+                let status = response.code.unwrap_or_default();
+                return if (200..300).contains(&status) {
+                    Ok(())
+                } else {
+                    Err(format!("Responded with status {}", status))
+                };
+            }
+            Ok(httparse::Status::Partial) if buffer.len() < MAX_REQUEST_SIZE => {}
+            Ok(httparse::Status::Partial) => return Err("Response too large".to_string()),
+            Err(e) => return Err(format!("Malformed response: {}", e)),
+        }
+    }
+}
+
+// fire and forget, a slow or broken receiver must not hold up the chat
+pub fn post_message(url: String, from: &str, to: &str, text: &str) {
+    let body = json!({
+        "event": "message",
+        "from": from,
+        "to": to,
+        "text": text,
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    })
+    .to_string();
+    tokio::spawn(async move {
+        match timeout(TIMEOUT, post_json(&url, &body)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => console_println!("Webhook {} failed: {}", url, e),
+            Err(_) => console_println!("Webhook {} timed out", url),
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::{authenticate, parse_request, parse_url};
+    use crate::config::IncomingWebhook;
+
+    #[test]
+    fn test_parse_request() {
+        let request = b"POST /hooks/ci HTTP/1.1\r\nHost: x\r\nContent-Length: 11\r\nAuthorization: Bearer s3cret\r\n\r\n{\"to\":\"a\"}";
+        assert_eq!(parse_request(request), Ok(None));
+
+        let mut request = request.to_vec();
+        request.push(b' ');
+        let parsed = parse_request(&request).unwrap().unwrap();
+        assert_eq!(parsed.method, "POST");
+        assert_eq!(parsed.path, "/hooks/ci");
+        assert_eq!(parsed.header("authorization"), Some("Bearer s3cret"));
+        assert_eq!(parsed.body, b"{\"to\":\"a\"} ");
+
+        assert_eq!(parse_request(b"GET / HTTP/1.1\r\n").unwrap(), None);
+        assert_eq!(parse_request(b"\x01 nonsense\r\n\r\n").unwrap_err().status, 400);
+        assert_eq!(
+            parse_request(b"POST / HTTP/1.1\r\nContent-Length: 999999\r\n\r\n")
+                .unwrap_err()
+                .status,
+            413
+        );
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let hooks = vec![IncomingWebhook {
+            bot: "ci".to_string(),
+            token: "s3cret".to_string(),
+        }];
+        let request = |method: &str, path: &str, token: &str| {
+            let text = format!(
+                "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+                method, path, token
+            );
+            parse_request(text.as_bytes()).unwrap().unwrap()
+        };
+        assert_eq!(authenticate(&hooks, &request("POST", "/hooks/ci", "s3cret")).unwrap().bot, "ci");
+        assert_eq!(authenticate(&hooks, &request("POST", "/hooks/ci", "guess")).unwrap_err().status, 401);
+        assert_eq!(authenticate(&hooks, &request("POST", "/hooks/cd", "s3cret")).unwrap_err().status, 401);
+        assert_eq!(authenticate(&hooks, &request("GET", "/hooks/ci", "s3cret")).unwrap_err().status, 405);
+        assert_eq!(authenticate(&hooks, &request("POST", "/other", "s3cret")).unwrap_err().status, 404);
+    }
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("http://127.0.0.1:9000/build/hook"),
+            Ok(("127.0.0.1:9000".to_string(), "127.0.0.1:9000".to_string(), "/build/hook".to_string()))
+        );
+        assert_eq!(
+            parse_url("http://localhost"),
+            Ok(("localhost:80".to_string(), "localhost".to_string(), "/".to_string()))
+        );
+        assert!(parse_url("https://example.com/").is_err());
+        assert!(parse_url("http:///path").is_err());
+    }
+}